@@ -0,0 +1,216 @@
+// Bit manipulation extensions Zba, Zbb, Zbc and Zbs. Their instructions share
+// the opcodes of the integer instructions, they are decoded first and only
+// when their extension is enabled
+
+use super::cpu::Xlen;
+use super::isa::Extensions;
+
+const OP: u32 = 0b011_0011;
+const OP_IMM: u32 = 0b001_0011;
+const OP_32: u32 = 0b011_1011;
+const OP_IMM_32: u32 = 0b001_1011;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op{
+    //Zba
+    Sh1Add,
+    Sh2Add,
+    Sh3Add,
+    Sh1AddUw,
+    Sh2AddUw,
+    Sh3AddUw,
+    AddUw,
+    SllUw,
+
+    //Zbb
+    Andn,
+    Orn,
+    Xnor,
+    Clz,
+    Ctz,
+    Cpop,
+    Clzw,
+    Ctzw,
+    Cpopw,
+    Max,
+    Maxu,
+    Min,
+    Minu,
+    SextB,
+    SextH,
+    ZextH,
+    Rol,
+    Ror,
+    Rolw,
+    Rorw,
+    OrcB,
+    Rev8,
+
+    //Zbc
+    Clmul,
+    Clmulh,
+    Clmulr,
+
+    //Zbs
+    Bclr,
+    Bext,
+    Binv,
+    Bset,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Instruction{
+    pub op: Op,
+    /// Shift amount or bit index of the immediate forms, it replaces rs2
+    pub imm: Option<u64>,
+}
+
+impl Instruction{
+    fn reg(op: Op) -> Option<Instruction>{
+        Some(Instruction{ op, imm: None })
+    }
+
+    fn imm(op: Op, imm: u64) -> Option<Instruction>{
+        Some(Instruction{ op, imm: Some(imm) })
+    }
+}
+
+/// Decode a bit manipulation instruction, returns None for the other
+/// instructions and for those of a disabled extension
+pub fn decode(instr: u32, xlen: Xlen, ext: &Extensions) -> Option<Instruction>{
+    let opcode = instr & 0b111_1111;
+    let funct3 = (instr >> 12) & 0b111;
+    let funct7 = instr >> 25;
+    let rs2 = (instr >> 20) & 0b1_1111;
+    let imm12 = instr >> 20;
+    let funct6 = instr >> 26;
+    let rv64 = xlen == Xlen::Rv64;
+
+    //shamt[5] is reserved in RV32
+    let shamt = ((instr >> 20) & 0b11_1111) as u64;
+    let shamt_valid = rv64 || shamt < 32;
+
+    match (opcode, funct7, funct3){
+        (OP, 0b001_0000, 0b010) if ext.zba => Instruction::reg(Op::Sh1Add),
+        (OP, 0b001_0000, 0b100) if ext.zba => Instruction::reg(Op::Sh2Add),
+        (OP, 0b001_0000, 0b110) if ext.zba => Instruction::reg(Op::Sh3Add),
+        (OP_32, 0b001_0000, 0b010) if ext.zba && rv64 => Instruction::reg(Op::Sh1AddUw),
+        (OP_32, 0b001_0000, 0b100) if ext.zba && rv64 => Instruction::reg(Op::Sh2AddUw),
+        (OP_32, 0b001_0000, 0b110) if ext.zba && rv64 => Instruction::reg(Op::Sh3AddUw),
+        (OP_32, 0b000_0100, 0b000) if ext.zba && rv64 => Instruction::reg(Op::AddUw),
+        (OP_IMM_32, _, 0b001) if ext.zba && rv64 && funct6 == 0b00_0010 => Instruction::imm(Op::SllUw, shamt),
+
+        (OP, 0b010_0000, 0b111) if ext.zbb => Instruction::reg(Op::Andn),
+        (OP, 0b010_0000, 0b110) if ext.zbb => Instruction::reg(Op::Orn),
+        (OP, 0b010_0000, 0b100) if ext.zbb => Instruction::reg(Op::Xnor),
+        (OP, 0b000_0101, 0b100) if ext.zbb => Instruction::reg(Op::Min),
+        (OP, 0b000_0101, 0b101) if ext.zbb => Instruction::reg(Op::Minu),
+        (OP, 0b000_0101, 0b110) if ext.zbb => Instruction::reg(Op::Max),
+        (OP, 0b000_0101, 0b111) if ext.zbb => Instruction::reg(Op::Maxu),
+        (OP, 0b011_0000, 0b001) if ext.zbb => Instruction::reg(Op::Rol),
+        (OP, 0b011_0000, 0b101) if ext.zbb => Instruction::reg(Op::Ror),
+        (OP_32, 0b011_0000, 0b001) if ext.zbb && rv64 => Instruction::reg(Op::Rolw),
+        (OP_32, 0b011_0000, 0b101) if ext.zbb && rv64 => Instruction::reg(Op::Rorw),
+        //ZEXT.H is an OP instruction in RV32 and an OP-32 one in RV64
+        (OP, 0b000_0100, 0b100) if ext.zbb && !rv64 && rs2 == 0 => Instruction::reg(Op::ZextH),
+        (OP_32, 0b000_0100, 0b100) if ext.zbb && rv64 && rs2 == 0 => Instruction::reg(Op::ZextH),
+        (OP_IMM, _, 0b001) if ext.zbb && imm12 == 0x600 => Instruction::reg(Op::Clz),
+        (OP_IMM, _, 0b001) if ext.zbb && imm12 == 0x601 => Instruction::reg(Op::Ctz),
+        (OP_IMM, _, 0b001) if ext.zbb && imm12 == 0x602 => Instruction::reg(Op::Cpop),
+        (OP_IMM, _, 0b001) if ext.zbb && imm12 == 0x604 => Instruction::reg(Op::SextB),
+        (OP_IMM, _, 0b001) if ext.zbb && imm12 == 0x605 => Instruction::reg(Op::SextH),
+        (OP_IMM_32, _, 0b001) if ext.zbb && rv64 && imm12 == 0x600 => Instruction::reg(Op::Clzw),
+        (OP_IMM_32, _, 0b001) if ext.zbb && rv64 && imm12 == 0x601 => Instruction::reg(Op::Ctzw),
+        (OP_IMM_32, _, 0b001) if ext.zbb && rv64 && imm12 == 0x602 => Instruction::reg(Op::Cpopw),
+        (OP_IMM, _, 0b101) if ext.zbb && imm12 == 0x287 => Instruction::reg(Op::OrcB),
+        //REV8 reverses the bytes of the whole register, the encoding holds
+        //its width
+        (OP_IMM, _, 0b101) if ext.zbb && imm12 == if rv64 { 0x6B8 } else { 0x698 } => Instruction::reg(Op::Rev8),
+        (OP_IMM, _, 0b101) if ext.zbb && funct6 == 0b01_1000 && shamt_valid => Instruction::imm(Op::Ror, shamt),
+        (OP_IMM_32, 0b011_0000, 0b101) if ext.zbb && rv64 => Instruction::imm(Op::Rorw, shamt),
+
+        (OP, 0b000_0101, 0b001) if ext.zbc => Instruction::reg(Op::Clmul),
+        (OP, 0b000_0101, 0b010) if ext.zbc => Instruction::reg(Op::Clmulr),
+        (OP, 0b000_0101, 0b011) if ext.zbc => Instruction::reg(Op::Clmulh),
+
+        (OP, 0b010_0100, 0b001) if ext.zbs => Instruction::reg(Op::Bclr),
+        (OP, 0b010_0100, 0b101) if ext.zbs => Instruction::reg(Op::Bext),
+        (OP, 0b011_0100, 0b001) if ext.zbs => Instruction::reg(Op::Binv),
+        (OP, 0b001_0100, 0b001) if ext.zbs => Instruction::reg(Op::Bset),
+        (OP_IMM, _, 0b001) if ext.zbs && funct6 == 0b01_0010 && shamt_valid => Instruction::imm(Op::Bclr, shamt),
+        (OP_IMM, _, 0b101) if ext.zbs && funct6 == 0b01_0010 && shamt_valid => Instruction::imm(Op::Bext, shamt),
+        (OP_IMM, _, 0b001) if ext.zbs && funct6 == 0b01_1010 && shamt_valid => Instruction::imm(Op::Binv, shamt),
+        (OP_IMM, _, 0b001) if ext.zbs && funct6 == 0b00_1010 && shamt_valid => Instruction::imm(Op::Bset, shamt),
+        _ => None,
+    }
+}
+
+/// Carry-less product of two values
+fn clmul(a: u64, b: u64) -> u128{
+    (0..64).filter(|i| (b >> i) & 1 == 1)
+        .fold(0, |product, i| product ^ (a as u128) << i)
+}
+
+/// Execute an instruction, in RV32 the operands are sign extended from bit 31
+/// and only the lower 32 bits of the result are significant
+pub fn execute(op: Op, rs1: u64, rs2: u64, xlen: Xlen) -> u64{
+    let bits = match xlen{
+        Xlen::Rv32 => 32,
+        Xlen::Rv64 => 64,
+    };
+    //Zero extended operands and bit index
+    let (a, b) = match xlen{
+        Xlen::Rv32 => (rs1 as u32 as u64, rs2 as u32 as u64),
+        Xlen::Rv64 => (rs1, rs2),
+    };
+    let index = rs2 & (bits - 1);
+
+    match op{
+        Op::Sh1Add => (rs1 << 1).wrapping_add(rs2),
+        Op::Sh2Add => (rs1 << 2).wrapping_add(rs2),
+        Op::Sh3Add => (rs1 << 3).wrapping_add(rs2),
+        Op::Sh1AddUw => ((rs1 as u32 as u64) << 1).wrapping_add(rs2),
+        Op::Sh2AddUw => ((rs1 as u32 as u64) << 2).wrapping_add(rs2),
+        Op::Sh3AddUw => ((rs1 as u32 as u64) << 3).wrapping_add(rs2),
+        Op::AddUw => (rs1 as u32 as u64).wrapping_add(rs2),
+        Op::SllUw => (rs1 as u32 as u64) << (rs2 & 0b11_1111),
+
+        Op::Andn => rs1 & !rs2,
+        Op::Orn => rs1 | !rs2,
+        Op::Xnor => !(rs1 ^ rs2),
+        Op::Clz => (a.leading_zeros() - (64 - bits as u32)) as u64,
+        Op::Ctz => a.trailing_zeros().min(bits as u32) as u64,
+        Op::Cpop => a.count_ones() as u64,
+        Op::Clzw => (rs1 as u32).leading_zeros() as u64,
+        Op::Ctzw => (rs1 as u32).trailing_zeros() as u64,
+        Op::Cpopw => (rs1 as u32).count_ones() as u64,
+        Op::Max => (rs1 as i64).max(rs2 as i64) as u64,
+        Op::Maxu => rs1.max(rs2),
+        Op::Min => (rs1 as i64).min(rs2 as i64) as u64,
+        Op::Minu => rs1.min(rs2),
+        Op::SextB => rs1 as i8 as i64 as u64,
+        Op::SextH => rs1 as i16 as i64 as u64,
+        Op::ZextH => rs1 as u16 as u64,
+        Op::Rol if bits == 32 => (rs1 as u32).rotate_left(index as u32) as u64,
+        Op::Rol => rs1.rotate_left(index as u32),
+        Op::Ror if bits == 32 => (rs1 as u32).rotate_right(index as u32) as u64,
+        Op::Ror => rs1.rotate_right(index as u32),
+        Op::Rolw => (rs1 as u32).rotate_left(rs2 as u32 & 0b1_1111) as i32 as i64 as u64,
+        Op::Rorw => (rs1 as u32).rotate_right(rs2 as u32 & 0b1_1111) as i32 as i64 as u64,
+        Op::OrcB => {
+            (0..8).map(|i| if (rs1 >> (i * 8)) & 0xFF != 0 { 0xFF << (i * 8) } else { 0 })
+                .fold(0, |value, byte| value | byte)
+        },
+        Op::Rev8 if bits == 32 => (rs1 as u32).swap_bytes() as u64,
+        Op::Rev8 => rs1.swap_bytes(),
+
+        Op::Clmul => clmul(a, b) as u64,
+        Op::Clmulh => (clmul(a, b) >> bits) as u64,
+        Op::Clmulr => (clmul(a, b) >> (bits - 1)) as u64,
+
+        Op::Bclr => rs1 & !(1 << index),
+        Op::Bext => (rs1 >> index) & 1,
+        Op::Binv => rs1 ^ (1 << index),
+        Op::Bset => rs1 | (1 << index),
+    }
+}
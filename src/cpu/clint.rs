@@ -0,0 +1,102 @@
+// Core Local Interruptor, holds the machine timer and the software interrupt
+// of the hart. mtime is a virtual clock counting the retired instructions so
+// that runs are deterministic, WFI moves it forward to the next timer event
+
+pub const CLINT_BASE: u64 = 0x200_0000;
+pub const CLINT_SIZE: u64 = 0x1_0000;
+
+//Registers offsets
+const MSIP: u64 = 0x0;
+const MTIMECMP: u64 = 0x4000;
+const MTIME: u64 = 0xBFF8;
+
+#[derive(Debug, Clone)]
+pub struct Clint{
+    pub msip: u32,
+    pub mtimecmp: u64,
+    /// Ticks mtime is ahead of the instruction count, added by WFI and
+    /// writes to mtime
+    time_offset: u64,
+}
+
+impl Clint{
+    pub fn new() -> Clint{
+        Clint{
+            msip: 0,
+            mtimecmp: u64::MAX,
+            time_offset: 0,
+        }
+    }
+
+    pub fn contains(paddr: u64) -> bool{
+        (CLINT_BASE..CLINT_BASE + CLINT_SIZE).contains(&paddr)
+    }
+
+    pub fn mtime(&self, instret: u64) -> u64{
+        instret.wrapping_add(self.time_offset)
+    }
+
+    pub fn software_pending(&self) -> bool{
+        self.msip & 1 != 0
+    }
+
+    pub fn timer_pending(&self, instret: u64) -> bool{
+        self.mtime(instret) >= self.mtimecmp
+    }
+
+    /// Move the clock to the next timer event
+    pub fn fast_forward(&mut self, instret: u64){
+        let mtime = self.mtime(instret);
+        if self.mtimecmp != u64::MAX && self.mtimecmp > mtime{
+            self.time_offset = self.time_offset.wrapping_add(self.mtimecmp - mtime);
+        }
+    }
+
+    /// Register containing offset, its base offset and its value
+    fn register(&self, offset: u64, instret: u64) -> Option<(u64, u64)>{
+        match offset{
+            MSIP..=0x3 => Some((MSIP, self.msip as u64)),
+            MTIMECMP..=0x4007 => Some((MTIMECMP, self.mtimecmp)),
+            MTIME..=0xBFFF => Some((MTIME, self.mtime(instret))),
+            _ => None,
+        }
+    }
+
+    /// Read the registers at offset, unknown registers read as zero
+    pub fn read(&self, offset: u64, buf: &mut [u8], instret: u64){
+        buf.iter_mut().for_each(|b| *b = 0);
+
+        if let Some((base, value)) = self.register(offset, instret){
+            let start = (offset - base) as usize;
+            let bytes = value.to_le_bytes();
+            let len = buf.len().min(bytes.len() - start);
+            buf[..len].copy_from_slice(&bytes[start..start + len]);
+        }
+    }
+
+    /// Write the registers at offset, writes to unknown registers are ignored
+    pub fn write(&mut self, offset: u64, buf: &[u8], instret: u64){
+        let (base, value) = match self.register(offset, instret){
+            Some(register) => register,
+            None => return,
+        };
+
+        let start = (offset - base) as usize;
+        let mut bytes = value.to_le_bytes();
+        let len = buf.len().min(bytes.len() - start);
+        bytes[start..start + len].copy_from_slice(&buf[..len]);
+        let value = u64::from_le_bytes(bytes);
+
+        match base{
+            MSIP => self.msip = value as u32 & 1,
+            MTIMECMP => self.mtimecmp = value,
+            _ => self.time_offset = value.wrapping_sub(instret),
+        }
+    }
+}
+
+impl Default for Clint{
+    fn default() -> Self{
+        Clint::new()
+    }
+}
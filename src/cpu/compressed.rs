@@ -0,0 +1,288 @@
+use super::instr_type::{*};
+use super::cpu::Xlen;
+
+//Encoders for the base 32 bits formats
+fn r_type(funct7: u32, rs2: usize, rs1: usize, funct3: u32, rd: usize, opcode: u32) -> u32{
+    funct7 << 25 | (rs2 as u32) << 20 | (rs1 as u32) << 15 | funct3 << 12 | (rd as u32) << 7 | opcode
+}
+
+fn i_type(imm: i32, rs1: usize, funct3: u32, rd: usize, opcode: u32) -> u32{
+    ((imm as u32) & 0xFFF) << 20 | (rs1 as u32) << 15 | funct3 << 12 | (rd as u32) << 7 | opcode
+}
+
+fn s_type(imm: i32, rs2: usize, rs1: usize, funct3: u32, opcode: u32) -> u32{
+    let imm = imm as u32;
+    ((imm >> 5) & 0b111_1111) << 25 | (rs2 as u32) << 20 | (rs1 as u32) << 15 | funct3 << 12 |
+        (imm & 0b1_1111) << 7 | opcode
+}
+
+fn b_type(imm: i32, rs2: usize, rs1: usize, funct3: u32, opcode: u32) -> u32{
+    let imm = imm as u32;
+    ((imm >> 12) & 0b1) << 31 | ((imm >> 5) & 0b11_1111) << 25 | (rs2 as u32) << 20 |
+        (rs1 as u32) << 15 | funct3 << 12 | ((imm >> 1) & 0b1111) << 8 | ((imm >> 11) & 0b1) << 7 | opcode
+}
+
+fn u_type(imm: u32, rd: usize, opcode: u32) -> u32{
+    (imm & 0xF_FFFF) << 12 | (rd as u32) << 7 | opcode
+}
+
+fn j_type(imm: i32, rd: usize, opcode: u32) -> u32{
+    let imm = imm as u32;
+    ((imm >> 20) & 0b1) << 31 | ((imm >> 1) & 0b11_1111_1111) << 21 | ((imm >> 11) & 0b1) << 20 |
+        ((imm >> 12) & 0b1111_1111) << 12 | (rd as u32) << 7 | opcode
+}
+
+//Sign extend the 6 bits immediate of the CI format
+fn ci_simm(imm: u32) -> i32{
+    ((imm << 26) as i32) >> 26
+}
+
+//Offsets of the loads and stores, scaled by the access size
+fn cl_word_offset(imm_hi: u32, imm_lo: u32) -> i32{
+    (imm_hi << 3 | (imm_lo & 0b1) << 6 | (imm_lo >> 1) << 2) as i32
+}
+
+fn cl_double_offset(imm_hi: u32, imm_lo: u32) -> i32{
+    (imm_hi << 3 | imm_lo << 6) as i32
+}
+
+const OP_LOAD: u32 = 0b000_0011;
+const OP_LOAD_FP: u32 = 0b000_0111;
+const OP_STORE: u32 = 0b010_0011;
+const OP_STORE_FP: u32 = 0b010_0111;
+const OP_IMM: u32 = 0b001_0011;
+const OP_IMM_32: u32 = 0b001_1011;
+const OP: u32 = 0b011_0011;
+const OP_32: u32 = 0b011_1011;
+const OP_LUI: u32 = 0b011_0111;
+const OP_BRANCH: u32 = 0b110_0011;
+const OP_JAL: u32 = 0b110_1111;
+const OP_JALR: u32 = 0b110_0111;
+
+const EBREAK: u32 = 0x0010_0073;
+
+/// Expand a compressed instruction (RVC) to its 32 bits equivalent so it can
+/// be executed by the common handlers of the CPU. Some encodings depend on
+/// XLEN, the RV64 only ones are float loads and stores and C.JAL in RV32.
+/// Returns None for reserved or illegal encodings
+pub fn expand(instr: u16, xlen: Xlen) -> Option<u32>{
+    let quadrant = instr & 0b11;
+    let funct3 = instr >> 13;
+    let rv32 = xlen == Xlen::Rv32;
+
+    match (quadrant, funct3){
+        //C.ADDI4SPN
+        (0b00, 0b000) => {
+            let instr = CIWType::from(instr);
+            //nzuimm[5:4|9:6|2|3]
+            let imm = (instr.imm & 0b1) << 3 |
+                ((instr.imm >> 1) & 0b1) << 2 |
+                ((instr.imm >> 2) & 0b1111) << 6 |
+                ((instr.imm >> 6) & 0b11) << 4;
+            //Also covers the all zero instruction which is defined as illegal
+            if imm == 0 { return None; }
+            Some(i_type(imm as i32, 2, 0b000, instr.rd, OP_IMM))
+        },
+        //C.FLD
+        (0b00, 0b001) => {
+            let instr = CLType::from(instr);
+            Some(i_type(cl_double_offset(instr.imm_hi, instr.imm_lo), instr.rs1, 0b011, instr.rd, OP_LOAD_FP))
+        },
+        //C.LW
+        (0b00, 0b010) => {
+            let instr = CLType::from(instr);
+            Some(i_type(cl_word_offset(instr.imm_hi, instr.imm_lo), instr.rs1, 0b010, instr.rd, OP_LOAD))
+        },
+        //C.FLW
+        (0b00, 0b011) if rv32 => {
+            let instr = CLType::from(instr);
+            Some(i_type(cl_word_offset(instr.imm_hi, instr.imm_lo), instr.rs1, 0b010, instr.rd, OP_LOAD_FP))
+        },
+        //C.LD
+        (0b00, 0b011) => {
+            let instr = CLType::from(instr);
+            Some(i_type(cl_double_offset(instr.imm_hi, instr.imm_lo), instr.rs1, 0b011, instr.rd, OP_LOAD))
+        },
+        //C.FSD
+        (0b00, 0b101) => {
+            let instr = CSType::from(instr);
+            Some(s_type(cl_double_offset(instr.imm_hi, instr.imm_lo), instr.rs2, instr.rs1, 0b011, OP_STORE_FP))
+        },
+        //C.SW
+        (0b00, 0b110) => {
+            let instr = CSType::from(instr);
+            Some(s_type(cl_word_offset(instr.imm_hi, instr.imm_lo), instr.rs2, instr.rs1, 0b010, OP_STORE))
+        },
+        //C.FSW
+        (0b00, 0b111) if rv32 => {
+            let instr = CSType::from(instr);
+            Some(s_type(cl_word_offset(instr.imm_hi, instr.imm_lo), instr.rs2, instr.rs1, 0b010, OP_STORE_FP))
+        },
+        //C.SD
+        (0b00, 0b111) => {
+            let instr = CSType::from(instr);
+            Some(s_type(cl_double_offset(instr.imm_hi, instr.imm_lo), instr.rs2, instr.rs1, 0b011, OP_STORE))
+        },
+        //C.ADDI / C.NOP
+        (0b01, 0b000) => {
+            let instr = CIType::from(instr);
+            Some(i_type(ci_simm(instr.imm), instr.rd_rs1, 0b000, instr.rd_rs1, OP_IMM))
+        },
+        //C.JAL
+        (0b01, 0b001) if rv32 => {
+            let instr = CJType::from(instr);
+            Some(j_type(instr.offset, 1, OP_JAL))
+        },
+        //C.ADDIW
+        (0b01, 0b001) => {
+            let instr = CIType::from(instr);
+            if instr.rd_rs1 == 0 { return None; }
+            Some(i_type(ci_simm(instr.imm), instr.rd_rs1, 0b000, instr.rd_rs1, OP_IMM_32))
+        },
+        //C.LI
+        (0b01, 0b010) => {
+            let instr = CIType::from(instr);
+            Some(i_type(ci_simm(instr.imm), 0, 0b000, instr.rd_rs1, OP_IMM))
+        },
+        (0b01, 0b011) => {
+            let instr = CIType::from(instr);
+            if instr.imm == 0 { return None; }
+
+            //C.ADDI16SP
+            if instr.rd_rs1 == 2{
+                let imm = ((instr.imm >> 4) & 0b1) << 4 |
+                    (instr.imm & 0b1) << 5 |
+                    ((instr.imm >> 3) & 0b1) << 6 |
+                    ((instr.imm >> 1) & 0b11) << 7;
+                //Sign extended from nzimm[9]
+                let imm = imm as i32 | ((((instr.imm << 26) as i32) >> 31) << 9);
+                Some(i_type(imm, 2, 0b000, 2, OP_IMM))
+            }
+            //C.LUI
+            else{
+                Some(u_type(ci_simm(instr.imm) as u32, instr.rd_rs1, OP_LUI))
+            }
+        },
+        (0b01, 0b100) => {
+            let instr = CBType::from(instr);
+            let shamt = instr.imm & 0b11_1111;
+
+            match instr.funct2{
+                //C.SRLI
+                0b00 => Some(i_type(shamt, instr.rs1, 0b101, instr.rs1, OP_IMM)),
+                //C.SRAI
+                0b01 => Some(i_type(shamt | 0b0100_0000_0000, instr.rs1, 0b101, instr.rs1, OP_IMM)),
+                //C.ANDI
+                0b10 => Some(i_type(instr.imm, instr.rs1, 0b111, instr.rs1, OP_IMM)),
+                _ => {
+                    let rs2 = ((instr.imm & 0b111) + 8) as usize;
+                    let word = (instr.imm >> 5) & 0b1 == 1;
+
+                    match (word, (instr.imm >> 3) & 0b11){
+                        //C.SUB
+                        (false, 0b00) => Some(r_type(0b010_0000, rs2, instr.rs1, 0b000, instr.rs1, OP)),
+                        //C.XOR
+                        (false, 0b01) => Some(r_type(0, rs2, instr.rs1, 0b100, instr.rs1, OP)),
+                        //C.OR
+                        (false, 0b10) => Some(r_type(0, rs2, instr.rs1, 0b110, instr.rs1, OP)),
+                        //C.AND
+                        (false, 0b11) => Some(r_type(0, rs2, instr.rs1, 0b111, instr.rs1, OP)),
+                        //C.SUBW
+                        (true, 0b00) => Some(r_type(0b010_0000, rs2, instr.rs1, 0b000, instr.rs1, OP_32)),
+                        //C.ADDW
+                        (true, 0b01) => Some(r_type(0, rs2, instr.rs1, 0b000, instr.rs1, OP_32)),
+                        _ => None,
+                    }
+                }
+            }
+        },
+        //C.J
+        (0b01, 0b101) => {
+            let instr = CJType::from(instr);
+            Some(j_type(instr.offset, 0, OP_JAL))
+        },
+        //C.BEQZ
+        (0b01, 0b110) => {
+            let instr = CBType::from(instr);
+            Some(b_type(instr.offset, 0, instr.rs1, 0b000, OP_BRANCH))
+        },
+        //C.BNEZ
+        (0b01, 0b111) => {
+            let instr = CBType::from(instr);
+            Some(b_type(instr.offset, 0, instr.rs1, 0b001, OP_BRANCH))
+        },
+        //C.SLLI
+        (0b10, 0b000) => {
+            let instr = CIType::from(instr);
+            Some(i_type(instr.imm as i32, instr.rd_rs1, 0b001, instr.rd_rs1, OP_IMM))
+        },
+        //C.FLDSP
+        (0b10, 0b001) => {
+            let instr = CIType::from(instr);
+            let imm = (instr.imm & 0b11_1000) | (instr.imm & 0b111) << 6;
+            Some(i_type(imm as i32, 2, 0b011, instr.rd_rs1, OP_LOAD_FP))
+        },
+        //C.LWSP
+        (0b10, 0b010) => {
+            let instr = CIType::from(instr);
+            if instr.rd_rs1 == 0 { return None; }
+            let imm = (instr.imm & 0b11_1100) | (instr.imm & 0b11) << 6;
+            Some(i_type(imm as i32, 2, 0b010, instr.rd_rs1, OP_LOAD))
+        },
+        //C.FLWSP
+        (0b10, 0b011) if rv32 => {
+            let instr = CIType::from(instr);
+            let imm = (instr.imm & 0b11_1100) | (instr.imm & 0b11) << 6;
+            Some(i_type(imm as i32, 2, 0b010, instr.rd_rs1, OP_LOAD_FP))
+        },
+        //C.LDSP
+        (0b10, 0b011) => {
+            let instr = CIType::from(instr);
+            if instr.rd_rs1 == 0 { return None; }
+            let imm = (instr.imm & 0b11_1000) | (instr.imm & 0b111) << 6;
+            Some(i_type(imm as i32, 2, 0b011, instr.rd_rs1, OP_LOAD))
+        },
+        (0b10, 0b100) => {
+            let instr = CRType::from(instr);
+
+            match (instr.funct4 & 0b1, instr.rd_rs1, instr.rs2){
+                //C.JR
+                (0, 0, 0) => None,
+                (0, rs1, 0) => Some(i_type(0, rs1, 0b000, 0, OP_JALR)),
+                //C.MV
+                (0, rd, rs2) => Some(r_type(0, rs2, 0, 0b000, rd, OP)),
+                //C.EBREAK
+                (1, 0, 0) => Some(EBREAK),
+                //C.JALR
+                (1, rs1, 0) => Some(i_type(0, rs1, 0b000, 1, OP_JALR)),
+                //C.ADD
+                (_, rd, rs2) => Some(r_type(0, rs2, rd, 0b000, rd, OP)),
+            }
+        },
+        //C.FSDSP
+        (0b10, 0b101) => {
+            let instr = CSSType::from(instr);
+            let imm = (instr.imm & 0b11_1000) | (instr.imm & 0b111) << 6;
+            Some(s_type(imm as i32, instr.rs2, 2, 0b011, OP_STORE_FP))
+        },
+        //C.SWSP
+        (0b10, 0b110) => {
+            let instr = CSSType::from(instr);
+            let imm = (instr.imm & 0b11_1100) | (instr.imm & 0b11) << 6;
+            Some(s_type(imm as i32, instr.rs2, 2, 0b010, OP_STORE))
+        },
+        //C.FSWSP
+        (0b10, 0b111) if rv32 => {
+            let instr = CSSType::from(instr);
+            let imm = (instr.imm & 0b11_1100) | (instr.imm & 0b11) << 6;
+            Some(s_type(imm as i32, instr.rs2, 2, 0b010, OP_STORE_FP))
+        },
+        //C.SDSP
+        (0b10, 0b111) => {
+            let instr = CSSType::from(instr);
+            let imm = (instr.imm & 0b11_1000) | (instr.imm & 0b111) << 6;
+            Some(s_type(imm as i32, instr.rs2, 2, 0b011, OP_STORE))
+        },
+        _ => None,
+    }
+}
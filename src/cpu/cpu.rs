@@ -7,26 +7,59 @@ use std::time::Instant;
 use std::rc::Rc;
 use std::cell::RefCell;
 
-use super::memory::{Memory, STACK_SIZE};
+use super::memory::{Memory, MemoryError, Violation};
+use super::heap::Heap;
+use super::process::Process;
 use super::instr_type::{*};
+use super::compressed;
+use super::isa::Extensions;
+use super::bitmanip;
+use super::vector::{self, VectorState};
+use super::sbi;
+use super::clint::{Clint, CLINT_BASE};
+use super::plic::{self, Plic};
+use super::float::{self, FloatFormat, RoundingMode};
+use super::csr::{self, CsrFile};
+use super::trap::{CpuFault, Exception, Privilege};
+use super::mmu::{self, Access, Mmu};
 use super::fuzzer::{Fuzzer};
 
+/// Width of the integer registers. RV32 values are kept sign extended to 64
+/// bits so that most instructions share their implementation with RV64
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Xlen{
+    Rv32,
+    Rv64,
+}
+
+/// Handler called before the instruction at the address of a breakpoint is
+/// executed, it can redirect the execution or end it with a fault
+pub type Breakpoint = fn(&mut CPU) -> Result<(), CpuFault>;
+
+/// Depth of the call stack tracked for the backtraces
+const CALL_STACK_DEPTH: usize = 1024;
+
 /// Memory management
 // Hold the registers 
 #[derive(Debug, Clone)]
 pub struct Registers{
     pub common: [u64; 32],
     pub pc: u64,
+
+    /// f0-f31, single precision values are NaN-boxed
+    pub float: [u64; 32],
+    /// Floating point control and status register, frm (bits 7-5) and
+    /// fflags (bits 4-0)
+    pub fcsr: u32,
 }
 
 impl Registers {
     pub fn new() -> Registers {
-        let mut common = [0; 32];
-        common[2] = STACK_SIZE as u64;
-
         Registers{
-            common: common,
-            pc: 0
+            common: [0; 32],
+            pc: 0,
+            float: [0; 32],
+            fcsr: 0,
         }
     }
 }
@@ -35,7 +68,7 @@ pub struct CPU{
     pub memory: Memory,
     pub registers: Registers,
     pub exit: bool, //Exit the execution
-    pub breakpoints: HashMap<u64, fn(&mut CPU)>,
+    pub breakpoints: HashMap<u64, Breakpoint>,
     pub redirect_stdout: bool,
 
     /// Everytime an instruction is executed its address is added to this set
@@ -44,6 +77,42 @@ pub struct CPU{
     pub coverage_enabled: bool,
     pub coverage: HashSet<u64>, 
 
+    pub csr: CsrFile,
+    pub privilege: Privilege,
+    pub mmu: Mmu,
+    /// When set the program is a user space binary, ECALLs are system calls
+    /// handled by the fuzzer and any other exception is fatal. Otherwise
+    /// traps are delivered to the handlers of the guest
+    pub syscall_emulation: bool,
+    /// When set a kernel runs without M-mode firmware, the ECALLs made from
+    /// S-mode are SBI calls handled by the emulator
+    pub emulated_sbi: bool,
+    /// Set through set_xlen, RV64 by default
+    pub xlen: Xlen,
+    /// Optional extensions, set through set_extensions, all but the vector
+    /// unit enabled by default
+    pub extensions: Extensions,
+    /// Vector registers and CSRs, VLEN follows the extensions
+    pub vector: VectorState,
+    pub clint: Clint,
+    /// External interrupt controller, also mapped in memory when present
+    pub plic: Option<Rc<RefCell<Plic>>>,
+    /// Number of retired instructions, also used as the cycle count and the
+    /// time so that runs are deterministic
+    pub instret: u64,
+    /// Call sites of the calls in progress, the innermost last. Calls and
+    /// returns are told apart with the link register hints of JAL and JALR
+    pub call_stack: Vec<u64>,
+    /// Allocator of the guest when its malloc family is hooked
+    pub heap: Option<Heap>,
+    /// Files, mappings and loaded objects of the emulated process
+    pub process: Process,
+    /// Called once the program made the exit or exit_group system call, the
+    /// execution stops without one
+    pub exit_handler: Option<Breakpoint>,
+    /// Set by the exit system calls, the run ends after the instruction
+    pub exited: bool,
+
     /// This is from this state that the delta for dirty pages will be calculed
     /// at that time only one snapshot is supported, a call must be made to
     /// save_as_initial_state before usage
@@ -55,13 +124,22 @@ pub struct CPU{
 /// at a given time
 struct CpuSnapshot{
     pub registers: Registers,
+    pub csr: CsrFile,
+    pub vector: VectorState,
+    pub privilege: Privilege,
+    pub clint: Clint,
+    pub plic: Option<Plic>,
+    pub instret: u64,
+    pub call_stack: Vec<u64>,
+    pub heap: Option<Heap>,
+    pub process: Process,
     pub coverage: Option<HashSet<u64>>,
 }
 
 impl CPU{
     //Return a new CPU
     pub fn new(coverage_enabled: bool) -> CPU {
-        CPU{
+        let mut cpu = CPU{
             memory: Memory::new(),
             registers: Registers::new(),
             exit: false,
@@ -69,13 +147,33 @@ impl CPU{
             redirect_stdout: true,
             coverage_enabled: coverage_enabled,
             coverage: HashSet::new(),
+            csr: CsrFile::new(),
+            privilege: Privilege::Machine,
+            mmu: Mmu::new(),
+            syscall_emulation: true,
+            emulated_sbi: false,
+            xlen: Xlen::Rv64,
+            extensions: Extensions::default(),
+            vector: VectorState::new(Extensions::default().vlen),
+            clint: Clint::new(),
+            plic: None,
+            instret: 0,
+            call_stack: Vec::new(),
+            heap: None,
+            process: Process::new(),
+            exit_handler: None,
+            exited: false,
             saved_state: None,
             nbr_exec: 0,
-        }
+        };
+        //misa reports the optional extensions
+        cpu.set_extensions(Extensions::default());
+        cpu
     }
 
-    //Execute one instruction
-    fn exec_instruction(&mut self, instr: u32, fuzzer: Rc<RefCell<Fuzzer>>){
+    //Execute one instruction, len is the size of the instruction in memory
+    //which is 2 for compressed instructions
+    fn exec_instruction(&mut self, instr: u32, len: u64, fuzzer: Rc<RefCell<Fuzzer>>) -> Result<(), Exception>{
         //The hash of the origin and the destination of a branch is recorded for code coverage calculation
         let mut branch_dest = 0;
 
@@ -83,6 +181,16 @@ impl CPU{
         let mut take_branch = false;
 
         match opcode{
+            //funct7 only selects SUB, SRA and their variants, the other values
+            //belong to Zba / Zbb / Zbc / Zbs
+            0b011_0011 | 0b001_0011 | 0b011_1011 | 0b001_1011 if reserved_integer_encoding(instr) => {
+                let bitmanip = bitmanip::decode(instr, self.xlen, &self.extensions)
+                    .ok_or(Exception::IllegalInstruction)?;
+                let instr = RType::from(instr);
+                let rs2 = bitmanip.imm.unwrap_or(self.registers.common[instr.rs2]);
+
+                self.registers.common[instr.rd] = bitmanip::execute(bitmanip.op, self.registers.common[instr.rs1], rs2, self.xlen);
+            },
             //LUI
             0b011_0111 => {
                 let instr = UType::from(instr);
@@ -99,22 +207,36 @@ impl CPU{
             0b110_1111 => {
                 let instr = JType::from(instr);
                 take_branch = true;
+                branch_dest = self.jump_target(self.registers.pc.wrapping_add(instr.imm as u64))?;
+
+                if is_link(instr.rd){
+                    self.push_call();
+                }
 
                 //plain unconditionnal jump are encoded with rd=x0
                 if instr.rd != 0{
-                    self.registers.common[instr.rd] = self.registers.pc.wrapping_add(4);
+                    self.registers.common[instr.rd] = self.registers.pc.wrapping_add(len);
                 }
-                branch_dest = self.registers.pc.wrapping_add(instr.imm as u64);
             },
             //JALR
             0b110_0111 => {
                 let instr = IType::from(instr);
                 take_branch = true;
 
-                branch_dest = self.registers.common[instr.rs1].wrapping_add(instr.imm as u64);
+                //The lowest bit of the target is cleared
+                branch_dest = self.jump_target(self.registers.common[instr.rs1].wrapping_add(instr.imm as u64) & !1)?;
+
+                //A return pops the call stack and a call pushes it, a
+                //coroutine swap through two link registers does both
+                if is_link(instr.rs1) && instr.rs1 != instr.rd{
+                    self.call_stack.pop();
+                }
+                if is_link(instr.rd){
+                    self.push_call();
+                }
                 
                 if instr.rd != 0{
-                    self.registers.common[instr.rd] = self.registers.pc.wrapping_add(4);
+                    self.registers.common[instr.rd] = self.registers.pc.wrapping_add(len);
                 }
             },
             //Conditional Branches
@@ -139,35 +261,37 @@ impl CPU{
                     },
                     //BLT
                     0b100 => {
-                        if (self.registers.common[instr.rs1] as i32) < (self.registers.common[instr.rs2] as i32){
+                        if (self.registers.common[instr.rs1] as i64) < (self.registers.common[instr.rs2] as i64){
                             branch_dest = self.registers.pc.wrapping_add(instr.imm as u64);
                             take_branch = true;
                         }
                     },
                     //BGE
                     0b101 => {
-                        if (self.registers.common[instr.rs1] as i32) >= (self.registers.common[instr.rs2] as i32){
+                        if (self.registers.common[instr.rs1] as i64) >= (self.registers.common[instr.rs2] as i64){
                             branch_dest = self.registers.pc.wrapping_add(instr.imm as u64);
                             take_branch = true;
                         }
                     }
                     //BLTU
                     0b110 => {
-                        if (self.registers.common[instr.rs1] as u32) < (self.registers.common[instr.rs2] as u32){
+                        if self.registers.common[instr.rs1] < self.registers.common[instr.rs2]{
                             branch_dest = self.registers.pc.wrapping_add(instr.imm as u64);
                             take_branch = true;
                         }
                     }
                     //BGEU
                     0b111 => {
-                        if (self.registers.common[instr.rs1] as u32) >= (self.registers.common[instr.rs2] as u32){
+                        if self.registers.common[instr.rs1] >= self.registers.common[instr.rs2]{
                             branch_dest = self.registers.pc.wrapping_add(instr.imm as u64);
                             take_branch = true;
                         }
                     },
-                    _ => {unreachable!()},
+                    _ => { return Err(Exception::IllegalInstruction) },
+                }
+                if take_branch{
+                    branch_dest = self.jump_target(branch_dest)?;
                 }
-
             },
             //LOAD 
             0b000_0011 => {
@@ -178,54 +302,54 @@ impl CPU{
                     //LB
                     0b000 => {
                         let mut buf = [0u8; 1];
-                        self.memory.read(addr, &mut buf);
+                        self.load(addr, &mut buf)?;
     
                         self.registers.common[instr.rd] = i8::from_le_bytes(buf) as i64 as u64;
                     },
                     //LH
                     0b001 => {
                         let mut buf = [0u8; 2];
-                        self.memory.read(addr, &mut buf);
+                        self.load(addr, &mut buf)?;
     
                         self.registers.common[instr.rd] = i16::from_le_bytes(buf) as i64 as u64;
                     },
                     //LW
                     0b010 => {
                         let mut buf = [0u8; 4];
-                        self.memory.read(addr, &mut buf);
+                        self.load(addr, &mut buf)?;
     
                         self.registers.common[instr.rd] = i32::from_le_bytes(buf) as i64 as u64;
                     },
                     //LBU
                     0b100 => {
                         let mut buf = [0u8; 1];
-                        self.memory.read(addr, &mut buf);
+                        self.load(addr, &mut buf)?;
     
                         self.registers.common[instr.rd] = u8::from_le_bytes(buf) as u64;
                     },
                     //LHU
                     0b101 => {
                         let mut buf = [0u8; 2];
-                        self.memory.read(addr, &mut buf);
+                        self.load(addr, &mut buf)?;
     
                         self.registers.common[instr.rd] = u16::from_le_bytes(buf) as u64;
                     },
                     //LD
-                    0b011 => {
+                    0b011 if self.xlen == Xlen::Rv64 => {
                         let mut buf = [0u8; 8];
-                        self.memory.read(addr, &mut buf);
+                        self.load(addr, &mut buf)?;
 
                         self.registers.common[instr.rd] = u64::from_le_bytes(buf);
 
                     }
                     //LWU
-                    0b110 => {
+                    0b110 if self.xlen == Xlen::Rv64 => {
                         let mut buf = [0u8; 4];
-                        self.memory.read(addr, &mut buf);
+                        self.load(addr, &mut buf)?;
     
                         self.registers.common[instr.rd] = u32::from_le_bytes(buf) as u64;
                     }
-                    _ => {unreachable!()}
+                    _ => { return Err(Exception::IllegalInstruction) }
                 }
             },
             //STORE
@@ -236,14 +360,14 @@ impl CPU{
                 //println!("==>{:?}, addr:{:#x}, base{:#X}", instr, addr, self.registers.common[instr.rs1]);
                 match instr.funct3 {
                     //SB
-                    0b000 => { self.memory.write(addr, &(self.registers.common[instr.rs2] as u8).to_le_bytes()); },
+                    0b000 => { self.store(addr, &(self.registers.common[instr.rs2] as u8).to_le_bytes())?; },
                     //SH
-                    0b001 => { self.memory.write(addr, &(self.registers.common[instr.rs2] as u16).to_le_bytes()); },
+                    0b001 => { self.store(addr, &(self.registers.common[instr.rs2] as u16).to_le_bytes())?; },
                     //SW
-                    0b010 => { self.memory.write(addr, &(self.registers.common[instr.rs2] as u32).to_le_bytes()); },
+                    0b010 => { self.store(addr, &(self.registers.common[instr.rs2] as u32).to_le_bytes())?; },
                     //SD
-                    0b011 => { self.memory.write(addr, &self.registers.common[instr.rs2].to_le_bytes()); },
-                    _ => { unreachable!(); }
+                    0b011 if self.xlen == Xlen::Rv64 => { self.store(addr, &self.registers.common[instr.rs2].to_le_bytes())?; },
+                    _ => { return Err(Exception::IllegalInstruction) }
                 }
             },
             //Integer register-immediate instructions
@@ -259,7 +383,7 @@ impl CPU{
 
                     //SLTI
                     0b010 => {
-                        if (self.registers.common[instr.rs1] as i32) < instr.imm{
+                        if (self.registers.common[instr.rs1] as i64) < instr.imm as i64{
                             self.registers.common[instr.rd] = 1;
                         }
                         else{
@@ -275,7 +399,7 @@ impl CPU{
                         }
 
                         else{
-                            if (self.registers.common[instr.rs1] as u32) < (instr.imm as u32){
+                            if self.registers.common[instr.rs1] < (instr.imm as i64 as u64){
                                 self.registers.common[instr.rd] = 1;
                             }
                             else{
@@ -293,11 +417,11 @@ impl CPU{
                     //These three instructions have a special encoding
                     //SLLI
                     0b001 => {
-                        let shamt = instr.imm & 0b11_1111;
+                        let shamt = self.shift_amount(instr.imm as u64)?;
                         self.registers.common[instr.rd] = self.registers.common[instr.rs1] << shamt; 
                     }
                     0b101 => {
-                        let shamt = instr.imm & 0b11_1111;
+                        let shamt = self.shift_amount(instr.imm as u64)?;
                         
                         //SRAI
                         if ((instr.imm >> 10) & 0b1) == 1{
@@ -305,12 +429,50 @@ impl CPU{
                         }
                         //SRLI
                         else{
-                            self.registers.common[instr.rd] = self.registers.common[instr.rs1] >> shamt; 
+                            self.registers.common[instr.rd] = self.unsigned(self.registers.common[instr.rs1]) >> shamt; 
                         }
                     },
-                    _ => { unreachable!() }
+                    _ => { return Err(Exception::IllegalInstruction) }
                 }
             },
+            //RV64M Multiply / Divide, share the opcode with the integer register-register
+            //instructions but use funct7 = 0b000_0001
+            0b011_0011 if (instr >> 25) == 0b000_0001 => {
+                let instr = RType::from(instr);
+                let rs1 = self.registers.common[instr.rs1];
+                let rs2 = self.registers.common[instr.rs2];
+
+                let rv32 = self.xlen == Xlen::Rv32;
+
+                self.registers.common[instr.rd] = match instr.funct3 {
+                    //MUL
+                    0b000 => rs1.wrapping_mul(rs2),
+                    //MULH, the RV32 high products are computed on 64 bits
+                    0b001 if rv32 => ((rs1 as i64).wrapping_mul(rs2 as i64) >> 32) as u64,
+                    0b001 => ((rs1 as i64 as i128 * rs2 as i64 as i128) >> 64) as u64,
+                    //MULHSU
+                    0b010 if rv32 => ((rs1 as i64).wrapping_mul(rs2 as u32 as i64) >> 32) as u64,
+                    0b010 => ((rs1 as i64 as i128 * rs2 as i128) >> 64) as u64,
+                    //MULHU
+                    0b011 if rv32 => (rs1 as u32 as u64 * rs2 as u32 as u64) >> 32,
+                    0b011 => ((rs1 as u128 * rs2 as u128) >> 64) as u64,
+                    //DIV, division by zero gives -1 and overflow gives the dividend
+                    0b100 => {
+                        if rs2 == 0 { u64::MAX }
+                        else { (rs1 as i64).wrapping_div(rs2 as i64) as u64 }
+                    },
+                    //DIVU
+                    0b101 => self.unsigned(rs1).checked_div(self.unsigned(rs2)).unwrap_or(u64::MAX),
+                    //REM, remainder by zero gives the dividend and overflow gives 0
+                    0b110 => {
+                        if rs2 == 0 { rs1 }
+                        else { (rs1 as i64).wrapping_rem(rs2 as i64) as u64 }
+                    },
+                    //REMU
+                    0b111 => self.unsigned(rs1).checked_rem(self.unsigned(rs2)).unwrap_or(rs1),
+                    _ => { return Err(Exception::IllegalInstruction) }
+                };
+            },
             0b011_0011 => {
                 let instr = RType::from(instr);
 
@@ -330,12 +492,12 @@ impl CPU{
                     //SLL
                     0b001 => {
                         self.registers.common[instr.rd] =
-                            self.registers.common[instr.rs1] << (self.registers.common[instr.rs2] & 0b11_1111);
+                            self.registers.common[instr.rs1] << (self.registers.common[instr.rs2] & self.shift_mask());
                     },
                     //SLT
                     0b010 => {
                         self.registers.common[instr.rd] = 
-                            if (self.registers.common[instr.rs1] as i32) < (self.registers.common[instr.rs2] as i32) {1} else {0};
+                            if (self.registers.common[instr.rs1] as i64) < (self.registers.common[instr.rs2] as i64) {1} else {0};
                     },
                     //SLTU
                     0b011 => {
@@ -346,7 +508,7 @@ impl CPU{
                         }
                         else {
                             self.registers.common[instr.rd] = 
-                                if (self.registers.common[instr.rs1] as u32) < (self.registers.common[instr.rs2] as u32) {1} else {0};
+                                if self.registers.common[instr.rs1] < self.registers.common[instr.rs2] {1} else {0};
                         }
                     },
                     //XOR
@@ -358,12 +520,12 @@ impl CPU{
                         //SRL
                         if instr.funct7 == 0{
                             self.registers.common[instr.rd] =
-                                self.registers.common[instr.rs1] >> (self.registers.common[instr.rs2] & 0b11_1111);
+                                self.unsigned(self.registers.common[instr.rs1]) >> (self.registers.common[instr.rs2] & self.shift_mask());
                         }
                         //SRA
                         else {
                             self.registers.common[instr.rd] =
-                                ((self.registers.common[instr.rs1] as i64) >> (self.registers.common[instr.rs2] & 0b11_1111)) as u64;
+                                ((self.registers.common[instr.rs1] as i64) >> (self.registers.common[instr.rs2] & self.shift_mask())) as u64;
                         }
                     }
                     //OR
@@ -376,18 +538,404 @@ impl CPU{
                         self.registers.common[instr.rd] = 
                             self.registers.common[instr.rs1] & self.registers.common[instr.rs2];
                     },
-                    _ => { unreachable!() }
+                    _ => { return Err(Exception::IllegalInstruction) }
+                }
+            },
+            //Vector loads and stores, they share LOAD-FP and STORE-FP and are
+            //selected by the width
+            0b000_0111 | 0b010_0111 if vector::is_memory_width(instr) => {
+                vector::exec_memory(self, instr)?;
+            },
+            //OP-V
+            0b101_0111 => {
+                vector::exec(self, instr)?;
+            },
+            //LOAD-FP
+            0b000_0111 => {
+                self.check_float_enabled()?;
+                let instr = IType::from(instr);
+                let addr = self.registers.common[instr.rs1].wrapping_add(instr.imm as u64);
+
+                match instr.funct3{
+                    //FLW
+                    0b010 => {
+                        let mut buf = [0u8; 4];
+                        self.load(addr, &mut buf)?;
+
+                        self.registers.float[instr.rd] = float::box_f32(u32::from_le_bytes(buf));
+                    },
+                    //FLD
+                    0b011 => {
+                        let mut buf = [0u8; 8];
+                        self.load(addr, &mut buf)?;
+
+                        self.registers.float[instr.rd] = u64::from_le_bytes(buf);
+                    },
+                    _ => { return Err(Exception::IllegalInstruction) }
+                }
+                self.csr.set_dirty(csr::MSTATUS_FS);
+            },
+            //STORE-FP
+            0b010_0111 => {
+                self.check_float_enabled()?;
+                let instr = SType::from(instr);
+                let addr = self.registers.common[instr.rs1].wrapping_add(instr.imm as u64);
+
+                match instr.funct3{
+                    //FSW, the raw bits are stored whether the value is NaN-boxed or not
+                    0b010 => { self.store(addr, &(self.registers.float[instr.rs2] as u32).to_le_bytes())?; },
+                    //FSD
+                    0b011 => { self.store(addr, &self.registers.float[instr.rs2].to_le_bytes())?; },
+                    _ => { return Err(Exception::IllegalInstruction) }
+                }
+            },
+            //FMADD / FMSUB / FNMSUB / FNMADD
+            0b100_0011 | 0b100_0111 | 0b100_1011 | 0b100_1111 => {
+                self.check_float_enabled()?;
+                let instr = R4Type::from(instr);
+                let fmt = FloatFormat::from_bits(instr.fmt as u32).ok_or(Exception::IllegalInstruction)?;
+                let rm = self.rounding_mode(instr.funct3)?;
+
+                //Negate the product and/or the addend
+                let (negate_product, negate_addend) = match instr.opcode{
+                    0b100_0011 => (false, false),
+                    0b100_0111 => (false, true),
+                    0b100_1011 => (true, false),
+                    _ => (true, true),
+                };
+
+                let result = float::mul_add(fmt,
+                    self.registers.float[instr.rs1], self.registers.float[instr.rs2], self.registers.float[instr.rs3],
+                    negate_product, negate_addend, rm);
+                self.write_float(instr.rd, result);
+                self.csr.set_dirty(csr::MSTATUS_FS);
+            },
+            //OP-FP
+            0b101_0011 => {
+                self.check_float_enabled()?;
+                let instr = RType::from(instr);
+                let fmt = FloatFormat::from_bits((instr.funct7 & 0b11) as u32).ok_or(Exception::IllegalInstruction)?;
+                let rs1 = self.registers.float[instr.rs1];
+                let rs2 = self.registers.float[instr.rs2];
+
+                match instr.funct7 >> 2{
+                    //FADD
+                    0b00000 => {
+                        let rm = self.rounding_mode(instr.funct3)?;
+                        self.write_float(instr.rd, float::add(fmt, rs1, rs2, rm));
+                    },
+                    //FSUB
+                    0b00001 => {
+                        let rm = self.rounding_mode(instr.funct3)?;
+                        self.write_float(instr.rd, float::sub(fmt, rs1, rs2, rm));
+                    },
+                    //FMUL
+                    0b00010 => {
+                        let rm = self.rounding_mode(instr.funct3)?;
+                        self.write_float(instr.rd, float::mul(fmt, rs1, rs2, rm));
+                    },
+                    //FDIV
+                    0b00011 => {
+                        let rm = self.rounding_mode(instr.funct3)?;
+                        self.write_float(instr.rd, float::div(fmt, rs1, rs2, rm));
+                    },
+                    //FSQRT
+                    0b01011 => {
+                        let rm = self.rounding_mode(instr.funct3)?;
+                        self.write_float(instr.rd, float::sqrt(fmt, rs1, rm));
+                    },
+                    //FSGNJ / FSGNJN / FSGNJX
+                    0b00100 => {
+                        self.registers.float[instr.rd] = float::sign_inject(fmt, rs1, rs2, instr.funct3)
+                            .ok_or(Exception::IllegalInstruction)?;
+                    },
+                    //FMIN / FMAX
+                    0b00101 => {
+                        let max = match instr.funct3{
+                            0b000 => false,
+                            0b001 => true,
+                            _ => { return Err(Exception::IllegalInstruction) }
+                        };
+                        self.write_float(instr.rd, float::min_max(fmt, rs1, rs2, max));
+                    },
+                    //FCVT.S.D / FCVT.D.S
+                    0b01000 => {
+                        let rm = self.rounding_mode(instr.funct3)?;
+                        self.write_float(instr.rd, float::convert_float(fmt, rs1, rm));
+                    },
+                    //FEQ / FLT / FLE
+                    0b10100 => {
+                        let (value, flags) = float::compare(fmt, rs1, rs2, instr.funct3)
+                            .ok_or(Exception::IllegalInstruction)?;
+                        self.registers.common[instr.rd] = value;
+                        self.registers.fcsr |= flags;
+                    },
+                    //FCVT.W / WU / L / LU, the source type is selected by rs2
+                    0b11000 if instr.rs2 < 2 || self.xlen == Xlen::Rv64 => {
+                        let rm = self.rounding_mode(instr.funct3)?;
+                        let (value, flags) = float::to_int(fmt, rs1, instr.rs2, rm)
+                            .ok_or(Exception::IllegalInstruction)?;
+                        self.registers.common[instr.rd] = value;
+                        self.registers.fcsr |= flags;
+                    },
+                    //FCVT.fmt.W / WU / L / LU
+                    0b11010 if instr.rs2 < 2 || self.xlen == Xlen::Rv64 => {
+                        let rm = self.rounding_mode(instr.funct3)?;
+                        let value = float::from_int(fmt, self.registers.common[instr.rs1], instr.rs2, rm)
+                            .ok_or(Exception::IllegalInstruction)?;
+                        self.write_float(instr.rd, value);
+                    },
+                    //FMV.X.W / FMV.X.D / FCLASS
+                    0b11100 => {
+                        self.registers.common[instr.rd] = match (instr.funct3, fmt){
+                            (0b000, FloatFormat::Single) => rs1 as u32 as i32 as i64 as u64,
+                            (0b000, FloatFormat::Double) if self.xlen == Xlen::Rv64 => rs1,
+                            (0b001, _) => float::classify(fmt, rs1),
+                            _ => { return Err(Exception::IllegalInstruction) }
+                        };
+                    },
+                    //FMV.W.X / FMV.D.X
+                    0b11110 => {
+                        let value = self.registers.common[instr.rs1];
+                        self.registers.float[instr.rd] = match fmt{
+                            FloatFormat::Single => float::box_f32(value as u32),
+                            FloatFormat::Double if self.xlen == Xlen::Rv64 => value,
+                            FloatFormat::Double => { return Err(Exception::IllegalInstruction) },
+                        };
+                    },
+                    _ => { return Err(Exception::IllegalInstruction) }
+                }
+                self.csr.set_dirty(csr::MSTATUS_FS);
+            },
+            //RV64A Atomic instructions
+            0b010_1111 => {
+                let instr = RType::from(instr);
+                let addr = self.registers.common[instr.rs1];
+                //The aq and rl bits are ignored, there is a single hart
+                let funct5 = instr.funct7 >> 2;
+
+                let size = match instr.funct3 {
+                    0b010 => 4,
+                    0b011 if self.xlen == Xlen::Rv64 => 8,
+                    _ => { return Err(Exception::IllegalInstruction) }
+                };
+
+                //Misaligned atomics are not supported, LR reports a load
+                //fault and the others a store/AMO fault
+                if addr % size != 0{
+                    if funct5 == 0b00010{
+                        return Err(Exception::LoadAddressMisaligned(addr));
+                    }
+                    return Err(Exception::StoreAddressMisaligned(addr));
+                }
+
+                //The reservation and the accesses use the physical address
+                let access = if funct5 == 0b00010 { Access::Load } else { Access::Store };
+                let paddr = self.translate(addr, access)?;
+                self.memory.check_and_grow(paddr, size, access.kind())
+                    .map_err(|e| self.memory_fault(access, addr, paddr, e))?;
+
+                match funct5 {
+                    //LR.W / LR.D
+                    0b00010 => {
+                        self.check_initialized(addr, paddr, size)?;
+                        self.registers.common[instr.rd] = self.read_atomic(paddr, size)
+                            .map_err(|e| self.memory_fault(access, addr, paddr, e))?;
+                        self.memory.reserve(paddr);
+                    },
+                    //SC.W / SC.D
+                    0b00011 => {
+                        if self.memory.check_reservation(paddr){
+                            let value = self.registers.common[instr.rs2];
+                            self.memory.write(paddr, &value.to_le_bytes()[..size as usize])
+                                .map_err(|e| self.memory_fault(access, addr, paddr, e))?;
+                            self.registers.common[instr.rd] = 0;
+                        }
+                        else{
+                            self.registers.common[instr.rd] = 1;
+                        }
+                    },
+                    //AMOs: load the value, apply the operation then store it back
+                    _ => {
+                        self.check_initialized(addr, paddr, size)?;
+                        let old = self.read_atomic(paddr, size)
+                            .map_err(|e| self.memory_fault(access, addr, paddr, e))?;
+                        let src = self.registers.common[instr.rs2];
+
+                        //Signed comparisons are made on the operand size
+                        let (old_s, src_s) = if size == 4 {
+                            (old as i32 as i64, src as i32 as i64)
+                        } else {
+                            (old as i64, src as i64)
+                        };
+                        let (old_u, src_u) = if size == 4 {
+                            (old as u32 as u64, src as u32 as u64)
+                        } else {
+                            (old, src)
+                        };
+
+                        let value = match funct5 {
+                            //AMOSWAP
+                            0b00001 => src,
+                            //AMOADD
+                            0b00000 => old.wrapping_add(src),
+                            //AMOXOR
+                            0b00100 => old ^ src,
+                            //AMOAND
+                            0b01100 => old & src,
+                            //AMOOR
+                            0b01000 => old | src,
+                            //AMOMIN
+                            0b10000 => if old_s < src_s {old} else {src},
+                            //AMOMAX
+                            0b10100 => if old_s > src_s {old} else {src},
+                            //AMOMINU
+                            0b11000 => if old_u < src_u {old} else {src},
+                            //AMOMAXU
+                            0b11100 => if old_u > src_u {old} else {src},
+                            _ => { return Err(Exception::IllegalInstruction) }
+                        };
+
+                        self.memory.write(paddr, &value.to_le_bytes()[..size as usize])
+                            .map_err(|e| self.memory_fault(access, addr, paddr, e))?;
+                        self.registers.common[instr.rd] = old;
+                    }
                 }
             },
-            //FENCE
-            0b000_1111 => { panic!("FENCE NYI"); },
-            //ECALL EBREAK
+            //MISC-MEM
+            0b000_1111 => {
+                match (instr >> 12) & 0b111{
+                    //FENCE / FENCE.TSO, there is a single hart and the
+                    //devices are accessed synchronously, memory is always
+                    //seen in program order
+                    0b000 => {},
+                    //FENCE.I, instructions are fetched from memory each time
+                    //they are executed, nothing decoded has to be dropped
+                    0b001 => {},
+                    _ => { return Err(Exception::IllegalInstruction) },
+                }
+            },
+            //SYSTEM
             0b111_0011 => { 
-                fuzzer.borrow_mut().syscall(self);
+                let instr = CsrType::from(instr);
+
+                let tvm = self.privilege == Privilege::Supervisor &&
+                    self.csr.get(csr::MSTATUS) & csr::MSTATUS_TVM != 0;
+
+                match instr.funct3{
+                    //SFENCE.VMA
+                    0b000 if instr.csr >> 5 == 0b000_1001 => {
+                        if self.privilege == Privilege::User || tvm || instr.rd != 0{
+                            return Err(Exception::IllegalInstruction);
+                        }
+
+                        if instr.rs1 == 0{
+                            self.mmu.flush();
+                        }
+                        else{
+                            self.mmu.flush_page(self.registers.common[instr.rs1]);
+                        }
+                    },
+                    0b000 => {
+                        if instr.rd != 0 || instr.rs1 != 0{
+                            return Err(Exception::IllegalInstruction);
+                        }
+
+                        match instr.csr{
+                            //ECALL, system calls are handled by the fuzzer
+                            //when the OS is emulated and SBI calls by the
+                            //emulator when there is no firmware
+                            0b0000_0000_0000 => {
+                                if self.syscall_emulation{
+                                    fuzzer.borrow_mut().syscall(self)?;
+                                }
+                                else if self.emulated_sbi && self.privilege == Privilege::Supervisor{
+                                    sbi::call(self);
+                                }
+                                else{
+                                    return Err(Exception::EnvironmentCall);
+                                }
+                            },
+                            //EBREAK
+                            0b0000_0000_0001 => { return Err(Exception::Breakpoint) },
+                            //SRET
+                            0b0001_0000_0010 => {
+                                let tsr = self.csr.get(csr::MSTATUS) & csr::MSTATUS_TSR != 0;
+                                if self.privilege < Privilege::Supervisor ||
+                                    (self.privilege == Privilege::Supervisor && tsr){
+                                    return Err(Exception::IllegalInstruction);
+                                }
+                                take_branch = true;
+                                branch_dest = self.sret();
+                            },
+                            //MRET
+                            0b0011_0000_0010 => {
+                                if self.privilege < Privilege::Machine{
+                                    return Err(Exception::IllegalInstruction);
+                                }
+                                take_branch = true;
+                                branch_dest = self.mret();
+                            },
+                            //WFI, when no interrupt is pending the clock jumps
+                            //to the next timer event if it can wake the hart
+                            0b0001_0000_0101 => {
+                                let tw = self.csr.get(csr::MSTATUS) & csr::MSTATUS_TW != 0;
+                                if self.privilege < Privilege::Machine && tw{
+                                    return Err(Exception::IllegalInstruction);
+                                }
+
+                                let mie = self.csr.get(csr::MIE);
+                                let timer_enabled = mie & (csr::MIP_MTIP | csr::MIP_STIP) != 0;
+                                if self.csr.get(csr::MIP) & mie == 0 && timer_enabled{
+                                    self.clint.fast_forward(self.instret);
+                                }
+                            },
+                            _ => { return Err(Exception::IllegalInstruction) }
+                        }
+                    },
+                    0b100 => { return Err(Exception::IllegalInstruction) },
+                    //CSRRW CSRRS CSRRC CSRRWI CSRRSI CSRRCI
+                    _ => {
+                        //CSRs can only be accessed from their privilege level
+                        //or above
+                        if (instr.csr >> 8) & 0b11 > self.privilege as u16 || (instr.csr == csr::SATP && tvm){
+                            return Err(Exception::IllegalInstruction);
+                        }
+
+                        let operand = if instr.funct3 & 0b100 != 0 {
+                            instr.rs1 as u64
+                        } else {
+                            self.registers.common[instr.rs1]
+                        };
+
+                        //CSRRW does not read the CSR when rd is x0 and
+                        //CSRRS/CSRRC do not write it when the operand is x0
+                        let read = instr.funct3 & 0b11 != 0b01 || instr.rd != 0;
+                        let old = if read {
+                            self.read_csr(instr.csr).ok_or(Exception::IllegalInstruction)?
+                        } else {
+                            0
+                        };
+
+                        let new = match instr.funct3 & 0b11{
+                            0b01 => Some(operand),
+                            0b10 if instr.rs1 != 0 => Some(old | operand),
+                            0b11 if instr.rs1 != 0 => Some(old & !operand),
+                            _ => None,
+                        };
+                        if let Some(new) = new {
+                            if !self.write_csr(instr.csr, new){
+                                return Err(Exception::IllegalInstruction);
+                            }
+                        }
+
+                        self.registers.common[instr.rd] = old;
+                    },
+                }
             },
             
             //RV64I specific instructions
-            0b001_1011 =>{
+            0b001_1011 if self.xlen == Xlen::Rv64 =>{
                 let instr = IType::from(instr);
 
                 match instr.funct3{
@@ -413,10 +961,38 @@ impl CPU{
                             self.registers.common[instr.rd] = ((self.registers.common[instr.rs1] as u32) >> shamt) as i32 as u64; 
                         }
                     },
-                    _ => {unreachable!()}
+                    _ => { return Err(Exception::IllegalInstruction) }
                 }
             },
-            0b011_1011 =>{
+            //RV64M specific instructions
+            0b011_1011 if self.xlen == Xlen::Rv64 && (instr >> 25) == 0b000_0001 => {
+                let instr = RType::from(instr);
+                let rs1 = self.registers.common[instr.rs1];
+                let rs2 = self.registers.common[instr.rs2];
+
+                self.registers.common[instr.rd] = match instr.funct3 {
+                    //MULW
+                    0b000 => (rs1 as i32).wrapping_mul(rs2 as i32) as i64 as u64,
+                    //DIVW
+                    0b100 => {
+                        if rs2 as i32 == 0 { u64::MAX }
+                        else { (rs1 as i32).wrapping_div(rs2 as i32) as i64 as u64 }
+                    },
+                    //DIVUW
+                    0b101 => {
+                        (rs1 as u32).checked_div(rs2 as u32).map_or(u64::MAX, |q| q as i32 as i64 as u64)
+                    },
+                    //REMW
+                    0b110 => {
+                        if rs2 as i32 == 0 { rs1 as i32 as i64 as u64 }
+                        else { (rs1 as i32).wrapping_rem(rs2 as i32) as i64 as u64 }
+                    },
+                    //REMUW
+                    0b111 => (rs1 as u32).checked_rem(rs2 as u32).unwrap_or(rs1 as u32) as i32 as i64 as u64,
+                    _ => { return Err(Exception::IllegalInstruction) }
+                };
+            },
+            0b011_1011 if self.xlen == Xlen::Rv64 =>{
                 let instr = RType::from(instr);
 
                 match instr.funct3{
@@ -449,24 +1025,535 @@ impl CPU{
                                 ((self.registers.common[instr.rs1] as i32) >> (self.registers.common[instr.rs2] & 0b1_1111)) as i64 as u64;
                         }
                     },
-                    _ => {unreachable!()}
+                    _ => { return Err(Exception::IllegalInstruction) }
                 }
             }
 
-            _ => unreachable!("{:b}", opcode)
+            _ => { return Err(Exception::IllegalInstruction) }
+        }
+
+        //RV32 results are sign extended from bit 31. rd is always at the same
+        //place, the instructions without destination have immediate bits
+        //there and extending an already extended register changes nothing
+        if self.xlen == Xlen::Rv32{
+            let rd = ((instr >> 7) & 0b1_1111) as usize;
+            self.registers.common[rd] = self.registers.common[rd] as i32 as i64 as u64;
         }
 
+        //x0 is hardwired to zero, instructions using it as destination are
+        //simply discarded
+        self.registers.common[0] = 0;
+
         //We branched
         if take_branch{
+            //Nothing is executable at 0, jumping there is following a null
+            //function pointer
             if branch_dest == 0{
-                panic!("Branching to a non set destination");
+                return Err(Exception::InstructionAccessFault(0));
             }
             //Record the xor of the origin and the destination
+            let branch_dest = self.address(branch_dest);
             self.coverage.insert(self.registers.pc ^ branch_dest);
             self.registers.pc = branch_dest;
         }
         else {
-            self.registers.pc = self.registers.pc.wrapping_add(4);
+            self.registers.pc = self.address(self.registers.pc.wrapping_add(len));
+        }
+        Ok(())
+    }
+
+    /// Select the width of the registers, misa and the instructions
+    /// available follow it
+    pub fn set_xlen(&mut self, xlen: Xlen){
+        self.xlen = xlen;
+        self.csr.set_xlen(xlen == Xlen::Rv32);
+        self.set_extensions(self.extensions);
+    }
+
+    /// Enable or disable the optional extensions, misa follows them. The
+    /// vector registers are cleared when VLEN changes
+    pub fn set_extensions(&mut self, extensions: Extensions){
+        if extensions.vlen != self.vector.vlen{
+            self.vector = VectorState::new(extensions.vlen);
+        }
+        self.extensions = extensions;
+        let misa = self.csr.get(csr::MISA) & !Extensions::all().misa_bits();
+        self.csr.set(csr::MISA, misa | extensions.misa_bits());
+    }
+
+    /// Addresses wrap at 4GiB in RV32
+    fn address(&self, value: u64) -> u64{
+        match self.xlen{
+            Xlen::Rv32 => value as u32 as u64,
+            Xlen::Rv64 => value,
+        }
+    }
+
+    /// Register value seen by the unsigned operations, the RV32 values
+    /// are zero extended
+    fn unsigned(&self, value: u64) -> u64{
+        self.address(value)
+    }
+
+    /// Target of a jump or of a taken branch, it must be aligned on 4 bytes
+    /// without the C extension
+    fn jump_target(&self, dest: u64) -> Result<u64, Exception>{
+        if !self.extensions.c && dest & 0b11 != 0{
+            return Err(Exception::InstructionAddressMisaligned(self.address(dest)));
+        }
+        Ok(dest)
+    }
+
+    /// Mask of the register shift amounts
+    fn shift_mask(&self) -> u64{
+        match self.xlen{
+            Xlen::Rv32 => 0b1_1111,
+            Xlen::Rv64 => 0b11_1111,
+        }
+    }
+
+    /// Shift amount of an immediate shift, shamt[5] is reserved in RV32
+    fn shift_amount(&self, imm: u64) -> Result<u64, Exception>{
+        let shamt = imm & 0b11_1111;
+        if shamt > self.shift_mask(){
+            return Err(Exception::IllegalInstruction);
+        }
+        Ok(shamt)
+    }
+
+    /// Read a CSR, None if it does not exist
+    fn read_csr(&self, addr: u16) -> Option<u64>{
+        match addr{
+            csr::FFLAGS | csr::FRM | csr::FCSR if self.check_float_enabled().is_err() => None,
+            csr::FFLAGS => Some((self.registers.fcsr & 0b1_1111) as u64),
+            csr::FRM => Some(((self.registers.fcsr >> 5) & 0b111) as u64),
+            csr::FCSR => Some((self.registers.fcsr & 0xFF) as u64),
+            csr::VSTART | csr::VXSAT | csr::VXRM | csr::VCSR | csr::VL | csr::VTYPE | csr::VLENB => vector::read_csr(self, addr),
+            csr::CYCLE | csr::INSTRET => {
+                if !self.counter_enabled(addr){
+                    return None;
+                }
+                Some(self.instret)
+            },
+            csr::TIME => {
+                if !self.counter_enabled(addr){
+                    return None;
+                }
+                Some(self.clint.mtime(self.instret))
+            },
+            csr::MCYCLE | csr::MINSTRET => Some(self.instret),
+            csr::CYCLEH | csr::TIMEH | csr::INSTRETH if self.xlen == Xlen::Rv32 => {
+                self.read_csr(addr - (csr::CYCLEH - csr::CYCLE)).map(|value| value >> 32)
+            },
+            csr::MCYCLEH | csr::MINSTRETH if self.xlen == Xlen::Rv32 => Some(self.instret >> 32),
+            _ => self.csr.read(addr),
+        }
+    }
+
+    /// Counters are readable from lower privileges only if they are enabled
+    /// in mcounteren, and in scounteren for U-mode
+    fn counter_enabled(&self, addr: u16) -> bool{
+        let bit = 1 << (addr - csr::CYCLE);
+        match self.privilege{
+            Privilege::Machine => true,
+            Privilege::Supervisor => self.csr.get(csr::MCOUNTEREN) & bit != 0,
+            Privilege::User => self.csr.get(csr::MCOUNTEREN) & self.csr.get(csr::SCOUNTEREN) & bit != 0,
+        }
+    }
+
+    /// Write a CSR, returns false if it does not exist or is read only
+    fn write_csr(&mut self, addr: u16, value: u64) -> bool{
+        match addr{
+            csr::FFLAGS | csr::FRM | csr::FCSR => {
+                if self.check_float_enabled().is_err(){
+                    return false;
+                }
+                self.registers.fcsr = match addr{
+                    csr::FFLAGS => (self.registers.fcsr & !0b1_1111) | (value as u32 & 0b1_1111),
+                    csr::FRM => (self.registers.fcsr & 0b1_1111) | ((value as u32 & 0b111) << 5),
+                    _ => value as u32 & 0xFF,
+                };
+                self.csr.set_dirty(csr::MSTATUS_FS);
+            },
+            csr::VSTART | csr::VXSAT | csr::VXRM | csr::VCSR | csr::VL | csr::VTYPE | csr::VLENB => {
+                return vector::write_csr(self, addr, value);
+            },
+            //RV32 writes replace one half of the counter
+            csr::MCYCLE | csr::MINSTRET if self.xlen == Xlen::Rv32 => {
+                self.instret = (self.instret & !0xFFFF_FFFF) | value as u32 as u64;
+            },
+            csr::MCYCLEH | csr::MINSTRETH if self.xlen == Xlen::Rv32 => {
+                self.instret = (self.instret & 0xFFFF_FFFF) | (value as u32 as u64) << 32;
+            },
+            csr::MCYCLE | csr::MINSTRET => self.instret = value,
+            //The TLB is not tagged with the ASID
+            csr::SATP => {
+                self.mmu.flush();
+                return self.csr.write(addr, value);
+            },
+            _ => return self.csr.write(addr, value),
+        }
+        true
+    }
+
+    /// Take a trap for exception raised by the instruction at pc, encoding
+    /// is the faulting instruction as found in memory. The trap is handled in
+    /// S-mode if it is delegated through medeleg, in M-mode otherwise
+    fn trap(&mut self, exception: Exception, encoding: u32) -> Result<(), CpuFault>{
+        let tval = match exception{
+            Exception::IllegalInstruction => encoding as u64,
+            Exception::Breakpoint => self.registers.pc,
+            _ => exception.address().unwrap_or(0),
+        };
+
+        //No trap handler exists when the OS is emulated
+        if self.syscall_emulation{
+            let pc = self.registers.pc;
+            return Err(match (exception, &self.heap){
+                (Exception::MemoryFault(error), Some(heap)) if error.violation == Violation::Poisoned =>
+                    heap.access_fault(pc, error),
+                _ => CpuFault::new(exception, pc, tval),
+            });
+        }
+
+        let cause = exception.cause(self.privilege);
+
+        let delegated = self.privilege <= Privilege::Supervisor &&
+            (self.csr.get(csr::MEDELEG) >> cause) & 1 == 1;
+        self.enter_trap(cause, tval, delegated);
+        Ok(())
+    }
+
+    /// Returns the code of the interrupt to take before the next instruction
+    /// if any, interrupts are taken by order of priority
+    fn pending_interrupt(&self) -> Option<u64>{
+        let pending = self.csr.get(csr::MIP) & self.csr.get(csr::MIE);
+        if pending == 0{
+            return None;
+        }
+
+        let mstatus = self.csr.get(csr::MSTATUS);
+        let mideleg = self.csr.get(csr::MIDELEG);
+
+        //Interrupts for a higher privilege are always enabled, those for the
+        //current one depend on the global enable bit and those for a lower
+        //one are never taken
+        let m_enabled = self.privilege < Privilege::Machine || mstatus & csr::MSTATUS_MIE != 0;
+        let s_enabled = self.privilege < Privilege::Supervisor ||
+            (self.privilege == Privilege::Supervisor && mstatus & csr::MSTATUS_SIE != 0);
+
+        let enabled = (pending & !mideleg & if m_enabled { u64::MAX } else { 0 }) |
+            (pending & mideleg & if s_enabled { u64::MAX } else { 0 });
+
+        csr::INTERRUPT_PRIORITY.iter()
+            .copied()
+            .find(|code| enabled & (1 << code) != 0)
+    }
+
+    /// Take the interrupt code, the interrupted instruction is resumed when
+    /// the handler returns
+    fn interrupt(&mut self, code: u64){
+        let delegated = (self.csr.get(csr::MIDELEG) >> code) & 1 == 1;
+        self.enter_trap(csr::INTERRUPT_CAUSE | code, 0, delegated);
+    }
+
+    /// Save the state of the hart in the CSRs of the privilege handling the
+    /// trap and jump to its handler, interrupts use the vectored mode if
+    /// enabled in xtvec
+    fn enter_trap(&mut self, cause: u64, tval: u64, delegated: bool){
+        self.memory.clear_reservation();
+        let mstatus = self.csr.get(csr::MSTATUS);
+
+        let tvec = if delegated{
+            self.csr.set(csr::SEPC, self.registers.pc);
+            self.csr.set(csr::SCAUSE, cause);
+            self.csr.set(csr::STVAL, tval);
+
+            //SPIE takes the value of SIE and SPP the previous privilege
+            let mut status = mstatus & !(csr::MSTATUS_SPIE | csr::MSTATUS_SPP | csr::MSTATUS_SIE);
+            if mstatus & csr::MSTATUS_SIE != 0{
+                status |= csr::MSTATUS_SPIE;
+            }
+            status |= (self.privilege as u64) << csr::MSTATUS_SPP_SHIFT;
+            self.csr.set(csr::MSTATUS, status);
+
+            self.privilege = Privilege::Supervisor;
+            self.csr.get(csr::STVEC)
+        }
+        else{
+            self.csr.set(csr::MEPC, self.registers.pc);
+            self.csr.set(csr::MCAUSE, cause);
+            self.csr.set(csr::MTVAL, tval);
+
+            //MPIE takes the value of MIE and MPP the previous privilege
+            let mut status = mstatus & !(csr::MSTATUS_MPIE | csr::MSTATUS_MPP | csr::MSTATUS_MIE);
+            if mstatus & csr::MSTATUS_MIE != 0{
+                status |= csr::MSTATUS_MPIE;
+            }
+            status |= (self.privilege as u64) << csr::MSTATUS_MPP_SHIFT;
+            self.csr.set(csr::MSTATUS, status);
+
+            self.privilege = Privilege::Machine;
+            self.csr.get(csr::MTVEC)
+        };
+
+        let base = tvec & !0b11;
+        self.registers.pc = if tvec & 0b11 == 1 && cause & csr::INTERRUPT_CAUSE != 0{
+            base.wrapping_add(4 * (cause & !csr::INTERRUPT_CAUSE))
+        }
+        else{
+            base
+        };
+    }
+
+    /// Refresh the interrupt pending bits driven by the CLINT, without
+    /// firmware the timer is routed to S-mode as the SBI timer
+    fn update_interrupts(&mut self){
+        let mut mip = self.csr.get(csr::MIP) & !(csr::MIP_MSIP | csr::MIP_MTIP);
+        let timer = self.clint.timer_pending(self.instret);
+
+        if self.clint.software_pending(){
+            mip |= csr::MIP_MSIP;
+        }
+        if self.emulated_sbi{
+            mip &= !csr::MIP_STIP;
+            if timer{
+                mip |= csr::MIP_STIP;
+            }
+        }
+        else if timer{
+            mip |= csr::MIP_MTIP;
+        }
+        if let Some(plic) = &self.plic{
+            self.memory.poll_devices();
+
+            let mut plic = plic.borrow_mut();
+            self.memory.update_irqs(&mut plic);
+
+            mip &= !(csr::MIP_MEIP | csr::MIP_SEIP);
+            if plic.context_pending(plic::CONTEXT_MACHINE){
+                mip |= csr::MIP_MEIP;
+            }
+            if plic.context_pending(plic::CONTEXT_SUPERVISOR){
+                mip |= csr::MIP_SEIP;
+            }
+        }
+        self.csr.set(csr::MIP, mip);
+    }
+
+    /// Return from a M-mode trap handler, returns the address to jump to
+    fn mret(&mut self) -> u64{
+        let mstatus = self.csr.get(csr::MSTATUS);
+        let previous = Privilege::from_bits(mstatus >> csr::MSTATUS_MPP_SHIFT);
+
+        let mut mstatus = mstatus & !(csr::MSTATUS_MIE | csr::MSTATUS_MPP);
+        if mstatus & csr::MSTATUS_MPIE != 0{
+            mstatus |= csr::MSTATUS_MIE;
+        }
+        mstatus |= csr::MSTATUS_MPIE;
+        //MPRV is cleared when leaving M-mode
+        if previous != Privilege::Machine{
+            mstatus &= !csr::MSTATUS_MPRV;
+        }
+        self.csr.set(csr::MSTATUS, mstatus);
+
+        self.privilege = previous;
+        self.csr.get(csr::MEPC)
+    }
+
+    /// Return from a S-mode trap handler, returns the address to jump to
+    fn sret(&mut self) -> u64{
+        let mstatus = self.csr.get(csr::MSTATUS);
+        let previous = Privilege::from_bits((mstatus & csr::MSTATUS_SPP) >> csr::MSTATUS_SPP_SHIFT);
+
+        let mut mstatus = mstatus & !(csr::MSTATUS_SIE | csr::MSTATUS_SPP | csr::MSTATUS_MPRV);
+        if mstatus & csr::MSTATUS_SPIE != 0{
+            mstatus |= csr::MSTATUS_SIE;
+        }
+        mstatus |= csr::MSTATUS_SPIE;
+        self.csr.set(csr::MSTATUS, mstatus);
+
+        self.privilege = previous;
+        self.csr.get(csr::SEPC)
+    }
+
+    /// Rounding mode encoded in an instruction, 0b111 selects the dynamic
+    /// rounding mode of fcsr
+    fn rounding_mode(&self, rm: u8) -> Result<RoundingMode, Exception>{
+        let rm = if rm == 0b111 { (self.registers.fcsr >> 5) & 0b111 } else { rm as u32 };
+        RoundingMode::from_bits(rm).ok_or(Exception::IllegalInstruction)
+    }
+
+    /// Write the result of a floating point operation and accrue its exception flags
+    /// The F and D instructions and CSRs are illegal when mstatus.FS is Off,
+    /// except for the user space programs that have no kernel to turn it on
+    fn check_float_enabled(&self) -> Result<(), Exception>{
+        if self.csr.get(csr::MSTATUS) & csr::MSTATUS_FS == 0 && !self.syscall_emulation{
+            return Err(Exception::IllegalInstruction);
+        }
+        Ok(())
+    }
+
+    fn write_float(&mut self, rd: usize, (value, flags): (u64, u32)){
+        self.registers.float[rd] = value;
+        self.registers.fcsr |= flags;
+    }
+
+    /// Read a word or a double word at a physical address for an atomic
+    /// instruction, words are sign extended
+    fn read_atomic(&self, paddr: u64, size: u64) -> Result<u64, MemoryError>{
+        if size == 4{
+            let mut buf = [0u8; 4];
+            self.memory.read(paddr, &mut buf)?;
+            Ok(i32::from_le_bytes(buf) as i64 as u64)
+        }
+        else{
+            let mut buf = [0u8; 8];
+            self.memory.read(paddr, &mut buf)?;
+            Ok(u64::from_le_bytes(buf))
+        }
+    }
+
+    /// Translate a virtual address, loads and stores made from M-mode with
+    /// MPRV set use the privilege in MPP
+    fn translate(&mut self, vaddr: u64, access: Access) -> Result<u64, Exception>{
+        let vaddr = self.address(vaddr);
+        let mstatus = self.csr.get(csr::MSTATUS);
+        let privilege = if access != Access::Fetch && mstatus & csr::MSTATUS_MPRV != 0 {
+            Privilege::from_bits(mstatus >> csr::MSTATUS_MPP_SHIFT)
+        } else {
+            self.privilege
+        };
+
+        let ctx = mmu::Context{
+            satp: self.csr.get(csr::SATP),
+            privilege,
+            sum: mstatus & csr::MSTATUS_SUM != 0,
+            mxr: mstatus & csr::MSTATUS_MXR != 0,
+        };
+        self.mmu.translate(&mut self.memory, &ctx, vaddr, access)
+    }
+
+    /// Translate the len bytes at vaddr, accesses crossing a page boundary
+    /// are split in two physical accesses. Returns the physical address and
+    /// length of each part
+    fn translate_range(&mut self, vaddr: u64, len: u64, access: Access) -> Result<[(u64, u64); 2], Exception>{
+        let first_len = len.min(mmu::PAGE_SIZE - (vaddr & (mmu::PAGE_SIZE - 1)));
+        let mut parts = [(0, 0); 2];
+
+        let ranges = [(vaddr, first_len), (vaddr.wrapping_add(first_len), len - first_len)];
+        for (i, &(addr, len)) in ranges.iter().enumerate(){
+            if len == 0{
+                continue;
+            }
+            let paddr = self.translate(addr, access)?;
+            //Code cannot be fetched from the device registers
+            let device = access != Access::Fetch && Clint::contains(paddr);
+            if !device{
+                self.memory.check_and_grow(paddr, len, access.kind())
+                    .map_err(|e| self.memory_fault(access, addr, paddr, e))?;
+            }
+            parts[i] = (paddr, len);
+        }
+        Ok(parts)
+    }
+
+    /// Read memory for a load
+    pub fn load(&mut self, addr: u64, buf: &mut [u8]) -> Result<(), Exception>{
+        let [(first, first_len), (second, second_len)] = self.translate_range(addr, buf.len() as u64, Access::Load)?;
+        self.check_initialized(addr, first, first_len)?;
+        self.check_initialized(addr.wrapping_add(first_len), second, second_len)?;
+        let (head, tail) = buf.split_at_mut(first_len as usize);
+
+        self.read_physical(first, head)
+            .map_err(|e| self.memory_fault(Access::Load, addr, first, e))?;
+        if !tail.is_empty(){
+            self.read_physical(second, tail)
+                .map_err(|e| self.memory_fault(Access::Load, addr.wrapping_add(first_len), second, e))?;
+        }
+        Ok(())
+    }
+
+    /// Write memory for a store, nothing is written if any part faults
+    pub fn store(&mut self, addr: u64, buf: &[u8]) -> Result<(), Exception>{
+        let [(first, first_len), (second, _)] = self.translate_range(addr, buf.len() as u64, Access::Store)?;
+        let (head, tail) = buf.split_at(first_len as usize);
+
+        self.write_physical(first, head)
+            .map_err(|e| self.memory_fault(Access::Store, addr, first, e))?;
+        if !tail.is_empty(){
+            self.write_physical(second, tail)
+                .map_err(|e| self.memory_fault(Access::Store, addr.wrapping_add(first_len), second, e))?;
+        }
+        Ok(())
+    }
+
+    /// Exception for the failed access to paddr made for vaddr, the error
+    /// is given the virtual address of the faulting byte and the kind of the
+    /// instruction, the AMOs are writes
+    fn memory_fault(&self, access: Access, vaddr: u64, paddr: u64, error: MemoryError) -> Exception{
+        Exception::MemoryFault(MemoryError{
+            addr: self.address(vaddr.wrapping_add(error.addr.wrapping_sub(paddr))),
+            kind: access.kind(),
+            ..error
+        })
+    }
+
+    /// Loading bytes that were never written is a bug of the program, vaddr
+    /// is translated to paddr
+    fn check_initialized(&self, vaddr: u64, paddr: u64, len: u64) -> Result<(), Exception>{
+        match self.memory.uninitialized(paddr, len){
+            Some(at) => Err(Exception::UninitializedRead(self.address(vaddr.wrapping_add(at - paddr)))),
+            None => Ok(()),
+        }
+    }
+
+    /// Read a physical address already checked by translate_range, the
+    /// device registers are served by the devices
+    fn read_physical(&self, paddr: u64, buf: &mut [u8]) -> Result<(), MemoryError>{
+        if Clint::contains(paddr){
+            self.clint.read(paddr - CLINT_BASE, buf, self.instret);
+            Ok(())
+        }
+        else{
+            self.memory.read(paddr, buf)
+        }
+    }
+
+    /// Write a physical address already checked by translate_range
+    fn write_physical(&mut self, paddr: u64, buf: &[u8]) -> Result<(), MemoryError>{
+        if Clint::contains(paddr){
+            self.clint.write(paddr - CLINT_BASE, buf, self.instret);
+            Ok(())
+        }
+        else{
+            self.memory.write(paddr, buf)
+        }
+    }
+
+    /// Fetch the instruction at pc, returns its encoding and its length
+    fn fetch(&mut self) -> Result<(u32, u64), Exception>{
+        let pc = self.registers.pc;
+
+        //The two lowest bits of the first half word are 0b11 for 32 bits
+        //instructions, anything else is a compressed instruction
+        let [(paddr, _), _] = self.translate_range(pc, 2, Access::Fetch)?;
+        let mut half = [0u8; 2];
+        self.memory.fetch(paddr, &mut half)
+            .map_err(|e| self.memory_fault(Access::Fetch, pc, paddr, e))?;
+        let half = u16::from_le_bytes(half);
+
+        if (half & 0b11) == 0b11{
+            //The upper half may be on the next page
+            let [(paddr, _), _] = self.translate_range(pc.wrapping_add(2), 2, Access::Fetch)?;
+            let mut upper = [0u8; 2];
+            self.memory.fetch(paddr, &mut upper)
+                .map_err(|e| self.memory_fault(Access::Fetch, pc.wrapping_add(2), paddr, e))?;
+
+            Ok(((u16::from_le_bytes(upper) as u32) << 16 | half as u32, 4))
+        }
+        else{
+            Ok((half as u32, 2))
         }
     }
 
@@ -474,6 +1561,15 @@ impl CPU{
     pub fn save_as_initial_state(&mut self){
         self.saved_state = Some(CpuSnapshot{
             registers: self.registers.clone(),
+            csr: self.csr.clone(),
+            vector: self.vector.clone(),
+            privilege: self.privilege,
+            clint: self.clint.clone(),
+            plic: self.plic.as_ref().map(|p| p.borrow().clone()),
+            instret: self.instret,
+            call_stack: self.call_stack.clone(),
+            heap: self.heap.clone(),
+            process: self.process.clone(),
             coverage:{
                 if self.coverage_enabled{ Some(self.coverage.clone()) }
                 else{ None }
@@ -482,6 +1578,11 @@ impl CPU{
         self.memory.save_state();
     }
 
+    /// Returns true once save_as_initial_state has been called
+    pub fn has_initial_state(&self) -> bool{
+        self.saved_state.is_some()
+    }
+
     /// Reset to the state snapshot saved thourgh save_as_initial_state
     /// here only dirty pages are reseted, returns the coverage of the last run 
     pub fn reset_to_initial_state(&mut self) -> HashSet<u64>{
@@ -489,6 +1590,18 @@ impl CPU{
             .expect("Trying to reset but no initial state has been saved");
 
         self.registers = initial_state.registers.clone();
+        self.csr = initial_state.csr.clone();
+        self.vector = initial_state.vector.clone();
+        self.privilege = initial_state.privilege;
+        self.mmu.flush();
+        self.clint = initial_state.clint.clone();
+        if let (Some(plic), Some(saved)) = (&self.plic, &initial_state.plic){
+            *plic.borrow_mut() = saved.clone();
+        }
+        self.instret = initial_state.instret;
+        self.call_stack = initial_state.call_stack.clone();
+        self.heap = initial_state.heap.clone();
+        self.process = initial_state.process.clone();
         self.memory.reset_to_saved_state();
 
         self.nbr_exec = self.nbr_exec.wrapping_add(1);
@@ -496,14 +1609,28 @@ impl CPU{
         self.coverage.clone()
     }
 
-    pub fn execute(&mut self, entrypoint: u64, fuzzer: Rc<RefCell<Fuzzer>>){
+    /// Run from entrypoint until exit is set, see run
+    pub fn execute(&mut self, entrypoint: u64, fuzzer: Rc<RefCell<Fuzzer>>) -> Result<(), CpuFault>{
         self.registers.pc = entrypoint;
+        self.run(fuzzer)
+    }
+
+    /// Run from the current pc until exit is set by a breakpoint handler,
+    /// a fault stops the execution at the faulting instruction
+    pub fn run(&mut self, fuzzer: Rc<RefCell<Fuzzer>>) -> Result<(), CpuFault>{
         let start_t = Instant::now();
 
         loop {
             if let Some(b) = self.breakpoints.get(&(self.registers.pc)){
                 //println!("<========>BREAKPOINT HIT:{:X}<=========>", self.registers.pc);
-                b(self);
+                let pc = self.registers.pc;
+                b(self)?;
+
+                //The handler moved the execution, there may be another
+                //breakpoint there
+                if self.registers.pc != pc{
+                    continue;
+                }
             }
 
             if self.exit{
@@ -512,23 +1639,112 @@ impl CPU{
                 }
                 
                 println!("Time elapsed (ms): {:}", start_t.elapsed().as_millis());
-                break;
+                return Ok(());
             }
 
-            let mut instr = [0 as u8; 4];
-            self.memory.read(self.registers.pc, &mut instr);
+            self.step(&fuzzer)?;
 
-            let instr = u32::from_le_bytes(instr);
+            if self.exited{
+                self.exited = false;
+                match self.exit_handler{
+                    Some(handler) => handler(self)?,
+                    None => self.exit = true,
+                }
+            }
+        }
+    }
 
-            //println!("{:08X}", self.registers.pc);
-            self.exec_instruction(instr, Rc::clone(&fuzzer));
-            //println!("{:?}", self);
+    /// Execute the instruction at pc or take a pending interrupt. Exceptions
+    /// are delivered to the guest, they are returned as a fault when there
+    /// is no trap handler
+    pub fn step(&mut self, fuzzer: &Rc<RefCell<Fuzzer>>) -> Result<(), CpuFault>{
+        //Interrupts are taken between instructions
+        self.update_interrupts();
+        if let Some(code) = self.pending_interrupt(){
+            self.interrupt(code);
+            return Ok(());
+        }
+
+        let (encoding, len) = match self.fetch(){
+            Ok(fetched) => fetched,
+            Err(exception) => return self.trap(exception, 0),
+        };
+
+        //The compressed encodings are illegal without the C extension
+        let instr = match len{
+            2 if self.extensions.c => compressed::expand(encoding as u16, self.xlen),
+            2 => None,
+            _ => Some(encoding),
+        };
+
+        //println!("{:08X}", self.registers.pc);
+        let result = match instr{
+            Some(instr) => self.exec_instruction(instr, len, Rc::clone(fuzzer)),
+            None => Err(Exception::IllegalInstruction),
+        };
+
+        //Instructions raising an exception do not retire
+        match result{
+            Ok(()) => {
+                self.instret = self.instret.wrapping_add(1);
+                Ok(())
+            },
+            Err(exception) => self.trap(exception, encoding),
         }
     }
 
-    pub fn set_breakpoint(&mut self, at: u64, handler: fn(&mut CPU)){
+    pub fn set_breakpoint(&mut self, at: u64, handler: Breakpoint){
         self.breakpoints.insert(at, handler);
     }
+
+    /// Record a call made at pc, the oldest calls are forgotten past
+    /// CALL_STACK_DEPTH
+    fn push_call(&mut self){
+        if self.call_stack.len() == CALL_STACK_DEPTH{
+            self.call_stack.remove(0);
+        }
+        self.call_stack.push(self.registers.pc);
+    }
+
+    /// Return to ra with value in a0 from a function emulated by a
+    /// breakpoint handler
+    pub fn return_from_function(&mut self, value: u64){
+        self.registers.common[10] = match self.xlen{
+            Xlen::Rv32 => value as i32 as i64 as u64,
+            Xlen::Rv64 => value,
+        };
+        self.registers.pc = self.address(self.registers.common[1]);
+        self.call_stack.pop();
+    }
+}
+
+/// ra and t0 are the link registers, the calls write them and the returns
+/// jump through them
+fn is_link(register: usize) -> bool{
+    register == 1 || register == 5
+}
+
+/// Returns true for the encodings of the integer register-register and shift
+/// instructions with a funct7 (funct6 for the 64 bits immediate shifts) that
+/// the base ISA and M do not define, the bit manipulation instructions are
+/// among them
+fn reserved_integer_encoding(instr: u32) -> bool{
+    let opcode = instr & 0b111_1111;
+    let funct3 = (instr >> 12) & 0b111;
+    let funct7 = instr >> 25;
+    let funct6 = instr >> 26;
+
+    match (opcode, funct3){
+        (0b011_0011, _) | (0b011_1011, _) if funct7 == 0b000_0001 => false,
+        (0b011_0011, 0b000) | (0b011_0011, 0b101) | (0b011_1011, 0b000) | (0b011_1011, 0b101) =>
+            funct7 != 0 && funct7 != 0b010_0000,
+        (0b011_0011, _) | (0b011_1011, _) => funct7 != 0,
+        (0b001_0011, 0b001) => funct6 != 0,
+        (0b001_0011, 0b101) => funct6 != 0 && funct6 != 0b01_0000,
+        (0b001_1011, 0b001) => funct7 != 0,
+        (0b001_1011, 0b101) => funct7 != 0 && funct7 != 0b010_0000,
+        _ => false,
+    }
 }
 
 impl fmt::Debug for CPU{
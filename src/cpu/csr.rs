@@ -0,0 +1,283 @@
+// Control and status registers (Zicsr)
+// fflags, frm and fcsr live in Registers, the vector CSRs in VectorState and
+// the counters are derived from the number of retired instructions, they are
+// handled by the CPU, every other CSR is stored here
+
+use super::mmu::{SATP_MODE_SHIFT, SATP_MODE_BARE, SATP_MODE_SV39, SATP_MODE_SV48};
+
+//Floating point
+pub const FFLAGS: u16 = 0x001;
+pub const FRM: u16 = 0x002;
+pub const FCSR: u16 = 0x003;
+
+//Vector, the vector state is handled by the CPU
+pub const VSTART: u16 = 0x008;
+pub const VXSAT: u16 = 0x009;
+pub const VXRM: u16 = 0x00A;
+pub const VCSR: u16 = 0x00F;
+pub const VL: u16 = 0xC20;
+pub const VTYPE: u16 = 0xC21;
+pub const VLENB: u16 = 0xC22;
+
+//Counters
+pub const CYCLE: u16 = 0xC00;
+pub const TIME: u16 = 0xC01;
+pub const INSTRET: u16 = 0xC02;
+pub const MCYCLE: u16 = 0xB00;
+pub const MINSTRET: u16 = 0xB02;
+//High halves of the counters, RV32 only
+pub const CYCLEH: u16 = 0xC80;
+pub const TIMEH: u16 = 0xC81;
+pub const INSTRETH: u16 = 0xC82;
+pub const MCYCLEH: u16 = 0xB80;
+pub const MINSTRETH: u16 = 0xB82;
+
+//Supervisor
+pub const SSTATUS: u16 = 0x100;
+pub const SIE: u16 = 0x104;
+pub const STVEC: u16 = 0x105;
+pub const SCOUNTEREN: u16 = 0x106;
+pub const SSCRATCH: u16 = 0x140;
+pub const SEPC: u16 = 0x141;
+pub const SCAUSE: u16 = 0x142;
+pub const STVAL: u16 = 0x143;
+pub const SIP: u16 = 0x144;
+pub const SATP: u16 = 0x180;
+
+//Machine
+pub const MSTATUS: u16 = 0x300;
+/// RV32 only, the upper half of mstatus has no field implemented
+pub const MSTATUSH: u16 = 0x310;
+pub const MISA: u16 = 0x301;
+pub const MEDELEG: u16 = 0x302;
+pub const MIDELEG: u16 = 0x303;
+pub const MIE: u16 = 0x304;
+pub const MTVEC: u16 = 0x305;
+pub const MCOUNTEREN: u16 = 0x306;
+pub const MSCRATCH: u16 = 0x340;
+pub const MEPC: u16 = 0x341;
+pub const MCAUSE: u16 = 0x342;
+pub const MTVAL: u16 = 0x343;
+pub const MIP: u16 = 0x344;
+pub const PMPCFG0: u16 = 0x3A0;
+pub const PMPADDR0: u16 = 0x3B0;
+pub const MVENDORID: u16 = 0xF11;
+pub const MARCHID: u16 = 0xF12;
+pub const MIMPID: u16 = 0xF13;
+pub const MHARTID: u16 = 0xF14;
+
+/// The I, M, A, F and D extensions and the supervisor and user modes, the
+/// optional ones are added by the CPU
+pub const MISA_EXTENSIONS: u64 = misa_bit(b'I') | misa_bit(b'M') |
+    misa_bit(b'A') | misa_bit(b'F') | misa_bit(b'D') |
+    misa_bit(b'S') | misa_bit(b'U');
+/// MXL=64 bits
+pub const MISA_VALUE: u64 = 2 << 62 | MISA_EXTENSIONS;
+/// MXL=32 bits
+pub const MISA_VALUE_RV32: u64 = 1 << 30 | MISA_EXTENSIONS;
+
+/// Bit of an extension letter in misa
+pub const fn misa_bit(extension: u8) -> u64{
+    1 << (extension - b'A')
+}
+
+//mstatus fields
+pub const MSTATUS_SIE: u64 = 1 << 1;
+pub const MSTATUS_MIE: u64 = 1 << 3;
+pub const MSTATUS_SPIE: u64 = 1 << 5;
+pub const MSTATUS_MPIE: u64 = 1 << 7;
+pub const MSTATUS_SPP: u64 = 1 << 8;
+pub const MSTATUS_VS: u64 = 0b11 << 9;
+pub const MSTATUS_MPP: u64 = 0b11 << 11;
+pub const MSTATUS_FS: u64 = 0b11 << 13;
+pub const MSTATUS_MPRV: u64 = 1 << 17;
+pub const MSTATUS_SUM: u64 = 1 << 18;
+pub const MSTATUS_MXR: u64 = 1 << 19;
+pub const MSTATUS_TVM: u64 = 1 << 20;
+pub const MSTATUS_TW: u64 = 1 << 21;
+pub const MSTATUS_TSR: u64 = 1 << 22;
+/// UXL and SXL are hardwired to 64 bits
+pub const MSTATUS_XL: u64 = 2 << 32 | 2 << 34;
+pub const MSTATUS_UXL: u64 = 0b11 << 32;
+/// Read only, set when FS or VS is Dirty
+pub const MSTATUS_SD: u64 = 1 << 63;
+
+//Interrupt pending and enable bits, shared by mip and mie
+pub const MIP_SSIP: u64 = 1 << 1;
+pub const MIP_MSIP: u64 = 1 << 3;
+pub const MIP_STIP: u64 = 1 << 5;
+pub const MIP_MTIP: u64 = 1 << 7;
+pub const MIP_SEIP: u64 = 1 << 9;
+pub const MIP_MEIP: u64 = 1 << 11;
+
+/// Set in mcause/scause when the trap is an interrupt
+pub const INTERRUPT_CAUSE: u64 = 1 << 63;
+/// Interrupt codes from the highest to the lowest priority
+pub const INTERRUPT_PRIORITY: [u64; 6] = [11, 3, 7, 9, 1, 5];
+
+pub const MSTATUS_MPP_SHIFT: u64 = 11;
+pub const MSTATUS_SPP_SHIFT: u64 = 8;
+
+const MSTATUS_WRITABLE: u64 = MSTATUS_SIE | MSTATUS_MIE | MSTATUS_SPIE | MSTATUS_MPIE |
+    MSTATUS_SPP | MSTATUS_VS | MSTATUS_MPP | MSTATUS_FS | MSTATUS_MPRV | MSTATUS_SUM |
+    MSTATUS_MXR | MSTATUS_TVM | MSTATUS_TW | MSTATUS_TSR;
+/// Fields of mstatus visible through sstatus, all but UXL and SD are
+/// writable
+const SSTATUS_MASK: u64 = SSTATUS_WRITABLE | MSTATUS_UXL | MSTATUS_SD;
+const SSTATUS_WRITABLE: u64 = MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | MSTATUS_VS | MSTATUS_FS |
+    MSTATUS_SUM | MSTATUS_MXR;
+
+//Interrupt enable and pending bits, only the supervisor ones can be
+//delegated and software can only raise the supervisor pending bits
+const MIE_WRITABLE: u64 = MIP_SSIP | MIP_MSIP | MIP_STIP | MIP_MTIP | MIP_SEIP | MIP_MEIP;
+const MIP_WRITABLE: u64 = MIP_SSIP | MIP_STIP | MIP_SEIP;
+const MIDELEG_WRITABLE: u64 = MIP_SSIP | MIP_STIP | MIP_SEIP;
+/// Every exception except the environment call from M-mode can be delegated
+const MEDELEG_WRITABLE: u64 = 0b1011_0011_1111_1111;
+
+/// CSRs whose address starts with 0b11 are read only
+pub fn is_read_only(addr: u16) -> bool{
+    (addr >> 10) == 0b11
+}
+
+#[derive(Debug, Clone)]
+pub struct CsrFile{
+    values: Vec<u64>,
+    /// The CSRs are 32 bits wide, the values are still stored in their 64
+    /// bits layout
+    rv32: bool,
+}
+
+impl CsrFile{
+    pub fn new() -> CsrFile{
+        let mut values = vec![0; 4096];
+        values[MISA as usize] = MISA_VALUE;
+        values[MSTATUS as usize] = MSTATUS_XL;
+
+        CsrFile{ values, rv32: false }
+    }
+
+    pub fn set_xlen(&mut self, rv32: bool){
+        self.rv32 = rv32;
+        self.set(MISA, if rv32 { MISA_VALUE_RV32 } else { MISA_VALUE });
+    }
+
+    fn is_implemented(&self, addr: u16) -> bool{
+        (self.rv32 && addr == MSTATUSH) || matches!(addr, SSTATUS | SIE | STVEC | SCOUNTEREN | SSCRATCH | SEPC |
+            SCAUSE | STVAL | SIP | SATP | MSTATUS | MISA | MEDELEG | MIDELEG |
+            MIE | MTVEC | MCOUNTEREN | MSCRATCH | MEPC | MCAUSE | MTVAL | MIP |
+            PMPCFG0 | PMPADDR0 | MVENDORID | MARCHID | MIMPID | MHARTID)
+    }
+
+    /// Raw value of a CSR, used by the CPU for its own state
+    pub fn get(&self, addr: u16) -> u64{
+        self.values[addr as usize]
+    }
+
+    /// Raw write of a CSR, no legality check is made
+    pub fn set(&mut self, addr: u16, value: u64){
+        self.values[addr as usize] = value;
+    }
+
+    /// Set the FS or VS field of mstatus to Dirty, the state it covers
+    /// changed and the kernel has to save it
+    pub fn set_dirty(&mut self, field: u64){
+        let mstatus = self.get(MSTATUS);
+        self.set(MSTATUS, mstatus | field | MSTATUS_SD);
+    }
+
+    /// Read a CSR, None if it does not exist
+    pub fn read(&self, addr: u16) -> Option<u64>{
+        if !self.is_implemented(addr){
+            return None;
+        }
+
+        let value = match addr{
+            //SD is the most significant bit
+            MSTATUS | SSTATUS if self.rv32 => {
+                let status = if addr == SSTATUS { self.get(MSTATUS) & SSTATUS_MASK } else { self.get(MSTATUS) };
+                (status >> 32) & (1 << 31) | (status & 0x7FFF_FFFF)
+            },
+            SSTATUS => self.get(MSTATUS) & SSTATUS_MASK,
+            SIE => self.get(MIE) & self.get(MIDELEG),
+            SIP => self.get(MIP) & self.get(MIDELEG),
+            //The interrupt bit is the most significant one
+            MCAUSE | SCAUSE if self.rv32 => {
+                let cause = self.get(addr);
+                (cause >> 32) & (1 << 31) | (cause & 0x7FFF_FFFF)
+            },
+            _ => self.get(addr),
+        };
+        Some(value)
+    }
+
+    /// Write a CSR, the WARL fields keep a legal value, returns false if the
+    /// CSR does not exist or is read only
+    pub fn write(&mut self, addr: u16, value: u64) -> bool{
+        if !self.is_implemented(addr) || is_read_only(addr){
+            return false;
+        }
+
+        let value = if self.rv32 { value as u32 as u64 } else { value };
+        let (addr, value) = match addr{
+            MSTATUS => (MSTATUS, legal_mstatus(self.get(MSTATUS), value, MSTATUS_WRITABLE)),
+            SSTATUS => (MSTATUS, legal_mstatus(self.get(MSTATUS), value, SSTATUS_WRITABLE)),
+            //No extension can be disabled
+            MISA | MSTATUSH => return true,
+            MCAUSE | SCAUSE if self.rv32 => (addr, (value & (1 << 31)) << 32 | (value & 0x7FFF_FFFF)),
+            MEDELEG => (MEDELEG, value & MEDELEG_WRITABLE),
+            MIDELEG => (MIDELEG, value & MIDELEG_WRITABLE),
+            MIE => (MIE, value & MIE_WRITABLE),
+            MIP => (MIP, merge(self.get(MIP), value, MIP_WRITABLE)),
+            //Only the delegated interrupts are visible from S-mode
+            SIE => (MIE, merge(self.get(MIE), value, self.get(MIDELEG) & MIE_WRITABLE)),
+            SIP => (MIP, merge(self.get(MIP), value, self.get(MIDELEG) & MIP_SSIP)),
+            //Only direct and vectored modes exist
+            MTVEC | STVEC => (addr, if value & 0b11 > 1 { value & !0b11 } else { value }),
+            MEPC | SEPC => (addr, value & !0b1),
+            //Writes selecting an unsupported translation mode are ignored,
+            //there is no Sv32
+            SATP if self.rv32 => {
+                if value >> 31 != 0{
+                    return true;
+                }
+                (SATP, value)
+            },
+            SATP => {
+                match value >> SATP_MODE_SHIFT{
+                    SATP_MODE_BARE | SATP_MODE_SV39 | SATP_MODE_SV48 => (SATP, value),
+                    _ => return true,
+                }
+            },
+            _ => (addr, value),
+        };
+        self.set(addr, value);
+        true
+    }
+}
+
+/// Replace the bits of old selected by mask
+fn merge(old: u64, value: u64, mask: u64) -> u64{
+    (old & !mask) | (value & mask)
+}
+
+/// mstatus after a write, MPP keeps its previous value if the new one is the
+/// reserved privilege 2 and SD follows FS and VS
+fn legal_mstatus(old: u64, value: u64, mask: u64) -> u64{
+    let mut value = merge(old, value, mask);
+    if (value & MSTATUS_MPP) >> MSTATUS_MPP_SHIFT == 2{
+        value = (value & !MSTATUS_MPP) | (old & MSTATUS_MPP);
+    }
+    if value & MSTATUS_FS == MSTATUS_FS || value & MSTATUS_VS == MSTATUS_VS{
+        value | MSTATUS_SD
+    }
+    else{
+        value & !MSTATUS_SD
+    }
+}
+
+impl Default for CsrFile{
+    fn default() -> Self{
+        CsrFile::new()
+    }
+}
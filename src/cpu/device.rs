@@ -0,0 +1,65 @@
+// Memory mapped devices, Memory routes the accesses falling in the range of a
+// device to it with the offset relative to its base
+
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::io::{self, Write};
+
+use super::memory::Memory;
+
+pub trait Device{
+    /// Read the registers at offset, reads can have side effects
+    fn read(&mut self, offset: u64, buf: &mut [u8]);
+
+    fn write(&mut self, offset: u64, buf: &[u8]);
+
+    /// Level of the interrupt line of the device
+    fn interrupt_pending(&self) -> bool{
+        false
+    }
+
+    /// Work the device does on its own, like DMA to the guest memory. Called
+    /// between instructions while the device is detached from the bus
+    fn process(&mut self, _memory: &mut Memory){}
+
+    /// Snapshot support, the state that does not live in the guest memory
+    fn save_state(&mut self){}
+
+    fn reset_to_saved_state(&mut self){}
+}
+
+/// Where the bytes sent by a device to the host go
+#[derive(Debug, Clone)]
+pub enum HostOutput{
+    Stdout,
+    Buffer(Vec<u8>),
+}
+
+impl HostOutput{
+    pub fn write(&mut self, bytes: &[u8]){
+        match self{
+            HostOutput::Stdout => {
+                let mut stdout = io::stdout();
+                stdout.write_all(bytes).expect("Couldnt write to stdout");
+                stdout.flush().expect("Couldnt flush stdout");
+            },
+            HostOutput::Buffer(buf) => buf.extend_from_slice(bytes),
+        }
+    }
+}
+
+/// A device attached to the bus, irq is the PLIC source its interrupt line
+/// is wired to
+#[derive(Clone)]
+pub struct MappedDevice{
+    pub base: u64,
+    pub size: u64,
+    pub irq: Option<u32>,
+    pub device: Rc<RefCell<dyn Device>>,
+}
+
+impl MappedDevice{
+    pub fn contains(&self, at: u64, len: u64) -> bool{
+        at >= self.base && at.saturating_add(len) <= self.base + self.size
+    }
+}
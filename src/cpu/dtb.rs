@@ -0,0 +1,297 @@
+// Flattened device tree generation, the tree describes the emulated machine
+// to the kernel which gets its address in a1 at boot
+
+use std::collections::HashMap;
+
+use super::clint::{CLINT_BASE, CLINT_SIZE};
+use super::plic::{PLIC_BASE, PLIC_SIZE, PLIC_SOURCES};
+use super::uart::{UART_BASE, UART_SIZE, UART_IRQ};
+use super::virtio::VIRTIO_MMIO_SIZE;
+
+const FDT_MAGIC: u32 = 0xD00D_FEED;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMPATIBLE_VERSION: u32 = 16;
+const FDT_HEADER_SIZE: usize = 40;
+/// An empty memory reservation map is a single null entry
+const FDT_RESERVATION_MAP_SIZE: usize = 16;
+
+//Structure block tokens
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_END: u32 = 0x9;
+
+/// Phandle of the interrupt controller of the hart
+pub const CPU_INTC_PHANDLE: u32 = 1;
+/// Phandle of the PLIC, parent of the devices interrupts
+pub const PLIC_PHANDLE: u32 = 2;
+
+//Local interrupt numbers of the hart interrupt controller
+const IRQ_M_SOFT: u32 = 3;
+const IRQ_M_TIMER: u32 = 7;
+const IRQ_S_EXT: u32 = 9;
+const IRQ_M_EXT: u32 = 11;
+
+/// Input clock of the UART, only used by the kernel to compute the divisor
+const UART_CLOCK_FREQUENCY: u32 = 3_686_400;
+
+/// Build a device tree blob node by node, properties belong to the last
+/// opened node
+pub struct DeviceTree{
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    /// Offset of the property names already in the strings block
+    string_offsets: HashMap<String, u32>,
+    depth: usize,
+}
+
+impl DeviceTree{
+    pub fn new() -> DeviceTree{
+        DeviceTree{
+            structure: Vec::new(),
+            strings: Vec::new(),
+            string_offsets: HashMap::new(),
+            depth: 0,
+        }
+    }
+
+    fn push_u32(&mut self, value: u32){
+        self.structure.extend_from_slice(&value.to_be_bytes());
+    }
+
+    /// Append raw bytes to the structure block, padded to 4 bytes
+    fn push_padded(&mut self, bytes: &[u8]){
+        self.structure.extend_from_slice(bytes);
+        while self.structure.len() % 4 != 0{
+            self.structure.push(0);
+        }
+    }
+
+    fn string_offset(&mut self, name: &str) -> u32{
+        if let Some(offset) = self.string_offsets.get(name){
+            return *offset;
+        }
+
+        let offset = self.strings.len() as u32;
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        self.string_offsets.insert(name.to_string(), offset);
+        offset
+    }
+
+    pub fn begin_node(&mut self, name: &str){
+        self.push_u32(FDT_BEGIN_NODE);
+
+        let mut bytes = name.as_bytes().to_vec();
+        bytes.push(0);
+        self.push_padded(&bytes);
+        self.depth += 1;
+    }
+
+    pub fn end_node(&mut self){
+        assert!(self.depth > 0, "Closing a device tree node that was never opened");
+        self.push_u32(FDT_END_NODE);
+        self.depth -= 1;
+    }
+
+    pub fn property(&mut self, name: &str, value: &[u8]){
+        let offset = self.string_offset(name);
+
+        self.push_u32(FDT_PROP);
+        self.push_u32(value.len() as u32);
+        self.push_u32(offset);
+        self.push_padded(value);
+    }
+
+    /// Property without value, like interrupt-controller
+    pub fn property_empty(&mut self, name: &str){
+        self.property(name, &[]);
+    }
+
+    pub fn property_u32(&mut self, name: &str, value: u32){
+        self.property(name, &value.to_be_bytes());
+    }
+
+    pub fn property_u64(&mut self, name: &str, value: u64){
+        self.property(name, &value.to_be_bytes());
+    }
+
+    /// Array of cells, used by reg and interrupts
+    pub fn property_cells(&mut self, name: &str, cells: &[u32]){
+        let bytes: Vec<u8> = cells.iter().flat_map(|c| c.to_be_bytes()).collect();
+        self.property(name, &bytes);
+    }
+
+    pub fn property_str(&mut self, name: &str, value: &str){
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.push(0);
+        self.property(name, &bytes);
+    }
+
+    /// List of strings, used by compatible
+    pub fn property_strings(&mut self, name: &str, values: &[&str]){
+        let mut bytes = Vec::new();
+        for v in values{
+            bytes.extend_from_slice(v.as_bytes());
+            bytes.push(0);
+        }
+        self.property(name, &bytes);
+    }
+
+    /// Returns the blob, every node must have been closed
+    pub fn finish(mut self) -> Vec<u8>{
+        assert!(self.depth == 0, "Device tree has unclosed nodes");
+        self.push_u32(FDT_END);
+
+        let off_mem_rsvmap = FDT_HEADER_SIZE;
+        let off_dt_struct = off_mem_rsvmap + FDT_RESERVATION_MAP_SIZE;
+        let off_dt_strings = off_dt_struct + self.structure.len();
+        let total_size = off_dt_strings + self.strings.len();
+
+        let header = [
+            FDT_MAGIC,
+            total_size as u32,
+            off_dt_struct as u32,
+            off_dt_strings as u32,
+            off_mem_rsvmap as u32,
+            FDT_VERSION,
+            FDT_LAST_COMPATIBLE_VERSION,
+            //boot_cpuid_phys
+            0,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ];
+
+        let mut blob = Vec::with_capacity(total_size);
+        for field in header.iter(){
+            blob.extend_from_slice(&field.to_be_bytes());
+        }
+        blob.extend_from_slice(&[0; FDT_RESERVATION_MAP_SIZE]);
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+        blob
+    }
+}
+
+impl Default for DeviceTree{
+    fn default() -> Self{
+        DeviceTree::new()
+    }
+}
+
+/// Description of the machine the device tree is generated for
+pub struct Machine<'a>{
+    pub memory_base: u64,
+    pub memory_size: u64,
+    pub cmdline: &'a str,
+    /// Start and end of the initramfs in memory
+    pub initrd: Option<(u64, u64)>,
+    pub timebase_frequency: u32,
+    pub isa: &'a str,
+    pub mmu_type: &'a str,
+    /// Address and PLIC source of the virtio-mmio devices
+    pub virtio: &'a [(u64, u32)],
+}
+
+/// Generate the device tree of a single hart machine
+pub fn generate(machine: &Machine) -> Vec<u8>{
+    let mut dt = DeviceTree::new();
+
+    dt.begin_node("");
+    dt.property_u32("#address-cells", 2);
+    dt.property_u32("#size-cells", 2);
+    dt.property_str("compatible", "riscv-emu");
+    dt.property_str("model", "riscv-emu");
+
+    dt.begin_node("chosen");
+    dt.property_str("bootargs", machine.cmdline);
+    dt.property_str("stdout-path", &format!("/soc/serial@{:x}", UART_BASE));
+    if let Some((start, end)) = machine.initrd{
+        dt.property_u64("linux,initrd-start", start);
+        dt.property_u64("linux,initrd-end", end);
+    }
+    dt.end_node();
+
+    dt.begin_node("cpus");
+    dt.property_u32("#address-cells", 1);
+    dt.property_u32("#size-cells", 0);
+    dt.property_u32("timebase-frequency", machine.timebase_frequency);
+
+    dt.begin_node("cpu@0");
+    dt.property_str("device_type", "cpu");
+    dt.property_u32("reg", 0);
+    dt.property_str("status", "okay");
+    dt.property_str("compatible", "riscv");
+    dt.property_str("riscv,isa", machine.isa);
+    dt.property_str("mmu-type", machine.mmu_type);
+
+    dt.begin_node("interrupt-controller");
+    dt.property_u32("#interrupt-cells", 1);
+    dt.property_empty("interrupt-controller");
+    dt.property_str("compatible", "riscv,cpu-intc");
+    dt.property_u32("phandle", CPU_INTC_PHANDLE);
+    dt.end_node();
+
+    dt.end_node();
+    dt.end_node();
+
+    dt.begin_node(&format!("memory@{:x}", machine.memory_base));
+    dt.property_str("device_type", "memory");
+    dt.property_cells("reg", &reg_cells(machine.memory_base, machine.memory_size));
+    dt.end_node();
+
+    dt.begin_node("soc");
+    dt.property_u32("#address-cells", 2);
+    dt.property_u32("#size-cells", 2);
+    dt.property_str("compatible", "simple-bus");
+    dt.property_empty("ranges");
+
+    dt.begin_node(&format!("clint@{:x}", CLINT_BASE));
+    dt.property_strings("compatible", &["sifive,clint0", "riscv,clint0"]);
+    dt.property_cells("reg", &reg_cells(CLINT_BASE, CLINT_SIZE));
+    dt.property_cells("interrupts-extended", &[
+        CPU_INTC_PHANDLE, IRQ_M_SOFT, CPU_INTC_PHANDLE, IRQ_M_TIMER,
+    ]);
+    dt.end_node();
+
+    //Context 0 is the M-mode external interrupt and context 1 the S-mode one
+    dt.begin_node(&format!("plic@{:x}", PLIC_BASE));
+    dt.property_strings("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+    dt.property_cells("reg", &reg_cells(PLIC_BASE, PLIC_SIZE));
+    dt.property_u32("#address-cells", 0);
+    dt.property_u32("#interrupt-cells", 1);
+    dt.property_empty("interrupt-controller");
+    dt.property_u32("riscv,ndev", PLIC_SOURCES as u32 - 1);
+    dt.property_cells("interrupts-extended", &[
+        CPU_INTC_PHANDLE, IRQ_M_EXT, CPU_INTC_PHANDLE, IRQ_S_EXT,
+    ]);
+    dt.property_u32("phandle", PLIC_PHANDLE);
+    dt.end_node();
+
+    dt.begin_node(&format!("serial@{:x}", UART_BASE));
+    dt.property_str("compatible", "ns16550a");
+    dt.property_cells("reg", &reg_cells(UART_BASE, UART_SIZE));
+    dt.property_u32("clock-frequency", UART_CLOCK_FREQUENCY);
+    dt.property_u32("interrupt-parent", PLIC_PHANDLE);
+    dt.property_u32("interrupts", UART_IRQ);
+    dt.end_node();
+
+    for &(base, irq) in machine.virtio{
+        dt.begin_node(&format!("virtio_mmio@{:x}", base));
+        dt.property_str("compatible", "virtio,mmio");
+        dt.property_cells("reg", &reg_cells(base, VIRTIO_MMIO_SIZE));
+        dt.property_u32("interrupt-parent", PLIC_PHANDLE);
+        dt.property_u32("interrupts", irq);
+        dt.end_node();
+    }
+
+    dt.end_node();
+
+    dt.end_node();
+    dt.finish()
+}
+
+/// reg value of a node with two address and two size cells
+fn reg_cells(base: u64, size: u64) -> [u32; 4]{
+    [(base >> 32) as u32, base as u32, (size >> 32) as u32, size as u32]
+}
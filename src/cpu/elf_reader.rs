@@ -2,6 +2,10 @@ use std::collections::HashMap;
 use core::convert::TryInto;
 use std::str;
 
+use super::cpu::Xlen;
+use super::memory::Permissions;
+use super::mmu::PAGE_SIZE;
+
 #[derive(Debug)]
 pub struct Symbol{
     name: u32,
@@ -12,8 +16,47 @@ pub struct Symbol{
     size: u64,
 }
 
+/// Size of a symbol table entry
+const ELF32_SYM_SIZE: usize = 16;
+const ELF64_SYM_SIZE: usize = 24;
+
+//Special section indexes of the symbols
+const SHN_UNDEF: u16 = 0;
+const SHN_ABS: u16 = 0xFFF1;
+const STB_WEAK: u8 = 2;
+
+//Tags of the dynamic section
+const DT_NULL: u64 = 0;
+const DT_PLTRELSZ: u64 = 2;
+const DT_SYMTAB: u64 = 6;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const DT_RELAENT: u64 = 9;
+const DT_SYMENT: u64 = 11;
+const DT_JMPREL: u64 = 23;
+
+//Relocation types
+const R_RISCV_NONE: u64 = 0;
+const R_RISCV_32: u64 = 1;
+const R_RISCV_64: u64 = 2;
+const R_RISCV_RELATIVE: u64 = 3;
+const R_RISCV_JUMP_SLOT: u64 = 5;
+
 impl Symbol{
-    fn read_symbol(data: &[u8]) -> Self{
+    /// Elf32_Sym, the value and size come before the info fields
+    fn read_symbol32(data: &[u8]) -> Self{
+        Self{
+            name: u32::from_le_bytes(data[0..4].try_into().unwrap()),
+            value: u32::from_le_bytes(data[4..8].try_into().unwrap()) as u64,
+            size: u32::from_le_bytes(data[8..12].try_into().unwrap()) as u64,
+            info: data[12],
+            other: data[13],
+            shndx: u16::from_le_bytes(data[14..16].try_into().unwrap()),
+        }
+    }
+
+    /// Elf64_Sym
+    fn read_symbol64(data: &[u8]) -> Self{
         Self{
             name: u32::from_le_bytes(data[0..4].try_into().unwrap()),
             info: data[4],
@@ -25,17 +68,243 @@ impl Symbol{
     }
 }
 
-pub fn read_symbols_list(symtab: elf::Section, strtab: elf::Section) -> HashMap<String, u64>{
+/// Width of the registers the program is compiled for
+pub fn xlen(class: elf::types::Class) -> Xlen{
+    if class == elf::types::ELFCLASS32 { Xlen::Rv32 } else { Xlen::Rv64 }
+}
+
+/// Pages of one or more PT_LOAD segments, the segments sharing a page are
+/// merged and get the union of their permissions
+#[derive(Debug)]
+pub struct LoadedSegment{
+    pub addr: u64,
+    pub data: Vec<u8>,
+    pub permissions: Permissions,
+}
+
+fn segment_permissions(flags: elf::types::ProgFlag) -> Permissions{
+    Permissions{
+        read: flags.0 & elf::types::PF_R.0 != 0,
+        write: flags.0 & elf::types::PF_W.0 != 0,
+        execute: flags.0 & elf::types::PF_X.0 != 0,
+    }
+}
+
+/// Memory image of the PT_LOAD segments of file moved by base, the bytes
+/// past p_filesz up to p_memsz are zeroed and each segment is extended to
+/// whole pages. The segments sharing a page keep their own permissions
+/// rather than making it writable and executable, the image is cut where
+/// they change. Only the program headers are used, the section headers may
+/// be stripped
+pub fn load_segments(file: &[u8], phdrs: &[elf::types::ProgramHeader], base: u64) -> Result<Vec<LoadedSegment>, String>{
+    let mut segments: Vec<&elf::types::ProgramHeader> = phdrs.iter()
+        .filter(|p| p.progtype == elf::types::PT_LOAD && p.memsz != 0)
+        .collect();
+    segments.sort_by_key(|p| p.vaddr);
+
+    //Page spans of the segments, the overlapping ones are merged
+    let mut spans: Vec<(u64, u64, Vec<&elf::types::ProgramHeader>)> = Vec::new();
+    for p in segments{
+        if p.filesz > p.memsz{
+            return Err(format!("Segment at {:#X} has more bytes in the file than in memory", p.vaddr));
+        }
+        let end = p.vaddr.checked_add(base)
+            .and_then(|vaddr| vaddr.checked_add(p.memsz))
+            .and_then(|end| end.checked_add(PAGE_SIZE - 1))
+            .ok_or_else(|| format!("Segment at {:#X} overflows the address space", p.vaddr))?;
+        let start = (p.vaddr + base) & !(PAGE_SIZE - 1);
+        let end = end & !(PAGE_SIZE - 1);
+
+        match spans.last_mut(){
+            Some(span) if start < span.1 => {
+                span.1 = span.1.max(end);
+                span.2.push(p);
+            },
+            _ => spans.push((start, end, vec![p])),
+        }
+    }
+
+    let mut loaded = Vec::new();
+    for (start, end, phdrs) in spans{
+        let mut data = vec![0; (end - start) as usize];
+        for p in &phdrs{
+            let bytes = p.offset.checked_add(p.filesz)
+                .and_then(|end| file.get(p.offset as usize..end as usize))
+                .ok_or_else(|| format!("Segment at {:#X} is past the end of the file", p.vaddr))?;
+            let at = (p.vaddr + base - start) as usize;
+            data[at..at + bytes.len()].copy_from_slice(bytes);
+        }
+
+        //The bytes of two overlapping segments get the permissions of both,
+        //the padding those of the segment before it, or after it at the
+        //start of the span
+        let ranges: Vec<(u64, u64, Permissions)> = phdrs.iter()
+            .map(|p| (p.vaddr + base, p.vaddr + base + p.memsz, segment_permissions(p.flags)))
+            .collect();
+        let mut bounds: Vec<u64> = ranges.iter().flat_map(|r| vec![r.0, r.1]).chain(vec![start, end]).collect();
+        bounds.sort_unstable();
+        bounds.dedup();
+
+        let mut pieces: Vec<(u64, Permissions)> = Vec::new();
+        for (&from, &to) in bounds.iter().zip(&bounds[1..]){
+            let permissions = ranges.iter()
+                .filter(|r| r.0 < to && r.1 > from)
+                .map(|r| r.2)
+                .reduce(|a, b| Permissions{
+                    read: a.read || b.read,
+                    write: a.write || b.write,
+                    execute: a.execute || b.execute,
+                })
+                .or_else(|| pieces.last().map(|piece| piece.1))
+                .unwrap_or(ranges[0].2);
+            if pieces.last().map(|piece| piece.1) != Some(permissions){
+                pieces.push((from, permissions));
+            }
+        }
+        for (i, &(from, permissions)) in pieces.iter().enumerate(){
+            let to = pieces.get(i + 1).map_or(end, |piece| piece.0);
+            let data = data[(from - start) as usize..(to - start) as usize].to_vec();
+            loaded.push(LoadedSegment{ addr: from, data, permissions });
+        }
+    }
+    Ok(loaded)
+}
+
+/// Bytes at addr in the image, None when they are not all in one segment
+fn image_bytes(segments: &[LoadedSegment], addr: u64, len: u64) -> Option<&[u8]>{
+    let s = segments.iter().find(|s| addr >= s.addr && addr - s.addr < s.data.len() as u64)?;
+    let at = (addr - s.addr) as usize;
+    s.data.get(at..at.checked_add(len as usize)?)
+}
+
+fn image_bytes_mut(segments: &mut [LoadedSegment], addr: u64, len: u64) -> Option<&mut [u8]>{
+    let s = segments.iter_mut().find(|s| addr >= s.addr && addr - s.addr < s.data.len() as u64)?;
+    let at = (addr - s.addr) as usize;
+    s.data.get_mut(at..at.checked_add(len as usize)?)
+}
+
+/// Little endian word of 4 or 8 bytes
+fn read_word(bytes: &[u8]) -> u64{
+    let mut word = [0u8; 8];
+    word[..bytes.len()].copy_from_slice(bytes);
+    u64::from_le_bytes(word)
+}
+
+/// Address of the dynamic symbol index once loaded at base. Only the
+/// symbols of the executable itself can be resolved
+fn dynamic_symbol(segments: &[LoadedSegment], symtab: u64, syment: u64, index: u64, base: u64, class: elf::types::Class) -> Result<u64, String>{
+    if index == 0{
+        return Ok(0);
+    }
+    let entry = index.checked_mul(syment).and_then(|offset| symtab.checked_add(offset))
+        .and_then(|addr| image_bytes(segments, addr, syment))
+        .ok_or_else(|| format!("Dynamic symbol {} is not loaded", index))?;
+    let s = if class == elf::types::ELFCLASS32 {
+        Symbol::read_symbol32(entry)
+    } else {
+        Symbol::read_symbol64(entry)
+    };
+
+    match s.shndx{
+        SHN_UNDEF if s.info >> 4 == STB_WEAK => Ok(0),
+        SHN_UNDEF => Err(format!("Dynamic symbol {} is undefined, it needs a dynamic linker", index)),
+        SHN_ABS => Ok(s.value),
+        _ => Ok(s.value.wrapping_add(base)),
+    }
+}
+
+/// Apply the relocations of the dynamic section to the image of a position
+/// independent executable loaded at base, returns how many were applied.
+/// The other types, IRELATIVE and TLS ones, are left to the startup code of
+/// the libc
+pub fn relocate(segments: &mut [LoadedSegment], phdrs: &[elf::types::ProgramHeader], base: u64, class: elf::types::Class) -> Result<usize, String>{
+    let dynamic = match phdrs.iter().find(|p| p.progtype == elf::types::PT_DYNAMIC){
+        Some(p) => p,
+        None => return Ok(0),
+    };
+    let (word, sym_size) = if class == elf::types::ELFCLASS32 { (4, ELF32_SYM_SIZE) } else { (8, ELF64_SYM_SIZE) };
+
+    let mut tags = HashMap::new();
+    let entries = image_bytes(segments, dynamic.vaddr.wrapping_add(base), dynamic.filesz)
+        .ok_or("The dynamic section is not loaded")?;
+    for entry in entries.chunks_exact(2 * word){
+        let tag = read_word(&entry[..word]);
+        if tag == DT_NULL{
+            break;
+        }
+        tags.insert(tag, read_word(&entry[word..]));
+    }
+
+    let symtab = tags.get(&DT_SYMTAB).map_or(0, |addr| addr.wrapping_add(base));
+    let syment = tags.get(&DT_SYMENT).copied().unwrap_or(sym_size as u64);
+    let relaent = tags.get(&DT_RELAENT).copied().unwrap_or(3 * word as u64);
+    if syment < sym_size as u64 || relaent < 3 * word as u64{
+        return Err(format!("Invalid dynamic entry sizes {} and {}", syment, relaent));
+    }
+
+    //The PLT relocations are applied right away, nothing is resolved lazily
+    let mut count = 0;
+    for (addr_tag, size_tag) in [(DT_RELA, DT_RELASZ), (DT_JMPREL, DT_PLTRELSZ)].iter(){
+        let (addr, size) = match (tags.get(addr_tag), tags.get(size_tag)){
+            (Some(addr), Some(size)) => (addr.wrapping_add(base), *size),
+            _ => continue,
+        };
+        let table = image_bytes(segments, addr, size)
+            .ok_or_else(|| format!("Relocation table at {:#X} is not loaded", addr))?
+            .to_vec();
+
+        for entry in table.chunks_exact(relaent as usize){
+            let offset = read_word(&entry[..word]);
+            let info = read_word(&entry[word..2 * word]);
+            let addend = read_word(&entry[2 * word..3 * word]);
+            let (index, rtype) = if word == 4 { (info >> 8, info & 0xFF) } else { (info >> 32, info & 0xFFFF_FFFF) };
+
+            //The truncation to the size of the field takes care of the sign
+            //of the 32 bits addends
+            let (value, size) = match rtype{
+                R_RISCV_NONE => continue,
+                R_RISCV_RELATIVE => (base.wrapping_add(addend), word),
+                R_RISCV_32 => (dynamic_symbol(segments, symtab, syment, index, base, class)?.wrapping_add(addend), 4),
+                R_RISCV_64 => (dynamic_symbol(segments, symtab, syment, index, base, class)?.wrapping_add(addend), 8),
+                R_RISCV_JUMP_SLOT => (dynamic_symbol(segments, symtab, syment, index, base, class)?, word),
+                _ => {
+                    println!("Relocation type {} at {:#X} skipped", rtype, offset);
+                    continue;
+                },
+            };
+            let target = image_bytes_mut(segments, offset.wrapping_add(base), size as u64)
+                .ok_or_else(|| format!("Relocation at {:#X} is not loaded", offset))?;
+            target.copy_from_slice(&value.to_le_bytes()[..size]);
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Read the symbols of the table, class is the one of the ELF header. The
+/// addresses are moved by the load base of a position independent
+/// executable, the undefined symbols are skipped
+pub fn read_symbols_list(symtab: elf::Section, strtab: elf::Section, class: elf::types::Class, base: u64) -> HashMap<String, u64>{
     let mut ret = HashMap::new();
 
-    for i in (0..symtab.data.len()).step_by(24){
-        let s = Symbol::read_symbol(&symtab.data[i..]);
+    let entry_size = if class == elf::types::ELFCLASS32 { ELF32_SYM_SIZE } else { ELF64_SYM_SIZE };
+    for i in (0..symtab.data.len()).step_by(entry_size){
+        let s = if class == elf::types::ELFCLASS32 {
+            Symbol::read_symbol32(&symtab.data[i..])
+        } else {
+            Symbol::read_symbol64(&symtab.data[i..])
+        };
+    
+        if s.shndx == SHN_UNDEF{
+            continue;
+        }
+        let value = if s.shndx == SHN_ABS { s.value } else { s.value.wrapping_add(base) };
     
         let name = str::from_utf8(&strtab.data[(s.name as usize)..]);
         if let Ok(name) = name{
             let name_end = name.find("\0");
             if let Some(name_end) = name_end{
-                ret.insert(String::from(&name[0..name_end]), s.value);
+                ret.insert(String::from(&name[0..name_end]), value);
             }
             else{
                 println!("Error reading end of name for symbol: {:#?}", s);
@@ -46,4 +315,46 @@ pub fn read_symbols_list(symtab: elf::Section, strtab: elf::Section) -> HashMap<
         } 
     }
     ret
-}
\ No newline at end of file
+}
+
+/// Symbols of the file moved by base, from the symbol table or from the
+/// dynamic one when it is stripped
+pub fn read_symbols(elf: elf::File, base: u64) -> Option<HashMap<String, u64>>{
+    let class = elf.ehdr.class;
+    let mut tables: HashMap<String, elf::Section> = elf.sections.into_iter()
+        .filter(|s| [".symtab", ".strtab", ".dynsym", ".dynstr"].contains(&s.shdr.name.as_str()))
+        .map(|s| (s.shdr.name.clone(), s))
+        .collect();
+
+    let (symtab, strtab) = match (tables.remove(".symtab"), tables.remove(".strtab")){
+        (Some(symtab), Some(strtab)) => (symtab, strtab),
+        _ => (tables.remove(".dynsym")?, tables.remove(".dynstr")?),
+    };
+    Some(read_symbols_list(symtab, strtab, class, base))
+}
+
+/// Path of the dynamic linker given by PT_INTERP
+pub fn interpreter(file: &[u8], phdrs: &[elf::types::ProgramHeader]) -> Option<String>{
+    let p = phdrs.iter().find(|p| p.progtype == elf::types::PT_INTERP)?;
+    let path = file.get(p.offset as usize..p.offset.checked_add(p.filesz)? as usize)?;
+    let path = path.split(|c| *c == 0).next()?;
+    str::from_utf8(path).ok().map(String::from)
+}
+
+/// Address of the program headers once loaded at base, given by PT_PHDR or
+/// found in the PT_LOAD segment holding them
+pub fn program_headers_address(file: &[u8], phdrs: &[elf::types::ProgramHeader], class: elf::types::Class, base: u64) -> Option<u64>{
+    if let Some(p) = phdrs.iter().find(|p| p.progtype == elf::types::PT_PHDR){
+        return Some(p.vaddr.wrapping_add(base));
+    }
+
+    //e_phoff is not kept by the parser
+    let phoff = if class == elf::types::ELFCLASS32 {
+        read_word(file.get(0x1C..0x20)?)
+    } else {
+        read_word(file.get(0x20..0x28)?)
+    };
+    phdrs.iter()
+        .find(|p| p.progtype == elf::types::PT_LOAD && phoff >= p.offset && phoff - p.offset < p.filesz)
+        .map(|p| (p.vaddr + phoff - p.offset).wrapping_add(base))
+}
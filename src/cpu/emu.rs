@@ -1,15 +1,92 @@
-use super::cpu::CPU;
-use super::elf_reader;
+use super::cpu::{Breakpoint, CPU};
+use super::elf_reader::{self, LoadedSegment};
+use super::isa::Extensions;
 use super::fuzzer::Fuzzer;
+use super::csr;
+use super::dtb;
+use super::memory::{Permissions, Shadow, Stack};
+use super::heap::{self, Chunk, Heap, HEAP_BASE, HEAP_SIZE};
+use super::process::{self, AT_BASE, AT_CLKTCK, AT_EGID, AT_ENTRY, AT_EUID, AT_FLAGS, AT_GID, AT_HWCAP,
+    AT_PAGESZ, AT_PHDR, AT_PHENT, AT_PHNUM, AT_SECURE, AT_UID};
+use super::mmu::PAGE_SIZE;
+use super::plic::{Plic, PLIC_BASE, PLIC_SIZE};
+use super::device::HostOutput;
+use super::uart::{Uart, UART_BASE, UART_SIZE, UART_IRQ};
+use super::virtio::{VirtioMmio, VIRTIO_MMIO_SIZE};
+use super::virtio_blk::VirtioBlk;
+use super::virtio_console::VirtioConsole;
+use super::trap::{CpuFault, Privilege};
 
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::str;
 use std::cell::RefCell;
 
+/// Physical memory of a booted kernel
+pub const RAM_BASE: u64 = 0x8000_0000;
+pub const RAM_SIZE: u64 = 0x800_0000;
+
+/// Offset of the kernel in memory when its Image header does not give one
+const KERNEL_DEFAULT_OFFSET: u64 = 0x20_0000;
+/// Magic of the RISC-V Linux Image header, at offset 0x38
+const KERNEL_IMAGE_MAGIC: &[u8] = b"RSC\x05";
+/// Space reserved for the device tree at the end of the memory
+const DTB_MAX_SIZE: u64 = 0x1_0000;
+/// Frequency of the time CSR as seen by the kernel, time counts the retired
+/// instructions
+const TIMEBASE_FREQUENCY: u32 = 10_000_000;
+
+//Virtio devices, address and PLIC source
+const VIRTIO_BLK_BASE: u64 = 0x1000_1000;
+const VIRTIO_BLK_IRQ: u32 = 1;
+const VIRTIO_CONSOLE_BASE: u64 = 0x1000_2000;
+const VIRTIO_CONSOLE_IRQ: u32 = 2;
+
+/// Load address of the position independent executables and of their
+/// dynamic linker, below the heap and within the reach of RV32
+const PIE_BASE: u64 = 0x2000_0000;
+const INTERP_BASE: u64 = 0x3000_0000;
+/// Size of a program header
+const ELF32_PHDR_SIZE: u64 = 32;
+const ELF64_PHDR_SIZE: u64 = 56;
+/// Frequency of the times given in clock ticks
+const CLOCK_TICKS: u64 = 100;
+
+/// Fault that ended a run of exec_elf
+#[derive(Debug, Clone)]
+pub struct Crash{
+    pub fault: CpuFault,
+    /// Call sites of the calls in progress, the innermost first
+    pub backtrace: Vec<u64>,
+    /// For the heap faults, the chunk closest to the faulting address
+    pub chunk: Option<Chunk>,
+    /// Fuzzed bytes the guest received during the run
+    pub input: Vec<u8>,
+}
+
 pub struct Emu{
     cpu: CPU,
     fuzzer: Rc<RefCell<Fuzzer>>,
+    /// Serial console of the booted kernel, its output goes to stdout
+    uart: Rc<RefCell<Uart>>,
+    console: Rc<RefCell<VirtioMmio<VirtioConsole>>>,
+    /// Root disk of the booted kernel, set through attach_disk
+    disk: Option<Rc<RefCell<VirtioMmio<VirtioBlk>>>>,
+    /// Faults that ended the runs of exec_elf
+    crashes: Vec<Crash>,
+    /// Command line and environment of the program run by exec_elf, the
+    /// path of the program is argv[0] when no arguments are set
+    args: Vec<String>,
+    env: Vec<String>,
+    /// Stack of the program run by exec_elf
+    stack: Stack,
+    /// Addresses where the kernel booted by boot_linux is snapshotted and
+    /// where its runs end, set through set_kernel_snapshot
+    kernel_snapshot: Option<u64>,
+    kernel_end: Option<u64>,
 }
 
 impl Emu{
@@ -17,9 +94,198 @@ impl Emu{
         Emu{
             cpu: CPU::new(true),
             fuzzer: Rc::new(RefCell::new(Fuzzer::new())),
+            uart: Rc::new(RefCell::new(Uart::new(HostOutput::Stdout))),
+            console: Rc::new(RefCell::new(VirtioMmio::new(VirtioConsole::new(HostOutput::Stdout)))),
+            disk: None,
+            crashes: Vec::new(),
+            args: Vec::new(),
+            env: Vec::new(),
+            stack: Stack::default(),
+            kernel_snapshot: None,
+            kernel_end: None,
+        }
+    }
+
+    /// Give a disk image to the kernel booted by boot_linux, with copy on
+    /// write the image is left untouched and the writes are rolled back with
+    /// the snapshots
+    pub fn attach_disk(&mut self, path: &PathBuf, copy_on_write: bool){
+        let disk = VirtioBlk::open(path, copy_on_write)
+            .unwrap_or_else(|e| panic!("Couldnt open the disk image {:?}: {:?}", path, e));
+        self.disk = Some(Rc::new(RefCell::new(VirtioMmio::new(disk))));
+    }
+
+    /// Arguments given to the program run by exec_elf, argv[0] included
+    pub fn set_args(&mut self, args: &[&str]){
+        self.args = args.iter().map(|arg| String::from(*arg)).collect();
+    }
+
+    /// Environment of the program run by exec_elf, as NAME=value entries
+    pub fn set_env(&mut self, env: &[&str]){
+        self.env = env.iter().map(|var| String::from(*var)).collect();
+    }
+
+    /// Place the stack of the program run by exec_elf below top, size bytes
+    /// are mapped at start and it grows up to limit bytes. The page below
+    /// the limit catches the overflows, all must be multiples of the page
+    /// size
+    pub fn set_stack(&mut self, top: u64, size: u64, limit: u64){
+        self.stack = Stack{ top, size, limit };
+    }
+
+    /// Snapshot the kernel booted by boot_linux when it reaches snapshot, the
+    /// fuzzed input is typed from there. Each run goes back to the snapshot
+    /// when it reaches end, crashes or shuts the machine down
+    pub fn set_kernel_snapshot(&mut self, snapshot: u64, end: Option<u64>){
+        self.kernel_snapshot = Some(snapshot);
+        self.kernel_end = end;
+    }
+
+    /// Crashes in the order they happened
+    pub fn crashes(&self) -> &[Crash]{
+        &self.crashes
+    }
+
+    /// Record the fault that ended the current run
+    fn record_crash(&mut self, fault: CpuFault){
+        let chunk = match (fault, &self.cpu.heap){
+            (CpuFault::HeapOverflow{ .. }, Some(heap)) |
+            (CpuFault::UseAfterFree{ .. }, Some(heap)) |
+            (CpuFault::DoubleFree{ .. }, Some(heap)) |
+            (CpuFault::InvalidFree{ .. }, Some(heap)) => heap.chunk_near(fault.tval()).cloned(),
+            _ => None,
+        };
+        let crash = Crash{
+            fault,
+            backtrace: self.cpu.call_stack.iter().rev().copied().collect(),
+            chunk,
+            input: self.fuzzer.borrow().input(self.cpu.nbr_exec).to_vec(),
+        };
+
+        println!("Crash: {:X?}", crash.fault);
+        println!("  called from {:X?}", crash.backtrace);
+        println!("  input {:X?}", crash.input);
+        if let Some(chunk) = &crash.chunk{
+            println!("  {} bytes chunk at {:#X} allocated from {:X?}", chunk.size, chunk.addr, chunk.allocated_at);
+            if let Some(freed_at) = &chunk.freed_at{
+                println!("  freed from {:X?}", freed_at);
+            }
+        }
+        self.crashes.push(crash);
+    }
+
+    /// Replace the malloc family of the guest by the sanitizing allocator of
+    /// the heap module, nothing is done if the program has none of them.
+    /// The malloc family of a dynamic program is hooked once its library is
+    /// loaded
+    fn hook_heap(&mut self, dynamic: bool){
+        let hooks: [(&str, Breakpoint); 7] = [
+            ("malloc", heap::malloc),
+            ("calloc", heap::calloc),
+            ("realloc", heap::realloc),
+            ("posix_memalign", heap::posix_memalign),
+            ("aligned_alloc", heap::aligned_alloc),
+            ("memalign", heap::memalign),
+            ("free", heap::free),
+        ];
+        if !dynamic && !hooks.iter().any(|(name, _)| self.cpu.process.symbols.contains_key(*name)){
+            return;
+        }
+
+        //The chunks are carved in a region poisoned but where they are
+        self.cpu.memory.allocate_uninitialized(HEAP_BASE, HEAP_SIZE, Permissions::RW);
+        self.cpu.memory.set_shadow(HEAP_BASE, HEAP_SIZE, Shadow::Poisoned);
+        self.cpu.heap = Some(Heap::new());
+
+        for (name, hook) in hooks.iter(){
+            if let Some(addr) = process::hook_symbol(&mut self.cpu, name, *hook){
+                println!("Hooked {} ({:#8X})", name, addr);
+            }
+        }
+    }
+
+    /// Use the files of path for the dynamically linked programs, their
+    /// dynamic linker and libraries are searched in it
+    pub fn set_sysroot(&mut self, path: &Path){
+        self.cpu.process.sysroot = Some(path.to_path_buf());
+    }
+
+    /// Map the loaded segments of an ELF file
+    fn map_segments(&mut self, segments: Vec<LoadedSegment>){
+        println!("Mapping memory segments:");
+        for s in segments{
+            let size = s.data.len() as u64;
+            println!("  * {:08X} -> {:08X} {}{}{}",
+                s.addr, s.addr + size,
+                if s.permissions.read { "r" } else { "-" },
+                if s.permissions.write { "w" } else { "-" },
+                if s.permissions.execute { "x" } else { "-" });
+            self.cpu.memory.allocate(s.addr, size, &s.data, s.permissions);
         }
     }
 
+    /// Load the dynamic linker of a program from the sysroot, returns its
+    /// entry point
+    fn load_interpreter(&mut self, interpreter: &str) -> u64{
+        let path = self.cpu.process.host_path(interpreter)
+            .unwrap_or_else(|| panic!("The program needs {} but no sysroot is set", interpreter));
+        let file = fs::read(&path)
+            .unwrap_or_else(|e| panic!("Couldnt read the interpreter {:?}: {:?}", path, e));
+        let elf = elf::File::open_stream(&mut Cursor::new(&file[..]))
+            .unwrap_or_else(|e| panic!("Error {:?}", e));
+        println!("Interpreter {:?}", path);
+
+        //The dynamic linker relocates itself
+        let segments = elf_reader::load_segments(&file, &elf.phdrs, INTERP_BASE)
+            .unwrap_or_else(|e| panic!("Error {}", e));
+        self.map_segments(segments);
+        process::add_object(&mut self.cpu, &path, &file, INTERP_BASE);
+
+        elf.ehdr.entry + INTERP_BASE
+    }
+
+    /// Select the optional extensions with an ISA string like
+    /// rv64gc_zba_zbb, the ones it does not list are disabled
+    pub fn set_isa(&mut self, isa: &str){
+        let extensions = Extensions::from_isa_string(isa)
+            .unwrap_or_else(|| panic!("Invalid ISA string {:?}", isa));
+        self.cpu.set_extensions(extensions);
+    }
+
+    /// Queue a fuzzed input on the serial console, the guest receives it
+    /// byte by byte
+    pub fn feed_uart_input(&mut self){
+        let input = self.fuzzer.borrow_mut().get_fuzz_input(self.cpu.nbr_exec);
+        self.uart.borrow_mut().push_input(&input);
+    }
+
+    /// Queue a fuzzed input on the virtio console
+    pub fn feed_console_input(&mut self){
+        let input = self.fuzzer.borrow_mut().get_fuzz_input(self.cpu.nbr_exec);
+        self.console.borrow_mut().device.push_input(&input);
+    }
+
+    /// Virtio devices of the machine, their address and PLIC source
+    fn virtio_devices(&self) -> Vec<(u64, u32)>{
+        let mut devices = vec![(VIRTIO_CONSOLE_BASE, VIRTIO_CONSOLE_IRQ)];
+        if self.disk.is_some(){
+            devices.push((VIRTIO_BLK_BASE, VIRTIO_BLK_IRQ));
+        }
+        devices
+    }
+
+    /// Map the interrupt controller and the devices behind it
+    fn attach_devices(&mut self){
+        let plic = Rc::new(RefCell::new(Plic::new()));
+        self.cpu.memory.attach(PLIC_BASE, PLIC_SIZE, None, plic.clone());
+        self.cpu.memory.attach(UART_BASE, UART_SIZE, Some(UART_IRQ), self.uart.clone());
+        self.cpu.memory.attach(VIRTIO_CONSOLE_BASE, VIRTIO_MMIO_SIZE, Some(VIRTIO_CONSOLE_IRQ), self.console.clone());
+        if let Some(disk) = &self.disk{
+            self.cpu.memory.attach(VIRTIO_BLK_BASE, VIRTIO_MMIO_SIZE, Some(VIRTIO_BLK_IRQ), disk.clone());
+        }
+        self.cpu.plic = Some(plic);
+    }
+
     /// OS Emulator part, should be in a different structure but i just cant
     /// get it working
     pub fn exec_elf(&mut self, path: &PathBuf) {
@@ -28,65 +294,293 @@ impl Emu{
             Err(e) => panic!("Error {:?}", e)
         };
     
-        let entrypoint = elf.ehdr.entry;
+        //Position independent executables are linked at 0 and moved to the
+        //load base
+        let base = if elf.ehdr.elftype == elf::types::ET_DYN { PIE_BASE } else { 0 };
+        let entrypoint = elf.ehdr.entry + base;
+        let class = elf.ehdr.class;
         println!("Entry point: {:#X}", entrypoint);
+        self.cpu.set_xlen(elf_reader::xlen(class));
+
+        //The program headers alone tell what to map, the sections are only
+        //read for the symbols
+        let file = fs::read(path)
+            .unwrap_or_else(|e| panic!("Couldnt read {:?}: {:?}", path, e));
+        let interpreter = elf_reader::interpreter(&file, &elf.phdrs);
+        let mut segments = elf_reader::load_segments(&file, &elf.phdrs, base)
+            .unwrap_or_else(|e| panic!("Error {}", e));
+        //With an interpreter the program is relocated by the dynamic linker
+        if base != 0 && interpreter.is_none(){
+            let count = elf_reader::relocate(&mut segments, &elf.phdrs, base, class)
+                .unwrap_or_else(|e| panic!("Error {}", e));
+            println!("Loaded at {:#X}, {} relocations applied", base, count);
+        }
+        //The heap grown by brk starts after the program
+        let end = segments.iter().map(|s| s.addr + s.data.len() as u64).max().unwrap_or(0);
+        self.cpu.process.set_brk((end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1));
+        self.map_segments(segments);
+
+        let phdr = elf_reader::program_headers_address(&file, &elf.phdrs, class, base);
+        let phnum = elf.phdrs.len() as u64;
+        self.cpu.process.symbols = elf_reader::read_symbols(elf, base).unwrap_or_else(|| {
+            println!("No symbol table, the binary is stripped");
+            HashMap::new()
+        });
+
+        let (start, interpreter_base) = match &interpreter{
+            Some(interpreter) => (self.load_interpreter(interpreter), INTERP_BASE),
+            None => (entrypoint, 0),
+        };
+
+        //The program starts with the stack set up by execve
+        let isa = self.cpu.extensions.isa_string(self.cpu.xlen);
+        let mut auxv = vec![
+            (AT_PHENT, if class == elf::types::ELFCLASS32 { ELF32_PHDR_SIZE } else { ELF64_PHDR_SIZE }),
+            (AT_PHNUM, phnum),
+            (AT_PAGESZ, PAGE_SIZE),
+            (AT_BASE, interpreter_base),
+            (AT_FLAGS, 0),
+            (AT_ENTRY, entrypoint),
+            (AT_UID, 0),
+            (AT_EUID, 0),
+            (AT_GID, 0),
+            (AT_EGID, 0),
+            (AT_SECURE, 0),
+            (AT_CLKTCK, CLOCK_TICKS),
+            (AT_HWCAP, hwcap(&isa)),
+        ];
+        match phdr{
+            Some(phdr) => auxv.push((AT_PHDR, phdr)),
+            None if interpreter.is_some() => panic!("The program headers are not loaded"),
+            None => {},
+        }
+        if !self.cpu.memory.map_stack(self.stack){
+            panic!("Invalid stack {:X?}", self.stack);
+        }
+        println!("Stack: {:08X} -> {:08X}, grows down to {:08X}",
+            self.stack.top - self.stack.size, self.stack.top, self.stack.top - self.stack.limit);
+
+        let args = if self.args.is_empty() { vec![path.to_string_lossy().into_owned()] } else { self.args.clone() };
+        let sp = process::initial_stack(&mut self.cpu, self.stack.top, &args, &self.env, &auxv)
+            .unwrap_or_else(|| panic!("The arguments and the environment do not fit in the stack"));
+        self.cpu.registers.common[2] = sp;
     
-        let mut symtab: Option<elf::Section> = None;
-        let mut strtab: Option<elf::Section> = None;
-        
-        println!("Mapping memory sections:");
-        for s in elf.sections{
-            if (s.shdr.flags.0 & elf::types::SHF_ALLOC.0) != 0 {
-                self.cpu.memory.allocate(s.shdr.addr, s.shdr.size, &s.data);
-
-                println!("  * {:}({}b): {:08X} -> {:08X}", 
-                    s.shdr.name, s.shdr.size, s.shdr.addr, s.shdr.addr + s.shdr.size);
-            }
+        //Main is the state from where we want to restart the execution,
+        //set a breakpoint on it and save a snapshot. Without symbols the
+        //snapshot is taken at the entry point of the program
+        let snapshot = match self.cpu.process.symbols.get("main"){
+            Some(addr) => *addr,
+            None => {
+                println!("Couldnt find main in exported symbols, using the entry point");
+                entrypoint
+            },
+        };
+        println!("Breakpoint set at {:#8X} to save the state", snapshot);
+        self.cpu.set_breakpoint(snapshot, Self::bp_save_state);
     
-            match s.shdr.name.as_ref() {
-                ".symtab" => {
-                    symtab = Some(s); 
-                },
-                ".strtab" => {
-                    strtab = Some(s);
-                }
-                _ => {},
+        //Exit represents the end of the execution, from there we want to
+        //reset to the initial state reached at main. The symbols of the
+        //shared libraries are known once the dynamic linker maps them
+        match process::hook_symbol(&mut self.cpu, "exit", Self::bp_reset_to_snapshot){
+            Some(addr) => println!("Breakpoint set at exit ({:#8X})", addr),
+            None if interpreter.is_some() => println!("Breakpoint on exit set once a library defines it"),
+            None => println!("Couldnt find exit in exported symbols, the runs end on a crash or the exit system calls"),
+        }
+        //_exit and the programs without libc go straight to the system call
+        self.cpu.exit_handler = Some(Self::bp_exit);
+        self.hook_heap(interpreter.is_some());
+
+        //A fault is a crash of the fuzzed program, the next run starts again
+        //from the snapshot
+        let mut result = self.cpu.execute(start, Rc::clone(&self.fuzzer));
+        while let Err(fault) = result{
+            self.record_crash(fault);
+            if !self.cpu.has_initial_state(){
+                println!("The crash happened before the snapshot, stopping");
+                return;
             }
+
+            Self::reset_to_snapshot(&mut self.cpu);
+            result = self.cpu.run(Rc::clone(&self.fuzzer));
         }
-        
-        let symtab = symtab.expect("Symtab memory region not found in ELF");
-        let strtab = strtab.expect("Strtab memory region not found in ELF");
-    
-        let symbols =  elf_reader::read_symbols_list(symtab, strtab);
+    }
     
-        //Test3 is the state from where we want to restart the execution,
-        //set a breakpoint on it and save a snapshot
-        if let Some(addr) = symbols.get("main"){
-            println!("Breakpoint set at main ({:#8X})", addr);
-            self.cpu.set_breakpoint(*addr, Self::bp_save_state);
+    /// Boot a Linux Image in S-mode, the emulator plays the role of the
+    /// firmware: the kernel gets the hart id in a0 and the device tree in a1
+    /// and its SBI calls are handled by the emulator
+    pub fn boot_linux(&mut self, kernel_path: &PathBuf, initrd_path: Option<&PathBuf>, cmdline: &str){
+        let kernel = fs::read(kernel_path)
+            .unwrap_or_else(|e| panic!("Couldnt read the kernel {:?}: {:?}", kernel_path, e));
+
+        //The Image header gives the offset the kernel expects to be loaded at
+        let text_offset = if kernel.len() >= 0x40 && &kernel[0x38..0x3C] == KERNEL_IMAGE_MAGIC{
+            let mut offset = [0u8; 8];
+            offset.copy_from_slice(&kernel[0x8..0x10]);
+            u64::from_le_bytes(offset)
         }
         else{
-            panic!("Couldnt find main in exported symbols");
+            KERNEL_DEFAULT_OFFSET
+        };
+        let kernel_addr = RAM_BASE + text_offset;
+        let kernel_end = text_offset + kernel.len() as u64;
+
+        //The device tree goes at the end of the memory and the initramfs
+        //right below it
+        let dtb_offset = RAM_SIZE - DTB_MAX_SIZE;
+        if kernel_end > dtb_offset{
+            panic!("Kernel too big for the memory ({} bytes)", kernel.len());
         }
-    
-        //The success symbol represents the end of the execution, from
-        //there we want to reset to the initial state reached at Test3
-        if let Some(addr) = symbols.get("exit"){
-            println!("Breakpoint set at exit ({:#8X})", addr);
-            self.cpu.set_breakpoint(*addr, Self::reset_to_snapshot);
+
+        let mut ram = vec![0u8; RAM_SIZE as usize];
+        ram[text_offset as usize..kernel_end as usize].copy_from_slice(&kernel);
+        println!("Kernel loaded at {:#X} ({} bytes)", kernel_addr, kernel.len());
+
+        let initrd = initrd_path.map(|path| {
+            let initrd = fs::read(path)
+                .unwrap_or_else(|e| panic!("Couldnt read the initrd {:?}: {:?}", path, e));
+
+            let start = dtb_offset.checked_sub(initrd.len() as u64)
+                .map(|start| start & !(PAGE_SIZE - 1))
+                .filter(|start| *start >= kernel_end)
+                .unwrap_or_else(|| panic!("Initrd too big for the memory ({} bytes)", initrd.len()));
+            let end = start + initrd.len() as u64;
+
+            ram[start as usize..end as usize].copy_from_slice(&initrd);
+            println!("Initrd loaded at {:#X} ({} bytes)", RAM_BASE + start, initrd.len());
+            (RAM_BASE + start, RAM_BASE + end)
+        });
+
+        let virtio = self.virtio_devices();
+        let isa = self.cpu.extensions.isa_string(self.cpu.xlen);
+        let dtb = dtb::generate(&dtb::Machine{
+            memory_base: RAM_BASE,
+            memory_size: RAM_SIZE,
+            cmdline,
+            initrd,
+            timebase_frequency: TIMEBASE_FREQUENCY,
+            isa: &isa,
+            mmu_type: "riscv,sv48",
+            virtio: &virtio,
+        });
+        if dtb.len() as u64 > DTB_MAX_SIZE{
+            panic!("Device tree too big ({} bytes)", dtb.len());
+        }
+        ram[dtb_offset as usize..dtb_offset as usize + dtb.len()].copy_from_slice(&dtb);
+        let dtb_addr = RAM_BASE + dtb_offset;
+
+        self.cpu.memory.allocate(RAM_BASE, RAM_SIZE, &ram, Permissions::RWX);
+        self.attach_devices();
+
+        //The fuzzed input is typed on the console the kernel uses, once the
+        //snapshot is taken when there is one
+        let console = cmdline.contains("console=hvc");
+        if self.kernel_snapshot.is_none(){
+            self.feed_kernel_input(console);
+        }
+
+        let cpu = &mut self.cpu;
+        cpu.syscall_emulation = false;
+        cpu.emulated_sbi = true;
+        cpu.privilege = Privilege::Supervisor;
+
+        //There is no firmware, every trap that can be is handled by the kernel
+        //and the counters are readable from S-mode
+        cpu.csr.write(csr::MEDELEG, !(1 << 9));
+        cpu.csr.write(csr::MIDELEG, u64::MAX);
+        cpu.csr.write(csr::MCOUNTEREN, 0b111);
+
+        cpu.registers.common[10] = 0;
+        cpu.registers.common[11] = dtb_addr;
+        if let Some(snapshot) = self.kernel_snapshot{
+            println!("Breakpoint set at {:#8X} to save the state", snapshot);
+            cpu.set_breakpoint(snapshot, Self::bp_kernel_snapshot);
+        }
+        if let Some(end) = self.kernel_end{
+            println!("Breakpoint set at {:#8X} to reset the state", end);
+            cpu.set_breakpoint(end, Self::bp_kernel_end);
+        }
+
+        //Every exception is delivered to the kernel, a fault means there was
+        //no handler for it, M-mode traps for instance
+        let mut result = cpu.execute(kernel_addr, Rc::clone(&self.fuzzer));
+        loop{
+            if let Err(fault) = result{
+                self.record_crash(fault);
+            }
+
+            let stopped_at_snapshot = result.is_ok() && self.kernel_snapshot == Some(self.cpu.registers.pc);
+            self.cpu.exit = false;
+            match (self.cpu.has_initial_state(), stopped_at_snapshot){
+                (false, true) => {
+                    println!("State saved at {:#X}", self.cpu.registers.pc);
+                    self.cpu.save_as_initial_state();
+                },
+                (false, false) => {
+                    println!("The kernel stopped before the snapshot, stopping");
+                    return;
+                },
+                //The end of a run, a crash or a shutdown
+                (true, _) => {
+                    Self::reset_to_snapshot(&mut self.cpu);
+                    if self.cpu.exit{
+                        return;
+                    }
+                },
+            }
+
+            self.feed_kernel_input(console);
+            result = self.cpu.run(Rc::clone(&self.fuzzer));
+        }
+    }
+
+    /// Type a fuzzed input on the serial console or on the virtio console
+    fn feed_kernel_input(&mut self, console: bool){
+        if console{
+            self.feed_console_input();
         }
         else{
-            panic!("Couldnt find pass in exported symbols");
+            self.feed_uart_input();
         }
-        self.cpu.execute(entrypoint, Rc::clone(&self.fuzzer));
     }
-    
-    fn bp_save_state(cpu: &mut CPU){
+
+    fn bp_save_state(cpu: &mut CPU) -> Result<(), CpuFault>{
         println!("State saved:");
         println!("{:?}", cpu);
         cpu.save_as_initial_state();
+        Ok(())
+    }
+
+    /// Stop the run the first time the kernel reaches the snapshot address,
+    /// boot_linux takes the snapshot
+    fn bp_kernel_snapshot(cpu: &mut CPU) -> Result<(), CpuFault>{
+        if !cpu.has_initial_state(){
+            cpu.exit = true;
+        }
+        Ok(())
+    }
+
+    /// Stop the run, boot_linux resets the kernel to the snapshot
+    fn bp_kernel_end(cpu: &mut CPU) -> Result<(), CpuFault>{
+        cpu.exit = true;
+        Ok(())
+    }
+
+    fn bp_reset_to_snapshot(cpu: &mut CPU) -> Result<(), CpuFault>{
+        Self::reset_to_snapshot(cpu);
+        Ok(())
     }
     
+    /// The program made the exit system call, there is nothing to reset to
+    /// when it exits before the snapshot
+    fn bp_exit(cpu: &mut CPU) -> Result<(), CpuFault>{
+        if !cpu.has_initial_state(){
+            println!("The program exited before the snapshot, stopping");
+            cpu.exit = true;
+            return Ok(());
+        }
+        Self::bp_reset_to_snapshot(cpu)
+    }
+
     fn reset_to_snapshot(cpu: &mut CPU){
         let coverage = cpu.reset_to_initial_state();
         println!("Coverage : {:X?}", coverage.len());
@@ -95,4 +589,12 @@ impl Emu{
             cpu.exit = true;
         }
     }
-}
\ No newline at end of file
+}
+
+/// AT_HWCAP, a bit for each single letter extension of the ISA string
+fn hwcap(isa: &str) -> u64{
+    let letters = isa.get(4..).unwrap_or("").split('_').next().unwrap_or("");
+    letters.bytes()
+        .filter(|c| c.is_ascii_lowercase())
+        .fold(0, |hwcap, c| hwcap | 1 << (c - b'a'))
+}
@@ -0,0 +1,645 @@
+// Floating point helpers for the F and D extensions.
+// Arithmetic is done on integers (soft float) so every rounding mode and
+// exception flag behaves as the specification requires whatever the host.
+// All the functions works on raw register values and return the raw result
+// with the accrued exception flags
+
+//Exception flags of fcsr
+pub const FLAG_INEXACT: u32 = 0b00001;
+pub const FLAG_UNDERFLOW: u32 = 0b00010;
+pub const FLAG_OVERFLOW: u32 = 0b00100;
+pub const FLAG_DIV_BY_ZERO: u32 = 0b01000;
+pub const FLAG_INVALID: u32 = 0b10000;
+
+pub const CANONICAL_NAN_F32: u32 = 0x7FC0_0000;
+pub const CANONICAL_NAN_F64: u64 = 0x7FF8_0000_0000_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FloatFormat{
+    Single,
+    Double,
+}
+
+impl FloatFormat{
+    //fmt field of the instructions
+    pub fn from_bits(bits: u32) -> Option<FloatFormat>{
+        match bits{
+            0b00 => Some(FloatFormat::Single),
+            0b01 => Some(FloatFormat::Double),
+            _ => None,
+        }
+    }
+
+    //Number of significant bits, including the implicit one
+    fn precision(self) -> i32{
+        match self{
+            FloatFormat::Single => 24,
+            FloatFormat::Double => 53,
+        }
+    }
+
+    fn exponent_bits(self) -> u32{
+        match self{
+            FloatFormat::Single => 8,
+            FloatFormat::Double => 11,
+        }
+    }
+
+    //Largest exponent, also the exponent bias
+    fn max_exponent(self) -> i32{
+        match self{
+            FloatFormat::Single => 127,
+            FloatFormat::Double => 1023,
+        }
+    }
+
+    fn min_exponent(self) -> i32{
+        1 - self.max_exponent()
+    }
+
+    //Register value of the canonical NaN
+    fn canonical_nan(self) -> u64{
+        match self{
+            FloatFormat::Single => box_f32(CANONICAL_NAN_F32),
+            FloatFormat::Double => CANONICAL_NAN_F64,
+        }
+    }
+
+    //Build a register value from its fields
+    fn encode(self, negative: bool, biased_exp: u64, fraction: u64) -> u64{
+        let fraction_bits = self.precision() as u32 - 1;
+        let sign_bit = fraction_bits + self.exponent_bits();
+        let value = (negative as u64) << sign_bit | biased_exp << fraction_bits | fraction;
+
+        match self{
+            FloatFormat::Single => box_f32(value as u32),
+            FloatFormat::Double => value,
+        }
+    }
+
+    fn infinity(self, negative: bool) -> u64{
+        self.encode(negative, (1 << self.exponent_bits()) - 1, 0)
+    }
+
+    fn largest(self, negative: bool) -> u64{
+        self.encode(negative, (1 << self.exponent_bits()) - 2, (1 << (self.precision() - 1)) - 1)
+    }
+
+    fn zero(self, negative: bool) -> u64{
+        self.encode(negative, 0, 0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RoundingMode{
+    NearestEven,
+    TowardZero,
+    Down,
+    Up,
+    NearestMaxMagnitude,
+}
+
+impl RoundingMode{
+    //rm field of the instructions or frm field of fcsr, dynamic rounding
+    //mode must be resolved by the caller
+    pub fn from_bits(bits: u32) -> Option<RoundingMode>{
+        match bits{
+            0b000 => Some(RoundingMode::NearestEven),
+            0b001 => Some(RoundingMode::TowardZero),
+            0b010 => Some(RoundingMode::Down),
+            0b011 => Some(RoundingMode::Up),
+            0b100 => Some(RoundingMode::NearestMaxMagnitude),
+            _ => None,
+        }
+    }
+}
+
+/// Single precision values are stored NaN-boxed in the 64 bits registers
+pub fn box_f32(value: u32) -> u64{
+    0xFFFF_FFFF_0000_0000 | value as u64
+}
+
+/// A single precision value that is not properly NaN-boxed is read as the
+/// canonical NaN
+pub fn unbox_f32(value: u64) -> u32{
+    if (value >> 32) == 0xFFFF_FFFF { value as u32 } else { CANONICAL_NAN_F32 }
+}
+
+fn is_signaling_f32(v: f32) -> bool{
+    v.is_nan() && (v.to_bits() & 0x0040_0000) == 0
+}
+
+fn is_signaling_f64(v: f64) -> bool{
+    v.is_nan() && (v.to_bits() & 0x0008_0000_0000_0000) == 0
+}
+
+fn f32_of(v: u64) -> f32{
+    f32::from_bits(unbox_f32(v))
+}
+
+fn f64_of(v: u64) -> f64{
+    f64::from_bits(v)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Class{
+    Zero,
+    Finite,
+    Infinite,
+    QuietNaN,
+    SignalingNaN,
+}
+
+/// Decoded register value, finite values are mant * 2^exp
+#[derive(Debug, Clone, Copy)]
+struct Unpacked{
+    negative: bool,
+    class: Class,
+    mant: u128,
+    exp: i32,
+}
+
+impl Unpacked{
+    fn is_nan(&self) -> bool{
+        self.class == Class::QuietNaN || self.class == Class::SignalingNaN
+    }
+
+    fn negate(mut self) -> Self{
+        self.negative = !self.negative;
+        self
+    }
+}
+
+fn unpack(fmt: FloatFormat, value: u64) -> Unpacked{
+    let value = match fmt{
+        FloatFormat::Single => unbox_f32(value) as u64,
+        FloatFormat::Double => value,
+    };
+    let fraction_bits = fmt.precision() as u32 - 1;
+    let max_biased = (1 << fmt.exponent_bits()) - 1;
+
+    let negative = (value >> (fraction_bits + fmt.exponent_bits())) & 0b1 == 1;
+    let biased = (value >> fraction_bits) & max_biased;
+    let fraction = (value & ((1 << fraction_bits) - 1)) as u128;
+
+    let (class, mant, exp) = if biased == max_biased{
+        if fraction == 0 { (Class::Infinite, 0, 0) }
+        else if (fraction >> (fraction_bits - 1)) == 1 { (Class::QuietNaN, 0, 0) }
+        else { (Class::SignalingNaN, 0, 0) }
+    }
+    else if biased == 0{
+        if fraction == 0 { (Class::Zero, 0, 0) }
+        //Subnormal
+        else { (Class::Finite, fraction, fmt.min_exponent() - fraction_bits as i32) }
+    }
+    else{
+        (Class::Finite, fraction | 1 << fraction_bits, biased as i32 - fmt.max_exponent() - fraction_bits as i32)
+    };
+
+    Unpacked{ negative, class, mant, exp }
+}
+
+/// Drop the shift lowest bits of mant with the rounding mode, returns the
+/// rounded value and true if it is inexact
+fn round_shift(mant: u128, shift: i32, negative: bool, rm: RoundingMode) -> (u128, bool){
+    if shift <= 0{
+        return (mant << -shift, false);
+    }
+    let (kept, rest) = if shift >= 128 { (0, mant) } else { (mant >> shift, mant & ((1 << shift) - 1)) };
+    if rest == 0{
+        return (kept, false);
+    }
+
+    //Position of the rest relative to the half of the last kept bit
+    let half = if shift > 128 { std::cmp::Ordering::Less } else { rest.cmp(&(1 << (shift - 1))) };
+    let increment = match rm{
+        RoundingMode::NearestEven => half == std::cmp::Ordering::Greater ||
+            (half == std::cmp::Ordering::Equal && (kept & 0b1) == 1),
+        RoundingMode::TowardZero => false,
+        RoundingMode::Down => negative,
+        RoundingMode::Up => !negative,
+        RoundingMode::NearestMaxMagnitude => half != std::cmp::Ordering::Less,
+    };
+    (kept + increment as u128, true)
+}
+
+/// Round mant * 2^exp to the format. The lowest bit of mant may be a sticky
+/// bit standing for bits that were shifted out
+fn pack(fmt: FloatFormat, negative: bool, mant: u128, exp: i32, rm: RoundingMode) -> (u64, u32){
+    if mant == 0{
+        return (fmt.zero(negative), 0);
+    }
+    let precision = fmt.precision();
+    let bits = 128 - mant.leading_zeros() as i32;
+    //Exponent of the most significant bit
+    let top = exp + bits - 1;
+
+    //Tininess is detected after rounding, as if the exponent range was unbounded
+    let (unbounded, _) = round_shift(mant, bits - precision, negative, rm);
+    let tiny = (top + (unbounded >> precision) as i32) < fmt.min_exponent();
+
+    let lsb_exp = top.max(fmt.min_exponent()) - (precision - 1);
+    let (mut mant, inexact) = round_shift(mant, lsb_exp - exp, negative, rm);
+    let mut lsb_exp = lsb_exp;
+    //The rounding carried to a new bit
+    if (mant >> precision) != 0{
+        mant >>= 1;
+        lsb_exp += 1;
+    }
+
+    let mut flags = 0;
+    if inexact{
+        flags |= FLAG_INEXACT;
+        if tiny{
+            flags |= FLAG_UNDERFLOW;
+        }
+    }
+
+    //Subnormal or rounded to zero
+    if (mant >> (precision - 1)) == 0{
+        return (fmt.encode(negative, 0, mant as u64), flags);
+    }
+
+    let exponent = lsb_exp + precision - 1;
+    if exponent > fmt.max_exponent(){
+        let largest = rm == RoundingMode::TowardZero ||
+            (rm == RoundingMode::Down && !negative) ||
+            (rm == RoundingMode::Up && negative);
+        let value = if largest { fmt.largest(negative) } else { fmt.infinity(negative) };
+        return (value, FLAG_OVERFLOW | FLAG_INEXACT);
+    }
+
+    let fraction = (mant & ((1 << (precision - 1)) - 1)) as u64;
+    (fmt.encode(negative, (exponent + fmt.max_exponent()) as u64, fraction), flags)
+}
+
+/// Propagate NaN operands, returns the canonical NaN if any operand is a NaN
+fn nan_operands(fmt: FloatFormat, operands: &[Unpacked]) -> Option<(u64, u32)>{
+    if operands.iter().any(|o| o.is_nan()){
+        let signaling = operands.iter().any(|o| o.class == Class::SignalingNaN);
+        return Some((fmt.canonical_nan(), if signaling { FLAG_INVALID } else { 0 }));
+    }
+    None
+}
+
+fn invalid(fmt: FloatFormat) -> (u64, u32){
+    (fmt.canonical_nan(), FLAG_INVALID)
+}
+
+/// Bring two finite values to the same exponent, the smallest one is shifted
+/// right with a sticky bit if there is not enough room to shift the other left
+fn align(x: &Unpacked, y: &Unpacked) -> (u128, u128, i32){
+    if x.exp < y.exp{
+        let (y_mant, x_mant, exp) = align(y, x);
+        return (x_mant, y_mant, exp);
+    }
+
+    //Two bits are kept free for the carry of the addition
+    let room = (x.mant.leading_zeros() as i32 - 2).max(0);
+    let diff = x.exp - y.exp;
+    let shift = diff.min(room);
+    let x_mant = x.mant << shift;
+    let exp = x.exp - shift;
+
+    let rest = diff - shift;
+    let y_mant = if rest == 0 { y.mant }
+        else if rest >= 127 { (y.mant != 0) as u128 }
+        else { (y.mant >> rest) | ((y.mant & ((1 << rest) - 1)) != 0) as u128 };
+    (x_mant, y_mant, exp)
+}
+
+/// Sum of two finite or zero values
+fn sum(fmt: FloatFormat, x: &Unpacked, y: &Unpacked, rm: RoundingMode) -> (u64, u32){
+    //An exact zero sum of values with opposite signs is +0 except when rounding down
+    let zero_sign = if x.negative == y.negative { x.negative } else { rm == RoundingMode::Down };
+
+    match (x.mant, y.mant){
+        (0, 0) => return (fmt.zero(zero_sign), 0),
+        (0, _) => return pack(fmt, y.negative, y.mant, y.exp, rm),
+        (_, 0) => return pack(fmt, x.negative, x.mant, x.exp, rm),
+        _ => {},
+    }
+
+    let (x_mant, y_mant, exp) = align(x, y);
+    if x.negative == y.negative{
+        pack(fmt, x.negative, x_mant + y_mant, exp, rm)
+    }
+    else if x_mant > y_mant{
+        pack(fmt, x.negative, x_mant - y_mant, exp, rm)
+    }
+    else if y_mant > x_mant{
+        pack(fmt, y.negative, y_mant - x_mant, exp, rm)
+    }
+    else{
+        (fmt.zero(zero_sign), 0)
+    }
+}
+
+fn add_unpacked(fmt: FloatFormat, a: Unpacked, b: Unpacked, rm: RoundingMode) -> (u64, u32){
+    if let Some(nan) = nan_operands(fmt, &[a, b]) { return nan; }
+
+    match (a.class, b.class){
+        (Class::Infinite, Class::Infinite) => {
+            if a.negative != b.negative { invalid(fmt) } else { (fmt.infinity(a.negative), 0) }
+        },
+        (Class::Infinite, _) => (fmt.infinity(a.negative), 0),
+        (_, Class::Infinite) => (fmt.infinity(b.negative), 0),
+        _ => sum(fmt, &a, &b, rm),
+    }
+}
+
+pub fn add(fmt: FloatFormat, a: u64, b: u64, rm: RoundingMode) -> (u64, u32){
+    add_unpacked(fmt, unpack(fmt, a), unpack(fmt, b), rm)
+}
+
+pub fn sub(fmt: FloatFormat, a: u64, b: u64, rm: RoundingMode) -> (u64, u32){
+    add_unpacked(fmt, unpack(fmt, a), unpack(fmt, b).negate(), rm)
+}
+
+pub fn mul(fmt: FloatFormat, a: u64, b: u64, rm: RoundingMode) -> (u64, u32){
+    let (a, b) = (unpack(fmt, a), unpack(fmt, b));
+    if let Some(nan) = nan_operands(fmt, &[a, b]) { return nan; }
+    let negative = a.negative != b.negative;
+
+    match (a.class, b.class){
+        (Class::Infinite, Class::Zero) | (Class::Zero, Class::Infinite) => invalid(fmt),
+        (Class::Infinite, _) | (_, Class::Infinite) => (fmt.infinity(negative), 0),
+        (Class::Zero, _) | (_, Class::Zero) => (fmt.zero(negative), 0),
+        //The product of two mantissas fits in 106 bits
+        _ => pack(fmt, negative, a.mant * b.mant, a.exp + b.exp, rm),
+    }
+}
+
+pub fn div(fmt: FloatFormat, a: u64, b: u64, rm: RoundingMode) -> (u64, u32){
+    let (a, b) = (unpack(fmt, a), unpack(fmt, b));
+    if let Some(nan) = nan_operands(fmt, &[a, b]) { return nan; }
+    let negative = a.negative != b.negative;
+
+    match (a.class, b.class){
+        (Class::Infinite, Class::Infinite) | (Class::Zero, Class::Zero) => invalid(fmt),
+        (Class::Infinite, _) => (fmt.infinity(negative), 0),
+        (_, Class::Infinite) => (fmt.zero(negative), 0),
+        (_, Class::Zero) => (fmt.infinity(negative), FLAG_DIV_BY_ZERO),
+        (Class::Zero, _) => (fmt.zero(negative), 0),
+        _ => {
+            //Shift the dividend so the quotient has at least two more bits
+            //than the precision, the remainder becomes a sticky bit
+            let a_bits = 128 - a.mant.leading_zeros() as i32;
+            let b_bits = 128 - b.mant.leading_zeros() as i32;
+            let shift = (fmt.precision() + 3 + b_bits - a_bits).max(0);
+
+            let dividend = a.mant << shift;
+            let quotient = dividend / b.mant;
+            let sticky = (dividend % b.mant != 0) as u128;
+            pack(fmt, negative, quotient << 1 | sticky, a.exp - b.exp - shift - 1, rm)
+        }
+    }
+}
+
+//Integer square root, rounded down
+fn isqrt(value: u128) -> u128{
+    let mut result: u128 = 0;
+    let mut bit: u128 = 1 << ((127 - value.leading_zeros()) & !1);
+    let mut rest = value;
+
+    while bit != 0{
+        if rest >= result + bit{
+            rest -= result + bit;
+            result = (result >> 1) + bit;
+        }
+        else{
+            result >>= 1;
+        }
+        bit >>= 2;
+    }
+    result
+}
+
+pub fn sqrt(fmt: FloatFormat, a: u64, rm: RoundingMode) -> (u64, u32){
+    let a = unpack(fmt, a);
+    if let Some(nan) = nan_operands(fmt, &[a]) { return nan; }
+
+    match a.class{
+        //sqrt(-0) is -0
+        Class::Zero => (fmt.zero(a.negative), 0),
+        _ if a.negative => invalid(fmt),
+        Class::Infinite => (fmt.infinity(false), 0),
+        _ => {
+            //The root must have two more bits than the precision and the
+            //exponent must be even
+            let bits = 128 - a.mant.leading_zeros() as i32;
+            let mut shift = 2 * (fmt.precision() + 3) - bits;
+            if (a.exp - shift) % 2 != 0{
+                shift += 1;
+            }
+
+            let value = a.mant << shift;
+            let root = isqrt(value);
+            let sticky = (root * root != value) as u128;
+            pack(fmt, false, root << 1 | sticky, (a.exp - shift) / 2 - 1, rm)
+        }
+    }
+}
+
+/// Fused multiply add (a * b) + c, the sign of the product and of the addend
+/// are flipped for FMSUB / FNMSUB / FNMADD
+pub fn mul_add(fmt: FloatFormat, a: u64, b: u64, c: u64, negate_product: bool, negate_addend: bool,
+    rm: RoundingMode) -> (u64, u32){
+    let (a, b, c) = (unpack(fmt, a), unpack(fmt, b), unpack(fmt, c));
+
+    //0 * inf is invalid even if the addend is a quiet NaN
+    let zero_inf = (a.class == Class::Zero && b.class == Class::Infinite) ||
+        (a.class == Class::Infinite && b.class == Class::Zero);
+    if zero_inf && !a.is_nan() && !b.is_nan() && c.class != Class::SignalingNaN{
+        return invalid(fmt);
+    }
+    if let Some(nan) = nan_operands(fmt, &[a, b, c]) { return nan; }
+
+    let product = Unpacked{
+        negative: (a.negative != b.negative) != negate_product,
+        class: if a.class == Class::Infinite || b.class == Class::Infinite { Class::Infinite }
+            else if a.class == Class::Zero || b.class == Class::Zero { Class::Zero }
+            else { Class::Finite },
+        mant: a.mant * b.mant,
+        exp: a.exp + b.exp,
+    };
+    let c = if negate_addend { c.negate() } else { c };
+
+    add_unpacked(fmt, product, c, rm)
+}
+
+/// FMIN / FMAX, -0 is considered smaller than +0 and a single NaN operand
+/// is ignored
+pub fn min_max(fmt: FloatFormat, a: u64, b: u64, max: bool) -> (u64, u32){
+    match fmt{
+        FloatFormat::Single => {
+            let (x, y) = (f32_of(a), f32_of(b));
+            let flags = if is_signaling_f32(x) || is_signaling_f32(y) { FLAG_INVALID } else { 0 };
+            let r = match (x.is_nan(), y.is_nan()){
+                (true, true) => f32::from_bits(CANONICAL_NAN_F32),
+                (true, false) => y,
+                (false, true) => x,
+                _ => {
+                    let x_smaller = x < y || (x == y && x.is_sign_negative());
+                    if x_smaller != max { x } else { y }
+                }
+            };
+            (box_f32(r.to_bits()), flags)
+        },
+        FloatFormat::Double => {
+            let (x, y) = (f64_of(a), f64_of(b));
+            let flags = if is_signaling_f64(x) || is_signaling_f64(y) { FLAG_INVALID } else { 0 };
+            let r = match (x.is_nan(), y.is_nan()){
+                (true, true) => f64::from_bits(CANONICAL_NAN_F64),
+                (true, false) => y,
+                (false, true) => x,
+                _ => {
+                    let x_smaller = x < y || (x == y && x.is_sign_negative());
+                    if x_smaller != max { x } else { y }
+                }
+            };
+            (r.to_bits(), flags)
+        }
+    }
+}
+
+/// FSGNJ / FSGNJN / FSGNJX, funct3 selects the operation
+pub fn sign_inject(fmt: FloatFormat, a: u64, b: u64, funct3: u8) -> Option<u64>{
+    let (x, y, sign_bit) = match fmt{
+        FloatFormat::Single => (unbox_f32(a) as u64, unbox_f32(b) as u64, 31),
+        FloatFormat::Double => (a, b, 63),
+    };
+    let sign = match funct3{
+        0b000 => y >> sign_bit,
+        0b001 => !y >> sign_bit,
+        0b010 => (x ^ y) >> sign_bit,
+        _ => return None,
+    } & 0b1;
+    let r = (x & !(1 << sign_bit)) | (sign << sign_bit);
+
+    Some(match fmt{
+        FloatFormat::Single => box_f32(r as u32),
+        FloatFormat::Double => r,
+    })
+}
+
+/// FEQ / FLT / FLE, FEQ only raises the invalid flag on signaling NaN
+pub fn compare(fmt: FloatFormat, a: u64, b: u64, funct3: u8) -> Option<(u64, u32)>{
+    let (x, y, signaling) = match fmt{
+        FloatFormat::Single => {
+            let (x, y) = (f32_of(a), f32_of(b));
+            (x as f64, y as f64, is_signaling_f32(x) || is_signaling_f32(y))
+        },
+        FloatFormat::Double => {
+            let (x, y) = (f64_of(a), f64_of(b));
+            (x, y, is_signaling_f64(x) || is_signaling_f64(y))
+        }
+    };
+    let nan = x.is_nan() || y.is_nan();
+
+    match funct3{
+        //FEQ
+        0b010 => Some(((x == y) as u64, if signaling { FLAG_INVALID } else { 0 })),
+        //FLT
+        0b001 => Some(((x < y) as u64, if nan { FLAG_INVALID } else { 0 })),
+        //FLE
+        0b000 => Some(((x <= y) as u64, if nan { FLAG_INVALID } else { 0 })),
+        _ => None,
+    }
+}
+
+/// FCLASS, returns the mask describing the class of the value
+pub fn classify(fmt: FloatFormat, a: u64) -> u64{
+    let (negative, infinite, normal, subnormal, zero, nan, signaling) = match fmt{
+        FloatFormat::Single => {
+            let x = f32_of(a);
+            (x.is_sign_negative(), x.is_infinite(), x.is_normal(), x.is_subnormal(), x == 0.0,
+                x.is_nan(), is_signaling_f32(x))
+        },
+        FloatFormat::Double => {
+            let x = f64_of(a);
+            (x.is_sign_negative(), x.is_infinite(), x.is_normal(), x.is_subnormal(), x == 0.0,
+                x.is_nan(), is_signaling_f64(x))
+        }
+    };
+
+    if nan{
+        return if signaling { 1 << 8 } else { 1 << 9 };
+    }
+    let bit = match (negative, infinite, normal, subnormal, zero){
+        (true, true, _, _, _) => 0,
+        (true, _, true, _, _) => 1,
+        (true, _, _, true, _) => 2,
+        (true, _, _, _, true) => 3,
+        (false, _, _, _, true) => 4,
+        (false, _, _, true, _) => 5,
+        (false, _, true, _, _) => 6,
+        _ => 7,
+    };
+    1 << bit
+}
+
+/// FCVT.S.D / FCVT.D.S
+pub fn convert_float(to: FloatFormat, a: u64, rm: RoundingMode) -> (u64, u32){
+    let from = match to{
+        FloatFormat::Single => FloatFormat::Double,
+        FloatFormat::Double => FloatFormat::Single,
+    };
+    let a = unpack(from, a);
+    if let Some(nan) = nan_operands(to, &[a]) { return nan; }
+
+    match a.class{
+        Class::Infinite => (to.infinity(a.negative), 0),
+        Class::Zero => (to.zero(a.negative), 0),
+        _ => pack(to, a.negative, a.mant, a.exp, rm),
+    }
+}
+
+/// FCVT.W / WU / L / LU, kind is the rs2 field of the instruction. The result
+/// saturates when out of range and NaN are converted to the largest value
+pub fn to_int(fmt: FloatFormat, a: u64, kind: usize, rm: RoundingMode) -> Option<(u64, u32)>{
+    let (min, max): (i128, i128) = match kind{
+        0 => (i32::MIN as i128, i32::MAX as i128),
+        1 => (0, u32::MAX as i128),
+        2 => (i64::MIN as i128, i64::MAX as i128),
+        3 => (0, u64::MAX as i128),
+        _ => return None,
+    };
+    //32 bits results are sign extended, even for the unsigned conversion
+    let result = |value: i128| -> u64 {
+        if kind < 2 { value as i32 as i64 as u64 } else { value as u64 }
+    };
+
+    let a = unpack(fmt, a);
+    let (value, inexact) = match a.class{
+        Class::QuietNaN | Class::SignalingNaN => return Some((result(max), FLAG_INVALID)),
+        Class::Infinite => return Some((result(if a.negative { min } else { max }), FLAG_INVALID)),
+        Class::Zero => (0, false),
+        Class::Finite => {
+            //Anything above 2^64 is out of range whatever the conversion
+            if a.exp > 64{
+                return Some((result(if a.negative { min } else { max }), FLAG_INVALID));
+            }
+            let (magnitude, inexact) = round_shift(a.mant, -a.exp, a.negative, rm);
+            let magnitude = magnitude as i128;
+            (if a.negative { -magnitude } else { magnitude }, inexact)
+        }
+    };
+
+    if value < min || value > max{
+        return Some((result(if a.negative { min } else { max }), FLAG_INVALID));
+    }
+    Some((result(value), if inexact { FLAG_INEXACT } else { 0 }))
+}
+
+/// FCVT.S / D . W / WU / L / LU, kind is the rs2 field of the instruction
+pub fn from_int(fmt: FloatFormat, value: u64, kind: usize, rm: RoundingMode) -> Option<(u64, u32)>{
+    let value: i128 = match kind{
+        0 => value as i32 as i128,
+        1 => value as u32 as i128,
+        2 => value as i64 as i128,
+        3 => value as i128,
+        _ => return None,
+    };
+    Some(pack(fmt, value < 0, value.unsigned_abs(), 0, rm))
+}
@@ -1,9 +1,14 @@
 extern crate rand;
 
 use rand::{thread_rng, Rng};
-use std::str;
 
 use super::cpu::CPU;
+use super::process::{self, EBADF, EFAULT, ENOSYS};
+use super::trap::Exception;
+
+/// Largest write given to the host at once, the guest gets a partial write
+/// rather than making the emulator allocate whatever length it passed
+pub const MAX_WRITE: u64 = 0x10_0000;
 
 pub enum SpecialFD{
     Stdin = 0,
@@ -20,6 +25,11 @@ pub struct Fuzzer{
     corpus: Vec<Vec<u8>>,
 
     mutated_input: Vec<Vec<u8>>,
+
+    /// Bytes given to the guest during the run input_run (CPU::nbr_exec),
+    /// they are stored with the crashes
+    input: Vec<u8>,
+    input_run: u64,
 }
 
 impl Fuzzer{
@@ -27,6 +37,8 @@ impl Fuzzer{
         let mut f = Fuzzer{
             corpus: Vec::new(),
             mutated_input: Vec::new(),
+            input: Vec::new(),
+            input_run: 0,
         };
         f.corpus.push(vec![0x42,0x4e,0x45,0x0a]);
         f.corpus.push(vec![12,12,12,0x0a]);
@@ -36,14 +48,28 @@ impl Fuzzer{
         f
     }
 
-    // Returns the last element 
-    pub fn get_fuzz_input(&mut self) -> Vec<u8> {
-        let corpus = self.corpus.pop();
-        
-        return corpus.unwrap();
+    // Returns the last element, it is kept as the input of the run. The first
+    // entry stays in the corpus and is given to the runs once the others are
+    // consumed
+    pub fn get_fuzz_input(&mut self, run: u64) -> Vec<u8> {
+        let corpus = if self.corpus.len() > 1 { self.corpus.pop().unwrap() } else { self.corpus[0].clone() };
+        if run != self.input_run{
+            self.input.clear();
+            self.input_run = run;
+        }
+        self.input.extend_from_slice(&corpus);
+
+        return corpus;
+    }
+
+    /// Bytes given to the guest so far during the run
+    pub fn input(&self, run: u64) -> &[u8]{
+        if run == self.input_run { &self.input } else { &[] }
     }
 
-    pub fn syscall(&mut self, cpu: &mut CPU){
+    /// Handle the system call of the program, the faults are the memory bugs
+    /// it made the kernel run into
+    pub fn syscall(&mut self, cpu: &mut CPU) -> Result<(), Exception>{
         let syscall_nbr = cpu.registers.common[17]; //a7
         
         println!("Syscall: {:X}, {:X}, {:X}, {:X}", 
@@ -51,58 +77,117 @@ impl Fuzzer{
             cpu.registers.common[12], cpu.registers.common[13]);
         
         match syscall_nbr{
+            // Openat
+            56 => {
+                println!("Openat");
+                cpu.registers.common[10] = process::openat(cpu);
+            },
+            // Close
+            57 => {
+                println!("Close");
+                cpu.registers.common[10] = process::close(cpu);
+            },
+            // Lseek
+            62 => {
+                println!("Lseek");
+                cpu.registers.common[10] = process::lseek(cpu);
+            },
+            // Read from a file
+            63 if cpu.registers.common[10] != SpecialFD::Stdin as u64 => {
+                println!("Read");
+                cpu.registers.common[10] = process::read(cpu)?;
+            },
             // Read
             63 => {
                 println!("Read");
-                let fp = cpu.registers.common[10];
                 let ptr = cpu.registers.common[11];
                 let len = cpu.registers.common[12];
                 
-                if fp != SpecialFD::Stdin as u64{
-                    panic!("Reading from files is not supported");
-                }
-                
-                let buf = self.get_fuzz_input();
+                let mut buf = self.get_fuzz_input(cpu.nbr_exec);
+                buf.truncate(len as usize);
     
                 println!("Pulled: {:X?} from fuzz queue", buf);
-                cpu.memory.write(ptr, &buf);
-                
-                cpu.registers.common[10] = buf.len() as u64;
+                cpu.registers.common[10] = process::copy_out(cpu, ptr, &buf)?;
             },
             // Write
             64 => {
                 println!("Write");
-                let fp = cpu.registers.common[10];
+                let fd = cpu.registers.common[10];
                 let ptr = cpu.registers.common[11];
-                let len = cpu.registers.common[12];
+                let len = cpu.registers.common[12].min(MAX_WRITE);
                 
-                if fp != SpecialFD::Stdout as u64{
-                    panic!("Writing to files is not supported");
+                //The files of the sysroot are opened read only
+                if fd != SpecialFD::Stdout as u64 && fd != SpecialFD::Stderr as u64{
+                    cpu.registers.common[10] = EBADF;
                 }
-                
-                if cpu.redirect_stdout{
+                else{
                     let mut buf = vec![0 as u8; len as usize];
-                    cpu.memory.read(ptr, &mut buf);
-                    
-                    let message = str::from_utf8(&buf).unwrap();
-                    println!("STDOUT: {}", message);
-                    
-                    // Returns the number of bytes written
-                    cpu.registers.common[10] = len;
+                    if cpu.memory.read(ptr, &mut buf).is_err(){
+                        cpu.registers.common[10] = EFAULT;
+                    }
+                    else{
+                        if cpu.redirect_stdout{
+                            //The program may write anything, not only text
+                            let message = String::from_utf8_lossy(&buf);
+                            if fd == SpecialFD::Stderr as u64{
+                                eprintln!("STDERR: {}", message);
+                            }
+                            else{
+                                println!("STDOUT: {}", message);
+                            }
+                        }
+                        
+                        // Returns the number of bytes written
+                        cpu.registers.common[10] = len;
+                    }
                 }
             },
+            // Exit, exit_group: the program is done, the run ends
+            93 | 94 => {
+                println!("Exit: {}", cpu.registers.common[10] as i32);
+                cpu.exited = true;
+            },
+            // Pread64
+            67 => {
+                println!("Pread64");
+                cpu.registers.common[10] = process::pread64(cpu)?;
+            },
+            // Newfstatat
+            79 => {
+                println!("Newfstatat");
+                cpu.registers.common[10] = process::newfstatat(cpu)?;
+            },
             // Fstat
             80 => {
                 println!("Fstate");
+                cpu.registers.common[10] = process::fstat(cpu)?;
             }
             // Brk
             214 => {
                 println!("Brk");
-            }
+                cpu.registers.common[10] = process::brk(cpu);
+            },
+            // Munmap
+            215 => {
+                println!("Munmap");
+                cpu.registers.common[10] = process::munmap(cpu);
+            },
+            // Mmap
+            222 => {
+                println!("Mmap");
+                cpu.registers.common[10] = process::mmap(cpu);
+            },
+            // Mprotect
+            226 => {
+                println!("Mprotect");
+                cpu.registers.common[10] = process::mprotect(cpu);
+            },
             _ => {
                 println!("Unknown syscall: {:?}", syscall_nbr);
+                cpu.registers.common[10] = ENOSYS;
             }
         }
         println!();
+        Ok(())
     }
 }
\ No newline at end of file
@@ -0,0 +1,329 @@
+// Allocator replacing the malloc family of the guest. Chunks are surrounded
+// by poisoned redzones and freed chunks stay poisoned in a quarantine, the
+// accesses out of a chunk or to a freed one fault on the spot
+
+use std::collections::{BTreeMap, VecDeque};
+
+use super::cpu::{Xlen, CPU};
+use super::memory::{MemoryError, Shadow, Violation};
+use super::trap::{CpuFault, Exception};
+
+/// Region holding the chunks
+pub const HEAP_BASE: u64 = 0x4000_0000;
+pub const HEAP_SIZE: u64 = 0x100_0000;
+
+/// Poisoned bytes on each side of a chunk
+const REDZONE: u64 = 32;
+const ALIGNMENT: u64 = 16;
+/// Freed bytes kept poisoned before their memory can be reused
+const QUARANTINE_SIZE: u64 = 0x10_0000;
+/// Frames kept in the backtraces of the chunks
+const BACKTRACE_DEPTH: usize = 16;
+
+/// Errors returned by posix_memalign, positive unlike the system calls
+const EINVAL: u64 = 22;
+const ENOMEM: u64 = 12;
+
+#[derive(Debug, Clone)]
+pub struct Chunk{
+    pub addr: u64,
+    pub size: u64,
+    /// Call sites of the allocation, the innermost first
+    pub allocated_at: Vec<u64>,
+    /// Call sites of the free, None while the chunk is live
+    pub freed_at: Option<Vec<u64>>,
+}
+
+impl Chunk{
+    /// Memory used by the chunk and its redzones
+    fn span(&self) -> (u64, u64){
+        (self.addr - REDZONE, span_size(self.size))
+    }
+}
+
+fn span_size(size: u64) -> u64{
+    REDZONE + (size + ALIGNMENT - 1) / ALIGNMENT * ALIGNMENT + REDZONE
+}
+
+#[derive(Debug, Clone)]
+pub struct Heap{
+    /// Start of the memory never handed out
+    next: u64,
+    /// Spans of the chunks evicted from the quarantine, they are reused
+    free: Vec<(u64, u64)>,
+    /// Live and quarantined chunks by address
+    chunks: BTreeMap<u64, Chunk>,
+    /// Quarantined chunks, the oldest first, and their total size
+    quarantine: VecDeque<u64>,
+    quarantined: u64,
+}
+
+impl Heap{
+    pub fn new() -> Heap{
+        Heap{
+            next: HEAP_BASE,
+            free: Vec::new(),
+            chunks: BTreeMap::new(),
+            quarantine: VecDeque::new(),
+            quarantined: 0,
+        }
+    }
+
+    /// Reserve a chunk of size bytes aligned on alignment, a power of two,
+    /// None when the heap is exhausted
+    fn allocate(&mut self, size: u64, alignment: u64, backtrace: Vec<u64>) -> Option<u64>{
+        //Sizes close to the end of the address space cannot be rounded, the
+        //span is moved up to the alignment
+        let alignment = alignment.max(ALIGNMENT);
+        let needed = size.checked_add(2 * REDZONE + ALIGNMENT)
+            .and_then(|_| span_size(size).checked_add(alignment - ALIGNMENT))?;
+
+        let start = match self.free.iter().position(|&(_, len)| len >= needed){
+            Some(i) => {
+                let (start, len) = self.free.swap_remove(i);
+                if len > needed{
+                    self.free.push((start + needed, len - needed));
+                }
+                start
+            },
+            None => {
+                if needed > HEAP_BASE + HEAP_SIZE - self.next{
+                    return None;
+                }
+                self.next += needed;
+                self.next - needed
+            },
+        };
+
+        //The bytes around the aligned span are given back
+        let addr = (start + REDZONE + alignment - 1) & !(alignment - 1);
+        let (span_start, span_end) = (addr - REDZONE, addr - REDZONE + span_size(size));
+        if span_start > start{
+            self.free.push((start, span_start - start));
+        }
+        if start + needed > span_end{
+            self.free.push((span_end, start + needed - span_end));
+        }
+
+        self.chunks.insert(addr, Chunk{ addr, size, allocated_at: backtrace, freed_at: None });
+        Some(addr)
+    }
+
+    /// Release the chunk at addr, returns its size. Evicted chunks are given
+    /// back to the allocator, their memory stays poisoned until reused. The
+    /// last freed chunk stays in the quarantine whatever its size
+    fn release(&mut self, addr: u64, backtrace: Vec<u64>) -> Result<u64, FreeError>{
+        let chunk = match self.chunks.get_mut(&addr){
+            Some(chunk) if chunk.freed_at.is_some() => return Err(FreeError::Double),
+            Some(chunk) => chunk,
+            None => return Err(FreeError::Invalid),
+        };
+        chunk.freed_at = Some(backtrace);
+        let size = chunk.size;
+
+        self.quarantine.push_back(addr);
+        self.quarantined += size;
+        while self.quarantined > QUARANTINE_SIZE && self.quarantine.len() > 1{
+            let evicted = match self.quarantine.pop_front().and_then(|addr| self.chunks.remove(&addr)){
+                Some(evicted) => evicted,
+                None => break,
+            };
+            self.quarantined -= evicted.size;
+            self.free.push(evicted.span());
+        }
+        Ok(size)
+    }
+
+    /// Chunk whose span holds addr, or the closest one. Only the live and
+    /// quarantined chunks are known
+    pub fn chunk_near(&self, addr: u64) -> Option<&Chunk>{
+        let before = self.chunks.range(..=addr).next_back().map(|(_, c)| c);
+        let after = self.chunks.range(addr..).next().map(|(_, c)| c);
+
+        match (before, after){
+            (Some(b), Some(a)) => {
+                if addr < b.addr + b.size || addr - (b.addr + b.size) <= a.addr - addr { Some(b) } else { Some(a) }
+            },
+            (b, a) => b.or(a),
+        }
+    }
+
+    /// Fault of an access made at pc to the poisoned byte error.addr, the
+    /// bytes of a freed chunk are used after free and any other is out of
+    /// bounds
+    pub fn access_fault(&self, pc: u64, error: MemoryError) -> CpuFault{
+        let addr = error.addr;
+        match self.chunk_near(addr){
+            Some(c) if c.freed_at.is_some() && addr >= c.addr && addr < c.addr + c.size =>
+                CpuFault::UseAfterFree{ pc, error },
+            _ => CpuFault::HeapOverflow{ pc, error },
+        }
+    }
+}
+
+impl Default for Heap{
+    fn default() -> Self{
+        Self::new()
+    }
+}
+
+enum FreeError{
+    Double,
+    Invalid,
+}
+
+/// Call sites of the calls in progress, the innermost first
+fn backtrace(cpu: &CPU) -> Vec<u64>{
+    cpu.call_stack.iter().rev().take(BACKTRACE_DEPTH).copied().collect()
+}
+
+/// Address of the call to the hooked function
+fn caller(cpu: &CPU) -> u64{
+    cpu.call_stack.last().copied().unwrap_or(cpu.registers.pc)
+}
+
+/// Allocate a chunk aligned on alignment, its bytes are uninitialized.
+/// Returns 0 when the heap is exhausted like the guest allocator would
+fn allocate(cpu: &mut CPU, size: u64, alignment: u64) -> u64{
+    let backtrace = backtrace(cpu);
+    let heap = cpu.heap.as_mut().expect("Heap hook called without heap");
+    match heap.allocate(size, alignment, backtrace){
+        Some(addr) => {
+            cpu.memory.set_shadow(addr, size, Shadow::Uninitialized);
+            addr
+        },
+        None => 0,
+    }
+}
+
+/// Free the chunk at addr, it is poisoned. Freeing a freed chunk or an
+/// address that is not a chunk is a crash
+fn release(cpu: &mut CPU, addr: u64) -> Result<(), CpuFault>{
+    let backtrace = backtrace(cpu);
+    let pc = caller(cpu);
+    let heap = cpu.heap.as_mut().expect("Heap hook called without heap");
+    match heap.release(addr, backtrace){
+        Ok(size) => {
+            cpu.memory.set_shadow(addr, size, Shadow::Poisoned);
+            Ok(())
+        },
+        Err(FreeError::Double) => Err(CpuFault::DoubleFree{ pc, tval: addr }),
+        Err(FreeError::Invalid) => Err(CpuFault::InvalidFree{ pc, tval: addr }),
+    }
+}
+
+/// malloc(size)
+pub fn malloc(cpu: &mut CPU) -> Result<(), CpuFault>{
+    let size = cpu.registers.common[10];
+    malloc_size(cpu, size)
+}
+
+fn malloc_size(cpu: &mut CPU, size: u64) -> Result<(), CpuFault>{
+    let addr = allocate(cpu, size, ALIGNMENT);
+    cpu.return_from_function(addr);
+    Ok(())
+}
+
+/// calloc(count, size), the chunk is zeroed
+pub fn calloc(cpu: &mut CPU) -> Result<(), CpuFault>{
+    let addr = match cpu.registers.common[10].checked_mul(cpu.registers.common[11]){
+        Some(size) => {
+            let addr = allocate(cpu, size, ALIGNMENT);
+            if addr != 0{
+                cpu.memory.write(addr, &vec![0; size as usize])
+                    .expect("Chunk not mapped");
+            }
+            addr
+        },
+        None => 0,
+    };
+    cpu.return_from_function(addr);
+    Ok(())
+}
+
+/// realloc(ptr, size), the content is moved to a new chunk so that the
+/// uses of the old pointer are caught
+pub fn realloc(cpu: &mut CPU) -> Result<(), CpuFault>{
+    let old = cpu.registers.common[10];
+    let size = cpu.registers.common[11];
+
+    if old == 0{
+        return malloc_size(cpu, size);
+    }
+    let old_size = match cpu.heap.as_ref().and_then(|heap| heap.chunks.get(&old)){
+        Some(chunk) if chunk.freed_at.is_none() => chunk.size,
+        //Fails like a free of the pointer
+        _ => return release(cpu, old),
+    };
+    if size == 0{
+        release(cpu, old)?;
+        cpu.return_from_function(0);
+        return Ok(());
+    }
+
+    let addr = allocate(cpu, size, ALIGNMENT);
+    if addr != 0{
+        let mut data = vec![0; old_size.min(size) as usize];
+        cpu.memory.read(old, &mut data).expect("Chunk not mapped");
+        cpu.memory.write(addr, &data).expect("Chunk not mapped");
+        release(cpu, old)?;
+    }
+    cpu.return_from_function(addr);
+    Ok(())
+}
+
+/// posix_memalign(memptr, alignment, size), the alignment is a power of
+/// two multiple of the pointer size
+pub fn posix_memalign(cpu: &mut CPU) -> Result<(), CpuFault>{
+    let (memptr, alignment, size) = (cpu.registers.common[10], cpu.registers.common[11], cpu.registers.common[12]);
+    let word = match cpu.xlen { Xlen::Rv32 => 4, Xlen::Rv64 => 8 };
+    if !alignment.is_power_of_two() || alignment < word{
+        cpu.return_from_function(EINVAL);
+        return Ok(());
+    }
+    let addr = allocate(cpu, size, alignment);
+    if addr == 0{
+        cpu.return_from_function(ENOMEM);
+        return Ok(());
+    }
+
+    //The pointer is stored for the caller, its faults are the caller's
+    if let Err(error) = cpu.memory.write(memptr, &addr.to_le_bytes()[..word as usize]){
+        let pc = caller(cpu);
+        return Err(match (error.violation, &cpu.heap){
+            (Violation::Poisoned, Some(heap)) => heap.access_fault(pc, error),
+            _ => CpuFault::new(Exception::MemoryFault(error), pc, error.addr),
+        });
+    }
+    cpu.return_from_function(0);
+    Ok(())
+}
+
+/// aligned_alloc(alignment, size), the alignment is a power of two
+pub fn aligned_alloc(cpu: &mut CPU) -> Result<(), CpuFault>{
+    let (alignment, size) = (cpu.registers.common[10], cpu.registers.common[11]);
+    let addr = if alignment.is_power_of_two() { allocate(cpu, size, alignment) } else { 0 };
+    cpu.return_from_function(addr);
+    Ok(())
+}
+
+/// memalign(alignment, size), the alignment is rounded up to a power of two
+pub fn memalign(cpu: &mut CPU) -> Result<(), CpuFault>{
+    let (alignment, size) = (cpu.registers.common[10], cpu.registers.common[11]);
+    let addr = match alignment.checked_next_power_of_two(){
+        Some(alignment) => allocate(cpu, size, alignment),
+        None => 0,
+    };
+    cpu.return_from_function(addr);
+    Ok(())
+}
+
+/// free(ptr)
+pub fn free(cpu: &mut CPU) -> Result<(), CpuFault>{
+    let addr = cpu.registers.common[10];
+    if addr != 0{
+        release(cpu, addr)?;
+    }
+    cpu.return_from_function(0);
+    Ok(())
+}
@@ -126,4 +126,244 @@ impl From<u32> for SType{
             opcode: (instruction  & 0b111_1111) as u8,
         }
     }
-}
\ No newline at end of file
+}
+
+#[derive(Debug)]
+pub struct R4Type{
+    pub rs3: usize,
+    pub fmt: u8,
+    pub rs2: usize,
+    pub rs1: usize,
+    pub funct3: u8,
+    pub rd: usize,
+    pub opcode: u8
+}
+
+impl From<u32> for R4Type{
+    fn from(instruction:u32) -> Self{
+        R4Type{
+            rs3:    (instruction >> 27) as usize,
+            fmt:    ((instruction >> 25) & 0b11) as u8,
+            rs2:    ((instruction >> 20) & 0b11111) as usize,
+            rs1:    ((instruction >> 15) & 0b11111) as usize,
+            funct3: ((instruction >> 12) & 0b111) as u8,
+            rd:     ((instruction >> 7) & 0b11111) as usize,
+            opcode: (instruction & 0b111_1111) as u8
+        }
+    }
+}
+
+
+#[derive(Debug)]
+pub struct CsrType{
+    pub csr: u16,
+    //rs1 for the register variants, the zero extended immediate otherwise
+    pub rs1: usize,
+    pub funct3: u8,
+    pub rd: usize,
+    pub opcode: u8
+}
+
+impl From<u32> for CsrType{
+    fn from(instruction:u32) -> Self{
+        CsrType{
+            csr:    (instruction >> 20) as u16,
+            rs1:    ((instruction >> 15) & 0b11111) as usize,
+            funct3: ((instruction >> 12) & 0b111) as u8,
+            rd:     ((instruction >> 7) & 0b11111) as usize,
+            opcode: (instruction & 0b111_1111) as u8
+        }
+    }
+}
+
+
+// Compressed instruction formats (RVC), the immediates of most formats are
+// scrambled differently for each instruction so only the raw bits are kept
+// here, registers noted rd'/rs1'/rs2' are already translated to x8-x15
+
+#[derive(Debug)]
+pub struct CRType{
+    pub funct4: u8,
+    pub rd_rs1: usize,
+    pub rs2: usize,
+    pub opcode: u8
+}
+
+impl From<u16> for CRType{
+    fn from(instruction: u16) -> Self{
+        CRType{
+            funct4: (instruction >> 12) as u8,
+            rd_rs1: ((instruction >> 7) & 0b1_1111) as usize,
+            rs2:    ((instruction >> 2) & 0b1_1111) as usize,
+            opcode: (instruction & 0b11) as u8
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CIType{
+    //imm[5] | imm[4:0] as laid out in the instruction
+    pub imm: u32,
+    pub rd_rs1: usize,
+    pub funct3: u8,
+    pub opcode: u8
+}
+
+impl From<u16> for CIType{
+    fn from(instruction: u16) -> Self{
+        CIType{
+            imm: (((instruction >> 12) & 0b1) << 5 |
+                ((instruction >> 2) & 0b1_1111)) as u32,
+            rd_rs1: ((instruction >> 7) & 0b1_1111) as usize,
+            funct3: (instruction >> 13) as u8,
+            opcode: (instruction & 0b11) as u8
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CSSType{
+    //Bits 12 to 7 of the instruction
+    pub imm: u32,
+    pub rs2: usize,
+    pub funct3: u8,
+    pub opcode: u8
+}
+
+impl From<u16> for CSSType{
+    fn from(instruction: u16) -> Self{
+        CSSType{
+            imm:    ((instruction >> 7) & 0b11_1111) as u32,
+            rs2:    ((instruction >> 2) & 0b1_1111) as usize,
+            funct3: (instruction >> 13) as u8,
+            opcode: (instruction & 0b11) as u8
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CIWType{
+    //Bits 12 to 5 of the instruction
+    pub imm: u32,
+    pub rd: usize,
+    pub funct3: u8,
+    pub opcode: u8
+}
+
+impl From<u16> for CIWType{
+    fn from(instruction: u16) -> Self{
+        CIWType{
+            imm:    ((instruction >> 5) & 0b1111_1111) as u32,
+            rd:     (((instruction >> 2) & 0b111) + 8) as usize,
+            funct3: (instruction >> 13) as u8,
+            opcode: (instruction & 0b11) as u8
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CLType{
+    //Bits 12 to 10 and bits 6 to 5 of the instruction
+    pub imm_hi: u32,
+    pub imm_lo: u32,
+    pub rs1: usize,
+    pub rd: usize,
+    pub funct3: u8,
+    pub opcode: u8
+}
+
+impl From<u16> for CLType{
+    fn from(instruction: u16) -> Self{
+        CLType{
+            imm_hi: ((instruction >> 10) & 0b111) as u32,
+            imm_lo: ((instruction >> 5) & 0b11) as u32,
+            rs1:    (((instruction >> 7) & 0b111) + 8) as usize,
+            rd:     (((instruction >> 2) & 0b111) + 8) as usize,
+            funct3: (instruction >> 13) as u8,
+            opcode: (instruction & 0b11) as u8
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CSType{
+    //Bits 12 to 10 and bits 6 to 5 of the instruction
+    pub imm_hi: u32,
+    pub imm_lo: u32,
+    pub rs1: usize,
+    pub rs2: usize,
+    pub funct3: u8,
+    pub opcode: u8
+}
+
+impl From<u16> for CSType{
+    fn from(instruction: u16) -> Self{
+        CSType{
+            imm_hi: ((instruction >> 10) & 0b111) as u32,
+            imm_lo: ((instruction >> 5) & 0b11) as u32,
+            rs1:    (((instruction >> 7) & 0b111) + 8) as usize,
+            rs2:    (((instruction >> 2) & 0b111) + 8) as usize,
+            funct3: (instruction >> 13) as u8,
+            opcode: (instruction & 0b11) as u8
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CBType{
+    //Branch offset of C.BEQZ / C.BNEZ
+    pub offset: i32,
+    //imm[5] | imm[4:0] sign extended, used by C.SRLI / C.SRAI / C.ANDI
+    pub imm: i32,
+    pub funct2: u8,
+    pub rs1: usize,
+    pub funct3: u8,
+    pub opcode: u8
+}
+
+impl From<u16> for CBType{
+    fn from(instruction: u16) -> Self{
+        let instruction = instruction as u32;
+        CBType{
+            offset: (((instruction >> 3) & 0b11) << 1 |
+                ((instruction >> 10) & 0b11) << 3 |
+                ((instruction >> 2) & 0b1) << 5 |
+                ((instruction >> 5) & 0b11) << 6) as i32 |
+                //Sign extended
+                ((((instruction << 19) as i32) >> 31) << 8),
+            imm: (((instruction >> 2) & 0b1_1111) as i32) |
+                //Sign extended
+                ((((instruction << 19) as i32) >> 31) << 5),
+            funct2: ((instruction >> 10) & 0b11) as u8,
+            rs1:    (((instruction >> 7) & 0b111) + 8) as usize,
+            funct3: (instruction >> 13) as u8,
+            opcode: (instruction & 0b11) as u8
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CJType{
+    pub offset: i32,
+    pub funct3: u8,
+    pub opcode: u8
+}
+
+impl From<u16> for CJType{
+    fn from(instruction: u16) -> Self{
+        let instruction = instruction as u32;
+        CJType{
+            offset: (((instruction >> 3) & 0b111) << 1 |
+                ((instruction >> 11) & 0b1) << 4 |
+                ((instruction >> 2) & 0b1) << 5 |
+                ((instruction >> 7) & 0b1) << 6 |
+                ((instruction >> 6) & 0b1) << 7 |
+                ((instruction >> 9) & 0b11) << 8 |
+                ((instruction >> 8) & 0b1) << 10) as i32 |
+                //Sign extended
+                ((((instruction << 19) as i32) >> 31) << 11),
+            funct3: (instruction >> 13) as u8,
+            opcode: (instruction & 0b11) as u8
+        }
+    }
+}
@@ -0,0 +1,129 @@
+// Optional extensions of the hart, they can be selected with an ISA string
+// like rv64gc_zba_zbb_zve64x_zvl256b. IMAFD, Zicsr and Zifencei are always
+// available. The vector unit has no floating point instructions so it is
+// only advertised as Zve64x, misa.V stays clear
+
+use super::cpu::Xlen;
+use super::csr::misa_bit;
+
+/// VLEN when the ISA string does not give one
+pub const VLEN_DEFAULT: usize = 128;
+pub const VLEN_MAX: usize = 65536;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extensions{
+    /// Compressed instructions, without them the targets of the jumps must
+    /// be aligned on 4 bytes
+    pub c: bool,
+    /// Address generation
+    pub zba: bool,
+    /// Basic bit manipulation
+    pub zbb: bool,
+    /// Carry-less multiplication
+    pub zbc: bool,
+    /// Single bit instructions
+    pub zbs: bool,
+    /// Integer vector instructions (Zve64x)
+    pub v: bool,
+    /// Width of the vector registers in bits, a power of two
+    pub vlen: usize,
+}
+
+impl Extensions{
+    /// Every optional extension enabled
+    pub fn all() -> Extensions{
+        Extensions{ c: true, zba: true, zbb: true, zbc: true, zbs: true, v: true, vlen: VLEN_DEFAULT }
+    }
+
+    /// Every optional extension but the vector unit, which has to be asked
+    /// for with Zve64x
+    pub fn standard() -> Extensions{
+        Extensions{ v: false, ..Extensions::all() }
+    }
+
+    pub fn none() -> Extensions{
+        Extensions{ c: false, zba: false, zbb: false, zbc: false, zbs: false, v: false, vlen: VLEN_DEFAULT }
+    }
+
+    /// Parse an ISA string, the extensions not listed are disabled and the
+    /// unknown ones ignored. Zve64x and Zve32x enable the vector unit and
+    /// Zvl<N>b sets VLEN. Returns None if it does not start with rv32 or
+    /// rv64, if VLEN is invalid or if it asks for V, which needs the vector
+    /// floating point instructions that are missing
+    pub fn from_isa_string(isa: &str) -> Option<Extensions>{
+        let isa = isa.to_ascii_lowercase();
+        let rest = isa.strip_prefix("rv32").or_else(|| isa.strip_prefix("rv64"))?;
+
+        let mut extensions = Extensions::none();
+        let mut parts = rest.split('_');
+
+        //Single letter extensions come first, B is Zba, Zbb and Zbs
+        let letters = parts.next()?;
+        if letters.contains('v'){
+            return None;
+        }
+        if letters.contains('b'){
+            extensions.zba = true;
+            extensions.zbb = true;
+            extensions.zbs = true;
+        }
+        extensions.c = letters.contains('c');
+
+        for name in parts{
+            match name{
+                "zba" => extensions.zba = true,
+                "zbb" => extensions.zbb = true,
+                "zbc" => extensions.zbc = true,
+                "zbs" => extensions.zbs = true,
+                "zve64x" | "zve32x" => extensions.v = true,
+                //The largest minimum VLEN given wins
+                _ if name.starts_with("zvl") && name.ends_with('b') => {
+                    let vlen: usize = name[3..name.len() - 1].parse().ok()?;
+                    if !vlen.is_power_of_two() || vlen > VLEN_MAX{
+                        return None;
+                    }
+                    extensions.vlen = extensions.vlen.max(vlen);
+                },
+                _ => {},
+            }
+        }
+        Some(extensions)
+    }
+
+    /// ISA string of the hart, as given to the kernel in the device tree
+    pub fn isa_string(&self, xlen: Xlen) -> String{
+        let mut isa = String::from(match xlen{
+            Xlen::Rv32 => "rv32imafd",
+            Xlen::Rv64 => "rv64imafd",
+        });
+        if self.c{
+            isa.push('c');
+        }
+        isa.push_str("_zicsr_zifencei");
+
+        let optional = [("zba", self.zba), ("zbb", self.zbb), ("zbc", self.zbc), ("zbs", self.zbs)];
+        for &(name, enabled) in optional.iter(){
+            if enabled{
+                isa.push('_');
+                isa.push_str(name);
+            }
+        }
+        if self.v{
+            isa.push_str(&format!("_zve64x_zvl{}b", self.vlen));
+        }
+        isa
+    }
+
+    /// Extension bits of misa that depend on the optional extensions
+    pub fn misa_bits(&self) -> u64{
+        let c = if self.c { misa_bit(b'C') } else { 0 };
+        let b = if self.zba && self.zbb && self.zbs { misa_bit(b'B') } else { 0 };
+        c | b
+    }
+}
+
+impl Default for Extensions{
+    fn default() -> Self{
+        Extensions::standard()
+    }
+}
@@ -1,15 +1,112 @@
 use std::fmt;
+use std::rc::Rc;
+use std::cell::RefCell;
 
-pub const STACK_SIZE: usize = 0x1000;
+use super::device::{Device, MappedDevice};
+use super::mmu::PAGE_SIZE;
+use super::plic::Plic;
+
+/// Default stack of the user space programs: its end, the bytes mapped at
+/// start and the most it grows to. Its top is the end of the user space
+/// of RV32 like on Linux
+pub const STACK_TOP: u64 = 0x7FFF_F000;
+pub const STACK_SIZE: u64 = 0x2_0000;
+pub const STACK_LIMIT: u64 = 0x80_0000;
 
 // No idea of what would be a good value 
 pub const BITMAP_SIZE: u64 = 0x10;
 
+// Size of the block covered by a LR reservation, any store touching it
+// invalidates the reservation
+pub const RESERVATION_SIZE: u64 = 0x8;
+
+/// Direction of a memory access
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind{
+    Read,
+    Write,
+    Execute,
+}
+
+/// Reason of a failed memory access
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation{
+    /// Mapped by no region nor device, or running past the end of the
+    /// region holding its first byte
+    Unmapped,
+    /// Not allowed by the permissions of the region
+    Denied,
+    /// Touching a poisoned byte
+    Poisoned,
+    /// Touching the guard page below the growth limit of the stack
+    StackOverflow,
+}
+
+/// Failed memory access, addr is the first poisoned byte for Poisoned and
+/// the start of the access otherwise
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryError{
+    pub addr: u64,
+    pub size: usize,
+    pub kind: AccessKind,
+    pub violation: Violation,
+}
+
+/// State of a byte of the memory tracked by a shadow
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shadow{
+    /// Never written since its allocation
+    Uninitialized,
+    Initialized,
+    /// Must not be accessed at all, like the redzones around heap chunks
+    Poisoned,
+}
+
+/// Accesses allowed on a memory region
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Permissions{
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl Permissions{
+    pub const RW: Permissions = Permissions{ read: true, write: true, execute: false };
+    pub const RWX: Permissions = Permissions{ read: true, write: true, execute: true };
+
+    pub fn allows(&self, kind: AccessKind) -> bool{
+        match kind{
+            AccessKind::Read => self.read,
+            AccessKind::Write => self.write,
+            AccessKind::Execute => self.execute,
+        }
+    }
+}
+
+/// Stack mapped through map_stack, it spans size bytes below top. The
+/// accesses below it make it grow down to top - limit, the page under that
+/// is a guard
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stack{
+    pub top: u64,
+    pub size: u64,
+    pub limit: u64,
+}
+
+impl Default for Stack{
+    fn default() -> Self{
+        Stack{ top: STACK_TOP, size: STACK_SIZE, limit: STACK_LIMIT }
+    }
+}
+
 #[derive(Debug, Clone)]
 struct MemoryRegion{
     data: Vec<u8>,
     virt_addr: u64,
     size: u64,
+    permissions: Permissions,
+    /// State of each byte, None when the whole region is initialized
+    shadow: Option<Vec<Shadow>>,
 
     dirty_bitmap: Vec<u8>,
 }
@@ -17,10 +114,18 @@ struct MemoryRegion{
 //Hold the memory
 #[derive(Clone)]
 pub struct Memory {
-    stack: [u8; STACK_SIZE],
     allocated: Vec<MemoryRegion>,
+    /// Bounds of the stack, its bytes are in the regions
+    stack: Option<Stack>,
+
+    /// Memory mapped devices, reached when no region holds the address
+    devices: Vec<MappedDevice>,
+
+    /// Reservation set of the hart registered by LR and consumed by SC
+    reservation: Option<u64>,
 
     saved_state: Option<Vec<MemoryRegion>>,
+    saved_stack: Option<Stack>,
 }
 
 //Manage memory
@@ -28,72 +133,402 @@ impl Memory{
     //Return a new memory with all null data
     pub fn new() -> Memory {
         Memory {
-            stack: [0; STACK_SIZE],
             allocated: Vec::new(),
+            stack: None,
+            devices: Vec::new(),
+            reservation: None,
             saved_state: None,
+            saved_stack: None,
         }
     }
     
-    pub fn read(&self, at: u64, buf: &mut [u8]) {
-        if at < STACK_SIZE as u64{
-            buf.copy_from_slice(&self.stack[at as usize .. at as usize + buf.len()]);
+    /// Returns true if the len bytes at at are backed by memory
+    pub fn is_mapped(&self, at: u64, len: u64) -> bool{
+        let end = match at.checked_add(len){
+            Some(end) => end,
+            None => return false,
+        };
+
+        //The bytes can span adjacent regions
+        let mut addr = at;
+        while let Some(m) = self.region(addr){
+            addr = m.virt_addr + m.size;
+            if addr >= end{
+                return true;
+            }
+        }
+        self.device(at, len).is_some()
+    }
+
+    /// Region holding at
+    fn region(&self, at: u64) -> Option<&MemoryRegion>{
+        self.allocated.iter().find(|m| at >= m.virt_addr && at - m.virt_addr < m.size)
+    }
+
+    /// Check that the len bytes at at are mapped, allow the access and are
+    /// not poisoned. The devices are readable and writable
+    pub fn check(&self, at: u64, len: u64, kind: AccessKind) -> Result<(), MemoryError>{
+        let error = |addr, violation| MemoryError{ addr, size: len as usize, kind, violation };
+        let end = at.checked_add(len).ok_or(error(at, Violation::Unmapped))?;
+
+        //The access goes on in the adjacent regions, like the pages added
+        //by two calls to brk. The errors are at at, but for the poisoned
+        //byte found
+        let mut addr = at;
+        loop{
+            let m = match self.region(addr){
+                Some(m) => m,
+                None if addr == at && self.device(at, len).is_some() => {
+                    return if Permissions::RW.allows(kind) { Ok(()) } else { Err(error(at, Violation::Denied)) };
+                },
+                None if self.in_stack_guard(addr) => return Err(error(at, Violation::StackOverflow)),
+                None => return Err(error(at, Violation::Unmapped)),
+            };
+
+            if !m.permissions.allows(kind){
+                return Err(error(at, Violation::Denied));
+            }
+            let stop = end.min(m.virt_addr + m.size);
+            if let Some(shadow) = &m.shadow{
+                let shadow = &shadow[(addr - m.virt_addr) as usize..(stop - m.virt_addr) as usize];
+                if let Some(i) = shadow.iter().position(|s| *s == Shadow::Poisoned){
+                    return Err(error(addr + i as u64, Violation::Poisoned));
+                }
+            }
+
+            if stop == end{
+                return Ok(());
+            }
+            addr = stop;
+        }
+    }
+
+    /// Like check, the stack first grows down to the accesses below it
+    pub fn check_and_grow(&mut self, at: u64, len: u64, kind: AccessKind) -> Result<(), MemoryError>{
+        match self.check(at, len, kind){
+            Err(e) if e.violation == Violation::Unmapped => match self.grow_stack(at){
+                Ok(true) => self.check(at, len, kind),
+                Ok(false) => Err(e),
+                Err(violation) => Err(MemoryError{ violation, ..e }),
+            },
+            result => result,
+        }
+    }
+
+    /// Map the stack, its bytes are uninitialized. False when its limit is
+    /// smaller than its size or leaves no room for the guard page
+    pub fn map_stack(&mut self, stack: Stack) -> bool{
+        let aligned = [stack.top, stack.size, stack.limit].iter().all(|v| v & (PAGE_SIZE - 1) == 0);
+        if !aligned || stack.size == 0 || stack.size > stack.limit || stack.limit > stack.top.saturating_sub(PAGE_SIZE){
+            return false;
+        }
+        self.allocate_uninitialized(stack.top - stack.size, stack.size, Permissions::RW);
+        self.stack = Some(stack);
+        true
+    }
+
+    /// Extend the stack down to the page holding at when at is between its
+    /// bottom and its limit. Returns true if it grew, and a stack overflow
+    /// when a mapping below the stack is in the way, the stack stops there
+    fn grow_stack(&mut self, at: u64) -> Result<bool, Violation>{
+        let stack = match self.stack{
+            Some(stack) => stack,
+            None => return Ok(false),
+        };
+        let bottom = stack.top - stack.size;
+        if at >= bottom || at < stack.top - stack.limit{
+            return Ok(false);
+        }
+
+        let new_bottom = at & !(PAGE_SIZE - 1);
+        let in_the_way = self.allocated.iter().any(|m| m.virt_addr < bottom && m.virt_addr + m.size > new_bottom)
+            || self.devices.iter().any(|d| d.base < bottom && d.base + d.size > new_bottom);
+        if in_the_way{
+            return Err(Violation::StackOverflow);
+        }
+
+        self.stack = Some(Stack{ size: stack.top - new_bottom, ..stack });
+        self.allocate_uninitialized(new_bottom, bottom - new_bottom, Permissions::RW);
+        Ok(true)
+    }
+
+    fn in_stack_guard(&self, at: u64) -> bool{
+        match self.stack{
+            Some(stack) => {
+                let limit = stack.top - stack.limit;
+                at < limit && at >= limit - PAGE_SIZE
+            },
+            None => false,
+        }
+    }
+
+    /// Map a device at base, irq is the PLIC source of its interrupt line
+    pub fn attach(&mut self, base: u64, size: u64, irq: Option<u32>, device: Rc<RefCell<dyn Device>>){
+        self.devices.push(MappedDevice{ base, size, irq, device });
+    }
+
+    fn device(&self, at: u64, len: u64) -> Option<&MappedDevice>{
+        self.devices.iter().find(|d| d.contains(at, len))
+    }
+
+    /// Let the devices do their pending work, they are detached from the bus
+    /// meanwhile so their DMA can only reach the memory regions
+    pub fn poll_devices(&mut self){
+        let devices = std::mem::take(&mut self.devices);
+        for d in &devices{
+            d.device.borrow_mut().process(self);
+        }
+        self.devices = devices;
+    }
+
+    /// Forward the interrupt lines of the devices to the PLIC
+    pub fn update_irqs(&self, plic: &mut Plic){
+        for d in &self.devices{
+            if let Some(irq) = d.irq{
+                plic.set_level(irq, d.device.borrow().interrupt_pending());
+            }
+        }
+    }
+
+    pub fn read(&self, at: u64, buf: &mut [u8]) -> Result<(), MemoryError>{
+        self.check(at, buf.len() as u64, AccessKind::Read)?;
+        self.copy_out(at, buf);
+        Ok(())
+    }
+
+    /// Read instructions, the memory must be executable
+    pub fn fetch(&self, at: u64, buf: &mut [u8]) -> Result<(), MemoryError>{
+        self.check(at, buf.len() as u64, AccessKind::Execute)?;
+        self.copy_out(at, buf);
+        Ok(())
+    }
+
+    /// Copy memory already checked to buf, it can span adjacent regions
+    fn copy_out(&self, at: u64, buf: &mut [u8]){
+        if self.region(at).is_none(){
+            if let Some(d) = self.device(at, buf.len() as u64){
+                d.device.borrow_mut().read(at - d.base, buf);
+            }
             return
         }
 
+        let end = at + buf.len() as u64;
+        let mut copied = 0;
         for m in &self.allocated{
-            if at >= m.virt_addr && at < (m.virt_addr + m.size){
-                buf.copy_from_slice(&m.data[(at - m.virt_addr) as usize..(at - m.virt_addr) as usize + buf.len()]);
-                return
+            let (start, stop) = (at.max(m.virt_addr), end.min(m.virt_addr + m.size));
+            if start < stop{
+                buf[(start - at) as usize..(stop - at) as usize]
+                    .copy_from_slice(&m.data[(start - m.virt_addr) as usize..(stop - m.virt_addr) as usize]);
+                copied += stop - start;
+                if copied == buf.len() as u64{
+                    return
+                }
             }
         }
     }
 
-    pub fn write(&mut self, at: u64, buf: &[u8]){
-        if at < STACK_SIZE as u64{
-            self.stack[at as usize.. at as usize + buf.len()].copy_from_slice(buf);
-            return
+    pub fn write(&mut self, at: u64, buf: &[u8]) -> Result<(), MemoryError>{
+        self.check_and_grow(at, buf.len() as u64, AccessKind::Write)?;
+
+        //A store overlapping the reserved block breaks the LR/SC sequence
+        if let Some(reserved) = self.reservation{
+            if at < reserved + RESERVATION_SIZE && at + buf.len() as u64 > reserved{
+                self.reservation = None;
+            }
         }
 
+        if self.region(at).is_none(){
+            if let Some(d) = self.device(at, buf.len() as u64){
+                d.device.borrow_mut().write(at - d.base, buf);
+            }
+            return Ok(());
+        }
+
+        //The store can span adjacent regions
+        let end = at + buf.len() as u64;
+        let mut written = 0;
         for m in &mut self.allocated{
-            if at >= m.virt_addr && at < (m.virt_addr + m.size){
-                /*println!("at:{:08X} virt_addr:{:08X} end_section{:08X}, section_buff_size:{:08X} buf len {:08X}",
-                    at,
-                    m.virt_addr,
-                    (at - m.virt_addr) as usize + buf.len(),
-                    m.data.len(),
-                    buf.len()
-                );*/
-                let relative_addr = (at - m.virt_addr) as usize;
-                m.data[relative_addr..relative_addr + buf.len()].copy_from_slice(buf);
-                
-                //Set first byte to 1 when data has been changed
-                m.dirty_bitmap[relative_addr / BITMAP_SIZE as usize] = 0x1;
+            let (start, stop) = (at.max(m.virt_addr), end.min(m.virt_addr + m.size));
+            if start >= stop{
+                continue;
+            }
+            let (from, to) = ((start - m.virt_addr) as usize, (stop - m.virt_addr) as usize);
+            m.data[from..to].copy_from_slice(&buf[(start - at) as usize..(stop - at) as usize]);
+            if let Some(shadow) = &mut m.shadow{
+                shadow[from..to].fill(Shadow::Initialized);
+            }
+
+            //Set to 1 the chunks holding the changed bytes
+            let chunks = from / BITMAP_SIZE as usize..=(to - 1) / BITMAP_SIZE as usize;
+            for dirty in &mut m.dirty_bitmap[chunks]{
+                *dirty = 0x1;
+            }
+
+            written += stop - start;
+            if written == buf.len() as u64{
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the address of the first of the len bytes at at that was
+    /// never written, memory without shadow is always initialized
+    pub fn uninitialized(&self, at: u64, len: u64) -> Option<u64>{
+        let end = at.checked_add(len)?;
+        let mut addr = at;
+        while addr < end{
+            let m = self.region(addr)?;
+            let stop = end.min(m.virt_addr + m.size);
+            if let Some(shadow) = &m.shadow{
+                let shadow = &shadow[(addr - m.virt_addr) as usize..(stop - m.virt_addr) as usize];
+                if let Some(i) = shadow.iter().position(|s| *s == Shadow::Uninitialized){
+                    return Some(addr + i as u64);
+                }
+            }
+            addr = stop;
+        }
+        None
+    }
+
+    /// Set the state of the len bytes at at, they must be in a region
+    /// allocated through allocate_uninitialized. Returns false otherwise
+    pub fn set_shadow(&mut self, at: u64, len: u64, state: Shadow) -> bool{
+        let end = match at.checked_add(len){
+            Some(end) => end,
+            None => return false,
+        };
+        let m = match self.allocated.iter_mut().find(|m| at >= m.virt_addr && end <= m.virt_addr + m.size){
+            Some(m) => m,
+            None => return false,
+        };
+        let start = (at - m.virt_addr) as usize;
+        match &mut m.shadow{
+            Some(shadow) => shadow[start..start + len as usize].fill(state),
+            None => return false,
+        }
 
-                return;
+        //The shadow is restored with the data of the dirty chunks
+        if len != 0{
+            let chunks = start / BITMAP_SIZE as usize..=(start + len as usize - 1) / BITMAP_SIZE as usize;
+            for dirty in &mut m.dirty_bitmap[chunks]{
+                *dirty = 0x1;
             }
         }
-        panic!("Try to get memory unmapped at: {:#8X}", at);
+        true
+    }
+
+    /// Register a reservation on the block containing at (LR)
+    pub fn reserve(&mut self, at: u64){
+        self.reservation = Some(at & !(RESERVATION_SIZE - 1));
+    }
+
+    /// Drop the reservation, a trap between LR and SC makes SC fail
+    pub fn clear_reservation(&mut self){
+        self.reservation = None;
+    }
+
+    /// Returns true if the reservation on at is still valid, the reservation
+    /// is always invalidated afterwards (SC)
+    pub fn check_reservation(&mut self, at: u64) -> bool{
+        let reservation = self.reservation.take();
+        reservation == Some(at & !(RESERVATION_SIZE - 1))
     }
 
-    pub fn allocate(&mut self, at: u64, size: u64, data: &[u8]){
+    /// Map size bytes at at holding data, the bytes past its end are zeros
+    pub fn allocate(&mut self, at: u64, size: u64, data: &[u8], permissions: Permissions){
+        let mut data = data.to_vec();
+        data.resize(size as usize, 0);
+
         self.allocated.push(
             MemoryRegion{
-                data: data.to_vec(),
+                data,
                 virt_addr: at,
                 size: size,
+                permissions,
+                shadow: None,
 
-                dirty_bitmap: vec![0; (size  / BITMAP_SIZE) as usize],
+                dirty_bitmap: vec![0; ((size + BITMAP_SIZE - 1) / BITMAP_SIZE) as usize],
             }
         )
     }
+
+    /// Map size bytes of fresh memory at at, loading them before they are
+    /// written is reported through uninitialized
+    pub fn allocate_uninitialized(&mut self, at: u64, size: u64, permissions: Permissions){
+        self.allocate(at, size, &[], permissions);
+        if let Some(m) = self.allocated.last_mut(){
+            m.shadow = Some(vec![Shadow::Uninitialized; size as usize]);
+        }
+    }
+    
+    /// Cut the region holding at in two so that a region starts at at
+    fn split(&mut self, at: u64){
+        let i = match self.allocated.iter().position(|m| at > m.virt_addr && at - m.virt_addr < m.size){
+            Some(i) => i,
+            None => return,
+        };
+        let m = &mut self.allocated[i];
+        let offset = at - m.virt_addr;
+
+        let tail = MemoryRegion{
+            data: m.data.split_off(offset as usize),
+            virt_addr: at,
+            size: m.size - offset,
+            permissions: m.permissions,
+            shadow: m.shadow.as_mut().map(|shadow| shadow.split_off(offset as usize)),
+            dirty_bitmap: vec![0; ((m.size - offset + BITMAP_SIZE - 1) / BITMAP_SIZE) as usize],
+        };
+        m.size = offset;
+        m.dirty_bitmap = vec![0; ((offset + BITMAP_SIZE - 1) / BITMAP_SIZE) as usize];
+        self.allocated.insert(i + 1, tail);
+    }
+
+    /// Remove the len bytes at at from the regions, the regions partly in
+    /// the range keep their other bytes
+    pub fn unmap(&mut self, at: u64, len: u64){
+        let end = at.saturating_add(len);
+        self.split(at);
+        self.split(end);
+        self.allocated.retain(|m| m.virt_addr + m.size <= at || m.virt_addr >= end);
+    }
+
+    /// Change the permissions of the len bytes at at, false when some of
+    /// them are not in a region
+    pub fn protect(&mut self, at: u64, len: u64, permissions: Permissions) -> bool{
+        let end = match at.checked_add(len){
+            Some(end) => end,
+            None => return false,
+        };
+        let mapped: u64 = self.allocated.iter()
+            .map(|m| end.min(m.virt_addr + m.size).saturating_sub(at.max(m.virt_addr)))
+            .sum();
+        if mapped != len{
+            return false;
+        }
+
+        self.split(at);
+        self.split(end);
+        for m in &mut self.allocated{
+            if m.virt_addr >= at && m.virt_addr < end{
+                m.permissions = permissions;
+            }
+        }
+        true
+    }
     
     pub fn save_state(&mut self){
         // Clone the current memory state
         self.saved_state = Some(self.allocated.clone());
+        self.saved_stack = self.stack;
 
         //Reset the dirty bytes bitmap
         for m in &mut self.allocated{
-            m.dirty_bitmap = vec![0; (m.size / BITMAP_SIZE) as usize];
+            m.dirty_bitmap = vec![0; ((m.size + BITMAP_SIZE - 1) / BITMAP_SIZE) as usize];
+        }
+
+        for d in &self.devices{
+            d.device.borrow_mut().save_state();
         }
     }
 
@@ -101,6 +536,29 @@ impl Memory{
         let saved_state = self.saved_state.as_mut()
             .expect("Trying to reset but no initial state has been saved");
 
+        self.reservation = None;
+        //The stack is back to its size at the snapshot, the regions it grew
+        //by are removed with the layout below
+        self.stack = self.saved_stack;
+
+        for d in &self.devices{
+            d.device.borrow_mut().reset_to_saved_state();
+        }
+
+        //Regions were mapped, unmapped or protected since the snapshot, they
+        //are all restored
+        let same_layout = self.allocated.len() == saved_state.len() &&
+            self.allocated.iter().zip(saved_state.iter()).all(|(m, saved)|
+                m.virt_addr == saved.virt_addr && m.size == saved.size && m.permissions == saved.permissions);
+        if !same_layout{
+            self.allocated = saved_state.clone();
+            for m in &mut self.allocated{
+                m.dirty_bitmap = vec![0; ((m.size + BITMAP_SIZE - 1) / BITMAP_SIZE) as usize];
+            }
+            println!("Mappings changed, restored {} memory regions", self.allocated.len());
+            return;
+        }
+
         let mut i: usize = 0;
         let mut nb_chunks = 0;
         let mut nb_chunks_reseted = 0;
@@ -111,9 +569,12 @@ impl Memory{
             for dirty in &m.dirty_bitmap{
                 if *dirty == 1{
                     let begin_block = j * BITMAP_SIZE as usize;
-                    let end_block = ((j + 1) * BITMAP_SIZE as usize) - 1;
+                    let end_block = ((j + 1) * BITMAP_SIZE as usize).min(m.data.len());
 
                     m.data[begin_block..end_block].copy_from_slice(&saved_state[i].data[begin_block..end_block]);
+                    if let (Some(shadow), Some(saved)) = (&mut m.shadow, &saved_state[i].shadow){
+                        shadow[begin_block..end_block].copy_from_slice(&saved[begin_block..end_block]);
+                    }
                     nb_chunks_reseted = nb_chunks_reseted + 1;
                 }
 
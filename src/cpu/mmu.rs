@@ -0,0 +1,242 @@
+// Virtual memory, translation of the addresses through the Sv39 and Sv48 page
+// tables pointed by satp. Memory holds the physical address space, the
+// translations are cached in a direct mapped software TLB
+
+use super::memory::{AccessKind, Memory};
+use super::trap::{Exception, Privilege};
+
+pub const PAGE_SIZE: u64 = 0x1000;
+const PAGE_SHIFT: u64 = 12;
+const PTE_SIZE: u64 = 8;
+const TLB_SIZE: usize = 256;
+
+//satp fields
+pub const SATP_MODE_SHIFT: u64 = 60;
+pub const SATP_MODE_BARE: u64 = 0;
+pub const SATP_MODE_SV39: u64 = 8;
+pub const SATP_MODE_SV48: u64 = 9;
+const SATP_PPN_MASK: u64 = (1 << 44) - 1;
+
+//Page table entry bits
+const PTE_V: u64 = 1 << 0;
+const PTE_R: u64 = 1 << 1;
+const PTE_W: u64 = 1 << 2;
+const PTE_X: u64 = 1 << 3;
+const PTE_U: u64 = 1 << 4;
+const PTE_A: u64 = 1 << 6;
+const PTE_D: u64 = 1 << 7;
+const PTE_PPN_SHIFT: u64 = 10;
+const PTE_PPN_MASK: u64 = (1 << 44) - 1;
+/// Bits 63-54 are reserved, Svpbmt and Svnapot are not supported
+const PTE_RESERVED: u64 = 0x3FF << 54;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access{
+    Fetch,
+    Load,
+    Store,
+}
+
+impl Access{
+    pub fn page_fault(self, addr: u64) -> Exception{
+        match self{
+            Access::Fetch => Exception::InstructionPageFault(addr),
+            Access::Load => Exception::LoadPageFault(addr),
+            Access::Store => Exception::StorePageFault(addr),
+        }
+    }
+
+    pub fn access_fault(self, addr: u64) -> Exception{
+        match self{
+            Access::Fetch => Exception::InstructionAccessFault(addr),
+            Access::Load => Exception::LoadAccessFault(addr),
+            Access::Store => Exception::StoreAccessFault(addr),
+        }
+    }
+
+    /// Access to the physical memory once translated
+    pub fn kind(self) -> AccessKind{
+        match self{
+            Access::Fetch => AccessKind::Execute,
+            Access::Load => AccessKind::Read,
+            Access::Store => AccessKind::Write,
+        }
+    }
+}
+
+/// State of the hart an access is translated with, privilege is the
+/// effective privilege (MPRV applied for loads and stores)
+pub struct Context{
+    pub satp: u64,
+    pub privilege: Privilege,
+    /// Supervisor may access user pages
+    pub sum: bool,
+    /// Loads from executable pages are allowed
+    pub mxr: bool,
+}
+
+impl Context{
+    /// Number of levels of the page table, 0 when translation is disabled
+    fn levels(&self) -> u64{
+        if self.privilege == Privilege::Machine{
+            return 0;
+        }
+
+        match self.satp >> SATP_MODE_SHIFT{
+            SATP_MODE_SV39 => 3,
+            SATP_MODE_SV48 => 4,
+            _ => 0,
+        }
+    }
+}
+
+/// A cached translation of a 4KB virtual page, superpages are cached one
+/// 4KB page at a time
+#[derive(Debug, Clone, Copy)]
+struct TlbEntry{
+    vpn: u64,
+    ppn: u64,
+    /// Flags of the leaf PTE
+    flags: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Mmu{
+    tlb: Vec<Option<TlbEntry>>,
+}
+
+impl Mmu{
+    pub fn new() -> Mmu{
+        Mmu{
+            tlb: vec![None; TLB_SIZE],
+        }
+    }
+
+    /// Drop every cached translation (SFENCE.VMA, satp writes)
+    pub fn flush(&mut self){
+        self.tlb.iter_mut().for_each(|e| *e = None);
+    }
+
+    /// Drop the cached translation of a single virtual address
+    pub fn flush_page(&mut self, vaddr: u64){
+        let vpn = vaddr >> PAGE_SHIFT;
+        let slot = &mut self.tlb[vpn as usize % TLB_SIZE];
+        if slot.is_some_and(|e| e.vpn == vpn){
+            *slot = None;
+        }
+    }
+
+    /// Translate a virtual address to a physical one
+    pub fn translate(&mut self, memory: &mut Memory, ctx: &Context, vaddr: u64, access: Access)
+        -> Result<u64, Exception>{
+        let levels = ctx.levels();
+        if levels == 0{
+            return Ok(vaddr);
+        }
+
+        let vpn = vaddr >> PAGE_SHIFT;
+        let offset = vaddr & (PAGE_SIZE - 1);
+
+        //A store to a page not yet marked as dirty goes through a walk to
+        //update the D bit
+        if let Some(entry) = self.tlb[vpn as usize % TLB_SIZE]{
+            if entry.vpn == vpn && (access != Access::Store || entry.flags & PTE_D != 0){
+                if !Mmu::allowed(entry.flags, ctx, access){
+                    return Err(access.page_fault(vaddr));
+                }
+                return Ok(entry.ppn << PAGE_SHIFT | offset);
+            }
+        }
+
+        let entry = Mmu::walk(memory, ctx, vaddr, levels, access)?;
+        self.tlb[vpn as usize % TLB_SIZE] = Some(entry);
+        Ok(entry.ppn << PAGE_SHIFT | offset)
+    }
+
+    /// Check the permissions of a leaf PTE against an access
+    fn allowed(flags: u64, ctx: &Context, access: Access) -> bool{
+        let user_page = flags & PTE_U != 0;
+        match ctx.privilege{
+            Privilege::User if !user_page => return false,
+            //Supervisor never executes user pages and only accesses their
+            //data when SUM is set
+            Privilege::Supervisor if user_page && (access == Access::Fetch || !ctx.sum) => return false,
+            _ => {},
+        }
+
+        match access{
+            Access::Fetch => flags & PTE_X != 0,
+            Access::Load => flags & PTE_R != 0 || (ctx.mxr && flags & PTE_X != 0),
+            Access::Store => flags & PTE_W != 0,
+        }
+    }
+
+    /// Walk the page table, the A and D bits of the leaf are updated
+    fn walk(memory: &mut Memory, ctx: &Context, vaddr: u64, levels: u64, access: Access)
+        -> Result<TlbEntry, Exception>{
+        //The bits above the virtual address must be copies of its top bit
+        let va_bits = PAGE_SHIFT + 9 * levels;
+        let top = (vaddr as i64) >> (va_bits - 1);
+        if top != 0 && top != -1{
+            return Err(access.page_fault(vaddr));
+        }
+
+        let mut table = (ctx.satp & SATP_PPN_MASK) << PAGE_SHIFT;
+        for level in (0..levels).rev(){
+            let index = (vaddr >> (PAGE_SHIFT + 9 * level)) & 0x1FF;
+            let pte_addr = table + index * PTE_SIZE;
+
+            let mut buf = [0u8; 8];
+            memory.read(pte_addr, &mut buf)
+                .map_err(|_| access.access_fault(vaddr))?;
+            let mut pte = u64::from_le_bytes(buf);
+
+            if pte & PTE_V == 0 || (pte & PTE_W != 0 && pte & PTE_R == 0) || pte & PTE_RESERVED != 0{
+                return Err(access.page_fault(vaddr));
+            }
+
+            let ppn = (pte >> PTE_PPN_SHIFT) & PTE_PPN_MASK;
+
+            //Pointer to the next level
+            if pte & (PTE_R | PTE_X) == 0{
+                table = ppn << PAGE_SHIFT;
+                continue;
+            }
+
+            if !Mmu::allowed(pte, ctx, access){
+                return Err(access.page_fault(vaddr));
+            }
+
+            //Superpages must be aligned on their size
+            let low_mask = (1 << (9 * level)) - 1;
+            if ppn & low_mask != 0{
+                return Err(access.page_fault(vaddr));
+            }
+
+            let mut updated = pte | PTE_A;
+            if access == Access::Store{
+                updated |= PTE_D;
+            }
+            if updated != pte{
+                memory.write(pte_addr, &updated.to_le_bytes())
+                    .map_err(|_| access.access_fault(vaddr))?;
+                pte = updated;
+            }
+
+            return Ok(TlbEntry{
+                vpn: vaddr >> PAGE_SHIFT,
+                ppn: ppn | ((vaddr >> PAGE_SHIFT) & low_mask),
+                flags: pte,
+            });
+        }
+
+        //The last level holds a pointer instead of a leaf
+        Err(access.page_fault(vaddr))
+    }
+}
+
+impl Default for Mmu{
+    fn default() -> Self{
+        Mmu::new()
+    }
+}
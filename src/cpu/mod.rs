@@ -1,6 +1,25 @@
 pub mod cpu;
 pub mod memory;
+pub mod heap;
+pub mod process;
 pub mod instr_type;
+pub mod compressed;
+pub mod isa;
+pub mod bitmanip;
+pub mod vector;
+pub mod float;
+pub mod csr;
+pub mod trap;
+pub mod mmu;
+pub mod sbi;
+pub mod clint;
+pub mod device;
+pub mod plic;
+pub mod uart;
+pub mod virtio;
+pub mod virtio_blk;
+pub mod virtio_console;
+pub mod dtb;
 pub mod elf_reader;
 pub mod fuzzer;
 pub mod emu;
\ No newline at end of file
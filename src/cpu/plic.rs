@@ -0,0 +1,162 @@
+// Platform-Level Interrupt Controller, routes the interrupt lines of the
+// devices to the external interrupt of the hart in M-mode (context 0) and in
+// S-mode (context 1). The layout is the one of the SiFive PLIC
+
+use super::device::Device;
+
+pub const PLIC_BASE: u64 = 0xC00_0000;
+pub const PLIC_SIZE: u64 = 0x400_0000;
+/// Interrupt sources, source 0 does not exist
+pub const PLIC_SOURCES: usize = 32;
+pub const PLIC_CONTEXTS: usize = 2;
+
+//Registers offsets
+const PRIORITY: u64 = 0x0;
+const PENDING: u64 = 0x1000;
+const ENABLE: u64 = 0x2000;
+const ENABLE_STRIDE: u64 = 0x80;
+const CONTEXT: u64 = 0x20_0000;
+const CONTEXT_STRIDE: u64 = 0x1000;
+
+pub const CONTEXT_MACHINE: usize = 0;
+pub const CONTEXT_SUPERVISOR: usize = 1;
+
+#[derive(Debug, Clone)]
+pub struct Plic{
+    priority: [u32; PLIC_SOURCES],
+    /// Bit i is set when source i is waiting to be claimed
+    pending: u32,
+    /// Sources claimed but not yet completed, their line is ignored
+    in_flight: u32,
+    enable: [u32; PLIC_CONTEXTS],
+    threshold: [u32; PLIC_CONTEXTS],
+}
+
+impl Plic{
+    pub fn new() -> Plic{
+        Plic{
+            priority: [0; PLIC_SOURCES],
+            pending: 0,
+            in_flight: 0,
+            enable: [0; PLIC_CONTEXTS],
+            threshold: [0; PLIC_CONTEXTS],
+        }
+    }
+
+    /// Sample the interrupt line of a source, the gateway forwards a single
+    /// request until it is completed
+    pub fn set_level(&mut self, source: u32, level: bool){
+        let bit = 1 << source;
+        if level && self.in_flight & bit == 0{
+            self.pending |= bit;
+        }
+    }
+
+    /// Highest priority source that can interrupt the context
+    fn best_source(&self, context: usize) -> Option<u32>{
+        let candidates = self.pending & self.enable[context];
+        (1..PLIC_SOURCES as u32)
+            .filter(|s| candidates & (1 << s) != 0 && self.priority[*s as usize] > self.threshold[context])
+            .max_by_key(|s| (self.priority[*s as usize], std::cmp::Reverse(*s)))
+    }
+
+    /// Returns true if the external interrupt of the context is pending
+    pub fn context_pending(&self, context: usize) -> bool{
+        self.best_source(context).is_some()
+    }
+
+    fn claim(&mut self, context: usize) -> u32{
+        match self.best_source(context){
+            Some(source) => {
+                self.pending &= !(1 << source);
+                self.in_flight |= 1 << source;
+                source
+            },
+            None => 0,
+        }
+    }
+
+    fn read_register(&mut self, offset: u64) -> u32{
+        match offset{
+            PRIORITY..=0xFFF => {
+                let source = (offset / 4) as usize;
+                if source < PLIC_SOURCES { self.priority[source] } else { 0 }
+            },
+            PENDING => self.pending,
+            ENABLE..=0x1F_FFFF => {
+                let context = ((offset - ENABLE) / ENABLE_STRIDE) as usize;
+                if context < PLIC_CONTEXTS && (offset - ENABLE) % ENABLE_STRIDE == 0 {
+                    self.enable[context]
+                } else {
+                    0
+                }
+            },
+            _ if offset >= CONTEXT => {
+                let context = ((offset - CONTEXT) / CONTEXT_STRIDE) as usize;
+                if context >= PLIC_CONTEXTS{
+                    return 0;
+                }
+                match (offset - CONTEXT) % CONTEXT_STRIDE{
+                    0 => self.threshold[context],
+                    4 => self.claim(context),
+                    _ => 0,
+                }
+            },
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, offset: u64, value: u32){
+        match offset{
+            PRIORITY..=0xFFF => {
+                let source = (offset / 4) as usize;
+                if source != 0 && source < PLIC_SOURCES{
+                    self.priority[source] = value & 0x7;
+                }
+            },
+            ENABLE..=0x1F_FFFF => {
+                let context = ((offset - ENABLE) / ENABLE_STRIDE) as usize;
+                if context < PLIC_CONTEXTS && (offset - ENABLE) % ENABLE_STRIDE == 0{
+                    //Source 0 does not exist
+                    self.enable[context] = value & !1;
+                }
+            },
+            _ if offset >= CONTEXT => {
+                let context = ((offset - CONTEXT) / CONTEXT_STRIDE) as usize;
+                if context >= PLIC_CONTEXTS{
+                    return;
+                }
+                match (offset - CONTEXT) % CONTEXT_STRIDE{
+                    0 => self.threshold[context] = value & 0x7,
+                    //Complete
+                    4 if (value as usize) < PLIC_SOURCES => self.in_flight &= !(1 << value),
+                    _ => {},
+                }
+            },
+            _ => {},
+        }
+    }
+}
+
+/// The registers are 32 bits wide and accessed as such
+impl Device for Plic{
+    fn read(&mut self, offset: u64, buf: &mut [u8]){
+        let value = self.read_register(offset & !0b11).to_le_bytes();
+        let len = buf.len().min(4);
+        buf.iter_mut().for_each(|b| *b = 0);
+        buf[..len].copy_from_slice(&value[..len]);
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]){
+        let mut value = [0u8; 4];
+        let len = buf.len().min(4);
+        value[..len].copy_from_slice(&buf[..len]);
+        self.write_register(offset & !0b11, u32::from_le_bytes(value));
+    }
+}
+
+impl Default for Plic{
+    fn default() -> Self{
+        Plic::new()
+    }
+}
@@ -0,0 +1,599 @@
+// State of the emulated Linux process: the files opened from the sysroot,
+// the memory mappings and the objects loaded by the dynamic linker along
+// with their symbols. The system calls working on it are handled here
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs;
+use std::io::Cursor;
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+
+use super::cpu::{Breakpoint, Xlen, CPU};
+use super::elf_reader;
+use super::memory::{Permissions, Violation};
+use super::mmu::PAGE_SIZE;
+use super::trap::Exception;
+
+/// Addresses handed out by mmap when the guest lets it choose
+pub const MMAP_BASE: u64 = 0x5000_0000;
+pub const MMAP_END: u64 = 0x7000_0000;
+/// Largest heap grown through brk
+const BRK_SIZE: u64 = 0x100_0000;
+
+/// Longest path read from the guest
+const PATH_MAX: u64 = 4096;
+/// First descriptor of the opened files, the lower ones are the stdio
+const FIRST_FD: u64 = 3;
+/// dirfd standing for the working directory, always the root
+const AT_FDCWD: i32 = -100;
+const AT_EMPTY_PATH: u64 = 0x1000;
+/// Symbolic links followed while resolving a path
+const MAX_SYMLINKS: usize = 40;
+const O_ACCMODE: u64 = 0b11;
+
+//Protections and flags of mmap
+const PROT_READ: u64 = 1;
+const PROT_WRITE: u64 = 2;
+const PROT_EXEC: u64 = 4;
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+
+//Whence of lseek
+const SEEK_SET: u64 = 0;
+const SEEK_CUR: u64 = 1;
+const SEEK_END: u64 = 2;
+
+//File types of st_mode
+const S_IFREG: u32 = 0o100000;
+const S_IFCHR: u32 = 0o020000;
+const S_IFDIR: u32 = 0o040000;
+/// Size of struct stat on RV64
+const STAT_SIZE: usize = 128;
+
+//Errors returned by the system calls
+const ENOENT: u64 = -2i64 as u64;
+pub const EBADF: u64 = -9i64 as u64;
+const ENOTDIR: u64 = -20i64 as u64;
+const ENOMEM: u64 = -12i64 as u64;
+pub const EFAULT: u64 = -14i64 as u64;
+const EINVAL: u64 = -22i64 as u64;
+const EROFS: u64 = -30i64 as u64;
+pub const ENOSYS: u64 = -38i64 as u64;
+
+//Types of the auxiliary vector entries
+pub const AT_NULL: u64 = 0;
+pub const AT_PHDR: u64 = 3;
+pub const AT_PHENT: u64 = 4;
+pub const AT_PHNUM: u64 = 5;
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_BASE: u64 = 7;
+pub const AT_FLAGS: u64 = 8;
+pub const AT_ENTRY: u64 = 9;
+pub const AT_UID: u64 = 11;
+pub const AT_EUID: u64 = 12;
+pub const AT_GID: u64 = 13;
+pub const AT_EGID: u64 = 14;
+pub const AT_HWCAP: u64 = 16;
+pub const AT_CLKTCK: u64 = 17;
+pub const AT_SECURE: u64 = 23;
+pub const AT_RANDOM: u64 = 25;
+
+/// Bytes pointed by AT_RANDOM, fixed so that the runs can be replayed
+const RANDOM_BYTES: [u8; 16] = *b"\x5e\x1f\x07\xa3\x92\x4c\xd0\x36\x8b\x11\xe4\x7a\x2d\xc9\x60\xf5";
+
+#[derive(Debug, Clone)]
+struct OpenFile{
+    data: Rc<Vec<u8>>,
+    offset: u64,
+    path: PathBuf,
+    /// Absolute path in the guest, the relative paths given with the fd of
+    /// a directory start from it
+    guest_path: String,
+    /// Directories are only opened to serve as dirfd, they hold no data
+    directory: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Process{
+    /// Directory holding the files of the guest, its absolute paths are
+    /// resolved in it. Without one no file can be opened
+    pub sysroot: Option<PathBuf>,
+    files: HashMap<u64, OpenFile>,
+    next_mmap: u64,
+    /// Program break, brk moves it between brk_start and BRK_SIZE bytes
+    /// above. There is none while brk_start is 0
+    brk_start: u64,
+    brk: u64,
+    /// Symbols of the program and of the objects loaded so far, the first
+    /// definition of a name is kept
+    pub symbols: HashMap<String, u64>,
+    /// Handlers set through hook_symbol waiting for their symbol
+    hooks: HashMap<String, Breakpoint>,
+    /// Objects loaded by the dynamic linker
+    objects: Vec<PathBuf>,
+}
+
+impl Process{
+    pub fn new() -> Process{
+        Process{
+            sysroot: None,
+            files: HashMap::new(),
+            next_mmap: MMAP_BASE,
+            brk_start: 0,
+            brk: 0,
+            symbols: HashMap::new(),
+            hooks: HashMap::new(),
+            objects: Vec::new(),
+        }
+    }
+
+    /// Start the program break at addr, the page aligned end of the program
+    pub fn set_brk(&mut self, addr: u64){
+        self.brk_start = addr;
+        self.brk = addr;
+    }
+
+    /// Path on the host of an absolute path of the guest. The symbolic
+    /// links are followed inside the sysroot: absolute targets start from
+    /// its root and .. stops there, so that the path never leaves it
+    pub fn host_path(&self, path: &str) -> Option<PathBuf>{
+        let sysroot = self.sysroot.as_ref()?;
+        let mut resolved = PathBuf::new();
+        let mut pending = Vec::new();
+        push_components(&mut pending, Path::new(path));
+
+        let mut links = 0;
+        while let Some(name) = pending.pop(){
+            if name == ".."{
+                resolved.pop();
+                continue;
+            }
+            resolved.push(&name);
+            if let Ok(target) = fs::read_link(sysroot.join(&resolved)){
+                links += 1;
+                if links > MAX_SYMLINKS{
+                    return None;
+                }
+                resolved.pop();
+                if target.is_absolute(){
+                    resolved = PathBuf::new();
+                }
+                push_components(&mut pending, &target);
+            }
+        }
+        Some(sysroot.join(resolved))
+    }
+
+    /// Absolute path of the guest for a path given relative to dirfd like
+    /// the *at system calls do, Err holds their error
+    fn path_at(&self, dirfd: u64, path: &str) -> Result<String, u64>{
+        if path.is_empty(){
+            return Err(ENOENT);
+        }
+        if path.starts_with('/'){
+            return Ok(String::from(path));
+        }
+        if dirfd as i32 == AT_FDCWD{
+            return Ok(format!("/{}", path));
+        }
+        match self.files.get(&dirfd){
+            Some(file) if file.directory => Ok(format!("{}/{}", file.guest_path, path)),
+            Some(_) => Err(ENOTDIR),
+            None if dirfd < FIRST_FD => Err(ENOTDIR),
+            None => Err(EBADF),
+        }
+    }
+}
+
+impl Default for Process{
+    fn default() -> Self{
+        Self::new()
+    }
+}
+
+/// Queue the components of path to resolve, the first one on top
+fn push_components(pending: &mut Vec<OsString>, path: &Path){
+    pending.extend(path.components().rev().filter_map(|c| match c{
+        Component::Normal(name) => Some(name.to_os_string()),
+        Component::ParentDir => Some(OsString::from("..")),
+        _ => None,
+    }));
+}
+
+fn page_align(size: u64) -> Option<u64>{
+    size.checked_add(PAGE_SIZE - 1).map(|size| size & !(PAGE_SIZE - 1))
+}
+
+fn permissions(prot: u64) -> Permissions{
+    Permissions{
+        read: prot & PROT_READ != 0,
+        write: prot & PROT_WRITE != 0,
+        execute: prot & PROT_EXEC != 0,
+    }
+}
+
+/// Copy data to the buffer of a system call at addr, returns the length
+/// copied or EFAULT. Writing to poisoned memory is a bug of the program, it
+/// is raised like the stores doing it
+pub fn copy_out(cpu: &mut CPU, addr: u64, data: &[u8]) -> Result<u64, Exception>{
+    match cpu.memory.write(addr, data){
+        Ok(()) => Ok(data.len() as u64),
+        Err(error) if error.violation == Violation::Poisoned => Err(Exception::MemoryFault(error)),
+        Err(_) => Ok(EFAULT),
+    }
+}
+
+/// NUL terminated string of the guest at addr
+fn read_string(cpu: &CPU, addr: u64) -> Option<String>{
+    let mut bytes = Vec::new();
+    for at in addr..addr.checked_add(PATH_MAX)?{
+        let mut byte = [0u8];
+        cpu.memory.read(at, &mut byte).ok()?;
+        if byte[0] == 0{
+            return String::from_utf8(bytes).ok();
+        }
+        bytes.push(byte[0]);
+    }
+    None
+}
+
+/// Add the symbols of an object loaded at base and set the breakpoints
+/// that were waiting for them
+pub fn add_object(cpu: &mut CPU, path: &Path, data: &[u8], base: u64){
+    let elf = match elf::File::open_stream(&mut Cursor::new(data)){
+        Ok(elf) => elf,
+        Err(_) => return,
+    };
+    let symbols = elf_reader::read_symbols(elf, base).unwrap_or_default();
+    println!("Loaded {:?} at {:#X} ({} symbols)", path, base, symbols.len());
+
+    let process = &mut cpu.process;
+    process.objects.push(path.to_path_buf());
+    for (name, addr) in symbols{
+        process.symbols.entry(name).or_insert(addr);
+    }
+
+    let ready: Vec<String> = process.hooks.keys()
+        .filter(|name| process.symbols.contains_key(*name))
+        .cloned()
+        .collect();
+    for name in ready{
+        let handler = cpu.process.hooks.remove(&name).unwrap();
+        let addr = cpu.process.symbols[&name];
+        println!("Breakpoint set at {} ({:#8X})", name, addr);
+        cpu.set_breakpoint(addr, handler);
+    }
+}
+
+/// Set handler as the breakpoint of the symbol name, now when it is known
+/// or once an object defining it is loaded. Returns its address if known
+pub fn hook_symbol(cpu: &mut CPU, name: &str, handler: Breakpoint) -> Option<u64>{
+    match cpu.process.symbols.get(name).copied(){
+        Some(addr) => {
+            cpu.set_breakpoint(addr, handler);
+            Some(addr)
+        },
+        None => {
+            cpu.process.hooks.insert(String::from(name), handler);
+            None
+        },
+    }
+}
+
+/// Copy bytes below at and move at to them
+fn push_bytes(cpu: &mut CPU, at: &mut u64, bytes: &[u8]) -> Option<u64>{
+    *at = at.checked_sub(bytes.len() as u64)?;
+    cpu.memory.write(*at, bytes).ok()?;
+    Some(*at)
+}
+
+/// Push NUL terminated copies of the strings below at, returns their
+/// addresses in the order of strings
+fn push_strings(cpu: &mut CPU, at: &mut u64, strings: &[String]) -> Option<Vec<u64>>{
+    let mut addresses = Vec::new();
+    for s in strings.iter().rev(){
+        let mut bytes = s.as_bytes().to_vec();
+        bytes.push(0);
+        addresses.push(push_bytes(cpu, at, &bytes)?);
+    }
+    addresses.reverse();
+    Some(addresses)
+}
+
+/// Lay out below top the stack a program starts with, as execve does:
+/// argc, the argv and envp arrays and the auxiliary vector from sp, then
+/// the AT_RANDOM bytes and the strings. AT_RANDOM is added to auxv.
+/// Returns sp
+pub fn initial_stack(cpu: &mut CPU, top: u64, argv: &[String], envp: &[String], auxv: &[(u64, u64)]) -> Option<u64>{
+    let word = match cpu.xlen { Xlen::Rv32 => 4, Xlen::Rv64 => 8 };
+
+    //The environment strings are above the arguments
+    let mut at = top;
+    let envp = push_strings(cpu, &mut at, envp)?;
+    let argv = push_strings(cpu, &mut at, argv)?;
+    at &= !0xF;
+    let random = push_bytes(cpu, &mut at, &RANDOM_BYTES)?;
+
+    let mut words = vec![argv.len() as u64];
+    words.extend(argv.iter());
+    words.push(0);
+    words.extend(envp.iter());
+    words.push(0);
+    for (key, value) in auxv.iter().chain([(AT_RANDOM, random), (AT_NULL, 0)].iter()){
+        words.push(*key);
+        words.push(*value);
+    }
+
+    //sp is 16 bytes aligned
+    let sp = at.checked_sub(words.len() as u64 * word)? & !0xF;
+    for (i, value) in words.iter().enumerate(){
+        cpu.memory.write(sp + i as u64 * word, &value.to_le_bytes()[..word as usize]).ok()?;
+    }
+    Some(sp)
+}
+
+/// openat(dirfd, path, flags), the paths are all resolved in the sysroot
+/// and the files can only be read. The directories are opened empty, for
+/// their use as dirfd
+pub fn openat(cpu: &mut CPU) -> u64{
+    let dirfd = cpu.registers.common[10];
+    let path = match read_string(cpu, cpu.registers.common[11]){
+        Some(path) => path,
+        None => return EFAULT,
+    };
+    if cpu.registers.common[12] & O_ACCMODE != 0{
+        return EROFS;
+    }
+    let guest_path = match cpu.process.path_at(dirfd, &path){
+        Ok(guest_path) => guest_path,
+        Err(error) => return error,
+    };
+    let host = match cpu.process.host_path(&guest_path){
+        Some(host) => host,
+        None => return ENOENT,
+    };
+    let directory = host.is_dir();
+    let data = if directory { Ok(Vec::new()) } else { fs::read(&host) };
+    let data = match data{
+        Ok(data) => data,
+        Err(_) => return ENOENT,
+    };
+
+    let process = &mut cpu.process;
+    let fd = (FIRST_FD..).find(|fd| !process.files.contains_key(fd)).unwrap();
+    process.files.insert(fd, OpenFile{ data: Rc::new(data), offset: 0, path: host, guest_path, directory });
+    fd
+}
+
+/// close(fd)
+pub fn close(cpu: &mut CPU) -> u64{
+    let fd = cpu.registers.common[10];
+    if fd < FIRST_FD || cpu.process.files.remove(&fd).is_some() { 0 } else { EBADF }
+}
+
+/// Copy up to len bytes of the file from offset to buf, returns the count
+fn read_at(cpu: &mut CPU, fd: u64, buf: u64, len: u64, offset: u64) -> Result<u64, Exception>{
+    let file = match cpu.process.files.get(&fd){
+        Some(file) => file,
+        None => return Ok(EBADF),
+    };
+    let start = offset.min(file.data.len() as u64) as usize;
+    let end = start + (file.data.len() - start).min(len as usize);
+    let data = Rc::clone(&file.data);
+
+    copy_out(cpu, buf, &data[start..end])
+}
+
+/// read(fd, buf, len) from an opened file
+pub fn read(cpu: &mut CPU) -> Result<u64, Exception>{
+    let (fd, buf, len) = (cpu.registers.common[10], cpu.registers.common[11], cpu.registers.common[12]);
+    let offset = match cpu.process.files.get(&fd){
+        Some(file) => file.offset,
+        None => return Ok(EBADF),
+    };
+
+    let count = read_at(cpu, fd, buf, len, offset)?;
+    if (count as i64) >= 0{
+        cpu.process.files.get_mut(&fd).unwrap().offset += count;
+    }
+    Ok(count)
+}
+
+/// pread64(fd, buf, len, offset)
+pub fn pread64(cpu: &mut CPU) -> Result<u64, Exception>{
+    let (fd, buf) = (cpu.registers.common[10], cpu.registers.common[11]);
+    let (len, offset) = (cpu.registers.common[12], cpu.registers.common[13]);
+    read_at(cpu, fd, buf, len, offset)
+}
+
+/// lseek(fd, offset, whence)
+pub fn lseek(cpu: &mut CPU) -> u64{
+    let (fd, offset, whence) = (cpu.registers.common[10], cpu.registers.common[11], cpu.registers.common[12]);
+    let file = match cpu.process.files.get_mut(&fd){
+        Some(file) => file,
+        None => return EBADF,
+    };
+    let from = match whence{
+        SEEK_SET => 0,
+        SEEK_CUR => file.offset,
+        SEEK_END => file.data.len() as u64,
+        _ => return EINVAL,
+    };
+
+    match (from as i64).checked_add(offset as i64){
+        Some(offset) if offset >= 0 => {
+            file.offset = offset as u64;
+            file.offset
+        },
+        _ => EINVAL,
+    }
+}
+
+/// Fill the struct stat at addr, only the type, the permissions and the
+/// size are given
+fn write_stat(cpu: &mut CPU, addr: u64, mode: u32, size: u64) -> Result<u64, Exception>{
+    let mut stat = [0u8; STAT_SIZE];
+    stat[16..20].copy_from_slice(&mode.to_le_bytes());
+    stat[20..24].copy_from_slice(&1u32.to_le_bytes());
+    stat[48..56].copy_from_slice(&size.to_le_bytes());
+    stat[56..60].copy_from_slice(&(PAGE_SIZE as u32).to_le_bytes());
+    stat[64..72].copy_from_slice(&((size + 511) / 512).to_le_bytes());
+
+    match copy_out(cpu, addr, &stat)?{
+        EFAULT => Ok(EFAULT),
+        _ => Ok(0),
+    }
+}
+
+fn stat_fd(cpu: &mut CPU, fd: u64, addr: u64) -> Result<u64, Exception>{
+    if fd < FIRST_FD{
+        return write_stat(cpu, addr, S_IFCHR | 0o620, 0);
+    }
+    match cpu.process.files.get(&fd).map(|file| (file.directory, file.data.len() as u64)){
+        Some((true, _)) => write_stat(cpu, addr, S_IFDIR | 0o755, 0),
+        Some((false, size)) => write_stat(cpu, addr, S_IFREG | 0o755, size),
+        None => Ok(EBADF),
+    }
+}
+
+/// fstat(fd, statbuf)
+pub fn fstat(cpu: &mut CPU) -> Result<u64, Exception>{
+    stat_fd(cpu, cpu.registers.common[10], cpu.registers.common[11])
+}
+
+/// newfstatat(dirfd, path, statbuf, flags), the path is relative to dirfd
+/// and an empty one with AT_EMPTY_PATH is the file of dirfd
+pub fn newfstatat(cpu: &mut CPU) -> Result<u64, Exception>{
+    let (dirfd, statbuf, flags) = (cpu.registers.common[10], cpu.registers.common[12], cpu.registers.common[13]);
+    let path = match read_string(cpu, cpu.registers.common[11]){
+        Some(path) => path,
+        None => return Ok(EFAULT),
+    };
+    if path.is_empty() && flags & AT_EMPTY_PATH != 0{
+        return stat_fd(cpu, dirfd, statbuf);
+    }
+
+    let guest_path = match cpu.process.path_at(dirfd, &path){
+        Ok(guest_path) => guest_path,
+        Err(error) => return Ok(error),
+    };
+    match cpu.process.host_path(&guest_path).and_then(|host| fs::metadata(host).ok()){
+        Some(metadata) if metadata.is_dir() => write_stat(cpu, statbuf, S_IFDIR | 0o755, 0),
+        Some(metadata) if metadata.is_file() => write_stat(cpu, statbuf, S_IFREG | 0o755, metadata.len()),
+        _ => Ok(ENOENT),
+    }
+}
+
+/// mmap(addr, len, prot, flags, fd, offset), the mappings replace the
+/// ones they overlap. The objects mapped from their first byte are
+/// added through add_object
+pub fn mmap(cpu: &mut CPU) -> u64{
+    let (addr, prot, flags) = (cpu.registers.common[10], cpu.registers.common[12], cpu.registers.common[13]);
+    let (fd, offset) = (cpu.registers.common[14], cpu.registers.common[15]);
+    let len = match page_align(cpu.registers.common[11]){
+        Some(len) if len != 0 => len,
+        _ => return EINVAL,
+    };
+
+    let addr = if flags & MAP_FIXED != 0{
+        if addr & (PAGE_SIZE - 1) != 0 || addr.checked_add(len).is_none(){
+            return EINVAL;
+        }
+        addr
+    }
+    else{
+        let process = &mut cpu.process;
+        if len > MMAP_END - process.next_mmap{
+            return ENOMEM;
+        }
+        process.next_mmap += len;
+        process.next_mmap - len
+    };
+
+    let file = if flags & MAP_ANONYMOUS != 0{
+        None
+    }
+    else{
+        match cpu.process.files.get(&fd){
+            Some(file) => Some((Rc::clone(&file.data), file.path.clone())),
+            None => return EBADF,
+        }
+    };
+    let data = match &file{
+        Some((data, _)) => {
+            let start = offset.min(data.len() as u64) as usize;
+            &data[start..start + (data.len() - start).min(len as usize)]
+        },
+        None => &[][..],
+    };
+
+    //The fresh anonymous memory must be written before it is read, except
+    //for the fixed mappings: the dynamic linker zeroes the end of the .bss
+    //of the objects with them, like the loader does for the program
+    cpu.memory.unmap(addr, len);
+    if file.is_none() && flags & MAP_FIXED == 0{
+        cpu.memory.allocate_uninitialized(addr, len, permissions(prot));
+    }
+    else{
+        cpu.memory.allocate(addr, len, data, permissions(prot));
+    }
+
+    if let Some((data, path)) = &file{
+        if offset == 0 && !cpu.process.objects.contains(path){
+            if let Ok(elf) = elf::File::open_stream(&mut Cursor::new(&data[..])){
+                let first = elf.phdrs.iter()
+                    .filter(|p| p.progtype == elf::types::PT_LOAD)
+                    .map(|p| p.vaddr & !(PAGE_SIZE - 1))
+                    .min()
+                    .unwrap_or(0);
+                add_object(cpu, path, data, addr.wrapping_sub(first));
+            }
+        }
+    }
+    addr
+}
+
+/// brk(addr), the pages added to the heap are uninitialized. Returns the
+/// new break, or the current one when addr is out of the heap
+pub fn brk(cpu: &mut CPU) -> u64{
+    let addr = cpu.registers.common[10];
+    let process = &mut cpu.process;
+    if process.brk_start == 0 || addr < process.brk_start || addr - process.brk_start > BRK_SIZE{
+        return process.brk;
+    }
+
+    //The break itself is not page aligned, the mapping is
+    let (end, new_end) = (page_align(process.brk).unwrap_or(0), page_align(addr).unwrap_or(0));
+    process.brk = addr;
+    if new_end > end{
+        cpu.memory.allocate_uninitialized(end, new_end - end, Permissions::RW);
+    }
+    else if new_end < end{
+        cpu.memory.unmap(new_end, end - new_end);
+    }
+    addr
+}
+
+/// munmap(addr, len)
+pub fn munmap(cpu: &mut CPU) -> u64{
+    let addr = cpu.registers.common[10];
+    match page_align(cpu.registers.common[11]){
+        Some(len) if addr & (PAGE_SIZE - 1) == 0 => {
+            cpu.memory.unmap(addr, len);
+            0
+        },
+        _ => EINVAL,
+    }
+}
+
+/// mprotect(addr, len, prot)
+pub fn mprotect(cpu: &mut CPU) -> u64{
+    let (addr, prot) = (cpu.registers.common[10], cpu.registers.common[12]);
+    match page_align(cpu.registers.common[11]){
+        Some(len) if addr & (PAGE_SIZE - 1) == 0 => {
+            if cpu.memory.protect(addr, len, permissions(prot)) { 0 } else { ENOMEM }
+        },
+        _ => EINVAL,
+    }
+}
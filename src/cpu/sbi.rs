@@ -0,0 +1,194 @@
+// Supervisor Binary Interface, when a kernel is booted there is no M-mode
+// firmware and the ECALLs made from S-mode are answered here. The extension
+// id is in a7, the function id in a6, the arguments in a0-a5 and the error
+// and value are returned in a0 and a1
+
+use std::io::{self, Write};
+
+use super::cpu::CPU;
+use super::csr;
+
+//Extension ids
+const EXT_LEGACY_SET_TIMER: u64 = 0x00;
+const EXT_LEGACY_CONSOLE_PUTCHAR: u64 = 0x01;
+const EXT_LEGACY_CONSOLE_GETCHAR: u64 = 0x02;
+const EXT_LEGACY_SHUTDOWN: u64 = 0x08;
+const EXT_BASE: u64 = 0x10;
+const EXT_TIMER: u64 = 0x5449_4D45;
+const EXT_IPI: u64 = 0x73_5049;
+const EXT_RFENCE: u64 = 0x5246_4E43;
+const EXT_HSM: u64 = 0x48_534D;
+const EXT_SRST: u64 = 0x5352_5354;
+const EXT_DBCN: u64 = 0x4442_434E;
+
+//Error codes
+const SBI_SUCCESS: i64 = 0;
+const SBI_ERR_NOT_SUPPORTED: i64 = -2;
+const SBI_ERR_INVALID_PARAM: i64 = -3;
+const SBI_ERR_ALREADY_AVAILABLE: i64 = -6;
+
+/// Version 2.0
+const SPEC_VERSION: u64 = 2 << 24;
+const IMPL_ID: u64 = 0x5249_5343;
+const IMPL_VERSION: u64 = 1;
+
+/// hart_get_status value of a running hart
+const HSM_STARTED: u64 = 0;
+
+const REGISTER_A0: usize = 10;
+const REGISTER_A1: usize = 11;
+const REGISTER_A6: usize = 16;
+const REGISTER_A7: usize = 17;
+
+/// Only hart 0 exists, returns true if it is selected by a hart mask
+fn selects_hart0(mask: u64, base: u64) -> bool{
+    base == u64::MAX || (base == 0 && mask & 1 == 1)
+}
+
+/// Handle a SBI call made by the kernel
+pub fn call(cpu: &mut CPU){
+    let extension = cpu.registers.common[REGISTER_A7];
+    let function = cpu.registers.common[REGISTER_A6];
+    let args = [cpu.registers.common[REGISTER_A0], cpu.registers.common[REGISTER_A1]];
+
+    //Legacy extensions only return a value in a0
+    match extension{
+        EXT_LEGACY_SET_TIMER => {
+            set_timer(cpu, args[0]);
+            cpu.registers.common[REGISTER_A0] = 0;
+            return;
+        },
+        EXT_LEGACY_CONSOLE_PUTCHAR => {
+            console_write(cpu, &[args[0] as u8]);
+            cpu.registers.common[REGISTER_A0] = 0;
+            return;
+        },
+        EXT_LEGACY_CONSOLE_GETCHAR => {
+            //No input
+            cpu.registers.common[REGISTER_A0] = -1i64 as u64;
+            return;
+        },
+        EXT_LEGACY_SHUTDOWN => {
+            cpu.exit = true;
+            return;
+        },
+        _ => {},
+    }
+
+    let (error, value) = match (extension, function){
+        //get_spec_version, get_impl_id, get_impl_version
+        (EXT_BASE, 0) => (SBI_SUCCESS, SPEC_VERSION),
+        (EXT_BASE, 1) => (SBI_SUCCESS, IMPL_ID),
+        (EXT_BASE, 2) => (SBI_SUCCESS, IMPL_VERSION),
+        //probe_extension
+        (EXT_BASE, 3) => {
+            let supported = matches!(args[0], EXT_LEGACY_SET_TIMER | EXT_LEGACY_CONSOLE_PUTCHAR |
+                EXT_LEGACY_CONSOLE_GETCHAR | EXT_LEGACY_SHUTDOWN | EXT_BASE | EXT_TIMER |
+                EXT_IPI | EXT_RFENCE | EXT_HSM | EXT_SRST | EXT_DBCN);
+            (SBI_SUCCESS, supported as u64)
+        },
+        //get_mvendorid, get_marchid, get_mimpid
+        (EXT_BASE, 4) => (SBI_SUCCESS, cpu.csr.get(csr::MVENDORID)),
+        (EXT_BASE, 5) => (SBI_SUCCESS, cpu.csr.get(csr::MARCHID)),
+        (EXT_BASE, 6) => (SBI_SUCCESS, cpu.csr.get(csr::MIMPID)),
+
+        //set_timer
+        (EXT_TIMER, 0) => {
+            set_timer(cpu, args[0]);
+            (SBI_SUCCESS, 0)
+        },
+
+        //send_ipi
+        (EXT_IPI, 0) => {
+            if selects_hart0(args[0], args[1]){
+                cpu.csr.set(csr::MIP, cpu.csr.get(csr::MIP) | csr::MIP_SSIP);
+            }
+            (SBI_SUCCESS, 0)
+        },
+
+        //remote_fence_i, there is no instruction cache
+        (EXT_RFENCE, 0) => (SBI_SUCCESS, 0),
+        //remote_sfence_vma, remote_sfence_vma_asid
+        (EXT_RFENCE, 1) | (EXT_RFENCE, 2) => {
+            if selects_hart0(args[0], args[1]){
+                cpu.mmu.flush();
+            }
+            (SBI_SUCCESS, 0)
+        },
+
+        //hart_start, the only hart is already running
+        (EXT_HSM, 0) => {
+            if args[0] == 0 { (SBI_ERR_ALREADY_AVAILABLE, 0) } else { (SBI_ERR_INVALID_PARAM, 0) }
+        },
+        //hart_stop
+        (EXT_HSM, 1) => {
+            cpu.exit = true;
+            (SBI_SUCCESS, 0)
+        },
+        //hart_get_status
+        (EXT_HSM, 2) => {
+            if args[0] == 0 { (SBI_SUCCESS, HSM_STARTED) } else { (SBI_ERR_INVALID_PARAM, 0) }
+        },
+
+        //system_reset, shutdown and reboots both stop the emulation
+        (EXT_SRST, 0) => {
+            if args[0] <= 2{
+                cpu.exit = true;
+                (SBI_SUCCESS, 0)
+            }
+            else{
+                (SBI_ERR_INVALID_PARAM, 0)
+            }
+        },
+
+        //console_write, the buffer is given by its physical address
+        (EXT_DBCN, 0) => {
+            let len = args[0];
+            let addr = args[1];
+            if len > usize::MAX as u64 || !cpu.memory.is_mapped(addr, len){
+                (SBI_ERR_INVALID_PARAM, 0)
+            }
+            else{
+                let mut buf = vec![0u8; len as usize];
+                match cpu.memory.read(addr, &mut buf){
+                    Ok(()) => {
+                        console_write(cpu, &buf);
+                        (SBI_SUCCESS, len)
+                    },
+                    Err(_) => (SBI_ERR_INVALID_PARAM, 0),
+                }
+            }
+        },
+        //console_read, no input is available
+        (EXT_DBCN, 1) => (SBI_SUCCESS, 0),
+        //console_write_byte
+        (EXT_DBCN, 2) => {
+            console_write(cpu, &[args[0] as u8]);
+            (SBI_SUCCESS, 0)
+        },
+
+        (EXT_BASE, _) | (EXT_TIMER, _) | (EXT_IPI, _) | (EXT_RFENCE, _) |
+        (EXT_HSM, _) | (EXT_SRST, _) | (EXT_DBCN, _) => (SBI_ERR_NOT_SUPPORTED, 0),
+        _ => {
+            println!("Unknown SBI call: {:#X} {:#X}", extension, function);
+            (SBI_ERR_NOT_SUPPORTED, 0)
+        }
+    };
+
+    cpu.registers.common[REGISTER_A0] = error as u64;
+    cpu.registers.common[REGISTER_A1] = value;
+}
+
+/// Program the next timer event, the CLINT timer is delivered to S-mode when
+/// the SBI is emulated
+fn set_timer(cpu: &mut CPU, deadline: u64){
+    cpu.clint.mtimecmp = deadline;
+}
+
+fn console_write(cpu: &CPU, buf: &[u8]){
+    if cpu.redirect_stdout{
+        let mut stdout = io::stdout();
+        stdout.write_all(buf).expect("Couldnt write to stdout");
+        stdout.flush().expect("Couldnt flush stdout");
+    }
+}
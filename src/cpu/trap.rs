@@ -0,0 +1,179 @@
+// Privilege levels, synchronous exceptions and the faults ending the execution
+
+use super::memory::{AccessKind, MemoryError, Violation};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Privilege{
+    User = 0,
+    Supervisor = 1,
+    Machine = 3,
+}
+
+impl Privilege{
+    /// Decode the MPP/SPP fields of mstatus, the reserved value 2 is never
+    /// stored thanks to the WARL masks of the CSR file
+    pub fn from_bits(bits: u64) -> Privilege{
+        match bits & 0b11{
+            0 => Privilege::User,
+            1 => Privilege::Supervisor,
+            _ => Privilege::Machine,
+        }
+    }
+}
+
+/// Synchronous exceptions, memory related ones hold the faulting address.
+/// UninitializedRead is raised by the loads of bytes never written and
+/// MemoryFault by the accesses the memory refused, with the virtual address
+/// of the first faulting byte. They are not architectural, a trap handler
+/// sees them as access faults
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception{
+    InstructionAddressMisaligned(u64),
+    InstructionAccessFault(u64),
+    IllegalInstruction,
+    Breakpoint,
+    LoadAddressMisaligned(u64),
+    LoadAccessFault(u64),
+    StoreAddressMisaligned(u64),
+    StoreAccessFault(u64),
+    EnvironmentCall,
+    InstructionPageFault(u64),
+    LoadPageFault(u64),
+    StorePageFault(u64),
+    UninitializedRead(u64),
+    MemoryFault(MemoryError),
+}
+
+impl Exception{
+    /// Exception code written to mcause/scause, environment calls are
+    /// encoded depending on the privilege they are made from
+    pub fn cause(&self, privilege: Privilege) -> u64{
+        match self{
+            Exception::InstructionAddressMisaligned(_) => 0,
+            Exception::InstructionAccessFault(_) => 1,
+            Exception::IllegalInstruction => 2,
+            Exception::Breakpoint => 3,
+            Exception::LoadAddressMisaligned(_) => 4,
+            Exception::LoadAccessFault(_) | Exception::UninitializedRead(_) => 5,
+            Exception::StoreAddressMisaligned(_) => 6,
+            Exception::StoreAccessFault(_) => 7,
+            Exception::EnvironmentCall => 8 + privilege as u64,
+            Exception::InstructionPageFault(_) => 12,
+            Exception::LoadPageFault(_) => 13,
+            Exception::StorePageFault(_) => 15,
+            Exception::MemoryFault(error) => match error.kind{
+                AccessKind::Execute => 1,
+                AccessKind::Read => 5,
+                AccessKind::Write => 7,
+            },
+        }
+    }
+
+    /// Faulting address for memory exceptions
+    pub fn address(&self) -> Option<u64>{
+        match *self{
+            Exception::InstructionAddressMisaligned(addr) |
+            Exception::InstructionAccessFault(addr) |
+            Exception::LoadAddressMisaligned(addr) |
+            Exception::LoadAccessFault(addr) |
+            Exception::StoreAddressMisaligned(addr) |
+            Exception::StoreAccessFault(addr) |
+            Exception::InstructionPageFault(addr) |
+            Exception::LoadPageFault(addr) |
+            Exception::StorePageFault(addr) |
+            Exception::UninitializedRead(addr) => Some(addr),
+            Exception::MemoryFault(error) => Some(error.addr),
+            _ => None,
+        }
+    }
+}
+
+/// Exception raised while no trap handler exists or bug caught by the
+/// sanitizers, it ends the execution. pc is the address of the faulting
+/// instruction and tval the value the trap would have written to xtval
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuFault{
+    MisalignedFetch{ pc: u64, tval: u64 },
+    FetchAccessFault{ pc: u64, tval: u64 },
+    IllegalInstruction{ pc: u64, tval: u64 },
+    Breakpoint{ pc: u64, tval: u64 },
+    MisalignedLoad{ pc: u64, tval: u64 },
+    LoadAccessFault{ pc: u64, tval: u64 },
+    MisalignedStore{ pc: u64, tval: u64 },
+    StoreAccessFault{ pc: u64, tval: u64 },
+    EnvironmentCall{ pc: u64, tval: u64 },
+    FetchPageFault{ pc: u64, tval: u64 },
+    LoadPageFault{ pc: u64, tval: u64 },
+    StorePageFault{ pc: u64, tval: u64 },
+    UninitializedRead{ pc: u64, tval: u64 },
+    /// Access to unmapped memory or not allowed by the permissions
+    MemoryFault{ pc: u64, error: MemoryError },
+    /// The stack grew past its limit
+    StackOverflow{ pc: u64, error: MemoryError },
+    //Heap sanitizer, the accesses hold the first poisoned byte and the
+    //frees the freed address in tval
+    HeapOverflow{ pc: u64, error: MemoryError },
+    UseAfterFree{ pc: u64, error: MemoryError },
+    DoubleFree{ pc: u64, tval: u64 },
+    InvalidFree{ pc: u64, tval: u64 },
+}
+
+impl CpuFault{
+    pub fn new(exception: Exception, pc: u64, tval: u64) -> CpuFault{
+        match exception{
+            Exception::InstructionAddressMisaligned(_) => CpuFault::MisalignedFetch{ pc, tval },
+            Exception::InstructionAccessFault(_) => CpuFault::FetchAccessFault{ pc, tval },
+            Exception::IllegalInstruction => CpuFault::IllegalInstruction{ pc, tval },
+            Exception::Breakpoint => CpuFault::Breakpoint{ pc, tval },
+            Exception::LoadAddressMisaligned(_) => CpuFault::MisalignedLoad{ pc, tval },
+            Exception::LoadAccessFault(_) => CpuFault::LoadAccessFault{ pc, tval },
+            Exception::StoreAddressMisaligned(_) => CpuFault::MisalignedStore{ pc, tval },
+            Exception::StoreAccessFault(_) => CpuFault::StoreAccessFault{ pc, tval },
+            Exception::EnvironmentCall => CpuFault::EnvironmentCall{ pc, tval },
+            Exception::InstructionPageFault(_) => CpuFault::FetchPageFault{ pc, tval },
+            Exception::LoadPageFault(_) => CpuFault::LoadPageFault{ pc, tval },
+            Exception::StorePageFault(_) => CpuFault::StorePageFault{ pc, tval },
+            Exception::UninitializedRead(_) => CpuFault::UninitializedRead{ pc, tval },
+            Exception::MemoryFault(error) => match error.violation{
+                Violation::Unmapped | Violation::Denied => CpuFault::MemoryFault{ pc, error },
+                Violation::StackOverflow => CpuFault::StackOverflow{ pc, error },
+                //Only the redzones of the heap chunks are poisoned
+                Violation::Poisoned => CpuFault::HeapOverflow{ pc, error },
+            },
+        }
+    }
+
+    /// Address of the faulting instruction
+    pub fn pc(&self) -> u64{
+        self.fields().0
+    }
+
+    /// Faulting address or instruction encoding, 0 when there is none
+    pub fn tval(&self) -> u64{
+        self.fields().1
+    }
+
+    fn fields(&self) -> (u64, u64){
+        match *self{
+            CpuFault::MisalignedFetch{ pc, tval } |
+            CpuFault::FetchAccessFault{ pc, tval } |
+            CpuFault::IllegalInstruction{ pc, tval } |
+            CpuFault::Breakpoint{ pc, tval } |
+            CpuFault::MisalignedLoad{ pc, tval } |
+            CpuFault::LoadAccessFault{ pc, tval } |
+            CpuFault::MisalignedStore{ pc, tval } |
+            CpuFault::StoreAccessFault{ pc, tval } |
+            CpuFault::EnvironmentCall{ pc, tval } |
+            CpuFault::FetchPageFault{ pc, tval } |
+            CpuFault::LoadPageFault{ pc, tval } |
+            CpuFault::StorePageFault{ pc, tval } |
+            CpuFault::UninitializedRead{ pc, tval } |
+            CpuFault::DoubleFree{ pc, tval } |
+            CpuFault::InvalidFree{ pc, tval } => (pc, tval),
+            CpuFault::MemoryFault{ pc, error } |
+            CpuFault::StackOverflow{ pc, error } |
+            CpuFault::HeapOverflow{ pc, error } |
+            CpuFault::UseAfterFree{ pc, error } => (pc, error.addr),
+        }
+    }
+}
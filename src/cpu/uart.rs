@@ -0,0 +1,192 @@
+// NS16550A UART, the transmitter is always ready and sends the bytes to a host
+// sink, the receiver is fed by the host (the fuzzer input for instance)
+
+use std::collections::VecDeque;
+
+use super::device::{Device, HostOutput};
+
+pub const UART_BASE: u64 = 0x1000_0000;
+pub const UART_SIZE: u64 = 0x100;
+/// PLIC source of the UART interrupt
+pub const UART_IRQ: u32 = 10;
+
+//Registers offsets
+const RBR_THR_DLL: u64 = 0;
+const IER_DLM: u64 = 1;
+const IIR_FCR: u64 = 2;
+const LCR: u64 = 3;
+const MCR: u64 = 4;
+const LSR: u64 = 5;
+const MSR: u64 = 6;
+const SCR: u64 = 7;
+
+//Interrupt enable
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+const IER_THR_EMPTY: u8 = 1 << 1;
+
+//Interrupt identification
+const IIR_NO_INTERRUPT: u8 = 0x1;
+const IIR_THR_EMPTY: u8 = 0x2;
+const IIR_RX_AVAILABLE: u8 = 0x4;
+const IIR_FIFO_ENABLED: u8 = 0xC0;
+
+const FCR_FIFO_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+
+/// Divisor latch access bit
+const LCR_DLAB: u8 = 1 << 7;
+
+//Line status
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+const LSR_TX_EMPTY: u8 = 1 << 6;
+
+/// Registers and receive FIFO, the part of the UART restored by the
+/// snapshots
+#[derive(Debug, Clone)]
+struct UartState{
+    rx: VecDeque<u8>,
+
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    fifo_enabled: bool,
+    divisor: u16,
+    /// The THR empty interrupt is raised when the transmitter becomes ready
+    /// and cleared by reading IIR or writing THR
+    thr_empty_pending: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Uart{
+    pub output: HostOutput,
+    state: UartState,
+    saved_state: Option<UartState>,
+}
+
+impl Uart{
+    pub fn new(output: HostOutput) -> Uart{
+        Uart{
+            output,
+            state: UartState{
+                rx: VecDeque::new(),
+                ier: 0,
+                lcr: 0,
+                mcr: 0,
+                scr: 0,
+                fifo_enabled: false,
+                divisor: 0,
+                thr_empty_pending: false,
+            },
+            saved_state: None,
+        }
+    }
+
+    /// Queue bytes to be received by the guest
+    pub fn push_input(&mut self, data: &[u8]){
+        self.state.rx.extend(data);
+    }
+
+    fn interrupt_id(&self) -> u8{
+        if self.state.ier & IER_RX_AVAILABLE != 0 && !self.state.rx.is_empty(){
+            IIR_RX_AVAILABLE
+        }
+        else if self.state.ier & IER_THR_EMPTY != 0 && self.state.thr_empty_pending{
+            IIR_THR_EMPTY
+        }
+        else{
+            IIR_NO_INTERRUPT
+        }
+    }
+
+    fn read_register(&mut self, offset: u64) -> u8{
+        let dlab = self.state.lcr & LCR_DLAB != 0;
+
+        match offset{
+            RBR_THR_DLL if dlab => self.state.divisor as u8,
+            RBR_THR_DLL => self.state.rx.pop_front().unwrap_or(0),
+            IER_DLM if dlab => (self.state.divisor >> 8) as u8,
+            IER_DLM => self.state.ier,
+            IIR_FCR => {
+                let id = self.interrupt_id();
+                if id == IIR_THR_EMPTY{
+                    self.state.thr_empty_pending = false;
+                }
+                if self.state.fifo_enabled { id | IIR_FIFO_ENABLED } else { id }
+            },
+            LCR => self.state.lcr,
+            MCR => self.state.mcr,
+            LSR => {
+                let ready = if self.state.rx.is_empty() { 0 } else { LSR_DATA_READY };
+                ready | LSR_THR_EMPTY | LSR_TX_EMPTY
+            },
+            MSR => 0,
+            SCR => self.state.scr,
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, offset: u64, value: u8){
+        let dlab = self.state.lcr & LCR_DLAB != 0;
+
+        match offset{
+            RBR_THR_DLL if dlab => self.state.divisor = (self.state.divisor & 0xFF00) | value as u16,
+            RBR_THR_DLL => {
+                self.output.write(&[value]);
+                //The byte is sent immediately, the holding register is empty
+                //again
+                self.state.thr_empty_pending = true;
+            },
+            IER_DLM if dlab => self.state.divisor = (self.state.divisor & 0xFF) | (value as u16) << 8,
+            IER_DLM => {
+                //Enabling the THR empty interrupt while the holding register
+                //is empty raises it
+                if value & IER_THR_EMPTY != 0 && self.state.ier & IER_THR_EMPTY == 0{
+                    self.state.thr_empty_pending = true;
+                }
+                self.state.ier = value & 0x0F;
+            },
+            IIR_FCR => {
+                self.state.fifo_enabled = value & FCR_FIFO_ENABLE != 0;
+                if value & FCR_CLEAR_RX != 0{
+                    self.state.rx.clear();
+                }
+            },
+            LCR => self.state.lcr = value,
+            MCR => self.state.mcr = value,
+            SCR => self.state.scr = value,
+            _ => {},
+        }
+    }
+}
+
+/// The registers are 8 bits wide, wider accesses only reach the first one
+impl Device for Uart{
+    fn read(&mut self, offset: u64, buf: &mut [u8]){
+        buf.iter_mut().for_each(|b| *b = 0);
+        if let Some(first) = buf.first_mut(){
+            *first = self.read_register(offset);
+        }
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]){
+        if let Some(first) = buf.first(){
+            self.write_register(offset, *first);
+        }
+    }
+
+    fn interrupt_pending(&self) -> bool{
+        self.interrupt_id() != IIR_NO_INTERRUPT
+    }
+
+    fn save_state(&mut self){
+        self.saved_state = Some(self.state.clone());
+    }
+
+    fn reset_to_saved_state(&mut self){
+        if let Some(saved) = &self.saved_state{
+            self.state = saved.clone();
+        }
+    }
+}
@@ -0,0 +1,1141 @@
+// Vector extension (RVV 1.0) without the floating point instructions, that
+// is Zve64x. It is enabled and VLEN selected with the ISA string
+// (zve64x_zvl256b for instance). The agnostic tail
+// and masked off elements are always filled with ones so that the runs are
+// reproducible
+
+use super::cpu::{CPU, Xlen};
+use super::csr;
+use super::trap::Exception;
+
+/// Widest element
+const ELEN: u64 = 64;
+
+//Arithmetic instruction categories, in funct3
+const OPIVV: u32 = 0b000;
+const OPMVV: u32 = 0b010;
+const OPIVI: u32 = 0b011;
+const OPIVX: u32 = 0b100;
+const OPMVX: u32 = 0b110;
+const OPCFG: u32 = 0b111;
+
+const STORE_FP: u32 = 0b010_0111;
+
+//Addressing modes of the loads and stores, in mop
+const MOP_UNIT_STRIDE: u32 = 0b00;
+const MOP_INDEXED_UNORDERED: u32 = 0b01;
+const MOP_STRIDED: u32 = 0b10;
+const MOP_INDEXED_ORDERED: u32 = 0b11;
+
+//Unit stride variants, in lumop/sumop
+const LUMOP_NORMAL: usize = 0b0_0000;
+const LUMOP_WHOLE_REGISTER: usize = 0b0_1000;
+const LUMOP_MASK: usize = 0b0_1011;
+const LUMOP_FAULT_ONLY_FIRST: usize = 0b1_0000;
+
+#[derive(Debug, Clone)]
+pub struct VectorState{
+    /// Width of a register in bits
+    pub vlen: usize,
+    /// v0 to v31, little endian
+    registers: Vec<u8>,
+    pub vl: u64,
+    /// vtype without vill
+    pub vtype: u64,
+    pub vill: bool,
+    pub vstart: u64,
+    /// Fixed point rounding mode and saturation flag
+    pub vxrm: u64,
+    pub vxsat: u64,
+}
+
+impl VectorState{
+    pub fn new(vlen: usize) -> VectorState{
+        VectorState{
+            vlen,
+            registers: vec![0; vlen / 8 * 32],
+            vl: 0,
+            vtype: 0,
+            vill: true,
+            vstart: 0,
+            vxrm: 0,
+            vxsat: 0,
+        }
+    }
+
+    pub fn vlenb(&self) -> u64{
+        self.vlen as u64 / 8
+    }
+
+    /// vtype as read through its CSR, vill is the most significant bit
+    pub fn vtype_csr(&self, xlen: Xlen) -> u64{
+        match (self.vill, xlen){
+            (false, _) => self.vtype,
+            (true, Xlen::Rv32) => 1 << 31,
+            (true, Xlen::Rv64) => 1 << 63,
+        }
+    }
+
+    /// Configuration set by the last vsetvl, illegal when vill is set
+    fn config(&self) -> Result<Vtype, Exception>{
+        Vtype::decode(self.vtype).filter(|_| !self.vill).ok_or(Exception::IllegalInstruction)
+    }
+
+    fn offset(&self, reg: usize, index: u64, eew: u64) -> usize{
+        reg * self.vlenb() as usize + (index * eew / 8) as usize
+    }
+
+    /// Element of width eew of the group starting at reg, zero extended
+    pub fn element(&self, reg: usize, index: u64, eew: u64) -> u64{
+        let at = self.offset(reg, index, eew);
+        let len = eew as usize / 8;
+
+        let mut buf = [0u8; 8];
+        buf[..len].copy_from_slice(&self.registers[at..at + len]);
+        u64::from_le_bytes(buf)
+    }
+
+    pub fn set_element(&mut self, reg: usize, index: u64, eew: u64, value: u64){
+        let at = self.offset(reg, index, eew);
+        let len = eew as usize / 8;
+        self.registers[at..at + len].copy_from_slice(&value.to_le_bytes()[..len]);
+    }
+
+    /// Bit of a mask register
+    pub fn mask(&self, reg: usize, index: u64) -> bool{
+        let byte = self.registers[reg * self.vlenb() as usize + (index / 8) as usize];
+        (byte >> (index % 8)) & 1 == 1
+    }
+
+    pub fn set_mask(&mut self, reg: usize, index: u64, value: bool){
+        let at = reg * self.vlenb() as usize + (index / 8) as usize;
+        let bit = 1 << (index % 8);
+        if value{
+            self.registers[at] |= bit;
+        }
+        else{
+            self.registers[at] &= !bit;
+        }
+    }
+}
+
+/// Decoded vtype
+#[derive(Debug, Clone, Copy)]
+struct Vtype{
+    /// Element width in bits
+    sew: u64,
+    /// log2 of the register group size, negative for the fractional groups
+    lmul: i32,
+    /// Tail and mask agnostic
+    ta: bool,
+    ma: bool,
+}
+
+impl Vtype{
+    /// None for the reserved values, they set vill
+    fn decode(vtype: u64) -> Option<Vtype>{
+        let vsew = (vtype >> 3) & 0b111;
+        if vtype >> 8 != 0 || vsew > 3{
+            return None;
+        }
+
+        let lmul = match vtype & 0b111{
+            0b100 => return None,
+            vlmul if vlmul < 4 => vlmul as i32,
+            vlmul => vlmul as i32 - 8,
+        };
+        let sew = 8 << vsew;
+        //The fractional groups must hold at least one element
+        if lmul < 0 && sew > ELEN >> -lmul{
+            return None;
+        }
+
+        Some(Vtype{
+            sew,
+            lmul,
+            ta: vtype & (1 << 6) != 0,
+            ma: vtype & (1 << 7) != 0,
+        })
+    }
+
+    fn vlmax(&self, vlen: usize) -> u64{
+        group_elements(vlen, self.sew, self.lmul)
+    }
+}
+
+/// Number of elements of width eew in a group of 2^emul registers
+fn group_elements(vlen: usize, eew: u64, emul: i32) -> u64{
+    let per_register = vlen as u64 / eew;
+    if emul >= 0 { per_register << emul } else { per_register >> -emul }
+}
+
+/// Group size (log2) of the elements of width eew, SEW/LMUL is the same
+/// for every operand
+fn emul(eew: u64, vt: &Vtype) -> i32{
+    vt.lmul + eew.trailing_zeros() as i32 - vt.sew.trailing_zeros() as i32
+}
+
+/// Number of registers of a group
+fn group_size(emul: i32) -> usize{
+    1 << emul.max(0)
+}
+
+/// Groups hold from 1/8 to 8 registers and are aligned on their size
+fn check_group(reg: usize, emul: i32) -> Result<(), Exception>{
+    if !(-3..=3).contains(&emul) || reg % group_size(emul) != 0{
+        return Err(Exception::IllegalInstruction);
+    }
+    Ok(())
+}
+
+fn overlaps(a: usize, a_emul: i32, b: usize, b_emul: i32) -> bool{
+    a < b + group_size(b_emul) && b < a + group_size(a_emul)
+}
+
+fn truncate(value: u64, eew: u64) -> u64{
+    if eew >= 64 { value } else { value & ((1 << eew) - 1) }
+}
+
+fn sext(value: u64, eew: u64) -> i64{
+    let shift = 64 - eew;
+    ((value << shift) as i64) >> shift
+}
+
+/// Fields of the arithmetic instructions
+#[derive(Debug, Clone, Copy)]
+struct Operands{
+    vd: usize,
+    vs1: usize,
+    vs2: usize,
+    funct3: u32,
+    funct6: u32,
+    /// Unmasked, every element is active
+    vm: bool,
+    /// x[rs1] for the .vx forms, the sign extended immediate for the .vi ones
+    scalar: u64,
+    /// x[rs1] zero extended from XLEN or the unsigned immediate, used by the
+    /// shifts, slides and gathers
+    offset: u64,
+}
+
+impl Operands{
+    fn is_vv(&self) -> bool{
+        self.funct3 == OPIVV || self.funct3 == OPMVV
+    }
+
+    /// Second operand of element i, vs1[i] or the scalar
+    fn operand(&self, state: &VectorState, i: u64, sew: u64) -> u64{
+        if self.is_vv() { state.element(self.vs1, i, sew) } else { truncate(self.scalar, sew) }
+    }
+
+    /// Second operand of the shifts, the immediate is unsigned
+    fn shift_operand(&self, state: &VectorState, i: u64, sew: u64) -> u64{
+        if self.is_vv() { state.element(self.vs1, i, sew) } else { self.offset }
+    }
+
+    /// Check the groups of the destination and the sources, None for the
+    /// mask registers and the scalars. Only a mask destination can overlap
+    /// the mask in v0
+    fn check(&self, vd: Option<i32>, vs2: Option<i32>, vs1: Option<i32>) -> Result<(), Exception>{
+        if let Some(emul) = vd{
+            check_group(self.vd, emul)?;
+            if !self.vm && self.vd == 0{
+                return Err(Exception::IllegalInstruction);
+            }
+        }
+        if let Some(emul) = vs2{
+            check_group(self.vs2, emul)?;
+        }
+        match vs1{
+            Some(emul) if self.is_vv() => check_group(self.vs1, emul),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Write the active elements of the group vd from start with the values
+/// given by op, op sees the registers as they were before the instruction
+fn write_elements(state: &mut VectorState, vt: &Vtype, vd: usize, eew: u64, vm: bool, start: u64,
+    mut op: impl FnMut(&VectorState, u64) -> u64){
+    let old = state.clone();
+    for i in start.max(state.vstart)..state.vl{
+        if !vm && !old.mask(0, i){
+            if vt.ma{
+                state.set_element(vd, i, eew, u64::MAX);
+            }
+            continue;
+        }
+        state.set_element(vd, i, eew, op(&old, i));
+    }
+    fill_tail(state, vt, vd, eew, state.vl);
+}
+
+/// Fill the agnostic tail from element start, with a fractional group it
+/// goes up to the end of the register
+fn fill_tail(state: &mut VectorState, vt: &Vtype, vd: usize, eew: u64, start: u64){
+    if vt.ta{
+        let end = group_elements(state.vlen, eew, emul(eew, vt).max(0));
+        for i in start..end{
+            state.set_element(vd, i, eew, u64::MAX);
+        }
+    }
+}
+
+/// Write the active bits of the mask register vd, the tail of a mask is
+/// always agnostic
+fn write_mask(state: &mut VectorState, vt: &Vtype, vd: usize, vm: bool, mut op: impl FnMut(&VectorState, u64) -> bool){
+    let old = state.clone();
+    for i in state.vstart..state.vl{
+        if !vm && !old.mask(0, i){
+            if vt.ma{
+                state.set_mask(vd, i, true);
+            }
+            continue;
+        }
+        state.set_mask(vd, i, op(&old, i));
+    }
+    for i in state.vl..state.vlen as u64{
+        state.set_mask(vd, i, true);
+    }
+}
+
+/// vd[i] = op(vs2[i], vs1[i] or the scalar)
+fn binary(state: &mut VectorState, vt: &Vtype, o: &Operands, op: impl Fn(u64, u64) -> u64) -> Result<(), Exception>{
+    o.check(Some(vt.lmul), Some(vt.lmul), Some(vt.lmul))?;
+    let sew = vt.sew;
+    write_elements(state, vt, o.vd, sew, o.vm, 0, |s, i| op(s.element(o.vs2, i, sew), o.operand(s, i, sew)));
+    Ok(())
+}
+
+/// vd[i] = op(vs2[i], shift amount)
+fn shift(state: &mut VectorState, vt: &Vtype, o: &Operands, op: impl Fn(u64, u64) -> u64) -> Result<(), Exception>{
+    o.check(Some(vt.lmul), Some(vt.lmul), Some(vt.lmul))?;
+    let sew = vt.sew;
+    write_elements(state, vt, o.vd, sew, o.vm, 0, |s, i| {
+        op(s.element(o.vs2, i, sew), o.shift_operand(s, i, sew) & (sew - 1))
+    });
+    Ok(())
+}
+
+/// Saturating operation, op returns the result and whether it saturated
+fn saturating(state: &mut VectorState, vt: &Vtype, o: &Operands, op: impl Fn(u64, u64) -> (u64, bool)) -> Result<(), Exception>{
+    o.check(Some(vt.lmul), Some(vt.lmul), Some(vt.lmul))?;
+    let sew = vt.sew;
+    let mut saturated = false;
+    write_elements(state, vt, o.vd, sew, o.vm, 0, |s, i| {
+        let (value, sat) = op(s.element(o.vs2, i, sew), o.operand(s, i, sew));
+        saturated |= sat;
+        value
+    });
+    if saturated{
+        state.vxsat = 1;
+    }
+    Ok(())
+}
+
+/// vd.mask[i] = op(vs2[i], vs1[i] or the scalar)
+fn compare(state: &mut VectorState, vt: &Vtype, o: &Operands, op: impl Fn(u64, u64) -> bool) -> Result<(), Exception>{
+    o.check(None, Some(vt.lmul), Some(vt.lmul))?;
+    let sew = vt.sew;
+    write_mask(state, vt, o.vd, o.vm, |s, i| op(s.element(o.vs2, i, sew), o.operand(s, i, sew)));
+    Ok(())
+}
+
+/// vd[0] = op(...op(vs1[0], vs2[0])..., vs2[vl - 1]) over the active
+/// elements, the sources have eew bits and the result wide bits
+fn reduce(state: &mut VectorState, vt: &Vtype, o: &Operands, eew: u64, wide: u64, op: impl Fn(u64, u64) -> u64) -> Result<(), Exception>{
+    check_group(o.vs2, emul(eew, vt))?;
+    if state.vstart != 0{
+        return Err(Exception::IllegalInstruction);
+    }
+    if state.vl == 0{
+        return Ok(());
+    }
+
+    let mut result = state.element(o.vs1, 0, wide);
+    for i in 0..state.vl{
+        if o.vm || state.mask(0, i){
+            result = op(result, state.element(o.vs2, i, eew));
+        }
+    }
+    state.set_element(o.vd, 0, wide, result);
+    if vt.ta{
+        for i in 1..state.vlen as u64 / wide{
+            state.set_element(o.vd, i, wide, u64::MAX);
+        }
+    }
+    Ok(())
+}
+
+/// vd[i] = vs2[index[i]], 0 when the index is out of the group
+fn gather(state: &mut VectorState, vt: &Vtype, o: &Operands, index_eew: u64) -> Result<(), Exception>{
+    let index_emul = emul(index_eew, vt);
+    o.check(Some(vt.lmul), Some(vt.lmul), Some(index_emul))?;
+    if overlaps(o.vd, vt.lmul, o.vs2, vt.lmul) || (o.is_vv() && overlaps(o.vd, vt.lmul, o.vs1, index_emul)){
+        return Err(Exception::IllegalInstruction);
+    }
+
+    let sew = vt.sew;
+    let vlmax = vt.vlmax(state.vlen);
+    write_elements(state, vt, o.vd, sew, o.vm, 0, |s, i| {
+        let index = if o.is_vv() { s.element(o.vs1, i, index_eew) } else { o.offset };
+        if index < vlmax { s.element(o.vs2, index, sew) } else { 0 }
+    });
+    Ok(())
+}
+
+/// Widening add, sub, multiply and multiply-add, vd has 2*SEW elements
+fn widening(state: &mut VectorState, vt: &Vtype, o: &Operands) -> Result<(), Exception>{
+    let sew = vt.sew;
+    if sew == ELEN{
+        return Err(Exception::IllegalInstruction);
+    }
+    let wide = sew * 2;
+    //The .W forms have a wide vs2
+    let wide_vs2 = (0b11_0100..=0b11_0111).contains(&o.funct6);
+    let vs2_eew = if wide_vs2 { wide } else { sew };
+    o.check(Some(vt.lmul + 1), Some(emul(vs2_eew, vt)), Some(vt.lmul))?;
+
+    let funct6 = o.funct6;
+    write_elements(state, vt, o.vd, wide, o.vm, 0, |s, i| {
+        let a = s.element(o.vs2, i, vs2_eew);
+        let b = o.operand(s, i, sew);
+        let (signed_a, signed_b) = (sext(a, vs2_eew) as u64, sext(b, sew) as u64);
+        let d = s.element(o.vd, i, wide);
+
+        match funct6{
+            //VWADDU VWADDU.W
+            0b11_0000 | 0b11_0100 => a.wrapping_add(b),
+            //VWADD VWADD.W
+            0b11_0001 | 0b11_0101 => signed_a.wrapping_add(signed_b),
+            //VWSUBU VWSUBU.W
+            0b11_0010 | 0b11_0110 => a.wrapping_sub(b),
+            //VWSUB VWSUB.W
+            0b11_0011 | 0b11_0111 => signed_a.wrapping_sub(signed_b),
+            //VWMULU
+            0b11_1000 => a.wrapping_mul(b),
+            //VWMULSU
+            0b11_1010 => signed_a.wrapping_mul(b),
+            //VWMUL
+            0b11_1011 => signed_a.wrapping_mul(signed_b),
+            //VWMACCU
+            0b11_1100 => d.wrapping_add(a.wrapping_mul(b)),
+            //VWMACC
+            0b11_1101 => d.wrapping_add(signed_a.wrapping_mul(signed_b)),
+            //VWMACCUS
+            0b11_1110 => d.wrapping_add(signed_a.wrapping_mul(b)),
+            //VWMACCSU
+            _ => d.wrapping_add(a.wrapping_mul(signed_b)),
+        }
+    });
+    Ok(())
+}
+
+/// The vector instructions and CSRs are illegal when the extension is
+/// disabled or mstatus.VS is Off, except for the user space programs that
+/// have no kernel to turn it on
+fn check_enabled(cpu: &CPU) -> Result<(), Exception>{
+    let off = cpu.csr.get(csr::MSTATUS) & csr::MSTATUS_VS == 0;
+    if !cpu.extensions.v || (off && !cpu.syscall_emulation){
+        return Err(Exception::IllegalInstruction);
+    }
+    Ok(())
+}
+
+/// The vector state changed, the kernel has to save it
+fn set_dirty(cpu: &mut CPU){
+    cpu.csr.set_dirty(csr::MSTATUS_VS);
+}
+
+/// Read a vector CSR, None if the vector instructions are not available
+pub fn read_csr(cpu: &CPU, addr: u16) -> Option<u64>{
+    check_enabled(cpu).ok()?;
+
+    let state = &cpu.vector;
+    match addr{
+        csr::VSTART => Some(state.vstart),
+        csr::VXSAT => Some(state.vxsat),
+        csr::VXRM => Some(state.vxrm),
+        csr::VCSR => Some(state.vxrm << 1 | state.vxsat),
+        csr::VL => Some(state.vl),
+        csr::VTYPE => Some(state.vtype_csr(cpu.xlen)),
+        csr::VLENB => Some(state.vlenb()),
+        _ => None,
+    }
+}
+
+/// Write a vector CSR, vl, vtype and vlenb are read only
+pub fn write_csr(cpu: &mut CPU, addr: u16, value: u64) -> bool{
+    if check_enabled(cpu).is_err(){
+        return false;
+    }
+
+    let state = &mut cpu.vector;
+    match addr{
+        //vstart only holds the element indexes
+        csr::VSTART => state.vstart = value & (state.vlen as u64 - 1),
+        csr::VXSAT => state.vxsat = value & 0b1,
+        csr::VXRM => state.vxrm = value & 0b11,
+        csr::VCSR => {
+            state.vxsat = value & 0b1;
+            state.vxrm = (value >> 1) & 0b11;
+        },
+        _ => return false,
+    }
+    set_dirty(cpu);
+    true
+}
+
+/// VSETVLI / VSETIVLI / VSETVL, vl is VLMAX when the AVL exceeds it
+fn set_vl(cpu: &mut CPU, instr: u32) -> Result<(), Exception>{
+    let rd = ((instr >> 7) & 0b1_1111) as usize;
+    let rs1 = ((instr >> 15) & 0b1_1111) as usize;
+
+    let (vtype, avl) = match instr >> 30{
+        //VSETVLI
+        0b00 | 0b01 => (((instr >> 20) & 0x7FF) as u64, None),
+        //VSETIVLI, rs1 holds the AVL
+        0b11 => (((instr >> 20) & 0x3FF) as u64, Some(rs1 as u64)),
+        //VSETVL
+        _ => {
+            if (instr >> 25) & 0b11_1111 != 0{
+                return Err(Exception::IllegalInstruction);
+            }
+            (cpu.registers.common[((instr >> 20) & 0b1_1111) as usize], None)
+        },
+    };
+    let xlen_mask = match cpu.xlen{
+        Xlen::Rv32 => u32::MAX as u64,
+        Xlen::Rv64 => u64::MAX,
+    };
+
+    let state = &mut cpu.vector;
+    let vl = match Vtype::decode(vtype & xlen_mask){
+        Some(vt) => {
+            //x0 as AVL keeps vl when rd is also x0 and selects VLMAX
+            //otherwise
+            let avl = match avl{
+                Some(avl) => avl,
+                None if rs1 != 0 => cpu.registers.common[rs1] & xlen_mask,
+                None if rd != 0 => u64::MAX,
+                None => state.vl,
+            };
+            state.vtype = vtype;
+            state.vill = false;
+            avl.min(vt.vlmax(state.vlen))
+        },
+        None => {
+            state.vtype = 0;
+            state.vill = true;
+            0
+        },
+    };
+    state.vl = vl;
+    state.vstart = 0;
+
+    cpu.registers.common[rd] = vl;
+    set_dirty(cpu);
+    Ok(())
+}
+
+/// Execute an OP-V instruction, the floating point ones are illegal
+pub fn exec(cpu: &mut CPU, instr: u32) -> Result<(), Exception>{
+    check_enabled(cpu)?;
+
+    let funct3 = (instr >> 12) & 0b111;
+    if funct3 == OPCFG{
+        return set_vl(cpu, instr);
+    }
+
+    let vs1 = ((instr >> 15) & 0b1_1111) as usize;
+    let o = Operands{
+        vd: ((instr >> 7) & 0b1_1111) as usize,
+        vs1,
+        vs2: ((instr >> 20) & 0b1_1111) as usize,
+        funct3,
+        funct6: instr >> 26,
+        vm: (instr >> 25) & 1 == 1,
+        scalar: match funct3{
+            OPIVX | OPMVX => cpu.registers.common[vs1],
+            OPIVI => sext(vs1 as u64, 5) as u64,
+            _ => 0,
+        },
+        offset: match (funct3, cpu.xlen){
+            (OPIVI, _) => vs1 as u64,
+            (_, Xlen::Rv32) => cpu.registers.common[vs1] as u32 as u64,
+            (_, Xlen::Rv64) => cpu.registers.common[vs1],
+        },
+    };
+
+    match funct3{
+        OPIVV | OPIVI | OPIVX => exec_integer(&mut cpu.vector, &o)?,
+        OPMVV | OPMVX => exec_multiply(cpu, &o)?,
+        _ => return Err(Exception::IllegalInstruction),
+    }
+
+    cpu.vector.vstart = 0;
+    set_dirty(cpu);
+    Ok(())
+}
+
+/// OPIVV / OPIVX / OPIVI instructions
+fn exec_integer(state: &mut VectorState, o: &Operands) -> Result<(), Exception>{
+    let vt = state.config()?;
+    let sew = vt.sew;
+    let lmul = vt.lmul;
+    let (vv, vi) = (o.funct3 == OPIVV, o.funct3 == OPIVI);
+    let max = truncate(u64::MAX, sew);
+    let (signed_min, signed_max) = (-(1i128 << (sew - 1)), (1i128 << (sew - 1)) - 1);
+
+    match o.funct6{
+        //VADD
+        0b00_0000 => binary(state, &vt, o, |a, b| a.wrapping_add(b)),
+        //VSUB
+        0b00_0010 if !vi => binary(state, &vt, o, |a, b| a.wrapping_sub(b)),
+        //VRSUB
+        0b00_0011 if !vv => binary(state, &vt, o, |a, b| b.wrapping_sub(a)),
+        //VMINU
+        0b00_0100 if !vi => binary(state, &vt, o, |a, b| a.min(b)),
+        //VMIN
+        0b00_0101 if !vi => binary(state, &vt, o, |a, b| if sext(a, sew) < sext(b, sew) { a } else { b }),
+        //VMAXU
+        0b00_0110 if !vi => binary(state, &vt, o, |a, b| a.max(b)),
+        //VMAX
+        0b00_0111 if !vi => binary(state, &vt, o, |a, b| if sext(a, sew) > sext(b, sew) { a } else { b }),
+        //VAND
+        0b00_1001 => binary(state, &vt, o, |a, b| a & b),
+        //VOR
+        0b00_1010 => binary(state, &vt, o, |a, b| a | b),
+        //VXOR
+        0b00_1011 => binary(state, &vt, o, |a, b| a ^ b),
+        //VRGATHER
+        0b00_1100 => gather(state, &vt, o, sew),
+        //VRGATHEREI16
+        0b00_1110 if vv => gather(state, &vt, o, 16),
+        //VSLIDEUP, the elements below the offset are left unchanged
+        0b00_1110 => {
+            o.check(Some(lmul), Some(lmul), None)?;
+            if overlaps(o.vd, lmul, o.vs2, lmul){
+                return Err(Exception::IllegalInstruction);
+            }
+            let offset = o.offset;
+            write_elements(state, &vt, o.vd, sew, o.vm, offset, |s, i| s.element(o.vs2, i - offset, sew));
+            Ok(())
+        },
+        //VSLIDEDOWN
+        0b00_1111 if !vv => {
+            o.check(Some(lmul), Some(lmul), None)?;
+            let vlmax = vt.vlmax(state.vlen);
+            write_elements(state, &vt, o.vd, sew, o.vm, 0, |s, i| {
+                match i.checked_add(o.offset){
+                    Some(j) if j < vlmax => s.element(o.vs2, j, sew),
+                    _ => 0,
+                }
+            });
+            Ok(())
+        },
+        //VADC, v0 holds the carries
+        0b01_0000 if !o.vm => {
+            o.check(Some(lmul), Some(lmul), Some(lmul))?;
+            write_elements(state, &vt, o.vd, sew, true, 0, |s, i| {
+                s.element(o.vs2, i, sew).wrapping_add(o.operand(s, i, sew)).wrapping_add(s.mask(0, i) as u64)
+            });
+            Ok(())
+        },
+        //VMADC, with the carries in v0 when masked
+        0b01_0001 => {
+            o.check(None, Some(lmul), Some(lmul))?;
+            write_mask(state, &vt, o.vd, true, |s, i| {
+                let carry = !o.vm && s.mask(0, i);
+                let sum = s.element(o.vs2, i, sew) as u128 + o.operand(s, i, sew) as u128 + carry as u128;
+                sum >> sew != 0
+            });
+            Ok(())
+        },
+        //VSBC, v0 holds the borrows
+        0b01_0010 if !vi && !o.vm => {
+            o.check(Some(lmul), Some(lmul), Some(lmul))?;
+            write_elements(state, &vt, o.vd, sew, true, 0, |s, i| {
+                s.element(o.vs2, i, sew).wrapping_sub(o.operand(s, i, sew)).wrapping_sub(s.mask(0, i) as u64)
+            });
+            Ok(())
+        },
+        //VMSBC
+        0b01_0011 if !vi => {
+            o.check(None, Some(lmul), Some(lmul))?;
+            write_mask(state, &vt, o.vd, true, |s, i| {
+                let borrow = !o.vm && s.mask(0, i);
+                (s.element(o.vs2, i, sew) as u128) < o.operand(s, i, sew) as u128 + borrow as u128
+            });
+            Ok(())
+        },
+        //VMERGE, and VMV.V when unmasked
+        0b01_0111 => {
+            if o.vm && o.vs2 != 0{
+                return Err(Exception::IllegalInstruction);
+            }
+            o.check(Some(lmul), if o.vm { None } else { Some(lmul) }, Some(lmul))?;
+            write_elements(state, &vt, o.vd, sew, true, 0, |s, i| {
+                if o.vm || s.mask(0, i) { o.operand(s, i, sew) } else { s.element(o.vs2, i, sew) }
+            });
+            Ok(())
+        },
+        //VMSEQ
+        0b01_1000 => compare(state, &vt, o, |a, b| a == b),
+        //VMSNE
+        0b01_1001 => compare(state, &vt, o, |a, b| a != b),
+        //VMSLTU
+        0b01_1010 if !vi => compare(state, &vt, o, |a, b| a < b),
+        //VMSLT
+        0b01_1011 if !vi => compare(state, &vt, o, |a, b| sext(a, sew) < sext(b, sew)),
+        //VMSLEU
+        0b01_1100 => compare(state, &vt, o, |a, b| a <= b),
+        //VMSLE
+        0b01_1101 => compare(state, &vt, o, |a, b| sext(a, sew) <= sext(b, sew)),
+        //VMSGTU
+        0b01_1110 if !vv => compare(state, &vt, o, |a, b| a > b),
+        //VMSGT
+        0b01_1111 if !vv => compare(state, &vt, o, |a, b| sext(a, sew) > sext(b, sew)),
+        //VSADDU
+        0b10_0000 => saturating(state, &vt, o, |a, b| {
+            let sum = a as u128 + b as u128;
+            if sum > max as u128 { (max, true) } else { (sum as u64, false) }
+        }),
+        //VSADD
+        0b10_0001 => saturating(state, &vt, o, |a, b| {
+            let sum = sext(a, sew) as i128 + sext(b, sew) as i128;
+            let clamped = sum.clamp(signed_min, signed_max);
+            (clamped as u64, clamped != sum)
+        }),
+        //VSSUBU
+        0b10_0010 if !vi => saturating(state, &vt, o, |a, b| {
+            a.checked_sub(b).map_or((0, true), |diff| (diff, false))
+        }),
+        //VSSUB
+        0b10_0011 if !vi => saturating(state, &vt, o, |a, b| {
+            let diff = sext(a, sew) as i128 - sext(b, sew) as i128;
+            let clamped = diff.clamp(signed_min, signed_max);
+            (clamped as u64, clamped != diff)
+        }),
+        //VSLL
+        0b10_0101 => shift(state, &vt, o, |a, shamt| a << shamt),
+        //VMV<NR>R, copies whole registers
+        0b10_0111 if vi => {
+            let nr = o.offset + 1;
+            if !o.vm || !nr.is_power_of_two() || nr > 8{
+                return Err(Exception::IllegalInstruction);
+            }
+            let emul = nr.trailing_zeros() as i32;
+            check_group(o.vd, emul)?;
+            check_group(o.vs2, emul)?;
+
+            let old = state.clone();
+            for i in state.vstart..group_elements(state.vlen, sew, emul){
+                state.set_element(o.vd, i, sew, old.element(o.vs2, i, sew));
+            }
+            Ok(())
+        },
+        //VSRL
+        0b10_1000 => shift(state, &vt, o, |a, shamt| a >> shamt),
+        //VSRA
+        0b10_1001 => shift(state, &vt, o, |a, shamt| (sext(a, sew) >> shamt) as u64),
+        //VNSRL / VNSRA, vs2 holds 2*SEW elements
+        0b10_1100 | 0b10_1101 => {
+            if sew == ELEN{
+                return Err(Exception::IllegalInstruction);
+            }
+            let wide = sew * 2;
+            o.check(Some(lmul), Some(lmul + 1), Some(lmul))?;
+
+            let arithmetic = o.funct6 == 0b10_1101;
+            write_elements(state, &vt, o.vd, sew, o.vm, 0, |s, i| {
+                let a = s.element(o.vs2, i, wide);
+                let shamt = o.shift_operand(s, i, sew) & (wide - 1);
+                if arithmetic { (sext(a, wide) >> shamt) as u64 } else { a >> shamt }
+            });
+            Ok(())
+        },
+        //VWREDSUMU / VWREDSUM, the sum has 2*SEW bits
+        0b11_0000 | 0b11_0001 if vv => {
+            if sew == ELEN{
+                return Err(Exception::IllegalInstruction);
+            }
+            let signed = o.funct6 == 0b11_0001;
+            reduce(state, &vt, o, sew, sew * 2, |sum, e| {
+                sum.wrapping_add(if signed { sext(e, sew) as u64 } else { e })
+            })
+        },
+        _ => Err(Exception::IllegalInstruction),
+    }
+}
+
+/// OPMVV / OPMVX instructions
+fn exec_multiply(cpu: &mut CPU, o: &Operands) -> Result<(), Exception>{
+    let vt = cpu.vector.config()?;
+    let sew = vt.sew;
+    let lmul = vt.lmul;
+    let vv = o.funct3 == OPMVV;
+
+    //VMV.X.S / VCPOP.M / VFIRST.M write x[rd]
+    if o.funct6 == 0b01_0000 && vv{
+        let state = &cpu.vector;
+        let active = |i: u64| o.vm || state.mask(0, i);
+        if o.vs1 != 0 && state.vstart != 0{
+            return Err(Exception::IllegalInstruction);
+        }
+
+        cpu.registers.common[o.vd] = match o.vs1{
+            0b0_0000 if o.vm => sext(state.element(o.vs2, 0, sew), sew) as u64,
+            0b1_0000 => (0..state.vl).filter(|&i| active(i) && state.mask(o.vs2, i)).count() as u64,
+            0b1_0001 => (0..state.vl).find(|&i| active(i) && state.mask(o.vs2, i)).unwrap_or(u64::MAX),
+            _ => return Err(Exception::IllegalInstruction),
+        };
+        return Ok(());
+    }
+
+    let state = &mut cpu.vector;
+    match o.funct6{
+        //VREDSUM
+        0b00_0000 if vv => reduce(state, &vt, o, sew, sew, |a, b| a.wrapping_add(b)),
+        //VREDAND
+        0b00_0001 if vv => reduce(state, &vt, o, sew, sew, |a, b| a & b),
+        //VREDOR
+        0b00_0010 if vv => reduce(state, &vt, o, sew, sew, |a, b| a | b),
+        //VREDXOR
+        0b00_0011 if vv => reduce(state, &vt, o, sew, sew, |a, b| a ^ b),
+        //VREDMINU
+        0b00_0100 if vv => reduce(state, &vt, o, sew, sew, |a, b| a.min(b)),
+        //VREDMIN
+        0b00_0101 if vv => reduce(state, &vt, o, sew, sew, |a, b| if sext(a, sew) < sext(b, sew) { a } else { b }),
+        //VREDMAXU
+        0b00_0110 if vv => reduce(state, &vt, o, sew, sew, |a, b| a.max(b)),
+        //VREDMAX
+        0b00_0111 if vv => reduce(state, &vt, o, sew, sew, |a, b| if sext(a, sew) > sext(b, sew) { a } else { b }),
+        //VSLIDE1UP
+        0b00_1110 if !vv => {
+            o.check(Some(lmul), Some(lmul), None)?;
+            if overlaps(o.vd, lmul, o.vs2, lmul){
+                return Err(Exception::IllegalInstruction);
+            }
+            let scalar = truncate(o.scalar, sew);
+            write_elements(state, &vt, o.vd, sew, o.vm, 0, |s, i| {
+                if i == 0 { scalar } else { s.element(o.vs2, i - 1, sew) }
+            });
+            Ok(())
+        },
+        //VSLIDE1DOWN
+        0b00_1111 if !vv => {
+            o.check(Some(lmul), Some(lmul), None)?;
+            let scalar = truncate(o.scalar, sew);
+            let last = state.vl.wrapping_sub(1);
+            write_elements(state, &vt, o.vd, sew, o.vm, 0, |s, i| {
+                if i == last { scalar } else { s.element(o.vs2, i + 1, sew) }
+            });
+            Ok(())
+        },
+        //VMV.S.X
+        0b01_0000 => {
+            if !o.vm || o.vs2 != 0{
+                return Err(Exception::IllegalInstruction);
+            }
+            if state.vstart < state.vl{
+                state.set_element(o.vd, 0, sew, o.scalar);
+                fill_tail(state, &vt, o.vd, sew, 1);
+            }
+            Ok(())
+        },
+        //VZEXT / VSEXT, vs1 selects the source width and the signedness
+        0b01_0010 if vv => {
+            let (factor, signed) = match o.vs1{
+                0b0_0010 => (8, false),
+                0b0_0011 => (8, true),
+                0b0_0100 => (4, false),
+                0b0_0101 => (4, true),
+                0b0_0110 => (2, false),
+                0b0_0111 => (2, true),
+                _ => return Err(Exception::IllegalInstruction),
+            };
+            let eew = sew / factor;
+            if eew < 8{
+                return Err(Exception::IllegalInstruction);
+            }
+            o.check(Some(lmul), Some(emul(eew, &vt)), None)?;
+
+            write_elements(state, &vt, o.vd, sew, o.vm, 0, |s, i| {
+                let value = s.element(o.vs2, i, eew);
+                if signed { sext(value, eew) as u64 } else { value }
+            });
+            Ok(())
+        },
+        //VMSBF / VMSOF / VMSIF, relative to the first active set bit of vs2
+        0b01_0100 if vv && (1..=3).contains(&o.vs1) => {
+            if o.vd == o.vs2 || (!o.vm && o.vd == 0) || state.vstart != 0{
+                return Err(Exception::IllegalInstruction);
+            }
+            let first = (0..state.vl).find(|&i| (o.vm || state.mask(0, i)) && state.mask(o.vs2, i));
+            write_mask(state, &vt, o.vd, o.vm, |_, i| {
+                match (o.vs1, first){
+                    (0b01, Some(first)) => i < first,
+                    (0b10, Some(first)) => i == first,
+                    (_, Some(first)) => i <= first,
+                    //No bit set, only VMSOF gives zeros
+                    (vs1, None) => vs1 != 0b10,
+                }
+            });
+            Ok(())
+        },
+        //VIOTA, number of set bits of vs2 in the active elements below
+        0b01_0100 if vv && o.vs1 == 0b1_0000 => {
+            o.check(Some(lmul), None, None)?;
+            if overlaps(o.vd, lmul, o.vs2, 0) || state.vstart != 0{
+                return Err(Exception::IllegalInstruction);
+            }
+            let mut count = 0;
+            write_elements(state, &vt, o.vd, sew, o.vm, 0, |s, i| {
+                let value = count;
+                if s.mask(o.vs2, i){
+                    count += 1;
+                }
+                value
+            });
+            Ok(())
+        },
+        //VID
+        0b01_0100 if vv && o.vs1 == 0b1_0001 && o.vs2 == 0 => {
+            o.check(Some(lmul), None, None)?;
+            write_elements(state, &vt, o.vd, sew, o.vm, 0, |_, i| i);
+            Ok(())
+        },
+        //VCOMPRESS, packs the elements of vs2 selected by the mask in vs1
+        0b01_0111 if vv => {
+            o.check(Some(lmul), Some(lmul), None)?;
+            if !o.vm || state.vstart != 0 || overlaps(o.vd, lmul, o.vs2, lmul) || overlaps(o.vd, lmul, o.vs1, 0){
+                return Err(Exception::IllegalInstruction);
+            }
+            let old = state.clone();
+            let mut count = 0;
+            for i in 0..state.vl{
+                if old.mask(o.vs1, i){
+                    state.set_element(o.vd, count, sew, old.element(o.vs2, i, sew));
+                    count += 1;
+                }
+            }
+            fill_tail(state, &vt, o.vd, sew, count);
+            Ok(())
+        },
+        //VMANDN VMAND VMOR VMXOR VMORN VMNAND VMNOR VMXNOR
+        0b01_1000..=0b01_1111 if vv && o.vm => {
+            let funct6 = o.funct6;
+            write_mask(state, &vt, o.vd, true, |s, i| {
+                let (a, b) = (s.mask(o.vs2, i), s.mask(o.vs1, i));
+                match funct6 & 0b111{
+                    0b000 => a && !b,
+                    0b001 => a && b,
+                    0b010 => a || b,
+                    0b011 => a != b,
+                    0b100 => a || !b,
+                    0b101 => !(a && b),
+                    0b110 => !(a || b),
+                    _ => a == b,
+                }
+            });
+            Ok(())
+        },
+        //VDIVU, division by zero gives all ones
+        0b10_0000 => binary(state, &vt, o, |a, b| a.checked_div(b).unwrap_or(u64::MAX)),
+        //VDIV, the overflow gives the dividend once truncated
+        0b10_0001 => binary(state, &vt, o, |a, b| {
+            let (a, b) = (sext(a, sew), sext(b, sew));
+            if b == 0 { u64::MAX } else { a.wrapping_div(b) as u64 }
+        }),
+        //VREMU, the remainder by zero is the dividend
+        0b10_0010 => binary(state, &vt, o, |a, b| a.checked_rem(b).unwrap_or(a)),
+        //VREM
+        0b10_0011 => binary(state, &vt, o, |a, b| {
+            let (a, b) = (sext(a, sew), sext(b, sew));
+            if b == 0 { a as u64 } else { a.wrapping_rem(b) as u64 }
+        }),
+        //VMULHU
+        0b10_0100 => binary(state, &vt, o, |a, b| ((a as u128 * b as u128) >> sew) as u64),
+        //VMUL
+        0b10_0101 => binary(state, &vt, o, |a, b| a.wrapping_mul(b)),
+        //VMULHSU, vs2 is signed
+        0b10_0110 => binary(state, &vt, o, |a, b| ((sext(a, sew) as i128 * b as i128) >> sew) as u64),
+        //VMULH
+        0b10_0111 => binary(state, &vt, o, |a, b| ((sext(a, sew) as i128 * sext(b, sew) as i128) >> sew) as u64),
+        //VMADD VNMSUB VMACC VNMSAC, they also read vd
+        0b10_1001 | 0b10_1011 | 0b10_1101 | 0b10_1111 => {
+            o.check(Some(lmul), Some(lmul), Some(lmul))?;
+            let funct6 = o.funct6;
+            write_elements(state, &vt, o.vd, sew, o.vm, 0, |s, i| {
+                let (d, a, b) = (s.element(o.vd, i, sew), s.element(o.vs2, i, sew), o.operand(s, i, sew));
+                match funct6{
+                    0b10_1001 => b.wrapping_mul(d).wrapping_add(a),
+                    0b10_1011 => a.wrapping_sub(b.wrapping_mul(d)),
+                    0b10_1101 => b.wrapping_mul(a).wrapping_add(d),
+                    _ => d.wrapping_sub(b.wrapping_mul(a)),
+                }
+            });
+            Ok(())
+        },
+        0b11_0000..=0b11_1000 | 0b11_1010 | 0b11_1011 | 0b11_1100 | 0b11_1101 | 0b11_1111 => widening(state, &vt, o),
+        //VWMACCUS only exists with a scalar
+        0b11_1110 if !vv => widening(state, &vt, o),
+        _ => Err(Exception::IllegalInstruction),
+    }
+}
+
+/// Returns true for the widths of LOAD-FP and STORE-FP that select the
+/// vector loads and stores
+pub fn is_memory_width(instr: u32) -> bool{
+    matches!((instr >> 12) & 0b111, 0b000 | 0b101 | 0b110 | 0b111)
+}
+
+/// Move an element between memory and a register
+fn transfer(cpu: &mut CPU, store: bool, addr: u64, reg: usize, index: u64, eew: u64) -> Result<(), Exception>{
+    let len = eew as usize / 8;
+    if store{
+        let value = cpu.vector.element(reg, index, eew);
+        cpu.store(addr, &value.to_le_bytes()[..len])
+    }
+    else{
+        let mut buf = [0u8; 8];
+        cpu.load(addr, &mut buf[..len])?;
+        cpu.vector.set_element(reg, index, eew, u64::from_le_bytes(buf));
+        Ok(())
+    }
+}
+
+/// Execute a vector load or store. A fault leaves vstart on the faulting
+/// element so that the instruction can be restarted
+pub fn exec_memory(cpu: &mut CPU, instr: u32) -> Result<(), Exception>{
+    check_enabled(cpu)?;
+
+    let store = instr & 0b111_1111 == STORE_FP;
+    let vd = ((instr >> 7) & 0b1_1111) as usize;
+    let rs1 = ((instr >> 15) & 0b1_1111) as usize;
+    //Also lumop / sumop for the unit stride accesses
+    let rs2 = ((instr >> 20) & 0b1_1111) as usize;
+    let vm = (instr >> 25) & 1 == 1;
+    let mop = (instr >> 26) & 0b11;
+    let nf = ((instr >> 29) + 1) as usize;
+    let eew = match (instr >> 12) & 0b111{
+        0b000 => 8,
+        0b101 => 16,
+        0b110 => 32,
+        _ => 64,
+    };
+    //mew selects the reserved 128 bits and wider elements
+    if (instr >> 28) & 1 != 0{
+        return Err(Exception::IllegalInstruction);
+    }
+    let base = cpu.registers.common[rs1];
+
+    match (mop, rs2){
+        //VL<NF>RE<EEW> / VS<NF>R, whole registers regardless of vtype
+        (MOP_UNIT_STRIDE, LUMOP_WHOLE_REGISTER) => {
+            if !vm || !nf.is_power_of_two() || (store && eew != 8){
+                return Err(Exception::IllegalInstruction);
+            }
+            let emul = nf.trailing_zeros() as i32;
+            check_group(vd, emul)?;
+
+            for i in cpu.vector.vstart..group_elements(cpu.vector.vlen, eew, emul){
+                let addr = base.wrapping_add(i * eew / 8);
+                if let Err(e) = transfer(cpu, store, addr, vd, i, eew){
+                    cpu.vector.vstart = i;
+                    return Err(e);
+                }
+            }
+        },
+        //VLM / VSM, the mask bytes up to vl
+        (MOP_UNIT_STRIDE, LUMOP_MASK) => {
+            if !vm || nf != 1 || eew != 8{
+                return Err(Exception::IllegalInstruction);
+            }
+            for i in cpu.vector.vstart..(cpu.vector.vl + 7) / 8{
+                if let Err(e) = transfer(cpu, store, base.wrapping_add(i), vd, i, 8){
+                    cpu.vector.vstart = i;
+                    return Err(e);
+                }
+            }
+        },
+        (MOP_UNIT_STRIDE, LUMOP_NORMAL) | (MOP_STRIDED, _) | (MOP_INDEXED_UNORDERED, _) | (MOP_INDEXED_ORDERED, _) => {
+            exec_elements(cpu, instr, eew, nf, false)?;
+        },
+        (MOP_UNIT_STRIDE, LUMOP_FAULT_ONLY_FIRST) if !store => {
+            exec_elements(cpu, instr, eew, nf, true)?;
+        },
+        _ => return Err(Exception::IllegalInstruction),
+    }
+
+    cpu.vector.vstart = 0;
+    if !store{
+        set_dirty(cpu);
+    }
+    Ok(())
+}
+
+/// Unit stride, strided and indexed accesses of nf fields per element. The
+/// fault only first loads trim vl when an element other than the first one
+/// faults
+fn exec_elements(cpu: &mut CPU, instr: u32, eew: u64, nf: usize, fault_only_first: bool) -> Result<(), Exception>{
+    let vt = cpu.vector.config()?;
+    let store = instr & 0b111_1111 == STORE_FP;
+    let vd = ((instr >> 7) & 0b1_1111) as usize;
+    let rs2 = ((instr >> 20) & 0b1_1111) as usize;
+    let vm = (instr >> 25) & 1 == 1;
+    let mop = (instr >> 26) & 0b11;
+    let base = cpu.registers.common[((instr >> 15) & 0b1_1111) as usize];
+
+    //The indexed accesses use eew for the indexes and SEW for the data
+    let indexed = mop == MOP_INDEXED_UNORDERED || mop == MOP_INDEXED_ORDERED;
+    let data_eew = if indexed { vt.sew } else { eew };
+    let data_emul = emul(data_eew, &vt);
+    check_group(vd, data_emul)?;
+    if indexed{
+        check_group(rs2, emul(eew, &vt))?;
+    }
+    let field_regs = group_size(data_emul);
+    if nf * field_regs > 8 || vd + nf * field_regs > 32 || (!vm && vd == 0){
+        return Err(Exception::IllegalInstruction);
+    }
+
+    let stride = match mop{
+        MOP_STRIDED => cpu.registers.common[rs2],
+        _ => nf as u64 * data_eew / 8,
+    };
+    let old = cpu.vector.clone();
+
+    'elements: for i in old.vstart..old.vl{
+        if !vm && !old.mask(0, i){
+            if !store && vt.ma{
+                for field in 0..nf{
+                    cpu.vector.set_element(vd + field * field_regs, i, data_eew, u64::MAX);
+                }
+            }
+            continue;
+        }
+
+        let offset = if indexed { old.element(rs2, i, eew) } else { i.wrapping_mul(stride) };
+        for field in 0..nf{
+            let addr = base.wrapping_add(offset).wrapping_add(field as u64 * data_eew / 8);
+            if let Err(e) = transfer(cpu, store, addr, vd + field * field_regs, i, data_eew){
+                if fault_only_first && i > 0{
+                    cpu.vector.vl = i;
+                    break 'elements;
+                }
+                cpu.vector.vstart = i;
+                return Err(e);
+            }
+        }
+    }
+
+    if !store{
+        for field in 0..nf{
+            let vl = cpu.vector.vl;
+            fill_tail(&mut cpu.vector, &vt, vd + field * field_regs, data_eew, vl);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,423 @@
+// Virtio over MMIO (version 2 of the transport) with split virtqueues, the
+// devices exchange buffers with the driver through rings in the guest memory
+
+use super::device::Device;
+use super::memory::Memory;
+
+/// Size of the register window of a virtio-mmio device
+pub const VIRTIO_MMIO_SIZE: u64 = 0x1000;
+
+const MAGIC_VALUE: u32 = 0x7472_6976;
+const VERSION: u32 = 2;
+const VENDOR_ID: u32 = 0x554D_4551;
+
+//Registers offsets
+const REG_MAGIC_VALUE: u64 = 0x000;
+const REG_VERSION: u64 = 0x004;
+const REG_DEVICE_ID: u64 = 0x008;
+const REG_VENDOR_ID: u64 = 0x00C;
+const REG_DEVICE_FEATURES: u64 = 0x010;
+const REG_DEVICE_FEATURES_SEL: u64 = 0x014;
+const REG_DRIVER_FEATURES: u64 = 0x020;
+const REG_DRIVER_FEATURES_SEL: u64 = 0x024;
+const REG_QUEUE_SEL: u64 = 0x030;
+const REG_QUEUE_NUM_MAX: u64 = 0x034;
+const REG_QUEUE_NUM: u64 = 0x038;
+const REG_QUEUE_READY: u64 = 0x044;
+const REG_QUEUE_NOTIFY: u64 = 0x050;
+const REG_INTERRUPT_STATUS: u64 = 0x060;
+const REG_INTERRUPT_ACK: u64 = 0x064;
+const REG_STATUS: u64 = 0x070;
+const REG_QUEUE_DESC_LOW: u64 = 0x080;
+const REG_QUEUE_DESC_HIGH: u64 = 0x084;
+const REG_QUEUE_DRIVER_LOW: u64 = 0x090;
+const REG_QUEUE_DRIVER_HIGH: u64 = 0x094;
+const REG_QUEUE_DEVICE_LOW: u64 = 0x0A0;
+const REG_QUEUE_DEVICE_HIGH: u64 = 0x0A4;
+const REG_CONFIG_GENERATION: u64 = 0x0FC;
+const REG_CONFIG: u64 = 0x100;
+
+/// Feature every device of a version 2 transport offers
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+const STATUS_DRIVER_OK: u32 = 4;
+
+const INTERRUPT_USED_BUFFER: u32 = 1 << 0;
+
+/// Largest queue the driver can set up
+pub const QUEUE_NUM_MAX: u16 = 256;
+
+//Descriptor flags
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+const DESC_SIZE: u64 = 16;
+
+/// Split virtqueue, the addresses are the guest physical ones
+#[derive(Debug, Clone, Default)]
+pub struct Queue{
+    pub num: u16,
+    pub ready: bool,
+    pub desc: u64,
+    /// Available ring, written by the driver
+    pub driver: u64,
+    /// Used ring, written by the device
+    pub device: u64,
+    /// Next entry of the available ring to consume
+    last_avail: u16,
+}
+
+/// Descriptor chain made available by the driver, the buffers the device
+/// reads from come before the ones it writes to
+#[derive(Debug, Clone)]
+pub struct Chain{
+    pub head: u16,
+    pub readable: Vec<(u64, u32)>,
+    pub writable: Vec<(u64, u32)>,
+}
+
+impl Queue{
+    fn read_u16(memory: &Memory, at: u64) -> Option<u16>{
+        let mut buf = [0u8; 2];
+        memory.read(at, &mut buf).ok()?;
+        Some(u16::from_le_bytes(buf))
+    }
+
+    fn write_bytes(memory: &mut Memory, at: u64, buf: &[u8]) -> Option<()>{
+        memory.write(at, buf).ok()
+    }
+
+    /// Returns true if the driver made buffers available
+    pub fn has_available(&self, memory: &Memory) -> bool{
+        self.ready && self.num != 0 &&
+            Self::read_u16(memory, self.driver.wrapping_add(2)).is_some_and(|idx| idx != self.last_avail)
+    }
+
+    /// Take the next available chain. A malformed chain comes without
+    /// buffers, it is still given back to the driver
+    pub fn pop(&mut self, memory: &Memory) -> Option<Chain>{
+        if !self.has_available(memory){
+            return None;
+        }
+
+        let slot = self.driver.wrapping_add(4 + 2 * (self.last_avail % self.num) as u64);
+        self.last_avail = self.last_avail.wrapping_add(1);
+        let head = Self::read_u16(memory, slot)?;
+
+        let mut chain = Chain{ head, readable: Vec::new(), writable: Vec::new() };
+        let malformed = Chain{ head, readable: Vec::new(), writable: Vec::new() };
+        let mut index = head;
+        //A chain is at most as long as the queue, anything longer loops
+        for _ in 0..self.num{
+            if index >= self.num{
+                return Some(malformed);
+            }
+
+            let at = self.desc.wrapping_add(DESC_SIZE * index as u64);
+            let mut desc = [0u8; DESC_SIZE as usize];
+            if memory.read(at, &mut desc).is_err(){
+                return Some(malformed);
+            }
+
+            let addr = u64::from_le_bytes([desc[0], desc[1], desc[2], desc[3], desc[4], desc[5], desc[6], desc[7]]);
+            let len = u32::from_le_bytes([desc[8], desc[9], desc[10], desc[11]]);
+            let flags = u16::from_le_bytes([desc[12], desc[13]]);
+            let next = u16::from_le_bytes([desc[14], desc[15]]);
+
+            if flags & DESC_F_WRITE != 0{
+                chain.writable.push((addr, len));
+            }
+            else{
+                chain.readable.push((addr, len));
+            }
+
+            if flags & DESC_F_NEXT == 0{
+                return Some(chain);
+            }
+            index = next;
+        }
+        Some(malformed)
+    }
+
+    /// Give the chain back to the driver, len is the number of bytes written
+    pub fn push_used(&mut self, memory: &mut Memory, head: u16, len: u32) -> Option<()>{
+        let idx = Self::read_u16(memory, self.device.wrapping_add(2))?;
+
+        let mut element = [0u8; 8];
+        element[..4].copy_from_slice(&(head as u32).to_le_bytes());
+        element[4..].copy_from_slice(&len.to_le_bytes());
+        let slot = self.device.wrapping_add(4 + 8 * (idx % self.num) as u64);
+        Self::write_bytes(memory, slot, &element)?;
+
+        //The index is published after the element
+        Self::write_bytes(memory, self.device.wrapping_add(2), &idx.wrapping_add(1).to_le_bytes())
+    }
+}
+
+impl Chain{
+    /// Concatenation of the readable buffers
+    pub fn read(&self, memory: &Memory) -> Option<Vec<u8>>{
+        let mut data = Vec::new();
+        for &(addr, len) in &self.readable{
+            if !memory.is_mapped(addr, len as u64){
+                return None;
+            }
+            let start = data.len();
+            data.resize(start + len as usize, 0);
+            memory.read(addr, &mut data[start..]).ok()?;
+        }
+        Some(data)
+    }
+
+    pub fn writable_len(&self) -> usize{
+        self.writable.iter().map(|&(_, len)| len as usize).sum()
+    }
+
+    /// Spread data over the writable buffers, returns the number of bytes
+    /// written
+    pub fn write(&self, memory: &mut Memory, data: &[u8]) -> Option<u32>{
+        self.write_at(memory, 0, data)
+    }
+
+    /// Like write, starting offset bytes into the writable buffers
+    pub fn write_at(&self, memory: &mut Memory, offset: usize, data: &[u8]) -> Option<u32>{
+        let mut skip = offset;
+        let mut written = 0;
+        for &(addr, len) in &self.writable{
+            if written == data.len(){
+                break;
+            }
+            let len = len as usize;
+            if skip >= len{
+                skip -= len;
+                continue;
+            }
+            let size = (len - skip).min(data.len() - written);
+            memory.write(addr.wrapping_add(skip as u64), &data[written..written + size]).ok()?;
+            written += size;
+            skip = 0;
+        }
+        Some(written as u32)
+    }
+}
+
+/// Device specific part of a virtio device
+pub trait VirtioDevice{
+    fn device_id(&self) -> u32;
+
+    /// Features offered to the driver, VIRTIO_F_VERSION_1 is added by the
+    /// transport
+    fn features(&self) -> u64;
+
+    fn queue_count(&self) -> usize;
+
+    /// Read the device configuration space
+    fn read_config(&self, offset: u64, buf: &mut [u8]);
+
+    /// Handle the buffers available on a queue, returns true if some were
+    /// used
+    fn process_queue(&mut self, index: usize, queue: &mut Queue, memory: &mut Memory) -> bool;
+
+    /// Returns true if the queue must be processed without being notified,
+    /// when host input is waiting for instance
+    fn wants_poll(&self, _index: usize) -> bool{
+        false
+    }
+
+    fn save_state(&mut self){}
+
+    fn reset_to_saved_state(&mut self){}
+}
+
+/// Registers of the transport, reset when the driver writes 0 to the status
+#[derive(Debug, Clone)]
+struct Transport{
+    device_features_sel: u32,
+    driver_features: u64,
+    driver_features_sel: u32,
+    queue_sel: u32,
+    queues: Vec<Queue>,
+    status: u32,
+    interrupt_status: u32,
+    /// Bit i is set when queue i has been notified
+    notified: u32,
+}
+
+impl Transport{
+    fn new(queue_count: usize) -> Transport{
+        Transport{
+            device_features_sel: 0,
+            driver_features: 0,
+            driver_features_sel: 0,
+            queue_sel: 0,
+            queues: vec![Queue::default(); queue_count],
+            status: 0,
+            interrupt_status: 0,
+            notified: 0,
+        }
+    }
+}
+
+pub struct VirtioMmio<D: VirtioDevice>{
+    pub device: D,
+    transport: Transport,
+    saved_transport: Option<Transport>,
+}
+
+impl<D: VirtioDevice> VirtioMmio<D>{
+    pub fn new(device: D) -> VirtioMmio<D>{
+        let transport = Transport::new(device.queue_count());
+        VirtioMmio{
+            device,
+            transport,
+            saved_transport: None,
+        }
+    }
+
+    fn features(&self) -> u64{
+        self.device.features() | VIRTIO_F_VERSION_1
+    }
+
+    fn queue(&mut self) -> Option<&mut Queue>{
+        self.transport.queues.get_mut(self.transport.queue_sel as usize)
+    }
+
+    fn read_register(&mut self, offset: u64) -> u32{
+        let t = &self.transport;
+        let queue = t.queues.get(t.queue_sel as usize);
+
+        match offset{
+            REG_MAGIC_VALUE => MAGIC_VALUE,
+            REG_VERSION => VERSION,
+            REG_DEVICE_ID => self.device.device_id(),
+            REG_VENDOR_ID => VENDOR_ID,
+            REG_DEVICE_FEATURES => match t.device_features_sel{
+                0 => self.features() as u32,
+                1 => (self.features() >> 32) as u32,
+                _ => 0,
+            },
+            REG_QUEUE_NUM_MAX => queue.map_or(0, |_| QUEUE_NUM_MAX as u32),
+            REG_QUEUE_READY => queue.map_or(0, |q| q.ready as u32),
+            REG_INTERRUPT_STATUS => t.interrupt_status,
+            REG_STATUS => t.status,
+            REG_CONFIG_GENERATION => 0,
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, offset: u64, value: u32){
+        let value64 = value as u64;
+
+        match offset{
+            REG_DEVICE_FEATURES_SEL => self.transport.device_features_sel = value,
+            REG_DRIVER_FEATURES => {
+                let t = &mut self.transport;
+                match t.driver_features_sel{
+                    0 => t.driver_features = (t.driver_features & !0xFFFF_FFFF) | value64,
+                    1 => t.driver_features = (t.driver_features & 0xFFFF_FFFF) | value64 << 32,
+                    _ => {},
+                }
+            },
+            REG_DRIVER_FEATURES_SEL => self.transport.driver_features_sel = value,
+            REG_QUEUE_SEL => self.transport.queue_sel = value,
+            REG_QUEUE_NUM => if let Some(q) = self.queue(){
+                //The size is a power of 2 no larger than the maximum
+                if value.is_power_of_two() && value <= QUEUE_NUM_MAX as u32{
+                    q.num = value as u16;
+                }
+            },
+            REG_QUEUE_READY => if let Some(q) = self.queue(){
+                q.ready = value & 1 != 0;
+            },
+            REG_QUEUE_NOTIFY if (value as usize) < self.transport.queues.len() => {
+                self.transport.notified |= 1 << value;
+            },
+            REG_INTERRUPT_ACK => self.transport.interrupt_status &= !value,
+            REG_STATUS => {
+                if value == 0{
+                    self.transport = Transport::new(self.device.queue_count());
+                }
+                else{
+                    self.transport.status = value;
+                }
+            },
+            REG_QUEUE_DESC_LOW => if let Some(q) = self.queue(){
+                q.desc = (q.desc & !0xFFFF_FFFF) | value64;
+            },
+            REG_QUEUE_DESC_HIGH => if let Some(q) = self.queue(){
+                q.desc = (q.desc & 0xFFFF_FFFF) | value64 << 32;
+            },
+            REG_QUEUE_DRIVER_LOW => if let Some(q) = self.queue(){
+                q.driver = (q.driver & !0xFFFF_FFFF) | value64;
+            },
+            REG_QUEUE_DRIVER_HIGH => if let Some(q) = self.queue(){
+                q.driver = (q.driver & 0xFFFF_FFFF) | value64 << 32;
+            },
+            REG_QUEUE_DEVICE_LOW => if let Some(q) = self.queue(){
+                q.device = (q.device & !0xFFFF_FFFF) | value64;
+            },
+            REG_QUEUE_DEVICE_HIGH => if let Some(q) = self.queue(){
+                q.device = (q.device & 0xFFFF_FFFF) | value64 << 32;
+            },
+            _ => {},
+        }
+    }
+}
+
+/// The registers are 32 bits wide, the configuration space can be accessed
+/// with any size
+impl<D: VirtioDevice> Device for VirtioMmio<D>{
+    fn read(&mut self, offset: u64, buf: &mut [u8]){
+        buf.iter_mut().for_each(|b| *b = 0);
+
+        if offset >= REG_CONFIG{
+            self.device.read_config(offset - REG_CONFIG, buf);
+            return;
+        }
+
+        let value = self.read_register(offset & !0b11).to_le_bytes();
+        let len = buf.len().min(4);
+        buf[..len].copy_from_slice(&value[..len]);
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]){
+        //The configuration space of the devices here is read only
+        if offset >= REG_CONFIG{
+            return;
+        }
+
+        let mut value = [0u8; 4];
+        let len = buf.len().min(4);
+        value[..len].copy_from_slice(&buf[..len]);
+        self.write_register(offset & !0b11, u32::from_le_bytes(value));
+    }
+
+    fn interrupt_pending(&self) -> bool{
+        self.transport.interrupt_status != 0
+    }
+
+    fn process(&mut self, memory: &mut Memory){
+        if self.transport.status & STATUS_DRIVER_OK == 0{
+            return;
+        }
+
+        let notified = std::mem::take(&mut self.transport.notified);
+        for (index, queue) in self.transport.queues.iter_mut().enumerate(){
+            let pending = notified & (1 << index) != 0 || self.device.wants_poll(index);
+            if pending && queue.ready && self.device.process_queue(index, queue, memory){
+                self.transport.interrupt_status |= INTERRUPT_USED_BUFFER;
+            }
+        }
+    }
+
+    fn save_state(&mut self){
+        self.saved_transport = Some(self.transport.clone());
+        self.device.save_state();
+    }
+
+    fn reset_to_saved_state(&mut self){
+        if let Some(saved) = &self.saved_transport{
+            self.transport = saved.clone();
+        }
+        self.device.reset_to_saved_state();
+    }
+}
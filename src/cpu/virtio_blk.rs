@@ -0,0 +1,206 @@
+// Virtio block device backed by a disk image on the host. With the copy on
+// write overlay the image is never modified, the written sectors are kept in
+// memory and rolled back with the snapshots
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use super::memory::Memory;
+use super::virtio::{Queue, VirtioDevice};
+
+const DEVICE_ID_BLOCK: u32 = 2;
+pub const SECTOR_SIZE: u64 = 512;
+
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+//Request types
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+//Request status
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+/// type, reserved and sector fields
+const REQUEST_HEADER_SIZE: usize = 16;
+const DEVICE_ID: &[u8] = b"riscv-emu";
+
+pub struct VirtioBlk{
+    file: File,
+    /// Size of the disk in sectors
+    capacity: u64,
+    copy_on_write: bool,
+    /// Sectors written since the disk was opened, only with copy on write
+    overlay: HashMap<u64, Vec<u8>>,
+
+    saved_overlay: Option<HashMap<u64, Vec<u8>>>,
+    /// Sectors written since the snapshot
+    dirty: HashSet<u64>,
+}
+
+impl VirtioBlk{
+    /// Open a disk image, without copy on write the guest writes go to the
+    /// image and are not rolled back with the snapshots
+    pub fn open(path: &Path, copy_on_write: bool) -> io::Result<VirtioBlk>{
+        let file = OpenOptions::new().read(true).write(!copy_on_write).open(path)?;
+        let capacity = file.metadata()?.len() / SECTOR_SIZE;
+
+        Ok(VirtioBlk{
+            file,
+            capacity,
+            copy_on_write,
+            overlay: HashMap::new(),
+            saved_overlay: None,
+            dirty: HashSet::new(),
+        })
+    }
+
+    fn read_sector(&mut self, sector: u64, buf: &mut [u8]) -> io::Result<()>{
+        if let Some(data) = self.overlay.get(&sector){
+            buf.copy_from_slice(data);
+            return Ok(());
+        }
+        self.file.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+        self.file.read_exact(buf)
+    }
+
+    fn write_sector(&mut self, sector: u64, buf: &[u8]) -> io::Result<()>{
+        if self.copy_on_write{
+            self.overlay.insert(sector, buf.to_vec());
+            self.dirty.insert(sector);
+            return Ok(());
+        }
+        self.file.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+        self.file.write_all(buf)
+    }
+
+    /// Returns true if the len bytes starting at sector are on the disk
+    fn in_range(&self, sector: u64, len: usize) -> bool{
+        len as u64 % SECTOR_SIZE == 0 &&
+            sector.checked_add(len as u64 / SECTOR_SIZE).is_some_and(|end| end <= self.capacity)
+    }
+
+    /// Execute a request, returns the data for the driver and the status
+    fn request(&mut self, header: &[u8], data: &[u8], data_len: usize) -> (Vec<u8>, u8){
+        let kind = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let mut sector = [0u8; 8];
+        sector.copy_from_slice(&header[8..16]);
+        let sector = u64::from_le_bytes(sector);
+
+        match kind{
+            VIRTIO_BLK_T_IN => {
+                if !self.in_range(sector, data_len){
+                    return (Vec::new(), VIRTIO_BLK_S_IOERR);
+                }
+                let mut buf = vec![0u8; data_len];
+                for (i, chunk) in buf.chunks_mut(SECTOR_SIZE as usize).enumerate(){
+                    if self.read_sector(sector + i as u64, chunk).is_err(){
+                        return (Vec::new(), VIRTIO_BLK_S_IOERR);
+                    }
+                }
+                (buf, VIRTIO_BLK_S_OK)
+            },
+            VIRTIO_BLK_T_OUT => {
+                if !self.in_range(sector, data.len()){
+                    return (Vec::new(), VIRTIO_BLK_S_IOERR);
+                }
+                for (i, chunk) in data.chunks(SECTOR_SIZE as usize).enumerate(){
+                    if self.write_sector(sector + i as u64, chunk).is_err(){
+                        return (Vec::new(), VIRTIO_BLK_S_IOERR);
+                    }
+                }
+                (Vec::new(), VIRTIO_BLK_S_OK)
+            },
+            VIRTIO_BLK_T_FLUSH => {
+                let status = if self.file.flush().is_ok() { VIRTIO_BLK_S_OK } else { VIRTIO_BLK_S_IOERR };
+                (Vec::new(), status)
+            },
+            VIRTIO_BLK_T_GET_ID => {
+                let mut id = DEVICE_ID.to_vec();
+                id.truncate(data_len);
+                (id, VIRTIO_BLK_S_OK)
+            },
+            _ => (Vec::new(), VIRTIO_BLK_S_UNSUPP),
+        }
+    }
+}
+
+impl VirtioDevice for VirtioBlk{
+    fn device_id(&self) -> u32{
+        DEVICE_ID_BLOCK
+    }
+
+    fn features(&self) -> u64{
+        VIRTIO_BLK_F_FLUSH
+    }
+
+    fn queue_count(&self) -> usize{
+        1
+    }
+
+    /// Only the capacity is exposed
+    fn read_config(&self, offset: u64, buf: &mut [u8]){
+        let config = self.capacity.to_le_bytes();
+        for (i, b) in buf.iter_mut().enumerate(){
+            *b = config.get(offset as usize + i).copied().unwrap_or(0);
+        }
+    }
+
+    fn process_queue(&mut self, _index: usize, queue: &mut Queue, memory: &mut Memory) -> bool{
+        let mut used = false;
+
+        while let Some(chain) = queue.pop(memory){
+            //The last writable byte receives the status, a malformed request
+            //fails with an I/O error. Without room for the status the chain
+            //is given back untouched
+            let writable = chain.writable_len();
+            let written = if writable == 0{
+                0
+            }
+            else{
+                //The reply is at most the writable bytes before the status
+                let (reply, status) = match chain.read(memory){
+                    Some(readable) if readable.len() >= REQUEST_HEADER_SIZE => {
+                        let (header, data) = readable.split_at(REQUEST_HEADER_SIZE);
+                        self.request(header, data, writable - 1)
+                    },
+                    _ => (Vec::new(), VIRTIO_BLK_S_IOERR),
+                };
+                let delivered = chain.write(memory, &reply).is_some() &&
+                    chain.write_at(memory, writable - 1, &[status]).is_some();
+                if delivered { writable as u32 } else { 0 }
+            };
+            if queue.push_used(memory, chain.head, written).is_some(){
+                used = true;
+            }
+        }
+        used
+    }
+
+    fn save_state(&mut self){
+        if self.copy_on_write{
+            self.saved_overlay = Some(self.overlay.clone());
+            self.dirty.clear();
+        }
+    }
+
+    /// Only the sectors written since the snapshot are restored
+    fn reset_to_saved_state(&mut self){
+        let saved = match &self.saved_overlay{
+            Some(saved) => saved,
+            None => return,
+        };
+
+        for sector in self.dirty.drain(){
+            match saved.get(&sector){
+                Some(data) => self.overlay.insert(sector, data.clone()),
+                None => self.overlay.remove(&sector),
+            };
+        }
+    }
+}
@@ -0,0 +1,109 @@
+// Virtio console with a single port, the transmitted bytes go to a host sink
+// and the host input is delivered in the receive buffers of the driver
+
+use std::collections::VecDeque;
+
+use super::device::HostOutput;
+use super::memory::Memory;
+use super::virtio::{Queue, VirtioDevice};
+
+const DEVICE_ID_CONSOLE: u32 = 3;
+
+const RECEIVE_QUEUE: usize = 0;
+const TRANSMIT_QUEUE: usize = 1;
+
+pub struct VirtioConsole{
+    pub output: HostOutput,
+    input: VecDeque<u8>,
+    saved_input: Option<VecDeque<u8>>,
+}
+
+impl VirtioConsole{
+    pub fn new(output: HostOutput) -> VirtioConsole{
+        VirtioConsole{
+            output,
+            input: VecDeque::new(),
+            saved_input: None,
+        }
+    }
+
+    /// Queue bytes to be received by the guest
+    pub fn push_input(&mut self, data: &[u8]){
+        self.input.extend(data);
+    }
+
+    /// Fill the receive buffers with the pending input
+    fn receive(&mut self, queue: &mut Queue, memory: &mut Memory) -> bool{
+        let mut used = false;
+
+        while !self.input.is_empty(){
+            let chain = match queue.pop(memory){
+                Some(chain) => chain,
+                None => break,
+            };
+
+            let len = chain.writable_len().min(self.input.len());
+            let data: Vec<u8> = self.input.drain(..len).collect();
+            let written = chain.write(memory, &data).unwrap_or(0);
+            if queue.push_used(memory, chain.head, written).is_some(){
+                used = true;
+            }
+        }
+        used
+    }
+
+    fn transmit(&mut self, queue: &mut Queue, memory: &mut Memory) -> bool{
+        let mut used = false;
+
+        while let Some(chain) = queue.pop(memory){
+            if let Some(data) = chain.read(memory){
+                self.output.write(&data);
+            }
+            if queue.push_used(memory, chain.head, 0).is_some(){
+                used = true;
+            }
+        }
+        used
+    }
+}
+
+impl VirtioDevice for VirtioConsole{
+    fn device_id(&self) -> u32{
+        DEVICE_ID_CONSOLE
+    }
+
+    fn features(&self) -> u64{
+        0
+    }
+
+    fn queue_count(&self) -> usize{
+        2
+    }
+
+    /// No feature exposes the configuration, it reads as zero
+    fn read_config(&self, _offset: u64, buf: &mut [u8]){
+        buf.iter_mut().for_each(|b| *b = 0);
+    }
+
+    fn process_queue(&mut self, index: usize, queue: &mut Queue, memory: &mut Memory) -> bool{
+        match index{
+            RECEIVE_QUEUE => self.receive(queue, memory),
+            TRANSMIT_QUEUE => self.transmit(queue, memory),
+            _ => false,
+        }
+    }
+
+    fn wants_poll(&self, index: usize) -> bool{
+        index == RECEIVE_QUEUE && !self.input.is_empty()
+    }
+
+    fn save_state(&mut self){
+        self.saved_input = Some(self.input.clone());
+    }
+
+    fn reset_to_saved_state(&mut self){
+        if let Some(saved) = &self.saved_input{
+            self.input = saved.clone();
+        }
+    }
+}
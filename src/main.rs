@@ -1,9 +1,115 @@
 pub mod cpu;
+#[cfg(test)]
+mod test;
 
 use cpu::emu::Emu;
-use std::path::PathBuf;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// Program run when none is given
+const DEFAULT_PROGRAM: &str = "test/real/main";
+/// Command line of the booted kernel when none is given
+const DEFAULT_CMDLINE: &str = "console=ttyS0";
+
+const USAGE: &str = "Usage: emu [options] [program [args...]]
+       emu --kernel IMAGE [options]
+
+Options:
+    --sysroot DIR            Directory holding the dynamic linker and the libraries
+    --isa ISA                ISA string of the hart, like rv64gc_zba_zbb
+    --stack TOP,SIZE,LIMIT   Stack end, bytes mapped at start and growth limit
+    --env NAME=VALUE         Variable of the environment, can be repeated
+    --kernel IMAGE           Boot a Linux Image instead of running a program
+    --initrd FILE            Initramfs of the kernel
+    --cmdline CMDLINE        Command line of the kernel, console=ttyS0 by default
+    --disk IMAGE             Disk of the kernel, its writes are rolled back with the snapshots
+    --snapshot ADDR          Snapshot the kernel when it reaches ADDR
+    --end ADDR               Reset the kernel to the snapshot when it reaches ADDR
+    --help                   Print this message";
+
+/// Print the error and the usage then stop
+fn usage_error(error: &str) -> !{
+    eprintln!("{}\n\n{}", error, USAGE);
+    process::exit(2);
+}
+
+/// Decimal or 0x prefixed hexadecimal number
+fn parse_number(s: &str) -> Option<u64>{
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")){
+        Some(hex) => u64::from_str_radix(&hex.replace('_', ""), 16).ok(),
+        None => s.replace('_', "").parse().ok(),
+    }
+}
 
 fn main(){
     let mut emu = Emu::new();
-    emu.exec_elf(&PathBuf::from("test/real/main"));
-}   
\ No newline at end of file
+    let mut env = Vec::new();
+    let mut kernel = None;
+    let mut initrd = None;
+    let mut cmdline = String::from(DEFAULT_CMDLINE);
+    let mut snapshot = None;
+    let mut end = None;
+
+    //The options come first, the program and its arguments after them
+    let mut args = env::args().skip(1);
+    let mut program = None;
+    while let Some(arg) = args.next(){
+        let mut value = |name: &str| args.next()
+            .unwrap_or_else(|| usage_error(&format!("Missing value for {}", name)));
+        match arg.as_str(){
+            "--sysroot" => emu.set_sysroot(Path::new(&value("--sysroot"))),
+            "--isa" => emu.set_isa(&value("--isa")),
+            "--stack" => {
+                let stack = value("--stack");
+                match stack.split(',').map(parse_number).collect::<Option<Vec<u64>>>().as_deref(){
+                    Some(&[top, size, limit]) => emu.set_stack(top, size, limit),
+                    _ => usage_error(&format!("Invalid stack {:?}", stack)),
+                }
+            },
+            "--env" => env.push(value("--env")),
+            "--kernel" => kernel = Some(PathBuf::from(value("--kernel"))),
+            "--initrd" => initrd = Some(PathBuf::from(value("--initrd"))),
+            "--cmdline" => cmdline = value("--cmdline"),
+            "--disk" => emu.attach_disk(&PathBuf::from(value("--disk")), true),
+            "--snapshot" | "--end" => {
+                let addr = value(&arg);
+                let addr = parse_number(&addr)
+                    .unwrap_or_else(|| usage_error(&format!("Invalid address {:?}", addr)));
+                if arg == "--snapshot" { snapshot = Some(addr) } else { end = Some(addr) }
+            },
+            "--help" => {
+                println!("{}", USAGE);
+                return;
+            },
+            "--" => {
+                program = args.next();
+                break;
+            },
+            _ if arg.starts_with("--") => usage_error(&format!("Unknown option {}", arg)),
+            _ => {
+                program = Some(arg);
+                break;
+            },
+        }
+    }
+
+    if let Some(kernel) = kernel{
+        if program.is_some(){
+            usage_error("A program cannot be run with a kernel");
+        }
+        match (snapshot, end){
+            (Some(snapshot), end) => emu.set_kernel_snapshot(snapshot, end),
+            (None, Some(_)) => usage_error("--end needs --snapshot"),
+            (None, None) => {},
+        }
+        emu.boot_linux(&kernel, initrd.as_ref(), &cmdline);
+        return;
+    }
+
+    let program = program.unwrap_or_else(|| String::from(DEFAULT_PROGRAM));
+    let argv: Vec<String> = Some(program.clone()).into_iter().chain(args).collect();
+    emu.set_args(&argv.iter().map(String::as_str).collect::<Vec<_>>());
+    emu.set_env(&env.iter().map(String::as_str).collect::<Vec<_>>());
+    emu.exec_elf(&PathBuf::from(program));
+}
@@ -1,44 +1,1759 @@
-use cpu::cpu::CPU;
-use cpu::elf_reader;
-use cpu::fuzzer::Fuzzer;
+// Tests of the hart. The riscv-tests binaries found in test/riscv-tests/ are
+// run too, nothing is run when it is missing
 
-use std::path::PathBuf;
-use std::fs::read_dir;
+use crate::cpu::clint::{Clint, CLINT_BASE};
+use crate::cpu::bitmanip;
+use crate::cpu::compressed;
+use crate::cpu::cpu::{CPU, Xlen};
+use crate::cpu::csr;
+use crate::cpu::device::{Device, HostOutput};
+use crate::cpu::elf_reader::{self, LoadedSegment};
+use crate::cpu::float::{self, FloatFormat, RoundingMode};
+use crate::cpu::fuzzer::{Fuzzer, MAX_WRITE};
+use crate::cpu::heap::{self, Heap, HEAP_BASE, HEAP_SIZE};
+use crate::cpu::isa::Extensions;
+use crate::cpu::memory::{AccessKind, Memory, MemoryError, Permissions, Shadow, Stack, Violation};
+use crate::cpu::mmu::{self, Access, Mmu};
+use crate::cpu::plic::{Plic, CONTEXT_MACHINE, CONTEXT_SUPERVISOR};
+use crate::cpu::process::{self, Process, AT_NULL, AT_PAGESZ, AT_RANDOM, EBADF, EFAULT};
+use crate::cpu::trap::{CpuFault, Exception, Privilege};
+use crate::cpu::uart::Uart;
+use crate::cpu::virtio::{Queue, VirtioDevice};
+use crate::cpu::virtio_blk::{VirtioBlk, SECTOR_SIZE};
 
-fn bp_end_of_test(cpu: &mut CPU){
+use std::convert::TryInto;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::fs::{self, read_dir};
+
+/// Where the programs of the execution tests are loaded
+const CODE_BASE: u64 = 0x1000;
+const CODE_SIZE: u64 = 0x1000;
+/// Data read and written by the execution tests
+const DATA_BASE: u64 = 0x3000;
+
+/// ADDI x0, x0, 0
+const NOP: u32 = 0x0000_0013;
+const OPCODE_OP: u32 = 0b011_0011;
+const OPCODE_OP_32: u32 = 0b011_1011;
+const OPCODE_OP_IMM: u32 = 0b001_0011;
+const OPCODE_OP_IMM_32: u32 = 0b001_1011;
+const FUNCT7_MULDIV: u32 = 0b000_0001;
+const OPCODE_AMO: u32 = 0b010_1111;
+const OPCODE_OP_FP: u32 = 0b101_0011;
+const OPCODE_SYSTEM: u32 = 0b111_0011;
+const OPCODE_OP_V: u32 = 0b101_0111;
+
+fn bp_end_of_test(cpu: &mut CPU) -> Result<(), CpuFault>{
     println!("Tests OK");
     cpu.exit = true;
+    Ok(())
 }
 
-fn bp_test_error(cpu: &mut CPU){
+fn bp_test_error(cpu: &mut CPU) -> Result<(), CpuFault>{
     println!("{:?}", cpu);
     panic!("Fail on test: {:#8?}", cpu.registers.common[3]);
 }
 
-fn start_test_elf(path: &PathBuf){
+/// Run the instructions from CODE_BASE until the end of the code
+fn run_code(cpu: &mut CPU, code: &[u32]){
+    let bytes: Vec<u8> = code.iter().flat_map(|instr| instr.to_le_bytes()).collect();
+    run_bytes(cpu, &bytes);
+}
+
+/// Run the code from CODE_BASE until its end, it can mix compressed and
+/// 32 bits instructions
+fn run_bytes(cpu: &mut CPU, code: &[u8]){
+    try_run_bytes(cpu, code).unwrap_or_else(|fault| panic!("{:X?}", fault));
+}
+
+/// Same as run_bytes but the fault stopping the execution is returned
+fn try_run_bytes(cpu: &mut CPU, code: &[u8]) -> Result<(), CpuFault>{
+    let mut bytes = code.to_vec();
+    let end = CODE_BASE + bytes.len() as u64;
+    bytes.resize(CODE_SIZE as usize, 0);
+    cpu.memory.allocate(CODE_BASE, CODE_SIZE, &bytes, Permissions::RWX);
+    cpu.set_breakpoint(end, bp_end_of_test);
+    cpu.execute(CODE_BASE, Rc::new(RefCell::new(Fuzzer::new())))
+}
+
+/// Map a page of data initialized with the words at DATA_BASE
+fn map_data(cpu: &mut CPU, words: &[u64]){
+    let mut bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    bytes.resize(CODE_SIZE as usize, 0);
+    cpu.memory.allocate(DATA_BASE, CODE_SIZE, &bytes, Permissions::RW);
+}
+
+fn read_u64(memory: &Memory, at: u64) -> u64{
+    let mut bytes = [0u8; 8];
+    memory.read(at, &mut bytes).unwrap();
+    u64::from_le_bytes(bytes)
+}
+
+fn read_cstring(memory: &Memory, mut at: u64) -> String{
+    let mut bytes = Vec::new();
+    let mut byte = [0u8];
+    loop{
+        memory.read(at, &mut byte).unwrap();
+        if byte[0] == 0{
+            return String::from_utf8(bytes).unwrap();
+        }
+        bytes.push(byte[0]);
+        at += 1;
+    }
+}
+
+fn r_type(opcode: u32, funct3: u32, funct7: u32, rd: u32, rs1: u32, rs2: u32) -> u32{
+    funct7 << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+/// SD rs2, offset(rs1)
+fn sd(rs2: u32, rs1: u32, offset: u32) -> u32{
+    (offset >> 5) << 25 | rs2 << 20 | rs1 << 15 | 0b011 << 12 | (offset & 0x1F) << 7 | 0b010_0011
+}
+
+fn amo(funct5: u32, funct3: u32, rd: u32, rs1: u32, rs2: u32) -> u32{
+    r_type(OPCODE_AMO, funct3, funct5 << 2, rd, rs1, rs2)
+}
+
+/// CSRRW, CSRRS... rs1 is the immediate of CSRRWI, CSRRSI and CSRRCI
+fn csr_op(funct3: u32, rd: u32, csr: u16, rs1: u32) -> u32{
+    (csr as u32) << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | OPCODE_SYSTEM
+}
+
+/// Hart running without syscall emulation, the traps go to the handlers of
+/// mtvec and stvec
+fn bare_metal_cpu(privilege: Privilege) -> CPU{
+    let mut cpu = CPU::new(false);
+    cpu.syscall_emulation = false;
+    cpu.privilege = privilege;
+    cpu
+}
+
+/// Two instructions, one of them trapping, followed at CODE_BASE + 8 by a
+/// handler reading the cause, epc and tval of the trap in x10, x11 and x12
+fn trap_test(first: u32, second: u32, cause: u16, epc: u16, tval: u16) -> [u32; 5]{
+    [first, second, csr_op(0b010, 10, cause, 0), csr_op(0b010, 11, epc, 0), csr_op(0b010, 12, tval, 0)]
+}
+
+/// Result of the register-register instruction funct3 with rs1 = a and
+/// rs2 = b
+fn exec_op(mut cpu: CPU, opcode: u32, funct3: u32, funct7: u32, a: u64, b: u64) -> u64{
+    cpu.registers.common[1] = a;
+    cpu.registers.common[2] = b;
+    run_code(&mut cpu, &[r_type(opcode, funct3, funct7, 3, 1, 2)]);
+    cpu.registers.common[3]
+}
+
+#[test]
+fn branches(){
+    let mut cpu = CPU::new(false);
+    run_code(&mut cpu, &[
+        0x0010_0293, //addi t0, x0, 1
+        0x0202_9293, //slli t0, t0, 32
+        0x0010_0313, //addi t1, x0, 1
+        0x0000_0513, //addi a0, x0, 0
+        0x0053_4463, //blt t1, t0, 8, only the upper halves differ
+        0x0080_006f, //jal x0, 8
+        0x0010_0513, //addi a0, x0, 1
+        0x0053_35b3, //sltu a1, t1, t0
+        0x0000_0397, //auipc t2, 0
+        0x0113_8393, //addi t2, t2, 17
+        0x0003_8067, //jalr x0, 0(t2), lands on the second addi
+        0x0630_0613, //addi a2, x0, 99
+        0x0016_0613, //addi a2, a2, 1
+    ]);
+    assert_eq!(cpu.registers.common[10], 1);
+    assert_eq!(cpu.registers.common[11], 1);
+    assert_eq!(cpu.registers.common[12], 1);
+
+    //The comparisons use the whole registers
+    assert_eq!(exec_op(CPU::new(false), OPCODE_OP, 0b010, 0, 1 << 32, 1), 0);
+    assert_eq!(exec_op(CPU::new(false), OPCODE_OP, 0b010, 0, -(1i64 << 32) as u64, 1), 1);
+}
+
+/// Run the immediate form of an instruction with x1=a, returns x3
+fn exec_imm(mut cpu: CPU, opcode: u32, funct3: u32, imm12: u32, a: u64) -> u64{
+    cpu.registers.common[1] = a;
+    run_code(&mut cpu, &[imm12 << 20 | 1 << 15 | funct3 << 12 | 3 << 7 | opcode]);
+    cpu.registers.common[3]
+}
+
+#[test]
+fn multiply(){
+    let mul = |funct3, a: i64, b: i64| exec_op(CPU::new(false), OPCODE_OP, funct3, FUNCT7_MULDIV, a as u64, b as u64) as i64;
+    assert_eq!(mul(0b000, -3, 7), -21);
+    assert_eq!(mul(0b000, i64::MAX, 2), -2);
+    //MULH
+    assert_eq!(mul(0b001, -1, -1), 0);
+    assert_eq!(mul(0b001, i64::MIN, i64::MIN), 1 << 62);
+    assert_eq!(mul(0b001, i64::MIN, 2), -1);
+    //MULHSU, rs2 is unsigned
+    assert_eq!(mul(0b010, -1, -1), -1);
+    assert_eq!(mul(0b010, 2, -1), 1);
+    //MULHU
+    assert_eq!(mul(0b011, -1, -1), -2);
+    assert_eq!(mul(0b011, 1 << 32, 1 << 32), 1);
+}
+
+#[test]
+fn divide(){
+    let div = |funct3, a: i64, b: i64| exec_op(CPU::new(false), OPCODE_OP, funct3, FUNCT7_MULDIV, a as u64, b as u64) as i64;
+    //DIV and REM round toward zero
+    assert_eq!(div(0b100, 20, -3), -6);
+    assert_eq!(div(0b110, 20, -3), 2);
+    assert_eq!(div(0b110, -20, 3), -2);
+    //DIVU and REMU
+    assert_eq!(div(0b101, -1, 2), i64::MAX);
+    assert_eq!(div(0b111, -1, 16), 15);
+
+    //Division by zero gives all ones and the remainder the dividend
+    for &funct3 in [0b100, 0b101].iter(){
+        assert_eq!(div(funct3, 1234, 0), -1);
+    }
+    for &funct3 in [0b110, 0b111].iter(){
+        assert_eq!(div(funct3, -1234, 0), -1234);
+    }
+    //The signed overflow gives the dividend and a null remainder
+    assert_eq!(div(0b100, i64::MIN, -1), i64::MIN);
+    assert_eq!(div(0b110, i64::MIN, -1), 0);
+}
+
+#[test]
+fn multiply_divide_words(){
+    //The upper halves of the operands are ignored and the results are sign
+    //extended
+    let op = |funct3, a: u64, b: u64| exec_op(CPU::new(false), OPCODE_OP_32, funct3, FUNCT7_MULDIV, a, b);
+    let garbage = 0xDEAD_BEEF << 32;
+    //MULW
+    assert_eq!(op(0b000, garbage | 0x7FFF_FFFF, 2), 0xFFFF_FFFF_FFFF_FFFE);
+    //DIVW, DIVUW, REMW and REMUW
+    assert_eq!(op(0b100, garbage | 0xFFFF_FFEC, 3), -6i64 as u64);
+    assert_eq!(op(0b101, 0xFFFF_FFEC, garbage | 2), 0x7FFF_FFF6);
+    assert_eq!(op(0b101, 0x8000_0000, 1), 0xFFFF_FFFF_8000_0000);
+    assert_eq!(op(0b110, 0xFFFF_FFEC, 3), -2i64 as u64);
+    assert_eq!(op(0b111, 0xFFFF_FFFF, 16), 15);
+
+    //Division by zero and overflow
+    assert_eq!(op(0b100, 7, garbage), u64::MAX);
+    assert_eq!(op(0b101, 7, garbage), u64::MAX);
+    assert_eq!(op(0b110, garbage | 0x8000_0007, garbage), 0xFFFF_FFFF_8000_0007);
+    assert_eq!(op(0b111, garbage | 0x8000_0007, garbage), 0xFFFF_FFFF_8000_0007);
+    assert_eq!(op(0b100, 0x8000_0000, u64::MAX), 0xFFFF_FFFF_8000_0000);
+    assert_eq!(op(0b110, 0x8000_0000, u64::MAX), 0);
+}
+
+#[test]
+fn multiply_divide_rv32(){
+    let rv32 = || {
+        let mut cpu = CPU::new(false);
+        cpu.set_xlen(Xlen::Rv32);
+        cpu
+    };
+    //The registers hold the values sign extended from bit 31
+    let op = |funct3, a: i32, b: i32| exec_op(rv32(), OPCODE_OP, funct3, FUNCT7_MULDIV, a as i64 as u64, b as i64 as u64) as i64;
+    assert_eq!(op(0b000, 0x1_0000, 0x1_0000), 0);
+    assert_eq!(op(0b000, 0x7FFF_FFFF, 2), -2);
+    //MULH
+    assert_eq!(op(0b001, i32::MIN, i32::MIN), 0x4000_0000);
+    assert_eq!(op(0b001, -1, 1), -1);
+    //MULHSU, the second operand is 0xFFFF_FFFF
+    assert_eq!(op(0b010, -1, -1), -1);
+    assert_eq!(op(0b010, 2, -1), 1);
+    //MULHU
+    assert_eq!(op(0b011, -1, -1), -2);
+    //DIV and REM overflow and division by zero
+    assert_eq!(op(0b100, i32::MIN, -1), i32::MIN as i64);
+    assert_eq!(op(0b110, i32::MIN, -1), 0);
+    assert_eq!(op(0b100, 7, 0), -1);
+    assert_eq!(op(0b110, -7, 0), -7);
+    //DIVU and REMU see 32 bits unsigned values
+    assert_eq!(op(0b101, -1, 2), 0x7FFF_FFFF);
+    assert_eq!(op(0b111, -1, 0x10), 15);
+    assert_eq!(op(0b101, 7, 0), -1);
+    assert_eq!(op(0b111, -7, 0), -7);
+}
+
+#[test]
+fn bit_manipulation(){
+    let op = |opcode, funct3, funct7, a: u64, b: u64| exec_op(CPU::new(false), opcode, funct3, funct7, a, b);
+    let imm = |opcode, funct3, imm12, a: u64| exec_imm(CPU::new(false), opcode, funct3, imm12, a);
+
+    //Zba
+    assert_eq!(op(OPCODE_OP, 0b010, 0b001_0000, 5, 7), 17);
+    assert_eq!(op(OPCODE_OP, 0b110, 0b001_0000, 5, 7), 47);
+    assert_eq!(op(OPCODE_OP_32, 0b110, 0b001_0000, 0xFFFF_FFFF_0000_0001, 1), 9);
+    assert_eq!(op(OPCODE_OP_32, 0b000, 0b000_0100, u64::MAX, 1), 0x1_0000_0000);
+    assert_eq!(imm(OPCODE_OP_IMM_32, 0b001, 0b00_0010 << 6 | 4, 0xFFFF_FFFF_8000_0001), 0x8_0000_0010);
+
+    //Zbb
+    assert_eq!(op(OPCODE_OP, 0b111, 0b010_0000, 0b1100, 0b1010), 0b0100);
+    assert_eq!(op(OPCODE_OP, 0b110, 0b010_0000, 0, 1), !1);
+    assert_eq!(op(OPCODE_OP, 0b100, 0b010_0000, 0b1100, 0b1010), !0b0110);
+    assert_eq!(op(OPCODE_OP, 0b100, 0b000_0101, u64::MAX, 1), u64::MAX);
+    assert_eq!(op(OPCODE_OP, 0b101, 0b000_0101, u64::MAX, 1), 1);
+    assert_eq!(op(OPCODE_OP, 0b110, 0b000_0101, u64::MAX, 1), 1);
+    assert_eq!(op(OPCODE_OP, 0b111, 0b000_0101, u64::MAX, 1), u64::MAX);
+    assert_eq!(op(OPCODE_OP, 0b101, 0b011_0000, 1, 1), 1 << 63);
+    assert_eq!(op(OPCODE_OP_32, 0b001, 0b011_0000, 0x8000_0000, 1), 1);
+    //ZEXT.H is PACKW with rs2 = x0
+    assert_eq!(imm(OPCODE_OP_32, 0b100, 0b000_0100 << 5, u64::MAX), 0xFFFF);
+    assert_eq!(imm(OPCODE_OP_IMM, 0b001, 0x600, 1), 63);
+    assert_eq!(imm(OPCODE_OP_IMM, 0b001, 0x601, 0), 64);
+    assert_eq!(imm(OPCODE_OP_IMM, 0b001, 0x602, 0xFF), 8);
+    assert_eq!(imm(OPCODE_OP_IMM, 0b001, 0x604, 0x80), -128i64 as u64);
+    assert_eq!(imm(OPCODE_OP_IMM_32, 0b001, 0x600, 1 << 40), 32);
+    assert_eq!(imm(OPCODE_OP_IMM, 0b101, 0x287, 0x0100), 0xFF00);
+    assert_eq!(imm(OPCODE_OP_IMM, 0b101, 0x6B8, 0x0102_0304_0506_0708), 0x0807_0605_0403_0201);
+
+    //Zbc
+    assert_eq!(op(OPCODE_OP, 0b001, 0b000_0101, 3, 3), 5);
+    assert_eq!(op(OPCODE_OP, 0b011, 0b000_0101, 1 << 63, 2), 1);
+    assert_eq!(op(OPCODE_OP, 0b010, 0b000_0101, 1 << 63, 1 << 63), 1 << 63);
+
+    //Zbs
+    assert_eq!(op(OPCODE_OP, 0b001, 0b001_0100, 0, 63), 1 << 63);
+    assert_eq!(op(OPCODE_OP, 0b001, 0b010_0100, 0b111, 1), 0b101);
+    assert_eq!(op(OPCODE_OP, 0b001, 0b011_0100, 0b101, 1), 0b111);
+    assert_eq!(op(OPCODE_OP, 0b101, 0b010_0100, 0b100, 2), 1);
+    assert_eq!(imm(OPCODE_OP_IMM, 0b101, 0b01_0010 << 6 | 40, 1 << 40), 1);
+
+    //The base instructions sharing their opcodes are left to the integer
+    //unit: SUB, SRA, SRAI and SRAIW
+    assert_eq!(op(OPCODE_OP, 0b000, 0b010_0000, 5, 3), 2);
+    assert_eq!(op(OPCODE_OP, 0b101, 0b010_0000, -8i64 as u64, 1), -4i64 as u64);
+    assert_eq!(imm(OPCODE_OP_IMM, 0b101, 0x400 | 1, -8i64 as u64), -4i64 as u64);
+    assert_eq!(imm(OPCODE_OP_IMM_32, 0b101, 0x400 | 1, 0x8000_0000), 0xFFFF_FFFF_C000_0000);
+
+    //Nothing is decoded for the disabled extensions
+    let andn = r_type(OPCODE_OP, 0b111, 0b010_0000, 3, 1, 2);
+    assert!(bitmanip::decode(andn, Xlen::Rv64, &Extensions::all()).is_some());
+    assert!(bitmanip::decode(andn, Xlen::Rv64, &Extensions::none()).is_none());
+}
+
+#[test]
+fn bit_manipulation_rv32(){
+    let rv32 = || {
+        let mut cpu = CPU::new(false);
+        cpu.set_xlen(Xlen::Rv32);
+        cpu
+    };
+    let op = |funct3, funct7, a: i32, b: i32| exec_op(rv32(), OPCODE_OP, funct3, funct7, a as i64 as u64, b as i64 as u64) as i64;
+    let imm = |funct3, imm12, a: i32| exec_imm(rv32(), OPCODE_OP_IMM, funct3, imm12, a as i64 as u64) as i64;
+
+    //CLZ counts the zeros of the 32 bits value
+    assert_eq!(imm(0b001, 0x600, 1), 31);
+    assert_eq!(imm(0b001, 0x600, 0), 32);
+    assert_eq!(imm(0b001, 0x601, 0), 32);
+    //REV8 has its own encoding and swaps 4 bytes
+    assert_eq!(imm(0b101, 0x698, 0x0102_0304), 0x0403_0201);
+    assert_eq!(imm(0b101, 0x698, 0x80), i32::MIN as i64);
+    let rev8_rv64 = OPCODE_OP_IMM | 0b101 << 12 | 0x6B8 << 20;
+    assert!(bitmanip::decode(rev8_rv64, Xlen::Rv32, &Extensions::all()).is_none());
+    //CLMULR and CLMULH return the upper bits of the 64 bits product
+    assert_eq!(op(0b010, 0b000_0101, i32::MIN, i32::MIN), i32::MIN as i64);
+    assert_eq!(op(0b011, 0b000_0101, i32::MIN, 4), 2);
+    //Rotations and bit indexes are modulo 32
+    assert_eq!(op(0b101, 0b011_0000, 1, 33), i32::MIN as i64);
+    assert_eq!(op(0b001, 0b001_0100, 0, 31), i32::MIN as i64);
+    //The word forms do not exist
+    let add_uw = r_type(OPCODE_OP_32, 0b000, 0b000_0100, 3, 1, 2);
+    assert!(bitmanip::decode(add_uw, Xlen::Rv32, &Extensions::all()).is_none());
+}
+
+/// vtype immediates of the vector tests
+const E32_M1_TA_MA: u32 = 0b1101_0000;
+const E32_M1_TU_MU: u32 = 0b0001_0000;
+
+fn vsetvli(rd: u32, rs1: u32, vtypei: u32) -> u32{
+    vtypei << 20 | rs1 << 15 | 0b111 << 12 | rd << 7 | OPCODE_OP_V
+}
+
+/// OP-V arithmetic instruction, vm = 0 for the masked form
+fn op_v(funct6: u32, funct3: u32, vm: u32, vd: u32, vs2: u32, vs1: u32) -> u32{
+    funct6 << 26 | vm << 25 | vs2 << 20 | vs1 << 15 | funct3 << 12 | vd << 7 | OPCODE_OP_V
+}
+
+/// Unmasked unit stride VLE32.V / VSE32.V
+fn vle32(vd: u32, rs1: u32) -> u32{
+    1 << 25 | rs1 << 15 | 0b110 << 12 | vd << 7 | 0b000_0111
+}
+
+fn vse32(vs3: u32, rs1: u32) -> u32{
+    1 << 25 | rs1 << 15 | 0b110 << 12 | vs3 << 7 | 0b010_0111
+}
+
+fn set_elements(cpu: &mut CPU, reg: usize, values: &[u64]){
+    for (i, &value) in values.iter().enumerate(){
+        cpu.vector.set_element(reg, i as u64, 32, value);
+    }
+}
+
+/// The 4 32 bits elements of a register
+fn elements(cpu: &CPU, reg: usize) -> Vec<u64>{
+    (0..4).map(|i| cpu.vector.element(reg, i, 32)).collect()
+}
+
+/// The vector unit is off unless asked for
+fn with_vector(mut cpu: CPU) -> CPU{
+    cpu.set_extensions(Extensions{ v: true, ..cpu.extensions });
+    cpu
+}
+
+#[test]
+fn vector_configuration(){
+    let mut cpu = with_vector(CPU::new(false));
+    cpu.registers.common[1] = 1000;
+    cpu.registers.common[2] = 3;
+    run_code(&mut cpu, &[
+        //The AVL is capped to VLMAX
+        vsetvli(3, 1, E32_M1_TA_MA),
+        vsetvli(4, 2, E32_M1_TA_MA),
+        //x0 as AVL selects VLMAX, e8 m2 then e16 mf2
+        vsetvli(5, 0, 0b1100_0001),
+        vsetvli(6, 0, 0b1100_1111),
+        csr_op(0b010, 7, csr::VLENB, 0),
+        //SEW = 128 is reserved
+        vsetvli(8, 0, 0b1110_0000),
+        csr_op(0b010, 9, csr::VTYPE, 0),
+        csr_op(0b010, 10, csr::VL, 0),
+    ]);
+    assert_eq!(&cpu.registers.common[3..11], &[4, 3, 32, 4, 16, 0, 1 << 63, 0]);
+    assert!(cpu.vector.vill);
+
+    //The vector unit is only enabled by Zve64x, it is off by default
+    assert!(!CPU::new(false).extensions.v);
+    let extensions = Extensions::from_isa_string("rv64gc_zve64x_zvl256b").unwrap();
+    assert!(extensions.v);
+    assert_eq!(extensions.vlen, 256);
+    assert!(!Extensions::from_isa_string("rv64gc_zvl256b").unwrap().v);
+    //V is refused rather than silently ignored
+    assert_eq!(Extensions::from_isa_string("rv64gcv"), None);
+    assert_eq!(Extensions::from_isa_string("rv64imafdcv_zvl128b"), None);
+}
+
+#[test]
+fn vector_mask_and_tail(){
+    //VADD.VV v3, v1, v2, v0.t on 3 of the 4 elements
+    let vadd = op_v(0b00_0000, 0b000, 0, 3, 1, 2);
+    let setup = || {
+        let mut cpu = with_vector(CPU::new(false));
+        cpu.registers.common[1] = 3;
+        set_elements(&mut cpu, 1, &[1, 2, 3, 4]);
+        set_elements(&mut cpu, 2, &[10, 20, 30, 40]);
+        set_elements(&mut cpu, 3, &[7, 7, 7, 7]);
+        set_elements(&mut cpu, 0, &[0b101]);
+        cpu
+    };
+
+    //The agnostic masked off and tail elements are filled with ones
+    let mut cpu = setup();
+    run_code(&mut cpu, &[vsetvli(0, 1, E32_M1_TA_MA), vadd]);
+    assert_eq!(elements(&cpu, 3), [11, 0xFFFF_FFFF, 33, 0xFFFF_FFFF]);
+
+    //Undisturbed they keep their value
+    let mut cpu = setup();
+    run_code(&mut cpu, &[vsetvli(0, 1, E32_M1_TU_MU), vadd]);
+    assert_eq!(elements(&cpu, 3), [11, 7, 33, 7]);
+
+    //VMSEQ.VV writes a mask, its tail is always agnostic
+    let mut cpu = setup();
+    run_code(&mut cpu, &[vsetvli(0, 1, E32_M1_TU_MU), op_v(0b01_1000, 0b000, 1, 4, 1, 1)]);
+    assert_eq!(cpu.vector.element(4, 0, 8), 0xFF);
+}
+
+#[test]
+fn vector_load_store(){
+    //Elements below vstart are not loaded, it is reset afterwards
+    let mut cpu = with_vector(CPU::new(false));
+    map_data(&mut cpu, &[0x0000_0002_0000_0001, 0x0000_0004_0000_0003]);
+    cpu.registers.common[1] = 4;
+    cpu.registers.common[2] = 2;
+    cpu.registers.common[5] = DATA_BASE;
+    cpu.registers.common[6] = DATA_BASE + 0x100;
+    set_elements(&mut cpu, 1, &[0xAA, 0xAA, 0xAA, 0xAA]);
+    run_code(&mut cpu, &[
+        vsetvli(0, 1, E32_M1_TU_MU),
+        csr_op(0b001, 0, csr::VSTART, 2),
+        vle32(1, 5),
+        vse32(1, 6),
+    ]);
+    assert_eq!(elements(&cpu, 1), [0xAA, 0xAA, 3, 4]);
+    assert_eq!(cpu.vector.vstart, 0);
+    assert_eq!(read_u64(&cpu.memory, DATA_BASE + 0x100), 0x0000_00AA_0000_00AA);
+    assert_eq!(read_u64(&cpu.memory, DATA_BASE + 0x108), 0x0000_0004_0000_0003);
+
+    //A fault leaves vstart on the faulting element, the elements before it
+    //are loaded
+    let mut cpu = with_vector(bare_metal_cpu(Privilege::Machine));
+    cpu.csr.set(csr::MTVEC, CODE_BASE + 8);
+    cpu.csr.set(csr::MSTATUS, cpu.csr.get(csr::MSTATUS) | csr::MSTATUS_VS);
+    map_data(&mut cpu, &[]);
+    cpu.memory.write(DATA_BASE + CODE_SIZE - 8, &[1, 0, 0, 0, 2, 0, 0, 0]).unwrap();
+    cpu.registers.common[1] = 4;
+    cpu.registers.common[5] = DATA_BASE + CODE_SIZE - 8;
+    let program = trap_test(vsetvli(0, 1, E32_M1_TU_MU), vle32(1, 5), csr::MCAUSE, csr::MEPC, csr::MTVAL);
+    run_code(&mut cpu, &program);
+    assert_eq!(&cpu.registers.common[10..13], &[5, CODE_BASE + 4, DATA_BASE + CODE_SIZE]);
+    assert_eq!(cpu.vector.vstart, 2);
+    assert_eq!(&elements(&cpu, 1)[..2], &[1, 2]);
+
+    //Once the page is mapped the load restarts from vstart
+    let mut page = vec![0u8; CODE_SIZE as usize];
+    page[..8].copy_from_slice(&[3, 0, 0, 0, 4, 0, 0, 0]);
+    cpu.memory.allocate(DATA_BASE + CODE_SIZE, CODE_SIZE, &page, Permissions::RW);
+    set_elements(&mut cpu, 1, &[0, 0]);
+    cpu.exit = false;
+    cpu.execute(CODE_BASE + 4, Rc::new(RefCell::new(Fuzzer::new()))).unwrap();
+    assert_eq!(elements(&cpu, 1), [0, 0, 3, 4]);
+    assert_eq!(cpu.vector.vstart, 0);
+}
+
+#[test]
+fn vector_reductions(){
+    const OPMVV: u32 = 0b010;
+    let reduce = |funct6, funct3, mask: u64| {
+        let mut cpu = with_vector(CPU::new(false));
+        cpu.registers.common[1] = 4;
+        set_elements(&mut cpu, 0, &[mask]);
+        set_elements(&mut cpu, 1, &[100, 5, 5, 5]);
+        set_elements(&mut cpu, 2, &[1, 2, 0xFFFF_FFFF, 4]);
+        run_code(&mut cpu, &[vsetvli(0, 1, E32_M1_TU_MU), op_v(funct6, funct3, (mask == 0b1111) as u32, 3, 2, 1)]);
+        cpu.vector.element(3, 0, 32)
+    };
+    //VREDSUM wraps at SEW, VWREDSUMU does not
+    assert_eq!(reduce(0b00_0000, OPMVV, 0b1111), 106);
+    assert_eq!(reduce(0b00_0000, OPMVV, 0b1011), 107);
+    assert_eq!(reduce(0b00_0110, OPMVV, 0b1011), 100);
+    assert_eq!(reduce(0b00_0111, OPMVV, 0b1111), 100);
+    assert_eq!(reduce(0b00_0101, OPMVV, 0b1111), 0xFFFF_FFFF);
+
+    let mut cpu = with_vector(CPU::new(false));
+    cpu.registers.common[1] = 4;
+    set_elements(&mut cpu, 1, &[0xFFFF_FFFF, 0]);
+    set_elements(&mut cpu, 2, &[0xFFFF_FFFF, 1, 0, 0]);
+    run_code(&mut cpu, &[vsetvli(0, 1, E32_M1_TU_MU), op_v(0b11_0000, 0b000, 1, 3, 2, 1)]);
+    assert_eq!(cpu.vector.element(3, 0, 64), 0x1_FFFF_FFFF);
+
+    //A reduction does not restart from vstart
+    let mut cpu = with_vector(bare_metal_cpu(Privilege::Machine));
+    cpu.csr.set(csr::MTVEC, CODE_BASE + 8);
+    cpu.csr.set(csr::MSTATUS, cpu.csr.get(csr::MSTATUS) | csr::MSTATUS_VS);
+    cpu.vector.vtype = E32_M1_TU_MU as u64;
+    cpu.vector.vill = false;
+    cpu.vector.vl = 4;
+    cpu.vector.vstart = 1;
+    run_code(&mut cpu, &trap_test(NOP, op_v(0b00_0000, OPMVV, 1, 3, 2, 1), csr::MCAUSE, csr::MEPC, csr::MTVAL));
+    assert_eq!(cpu.registers.common[10], 2);
+}
+
+#[test]
+fn vector_gather(){
+    let gather = |funct3, vs1, x5: u64| {
+        let mut cpu = with_vector(CPU::new(false));
+        cpu.registers.common[1] = 4;
+        cpu.registers.common[5] = x5;
+        set_elements(&mut cpu, 1, &[3, 0, 9, 1]);
+        set_elements(&mut cpu, 2, &[10, 20, 30, 40]);
+        run_code(&mut cpu, &[vsetvli(0, 1, E32_M1_TU_MU), op_v(0b00_1100, funct3, 1, 3, 2, vs1)]);
+        elements(&cpu, 3)
+    };
+    //The indexes past VLMAX read 0
+    assert_eq!(gather(0b000, 1, 0), [40, 10, 0, 20]);
+    assert_eq!(gather(0b100, 5, 2), [30, 30, 30, 30]);
+    assert_eq!(gather(0b100, 5, 1 << 40), [0, 0, 0, 0]);
+    assert_eq!(gather(0b011, 3, 0), [40, 40, 40, 40]);
+
+    //vd cannot overlap the source
+    let mut cpu = with_vector(bare_metal_cpu(Privilege::Machine));
+    cpu.csr.set(csr::MTVEC, CODE_BASE + 8);
+    cpu.csr.set(csr::MSTATUS, cpu.csr.get(csr::MSTATUS) | csr::MSTATUS_VS);
+    cpu.registers.common[1] = 4;
+    run_code(&mut cpu, &trap_test(vsetvli(0, 1, E32_M1_TU_MU), op_v(0b00_1100, 0b000, 1, 2, 2, 1), csr::MCAUSE, csr::MEPC, csr::MTVAL));
+    assert_eq!(&cpu.registers.common[10..12], &[2, CODE_BASE + 4]);
+}
+
+#[test]
+fn fences(){
+    let mut cpu = CPU::new(false);
+    //FENCE iorw, iorw then FENCE.TSO then FENCE.I
+    run_code(&mut cpu, &[0x0FF0_000F, 0x8330_000F, 0x0000_100F, 0x0010_0093]);
+    assert_eq!(cpu.registers.common[1], 1);
+
+    //The other MISC-MEM encodings are illegal
+    let mut cpu = bare_metal_cpu(Privilege::Machine);
+    cpu.csr.set(csr::MTVEC, CODE_BASE + 8);
+    run_code(&mut cpu, &trap_test(NOP, 0x0000_200F, csr::MCAUSE, csr::MEPC, csr::MTVAL));
+    assert_eq!(&cpu.registers.common[10..13], &[2, CODE_BASE + 4, 0x200F]);
+}
+
+#[test]
+fn load_reserved_store_conditional(){
+    const LR: u32 = 0b00010;
+    const SC: u32 = 0b00011;
+    let run = |code: &[u32]| {
+        let mut cpu = CPU::new(false);
+        map_data(&mut cpu, &[1, 2]);
+        cpu.registers.common[1] = DATA_BASE;
+        cpu.registers.common[2] = 42;
+        cpu.registers.common[5] = 7;
+        run_code(&mut cpu, code);
+        (cpu.registers.common[3], cpu.registers.common[4], read_u64(&cpu.memory, DATA_BASE))
+    };
+
+    //x3 gets the loaded value and x4 the status of SC, 0 on success
+    assert_eq!(run(&[amo(LR, 0b011, 3, 1, 0), amo(SC, 0b011, 4, 1, 2)]), (1, 0, 42));
+    //A store out of the reserved block keeps the reservation
+    assert_eq!(run(&[amo(LR, 0b011, 3, 1, 0), sd(5, 1, 8), amo(SC, 0b011, 4, 1, 2)]), (1, 0, 42));
+    //A store to the reserved block, SC without LR and a second SC fail
+    assert_eq!(run(&[amo(LR, 0b011, 3, 1, 0), sd(5, 1, 0), amo(SC, 0b011, 4, 1, 2)]), (1, 1, 7));
+    assert_eq!(run(&[amo(SC, 0b011, 4, 1, 2)]), (0, 1, 1));
+    assert_eq!(run(&[amo(LR, 0b011, 3, 1, 0), amo(SC, 0b011, 4, 1, 5), amo(SC, 0b011, 4, 1, 2)]), (1, 1, 7));
+
+    //Nor a trap, ECALL goes to the SC at CODE_BASE + 8
+    let mut cpu = bare_metal_cpu(Privilege::Machine);
+    cpu.csr.set(csr::MTVEC, CODE_BASE + 8);
+    map_data(&mut cpu, &[1]);
+    cpu.registers.common[1] = DATA_BASE;
+    cpu.registers.common[2] = 42;
+    run_code(&mut cpu, &[amo(LR, 0b011, 3, 1, 0), OPCODE_SYSTEM, amo(SC, 0b011, 4, 1, 2)]);
+    assert_eq!(cpu.csr.get(csr::MCAUSE), 11);
+    assert_eq!((cpu.registers.common[4], read_u64(&cpu.memory, DATA_BASE)), (1, 1));
+
+    //The reservation does not survive a reset to the snapshot
+    let mut memory = Memory::new();
+    memory.allocate(DATA_BASE, CODE_SIZE, &[0; CODE_SIZE as usize], Permissions::RW);
+    memory.save_state();
+    memory.reserve(DATA_BASE);
+    memory.reset_to_saved_state();
+    assert!(!memory.check_reservation(DATA_BASE));
+}
+
+#[test]
+fn atomic_memory_operations(){
+    let run = |funct5, funct3, old: u64, src: u64| {
+        let mut cpu = CPU::new(false);
+        map_data(&mut cpu, &[old]);
+        cpu.registers.common[1] = DATA_BASE;
+        cpu.registers.common[2] = src;
+        run_code(&mut cpu, &[amo(funct5, funct3, 3, 1, 2)]);
+        (cpu.registers.common[3], read_u64(&cpu.memory, DATA_BASE))
+    };
+
+    //AMOADD.W and AMOSWAP.W return the old word sign extended and only
+    //write the word
+    assert_eq!(run(0b00000, 0b010, 0x1_FFFF_FFFF, 1), (u64::MAX, 0x1_0000_0000));
+    assert_eq!(run(0b00001, 0b010, 0x1_8000_0000, 5), (0xFFFF_FFFF_8000_0000, 0x1_0000_0005));
+    //AMOMIN.W and AMOMINU.W compare the words signed and unsigned, AMOMAXU.D
+    //and AMOMAX.D the double words
+    assert_eq!(run(0b10000, 0b010, 0xFFFF_FFFF, 1).1, 0xFFFF_FFFF);
+    assert_eq!(run(0b11000, 0b010, 0xFFFF_FFFF, 1).1, 1);
+    assert_eq!(run(0b11100, 0b011, u64::MAX, 1).1, u64::MAX);
+    assert_eq!(run(0b10100, 0b011, u64::MAX, 1).1, 1);
+    //AMOXOR.D, AMOAND.D and AMOOR.D
+    assert_eq!(run(0b00100, 0b011, 0b1100, 0b1010), (0b1100, 0b0110));
+    assert_eq!(run(0b01100, 0b011, 0b1100, 0b1010).1, 0b1000);
+    assert_eq!(run(0b01000, 0b011, 0b1100, 0b1010).1, 0b1110);
+}
+
+#[test]
+fn compressed_expansion(){
+    let cases = [
+        //C.ADDI a0, 1
+        (0x0505, Some(0x0015_0513)),
+        //C.LW a0, 4(a1)
+        (0x41C8, Some(0x0045_A503)),
+        //C.LDSP a2, 8(sp)
+        (0x6622, Some(0x0081_3603)),
+        //C.J 8
+        (0xA021, Some(0x0080_006F)),
+        //C.ADDIW a0, -1
+        (0x357D, Some(0xFFF5_051B)),
+        //C.MV a0, a1
+        (0x852E, Some(0x00B0_0533)),
+        //C.JALR ra
+        (0x9082, Some(0x0000_80E7)),
+        //The all zeros encoding is illegal
+        (0x0000, None),
+    ];
+    for &(instr, expanded) in cases.iter(){
+        assert_eq!(compressed::expand(instr, Xlen::Rv64), expanded, "{:#06X}", instr);
+    }
+
+    //C.JAL only exists in RV32, its encoding is C.ADDIW x0 in RV64
+    assert_eq!(compressed::expand(0x2021, Xlen::Rv32), Some(0x0080_00EF));
+    assert_eq!(compressed::expand(0x2021, Xlen::Rv64), None);
+    //C.FLW a0, 4(a1) replaces C.LD
+    assert_eq!(compressed::expand(0x61C8, Xlen::Rv32), Some(0x0045_A507));
+}
+
+#[test]
+fn mixed_instruction_lengths(){
+    let mut cpu = CPU::new(false);
+    //C.LI a0, 5 then ADDI a0, a0, 1 then C.ADDI a0, 1
+    run_bytes(&mut cpu, &[0x15, 0x45, 0x13, 0x05, 0x15, 0x00, 0x05, 0x05]);
+    assert_eq!(cpu.registers.common[10], 7);
+    assert_eq!(cpu.registers.pc, CODE_BASE + 8);
+}
+
+#[test]
+fn soft_float(){
+    let rne = RoundingMode::NearestEven;
+    let double = |v: f64| v.to_bits();
+    let single = |v: f32| float::box_f32(v.to_bits());
+
+    assert_eq!(float::add(FloatFormat::Double, double(0.1), double(0.2), rne), (double(0.1 + 0.2), float::FLAG_INEXACT));
+    assert_eq!(float::add(FloatFormat::Single, single(1.0), single(2.0), rne), (single(3.0), 0));
+    assert_eq!(float::div(FloatFormat::Double, double(1.0), double(0.0), rne), (double(f64::INFINITY), float::FLAG_DIV_BY_ZERO));
+    assert_eq!(float::sqrt(FloatFormat::Double, double(-1.0), rne), (float::CANONICAL_NAN_F64, float::FLAG_INVALID));
+
+    //A single precision value that is not NaN-boxed reads as the canonical NaN
+    let (result, _) = float::add(FloatFormat::Single, 1.0f32.to_bits() as u64, single(1.0), rne);
+    assert_eq!(result, float::box_f32(float::CANONICAL_NAN_F32));
+
+    //FCVT.W.D rounds with the given mode and saturates
+    let to_word = |v: f64, rm| float::to_int(FloatFormat::Double, double(v), 0, rm).unwrap();
+    assert_eq!(to_word(2.5, rne), (2, float::FLAG_INEXACT));
+    assert_eq!(to_word(2.5, RoundingMode::NearestMaxMagnitude), (3, float::FLAG_INEXACT));
+    assert_eq!(to_word(-2.5, RoundingMode::TowardZero), (-2i64 as u64, float::FLAG_INEXACT));
+    assert_eq!(to_word(-2.5, RoundingMode::Down), (-3i64 as u64, float::FLAG_INEXACT));
+    assert_eq!(to_word(f64::NAN, rne), (i32::MAX as u64, float::FLAG_INVALID));
+    assert_eq!(to_word(1e10, rne), (i32::MAX as u64, float::FLAG_INVALID));
+}
+
+#[test]
+fn float_instructions(){
+    let mut cpu = CPU::new(false);
+    map_data(&mut cpu, &[1.5f32.to_bits() as u64]);
+    cpu.registers.common[1] = 0.1f64.to_bits();
+    cpu.registers.common[2] = 0.2f64.to_bits();
+    cpu.registers.common[5] = DATA_BASE;
+    run_code(&mut cpu, &[
+        //FMV.D.X f1, x1 and FMV.D.X f2, x2
+        r_type(OPCODE_OP_FP, 0b000, 0b111_1001, 1, 1, 0),
+        r_type(OPCODE_OP_FP, 0b000, 0b111_1001, 2, 2, 0),
+        //FADD.D f3, f1, f2 with the dynamic rounding mode
+        r_type(OPCODE_OP_FP, 0b111, 0b000_0001, 3, 1, 2),
+        //FMV.X.D x3, f3 and FCVT.W.D x4, f3, rtz
+        r_type(OPCODE_OP_FP, 0b000, 0b111_0001, 3, 3, 0),
+        r_type(OPCODE_OP_FP, 0b001, 0b110_0001, 4, 3, 0),
+        //FLW f4, 0(x5) then FMV.X.D x6, f4
+        5 << 15 | 0b010 << 12 | 4 << 7 | 0b000_0111,
+        r_type(OPCODE_OP_FP, 0b000, 0b111_0001, 6, 4, 0),
+    ]);
+
+    assert_eq!(cpu.registers.common[3], (0.1f64 + 0.2).to_bits());
+    assert_eq!(cpu.registers.common[4], 0);
+    assert_eq!(cpu.registers.fcsr, float::FLAG_INEXACT);
+    //Single precision values are NaN-boxed in the registers
+    assert_eq!(cpu.registers.common[6], 0xFFFF_FFFF_0000_0000 | 1.5f32.to_bits() as u64);
+}
+
+#[test]
+fn float_state(){
+    const FS_INITIAL: u64 = 1 << 13;
+    const FS_CLEAN: u64 = 2 << 13;
+    //FADD.D f3, f1, f2
+    let fadd = r_type(OPCODE_OP_FP, 0b000, 0b000_0001, 3, 1, 2);
+    let read_fcsr = csr_op(0b010, 1, csr::FCSR, 0);
+
+    //The F and D instructions and CSRs are illegal when FS is Off
+    for instr in [fadd, read_fcsr].iter(){
+        let mut cpu = bare_metal_cpu(Privilege::Machine);
+        cpu.csr.set(csr::MTVEC, CODE_BASE + 8);
+        run_code(&mut cpu, &trap_test(NOP, *instr, csr::MCAUSE, csr::MEPC, csr::MTVAL));
+        assert_eq!(&cpu.registers.common[10..13], &[2, CODE_BASE + 4, *instr as u64]);
+    }
+
+    //Otherwise they make FS Dirty, which sets SD until the kernel saves the
+    //state and marks it Clean
+    let mut cpu = bare_metal_cpu(Privilege::Machine);
+    cpu.csr.set(csr::MSTATUS, cpu.csr.get(csr::MSTATUS) | FS_INITIAL);
+    cpu.registers.common[6] = FS_CLEAN ^ csr::MSTATUS_FS;
+    run_code(&mut cpu, &[
+        fadd,
+        csr_op(0b010, 5, csr::MSTATUS, 0),
+        csr_op(0b011, 0, csr::MSTATUS, 6),
+        csr_op(0b010, 7, csr::SSTATUS, 0),
+    ]);
+    assert_eq!(cpu.registers.common[5] & (csr::MSTATUS_FS | csr::MSTATUS_SD), csr::MSTATUS_FS | csr::MSTATUS_SD);
+    assert_eq!(cpu.registers.common[7] & (csr::MSTATUS_FS | csr::MSTATUS_SD), FS_CLEAN);
+
+    //SD is bit 31 in RV32
+    let mut csrs = csr::CsrFile::new();
+    csrs.set_xlen(true);
+    csrs.set_dirty(csr::MSTATUS_FS);
+    assert_eq!(csrs.read(csr::MSTATUS).map(|status| status >> 31), Some(1));
+    assert_eq!(csrs.read(csr::SSTATUS).map(|status| status >> 31), Some(1));
+}
+
+#[test]
+fn csr_instructions(){
+    let mut cpu = CPU::new(false);
+    cpu.registers.common[1] = 0xA5;
+    cpu.registers.common[8] = u64::MAX;
+    cpu.registers.common[9] = 0x8000_0003;
+    run_code(&mut cpu, &[
+        //CSRRW x2, fcsr, x1 then CSRRS x3, frm, x0 and CSRRCI x4, fflags, 1
+        csr_op(0b001, 2, csr::FCSR, 1),
+        csr_op(0b010, 3, csr::FRM, 0),
+        csr_op(0b111, 4, csr::FFLAGS, 1),
+        //CSRRW x0, mscratch, x1 then CSRRSI x5, mscratch, 2
+        csr_op(0b001, 0, csr::MSCRATCH, 1),
+        csr_op(0b110, 5, csr::MSCRATCH, 2),
+        //CSRRW x6, mstatus, x8 then CSRRS x6, mstatus, x0
+        csr_op(0b001, 6, csr::MSTATUS, 8),
+        csr_op(0b010, 6, csr::MSTATUS, 0),
+        //CSRRW x7, mtvec, x9 then CSRRS x7, mtvec, x0
+        csr_op(0b001, 7, csr::MTVEC, 9),
+        csr_op(0b010, 7, csr::MTVEC, 0),
+        //CSRRS x10, instret, x0 and CSRRS x11, misa, x0
+        csr_op(0b010, 10, csr::INSTRET, 0),
+        csr_op(0b010, 11, csr::MISA, 0),
+    ]);
+
+    let r = &cpu.registers.common;
+    assert_eq!(r[2], 0);
+    //frm and fflags are fields of fcsr
+    assert_eq!(r[3], 0b101);
+    assert_eq!(r[4], 0b00101);
+    assert_eq!(cpu.registers.fcsr, 0b101_00100);
+    assert_eq!(r[5], 0xA5);
+    assert_eq!(cpu.csr.read(csr::MSCRATCH), Some(0xA7));
+    //Only the fields of mstatus that exist are written, UXL and SXL are
+    //fixed
+    assert_eq!(r[6] & csr::MSTATUS_XL, csr::MSTATUS_XL);
+    assert_eq!(r[6] & csr::MSTATUS_MPP, csr::MSTATUS_MPP);
+    //SD is set since FS and VS are now Dirty
+    assert_eq!(r[6] & csr::MSTATUS_SD, csr::MSTATUS_SD);
+    //The reserved mode of mtvec is replaced by the direct mode
+    assert_eq!(r[7], 0x8000_0000);
+    assert_eq!(r[10], 9);
+    assert_eq!(r[11], csr::MISA_VALUE | Extensions::all().misa_bits());
+
+    //misa cannot be changed, the read only and missing CSRs are refused
+    let mut csrs = csr::CsrFile::new();
+    assert!(csrs.write(csr::MISA, 0));
+    assert_eq!(csrs.read(csr::MISA), Some(csr::MISA_VALUE));
+    assert!(!csrs.write(csr::MHARTID, 1));
+    assert!(!csrs.write(0x7C0, 1));
+    assert_eq!(csrs.read(0x7C0), None);
+}
+
+#[test]
+fn trap_delivery(){
+    const ECALL: u32 = OPCODE_SYSTEM;
+    const HANDLER: u64 = CODE_BASE + 8;
+
+    //An ECALL from U-mode goes to M-mode which saves the previous privilege
+    let mut cpu = bare_metal_cpu(Privilege::User);
+    cpu.csr.set(csr::MTVEC, HANDLER);
+    run_code(&mut cpu, &trap_test(ECALL, 0, csr::MCAUSE, csr::MEPC, csr::MTVAL));
+    assert_eq!(cpu.privilege, Privilege::Machine);
+    assert_eq!(&cpu.registers.common[10..13], &[8, CODE_BASE, 0]);
+    assert_eq!(cpu.csr.get(csr::MSTATUS) & csr::MSTATUS_MPP, 0);
+
+    //Accessing a M-mode CSR from S-mode is illegal, mtval holds the
+    //instruction
+    let mut cpu = bare_metal_cpu(Privilege::Supervisor);
+    cpu.csr.set(csr::MTVEC, HANDLER);
+    let read_mstatus = csr_op(0b010, 1, csr::MSTATUS, 0);
+    run_code(&mut cpu, &trap_test(read_mstatus, 0, csr::MCAUSE, csr::MEPC, csr::MTVAL));
+    assert_eq!(&cpu.registers.common[10..13], &[2, CODE_BASE, read_mstatus as u64]);
+    assert_eq!(cpu.csr.get(csr::MSTATUS) & csr::MSTATUS_MPP, 1 << csr::MSTATUS_MPP_SHIFT);
+
+    //The load access faults report the address in mtval
+    let mut cpu = bare_metal_cpu(Privilege::Machine);
+    cpu.csr.set(csr::MTVEC, HANDLER);
+    cpu.registers.common[1] = 0xDEAD_0000;
+    //LD x2, 8(x1)
+    let load = 8 << 20 | 1 << 15 | 0b011 << 12 | 2 << 7 | 0b000_0011;
+    run_code(&mut cpu, &trap_test(NOP, load, csr::MCAUSE, csr::MEPC, csr::MTVAL));
+    assert_eq!(&cpu.registers.common[10..13], &[5, CODE_BASE + 4, 0xDEAD_0008]);
+}
+
+#[test]
+fn trap_delegation(){
+    const HANDLER: u64 = CODE_BASE + 8;
+    let read_mstatus = csr_op(0b010, 1, csr::MSTATUS, 0);
+    let setup = |privilege| {
+        let mut cpu = bare_metal_cpu(privilege);
+        cpu.csr.set(csr::MTVEC, HANDLER);
+        cpu.csr.set(csr::STVEC, HANDLER);
+        //Illegal instructions are delegated to S-mode
+        cpu.csr.set(csr::MEDELEG, 1 << 2);
+        cpu
+    };
+
+    let mut cpu = setup(Privilege::User);
+    run_code(&mut cpu, &trap_test(NOP, read_mstatus, csr::SCAUSE, csr::SEPC, csr::STVAL));
+    assert_eq!(cpu.privilege, Privilege::Supervisor);
+    assert_eq!(&cpu.registers.common[10..13], &[2, CODE_BASE + 4, read_mstatus as u64]);
+    assert_eq!(cpu.csr.get(csr::MSTATUS) & csr::MSTATUS_SPP, 0);
+    assert_eq!(cpu.csr.get(csr::MEPC), 0);
+
+    //The exceptions that are not delegated and those raised in M-mode stay
+    //in M-mode
+    let mut cpu = setup(Privilege::Supervisor);
+    run_code(&mut cpu, &trap_test(OPCODE_SYSTEM, 0, csr::MCAUSE, csr::MEPC, csr::MTVAL));
+    assert_eq!(cpu.privilege, Privilege::Machine);
+    assert_eq!(&cpu.registers.common[10..12], &[9, CODE_BASE]);
+
+    let mut cpu = setup(Privilege::Machine);
+    run_code(&mut cpu, &trap_test(NOP, csr_op(0b010, 1, 0x7C0, 0), csr::MCAUSE, csr::MEPC, csr::MTVAL));
+    assert_eq!(&cpu.registers.common[10..12], &[2, CODE_BASE + 4]);
+    assert_eq!(cpu.csr.get(csr::SCAUSE), 0);
+}
+
+#[test]
+fn trap_return(){
+    const MRET: u32 = 0b0011_0000_0010 << 20 | OPCODE_SYSTEM;
+    const SRET: u32 = 0b0001_0000_0010 << 20 | OPCODE_SYSTEM;
+
+    //MRET goes to S-mode at mepc and restores MIE from MPIE, then SRET goes
+    //to U-mode at sepc
+    let mut cpu = bare_metal_cpu(Privilege::Machine);
+    cpu.csr.set(csr::MSTATUS, csr::MSTATUS_XL | csr::MSTATUS_MPIE | 1 << csr::MSTATUS_MPP_SHIFT);
+    cpu.csr.set(csr::MEPC, CODE_BASE + 8);
+    cpu.csr.set(csr::SEPC, CODE_BASE + 16);
+    run_code(&mut cpu, &[MRET, 0, SRET, 0, NOP]);
+    assert_eq!(cpu.privilege, Privilege::User);
+    let mstatus = cpu.csr.get(csr::MSTATUS);
+    assert_eq!(mstatus & (csr::MSTATUS_MIE | csr::MSTATUS_MPIE | csr::MSTATUS_MPP), csr::MSTATUS_MIE | csr::MSTATUS_MPIE);
+    assert_eq!(mstatus & (csr::MSTATUS_SPIE | csr::MSTATUS_SPP), csr::MSTATUS_SPIE);
+
+    //xRET from a lower privilege is illegal
+    let mut cpu = bare_metal_cpu(Privilege::Supervisor);
+    cpu.csr.set(csr::MTVEC, CODE_BASE + 8);
+    run_code(&mut cpu, &trap_test(MRET, 0, csr::MCAUSE, csr::MEPC, csr::MTVAL));
+    assert_eq!(&cpu.registers.common[10..13], &[2, CODE_BASE, MRET as u64]);
+    let mut cpu = bare_metal_cpu(Privilege::User);
+    cpu.csr.set(csr::MTVEC, CODE_BASE + 8);
+    run_code(&mut cpu, &trap_test(SRET, 0, csr::MCAUSE, csr::MEPC, csr::MTVAL));
+    assert_eq!(&cpu.registers.common[10..13], &[2, CODE_BASE, SRET as u64]);
+}
+
+#[test]
+fn misaligned_atomics(){
+    const LR: u32 = 0b00010;
+    const AMOADD: u32 = 0b00000;
+    let run = |funct5, rs2| {
+        let mut cpu = bare_metal_cpu(Privilege::Machine);
+        cpu.csr.set(csr::MTVEC, CODE_BASE + 8);
+        map_data(&mut cpu, &[]);
+        cpu.registers.common[1] = DATA_BASE + 4;
+        run_code(&mut cpu, &trap_test(NOP, amo(funct5, 0b011, 3, 1, rs2), csr::MCAUSE, csr::MEPC, csr::MTVAL));
+        [cpu.registers.common[10], cpu.registers.common[12]]
+    };
+    //LR reports a misaligned load, the others a misaligned store/AMO
+    assert_eq!(run(LR, 0), [4, DATA_BASE + 4]);
+    assert_eq!(run(AMOADD, 2), [6, DATA_BASE + 4]);
+}
+
+#[test]
+fn misaligned_jump_without_c(){
+    let mut cpu = bare_metal_cpu(Privilege::Machine);
+    cpu.csr.set(csr::MTVEC, CODE_BASE + 8);
+    let mut extensions = cpu.extensions;
+    extensions.c = false;
+    cpu.set_extensions(extensions);
+    //The target of a jump must be aligned on 4 bytes, the trap is taken by
+    //the jump
+    run_code(&mut cpu, &trap_test(NOP, jal(0, CODE_BASE + 4, CODE_BASE + 6), csr::MCAUSE, csr::MEPC, csr::MTVAL));
+    assert_eq!(&cpu.registers.common[10..13], &[0, CODE_BASE + 4, CODE_BASE + 6]);
+
+    //UXL is read only in sstatus
+    let mut cpu = bare_metal_cpu(Privilege::Machine);
+    run_code(&mut cpu, &[csr_op(0b001, 0, csr::SSTATUS, 0)]);
+    assert_eq!(cpu.csr.get(csr::MSTATUS) & csr::MSTATUS_UXL, 2 << 32);
+}
+
+#[test]
+fn mmu_translation(){
+    const PTE_V: u64 = 1;
+    const PTE_R: u64 = 1 << 1;
+    const PTE_W: u64 = 1 << 2;
+    const PTE_U: u64 = 1 << 4;
+    const PTE_A: u64 = 1 << 6;
+    const PTE_D: u64 = 1 << 7;
+
+    //Sv39 tables mapping the user page 0x4000_1000 to 0x8000_5000
+    let mut memory = Memory::new();
+    memory.allocate(0x8000_0000, 0x10000, &[0; 0x10000], Permissions::RW);
+    let pte = |ppn: u64, flags: u64| (ppn << 10 | flags).to_le_bytes();
+    memory.write(0x8000_0008, &pte(0x80001, PTE_V)).unwrap();
+    memory.write(0x8000_1000, &pte(0x80002, PTE_V)).unwrap();
+    memory.write(0x8000_2008, &pte(0x80005, PTE_V | PTE_R | PTE_W | PTE_U)).unwrap();
+
+    let satp = mmu::SATP_MODE_SV39 << mmu::SATP_MODE_SHIFT | 0x80000;
+    let user = mmu::Context{ satp, privilege: Privilege::User, sum: false, mxr: false };
+    let mut mmu = Mmu::new();
+
+    assert_eq!(mmu.translate(&mut memory, &user, 0x4000_1234, Access::Load), Ok(0x8000_5234));
+    assert_eq!(read_u64(&memory, 0x8000_2008) & (PTE_A | PTE_D), PTE_A);
+    assert_eq!(mmu.translate(&mut memory, &user, 0x4000_1238, Access::Store), Ok(0x8000_5238));
+    assert_eq!(read_u64(&memory, 0x8000_2008) & (PTE_A | PTE_D), PTE_A | PTE_D);
+
+    //The page is not executable, unmapped pages fault
+    assert_eq!(mmu.translate(&mut memory, &user, 0x4000_1000, Access::Fetch), Err(Exception::InstructionPageFault(0x4000_1000)));
+    assert_eq!(mmu.translate(&mut memory, &user, 0x4000_2000, Access::Load), Err(Exception::LoadPageFault(0x4000_2000)));
+
+    //Supervisor needs SUM for the user pages, M-mode is not translated
+    let supervisor = mmu::Context{ privilege: Privilege::Supervisor, ..user };
+    assert_eq!(Mmu::new().translate(&mut memory, &supervisor, 0x4000_1234, Access::Load), Err(Exception::LoadPageFault(0x4000_1234)));
+    let supervisor = mmu::Context{ sum: true, ..supervisor };
+    assert_eq!(Mmu::new().translate(&mut memory, &supervisor, 0x4000_1234, Access::Load), Ok(0x8000_5234));
+    let machine = mmu::Context{ privilege: Privilege::Machine, ..user };
+    assert_eq!(Mmu::new().translate(&mut memory, &machine, 0x4000_1234, Access::Load), Ok(0x4000_1234));
+
+    //The TLB keeps the old mapping until the page is flushed
+    memory.write(0x8000_2008, &pte(0x80006, PTE_V | PTE_R | PTE_W | PTE_U | PTE_A | PTE_D)).unwrap();
+    assert_eq!(mmu.translate(&mut memory, &user, 0x4000_1234, Access::Load), Ok(0x8000_5234));
+    mmu.flush_page(0x4000_1000);
+    assert_eq!(mmu.translate(&mut memory, &user, 0x4000_1234, Access::Load), Ok(0x8000_6234));
+}
+
+#[test]
+fn clint_timer(){
+    let mut clint = Clint::new();
+    assert!(!clint.timer_pending(u64::MAX - 1));
+
+    //mtimecmp is written in two halves, mtime counts the instructions
+    clint.write(0x4000, &100u32.to_le_bytes(), 10);
+    clint.write(0x4004, &0u32.to_le_bytes(), 10);
+    assert_eq!(clint.mtimecmp, 100);
+    assert!(!clint.timer_pending(99));
+    assert!(clint.timer_pending(100));
+
+    //Writing mtime moves the clock relative to the instruction count
+    clint.write(0xBFF8, &1000u64.to_le_bytes(), 10);
+    let mut mtime = [0u8; 8];
+    clint.read(0xBFF8, &mut mtime, 20);
+    assert_eq!(u64::from_le_bytes(mtime), 1010);
+
+    //Fast forward jumps to mtimecmp but never moves the clock back
+    clint.mtimecmp = 2000;
+    clint.fast_forward(20);
+    assert_eq!(clint.mtime(20), 2000);
+    clint.mtimecmp = 10;
+    clint.fast_forward(20);
+    assert_eq!(clint.mtime(20), 2000);
+}
+
+#[test]
+fn timer_interrupt_after_wfi(){
+    const WFI: u32 = 0b0001_0000_0101 << 20 | OPCODE_SYSTEM;
+    const HANDLER: u64 = CODE_BASE + 12;
+
+    let mut cpu = bare_metal_cpu(Privilege::Machine);
+    cpu.csr.set(csr::MTVEC, HANDLER);
+    cpu.csr.set(csr::MSTATUS, csr::MSTATUS_XL | csr::MSTATUS_MIE);
+    cpu.csr.set(csr::MIE, csr::MIP_MTIP);
+    cpu.registers.common[1] = CLINT_BASE + 0x4000;
+    cpu.registers.common[2] = 1000;
+    //mtimecmp = 1000, then wait for the interrupt
+    run_code(&mut cpu, &[
+        sd(2, 1, 0), WFI, NOP,
+        csr_op(0b010, 10, csr::MCAUSE, 0), csr_op(0b010, 11, csr::MEPC, 0), csr_op(0b010, 12, csr::TIME, 0),
+    ]);
+
+    //The interrupt is taken after WFI retires, when the clock reached mtimecmp
+    assert_eq!(cpu.registers.common[10], csr::INTERRUPT_CAUSE | 7);
+    assert_eq!(cpu.registers.common[11], CODE_BASE + 8);
+    assert!((1000..1010).contains(&cpu.registers.common[12]), "{}", cpu.registers.common[12]);
+    assert_eq!(cpu.csr.get(csr::MSTATUS) & (csr::MSTATUS_MIE | csr::MSTATUS_MPIE), csr::MSTATUS_MPIE);
+
+    //Without an enabled interrupt WFI does not move the clock
+    let mut cpu = bare_metal_cpu(Privilege::Machine);
+    cpu.clint.mtimecmp = 1000;
+    run_code(&mut cpu, &[WFI, csr_op(0b010, 12, csr::TIME, 0)]);
+    assert_eq!(cpu.registers.common[12], 1);
+}
+
+fn read_register(device: &mut dyn Device, offset: u64) -> u32{
+    let mut value = [0u8; 4];
+    device.read(offset, &mut value);
+    u32::from_le_bytes(value)
+}
+
+#[test]
+fn plic_claim_complete(){
+    const CLAIM: u64 = 0x20_1004;
+    let mut plic = Plic::new();
+    plic.write(3 * 4, &5u32.to_le_bytes());
+    plic.write(10 * 4, &1u32.to_le_bytes());
+    //Both sources are enabled for S-mode only
+    plic.write(0x2080, &(1u32 << 3 | 1 << 10).to_le_bytes());
+
+    plic.set_level(10, true);
+    plic.set_level(3, true);
+    assert_eq!(read_register(&mut plic, 0x1000), 1 << 3 | 1 << 10);
+    assert!(plic.context_pending(CONTEXT_SUPERVISOR));
+    assert!(!plic.context_pending(CONTEXT_MACHINE));
+
+    //The claims go by priority then nothing is left
+    assert_eq!(read_register(&mut plic, CLAIM), 3);
+    assert_eq!(read_register(&mut plic, CLAIM), 10);
+    assert_eq!(read_register(&mut plic, CLAIM), 0);
+    assert!(!plic.context_pending(CONTEXT_SUPERVISOR));
+
+    //A claimed source is ignored until completed
+    plic.set_level(3, true);
+    assert!(!plic.context_pending(CONTEXT_SUPERVISOR));
+    plic.write(CLAIM, &3u32.to_le_bytes());
+    plic.set_level(3, true);
+    assert!(plic.context_pending(CONTEXT_SUPERVISOR));
+
+    //Only the priorities above the threshold interrupt
+    plic.write(0x20_1000, &5u32.to_le_bytes());
+    assert!(!plic.context_pending(CONTEXT_SUPERVISOR));
+}
+
+#[test]
+fn uart_registers(){
+    const THR: u64 = 0;
+    const IER: u64 = 1;
+    const IIR: u64 = 2;
+    const LCR: u64 = 3;
+    const LSR: u64 = 5;
+    let mut uart = Uart::new(HostOutput::Buffer(Vec::new()));
+
+    //The transmitter is always empty and ready
+    assert_eq!(read_register(&mut uart, LSR), 0x60);
+    uart.write(THR, b"h");
+    uart.write(THR, b"i");
+    match &uart.output{
+        HostOutput::Buffer(buf) => assert_eq!(buf, b"hi"),
+        HostOutput::Stdout => unreachable!(),
+    }
+
+    //Received bytes set data ready and raise the interrupt when enabled
+    uart.write(IER, &[0b01]);
+    assert!(!uart.interrupt_pending());
+    uart.push_input(b"x");
+    assert_eq!(read_register(&mut uart, LSR), 0x61);
+    assert!(uart.interrupt_pending());
+    assert_eq!(read_register(&mut uart, IIR), 0x4);
+    assert_eq!(read_register(&mut uart, THR), b'x' as u32);
+    assert_eq!(read_register(&mut uart, LSR), 0x60);
+    assert!(!uart.interrupt_pending());
+
+    //Enabling the THR empty interrupt raises it until IIR is read
+    uart.write(IER, &[0b11]);
+    assert!(uart.interrupt_pending());
+    assert_eq!(read_register(&mut uart, IIR), 0x2);
+    assert_eq!(read_register(&mut uart, IIR), 0x1);
+
+    //With DLAB set the first registers hold the divisor
+    uart.write(LCR, &[0x80]);
+    uart.write(THR, &[3]);
+    uart.write(IER, &[0]);
+    assert_eq!(read_register(&mut uart, THR), 3);
+    uart.write(LCR, &[0x03]);
+    assert_eq!(read_register(&mut uart, IER), 0b11);
+}
+
+#[test]
+fn cpu_faults(){
+    let code = |words: &[u32]| -> Vec<u8> { words.iter().flat_map(|word| word.to_le_bytes()).collect() };
+
+    //Without trap handler the execution stops on the faulting instruction
+    let mut cpu = CPU::new(false);
+    let fault = try_run_bytes(&mut cpu, &code(&[NOP, 0x0000_200F, NOP]));
+    assert_eq!(fault, Err(CpuFault::IllegalInstruction{ pc: CODE_BASE + 4, tval: 0x200F }));
+    assert_eq!(cpu.registers.pc, CODE_BASE + 4);
+    assert_eq!(cpu.instret, 1);
+
+    //LD x2, 8(x1) reports the address
+    let mut cpu = CPU::new(false);
+    cpu.registers.common[1] = 0xDEAD_0000;
+    let fault = try_run_bytes(&mut cpu, &code(&[8 << 20 | 1 << 15 | 0b011 << 12 | 2 << 7 | 0b000_0011]));
+    assert_eq!(fault, Err(CpuFault::MemoryFault{
+        pc: CODE_BASE,
+        error: MemoryError{ addr: 0xDEAD_0008, size: 8, kind: AccessKind::Read, violation: Violation::Unmapped },
+    }));
+    assert_eq!(fault.unwrap_err().tval(), 0xDEAD_0008);
+
+    //EBREAK faults instead of ending the run
+    let mut cpu = CPU::new(false);
+    let fault = try_run_bytes(&mut cpu, &code(&[0x0010_0073]));
+    assert_eq!(fault.map_err(|fault| fault.pc()), Err(CODE_BASE));
+}
+
+#[test]
+fn memory_errors(){
+    let mut memory = Memory::new();
+    memory.allocate(0x1_0000, 0x1000, &[], Permissions::RW);
+    let mut buf = [0u8; 8];
+    assert_eq!(memory.read(0x1_0FF8, &mut buf), Ok(()));
+
+    //Unmapped, past the end of the region and wrapping accesses
+    assert_eq!(memory.read(0x2_0000, &mut buf), Err(MemoryError{ addr: 0x2_0000, size: 8, kind: AccessKind::Read, violation: Violation::Unmapped }));
+    assert_eq!(memory.read(0x1_0FFC, &mut buf), Err(MemoryError{ addr: 0x1_0FFC, size: 8, kind: AccessKind::Read, violation: Violation::Unmapped }));
+    assert_eq!(memory.write(0x1_0FFC, &buf), Err(MemoryError{ addr: 0x1_0FFC, size: 8, kind: AccessKind::Write, violation: Violation::Unmapped }));
+    assert!(memory.write(u64::MAX - 3, &buf).is_err());
+
+    //A failed write changes nothing
+    assert_eq!(memory.read(0x1_0FF8, &mut buf), Ok(()));
+    assert_eq!(buf, [0; 8]);
+}
+
+#[test]
+fn memory_permissions(){
+    const READ_ONLY: Permissions = Permissions{ read: true, write: false, execute: false };
+    let mut memory = Memory::new();
+    memory.allocate(0x1_0000, 0x1000, &[], READ_ONLY);
+    let mut buf = [0u8; 4];
+    assert_eq!(memory.read(0x1_0000, &mut buf), Ok(()));
+    assert_eq!(memory.write(0x1_0000, &buf), Err(MemoryError{ addr: 0x1_0000, size: 4, kind: AccessKind::Write, violation: Violation::Denied }));
+    assert_eq!(memory.fetch(0x1_0000, &mut buf), Err(MemoryError{ addr: 0x1_0000, size: 4, kind: AccessKind::Execute, violation: Violation::Denied }));
+
+    //The hart reports them as access faults
+    let mut cpu = CPU::new(false);
+    cpu.memory.allocate(DATA_BASE, CODE_SIZE, &[], READ_ONLY);
+    cpu.registers.common[1] = DATA_BASE;
+    let fault = try_run_bytes(&mut cpu, &sd(0, 1, 8).to_le_bytes());
+    assert_eq!(fault, Err(CpuFault::MemoryFault{
+        pc: CODE_BASE,
+        error: MemoryError{ addr: DATA_BASE + 8, size: 8, kind: AccessKind::Write, violation: Violation::Denied },
+    }));
+
+    //JALR x0, 0(x1) to the data
+    let mut cpu = CPU::new(false);
+    map_data(&mut cpu, &[NOP as u64]);
+    cpu.registers.common[1] = DATA_BASE;
+    let fault = try_run_bytes(&mut cpu, &(1u32 << 15 | 0b110_0111).to_le_bytes());
+    assert_eq!(fault, Err(CpuFault::MemoryFault{
+        pc: DATA_BASE,
+        error: MemoryError{ addr: DATA_BASE, size: 2, kind: AccessKind::Execute, violation: Violation::Denied },
+    }));
+}
+
+#[test]
+fn poisoned_reads(){
+    //LD x2, 0(x1)
+    let load: u32 = 1 << 15 | 0b011 << 12 | 2 << 7 | 0b000_0011;
+    let setup = || {
+        let mut cpu = CPU::new(false);
+        cpu.memory.allocate_uninitialized(DATA_BASE, CODE_SIZE, Permissions::RW);
+        cpu.registers.common[1] = DATA_BASE;
+        cpu
+    };
+
+    //The first byte never written is reported
+    let mut cpu = setup();
+    cpu.memory.write(DATA_BASE, &[1, 2, 3]).unwrap();
+    let fault = try_run_bytes(&mut cpu, &load.to_le_bytes());
+    assert_eq!(fault, Err(CpuFault::UninitializedRead{ pc: CODE_BASE, tval: DATA_BASE + 3 }));
+
+    let mut cpu = setup();
+    cpu.memory.write(DATA_BASE, &[0; 8]).unwrap();
+    run_code(&mut cpu, &[load]);
+    assert_eq!(cpu.memory.uninitialized(DATA_BASE, 16), Some(DATA_BASE + 8));
+
+    //Stores never read the memory and the snapshots restore the shadow
+    let mut cpu = setup();
+    cpu.memory.save_state();
+    run_code(&mut cpu, &[sd(1, 1, 8)]);
+    assert_eq!(cpu.memory.uninitialized(DATA_BASE + 8, 8), None);
+    cpu.memory.reset_to_saved_state();
+    assert_eq!(cpu.memory.uninitialized(DATA_BASE + 8, 8), Some(DATA_BASE + 8));
+
+    //The regions without shadow are always initialized
+    let mut memory = Memory::new();
+    memory.allocate(DATA_BASE, CODE_SIZE, &[], Permissions::RW);
+    assert_eq!(memory.uninitialized(DATA_BASE, 8), None);
+}
+
+#[test]
+fn buffer_across_regions(){
+    const BRK: u64 = 0x10_0000;
+    let mut cpu = CPU::new(false);
+    cpu.process.set_brk(BRK);
+    //Each call to brk maps the new pages in a region of their own
+    for end in [BRK + 0x800, BRK + 0x1800]{
+        cpu.registers.common[10] = end;
+        assert_eq!(process::brk(&mut cpu), end);
+    }
+
+    //The buffer of a read syscall filled across both growths
+    let data: Vec<u8> = (0..32).collect();
+    assert_eq!(process::copy_out(&mut cpu, BRK + 0xff0, &data).unwrap(), 32);
+    assert_eq!(read_bytes(&cpu.memory, BRK + 0xff0, 32), data);
+    assert!(cpu.memory.is_mapped(BRK + 0xff0, 0x1010));
+    assert_eq!(cpu.memory.uninitialized(BRK + 0xff0, 40), Some(BRK + 0x1010));
+
+    //SD x1, 0(x2) across them, then LD x3, 0(x2)
+    cpu.registers.common[1] = 0x1122_3344_5566_7788;
+    cpu.registers.common[2] = BRK + 0xffc;
+    run_code(&mut cpu, &[sd(1, 2, 0), 2 << 15 | 0b011 << 12 | 3 << 7 | 0b000_0011]);
+    assert_eq!(cpu.registers.common[3], 0x1122_3344_5566_7788);
+
+    //Every region on the way is checked, past the end of the second one,
+    //for its permissions and for its poisoned bytes
+    let error = |addr, kind, violation| MemoryError{ addr, size: 32, kind, violation };
+    assert_eq!(cpu.memory.check(BRK + 0x1ff0, 32, AccessKind::Read), Err(error(BRK + 0x1ff0, AccessKind::Read, Violation::Unmapped)));
+    assert!(cpu.memory.protect(BRK + 0x1000, 0x1000, Permissions{ read: true, write: false, execute: false }));
+    assert_eq!(cpu.memory.check(BRK + 0xff0, 32, AccessKind::Write), Err(error(BRK + 0xff0, AccessKind::Write, Violation::Denied)));
+    assert!(cpu.memory.set_shadow(BRK + 0x1008, 1, Shadow::Poisoned));
+    assert_eq!(cpu.memory.check(BRK + 0xff0, 32, AccessKind::Read), Err(error(BRK + 0x1008, AccessKind::Read, Violation::Poisoned)));
+}
+
+#[test]
+fn stack_growth(){
+    const TOP: u64 = 0x10_0000;
+    let stack = Stack{ top: TOP, size: 0x1000, limit: 0x4000 };
+    let error = |addr, kind, violation| MemoryError{ addr, size: 8, kind, violation };
+    let mut memory = Memory::new();
+    assert!(memory.map_stack(stack));
+
+    //The stores below the stack map the pages down to them, uninitialized
+    memory.write(TOP - 0x2ff8, &[1; 8]).unwrap();
+    assert!(memory.is_mapped(TOP - 0x3000, 0x3000));
+    assert_eq!(memory.uninitialized(TOP - 0x3000, 16), Some(TOP - 0x3000));
+    //The loads do not make it grow
+    let mut buf = [0u8; 8];
+    assert_eq!(memory.read(TOP - 0x3ff8, &mut buf), Err(error(TOP - 0x3ff8, AccessKind::Read, Violation::Unmapped)));
+
+    //It grows up to its limit, the page under it is the guard
+    memory.write(TOP - 0x4000, &[2; 8]).unwrap();
+    assert_eq!(memory.write(TOP - 0x4008, &[3; 8]), Err(error(TOP - 0x4008, AccessKind::Write, Violation::StackOverflow)));
+    assert_eq!(memory.write(TOP - 0x5008, &[3; 8]), Err(error(TOP - 0x5008, AccessKind::Write, Violation::Unmapped)));
+
+    //A mapping below the stack stops it and is left untouched
+    let mut memory = Memory::new();
+    assert!(memory.map_stack(stack));
+    memory.allocate(TOP - 0x3000, 0x1000, &[4; 0x1000], Permissions::RW);
+    memory.write(TOP - 0x1ff8, &[1; 8]).unwrap();
+    assert_eq!(memory.write(TOP - 0x3ff8, &[3; 8]), Err(error(TOP - 0x3ff8, AccessKind::Write, Violation::StackOverflow)));
+    assert!(!memory.is_mapped(TOP - 0x4000, 8));
+    assert_eq!(read_bytes(&memory, TOP - 0x3000, 0x1000), vec![4; 0x1000]);
+}
+
+/// Entry points of the hooked malloc and free of the heap tests
+const MALLOC: u64 = CODE_BASE + 0x800;
+const FREE: u64 = CODE_BASE + 0x900;
+
+/// JAL rd, to, the instruction being at at
+fn jal(rd: u32, at: u64, to: u64) -> u32{
+    let offset = to.wrapping_sub(at) as u32;
+    (offset >> 20 & 1) << 31 | (offset >> 1 & 0x3FF) << 21 | (offset >> 11 & 1) << 20 | (offset >> 12 & 0xFF) << 12 | rd << 7 | 0b110_1111
+}
+
+/// Hart with the heap hooks set up like for a program defining malloc
+fn heap_cpu() -> CPU{
+    let mut cpu = CPU::new(false);
+    cpu.memory.allocate_uninitialized(HEAP_BASE, HEAP_SIZE, Permissions::RW);
+    cpu.memory.set_shadow(HEAP_BASE, HEAP_SIZE, Shadow::Poisoned);
+    cpu.heap = Some(Heap::new());
+    cpu.set_breakpoint(MALLOC, heap::malloc);
+    cpu.set_breakpoint(FREE, heap::free);
+    cpu
+}
+
+#[test]
+fn heap_faults(){
+    const FIRST_CHUNK: u64 = HEAP_BASE + 32;
+    //ADDI a0, x0, 24 then ADDI s0, a0, 0 and ADDI a0, s0, 0
+    const SIZE_24: u32 = 0x0180_0513;
+    const SAVE_A0: u32 = 0x0005_0413;
+    const RESTORE_A0: u32 = 0x0004_0513;
+    let code = |words: &[u32]| -> Vec<u8> { words.iter().flat_map(|word| word.to_le_bytes()).collect() };
+
+    //The chunk is usable but its redzone is not, SB x0, 24(a0)
+    let mut cpu = heap_cpu();
+    let fault = try_run_bytes(&mut cpu, &code(&[SIZE_24, jal(1, CODE_BASE + 4, MALLOC), 10 << 15 | 23 << 7 | 0b010_0011, 10 << 15 | 24 << 7 | 0b010_0011]));
+    assert_eq!(cpu.registers.common[10], FIRST_CHUNK);
+    assert_eq!(fault, Err(CpuFault::HeapOverflow{
+        pc: CODE_BASE + 12,
+        error: MemoryError{ addr: FIRST_CHUNK + 24, size: 1, kind: AccessKind::Write, violation: Violation::Poisoned },
+    }));
+
+    //LB x5, 0(s0) once freed
+    let mut cpu = heap_cpu();
+    let fault = try_run_bytes(&mut cpu, &code(&[
+        SIZE_24, jal(1, CODE_BASE + 4, MALLOC), SAVE_A0, jal(1, CODE_BASE + 12, FREE), 8 << 15 | 5 << 7 | 0b000_0011,
+    ]));
+    assert_eq!(fault, Err(CpuFault::UseAfterFree{
+        pc: CODE_BASE + 16,
+        error: MemoryError{ addr: FIRST_CHUNK, size: 1, kind: AccessKind::Read, violation: Violation::Poisoned },
+    }));
+    assert!(cpu.heap.as_ref().unwrap().chunk_near(FIRST_CHUNK).unwrap().freed_at.is_some());
+
+    //The second free is reported at its call
+    let mut cpu = heap_cpu();
+    let fault = try_run_bytes(&mut cpu, &code(&[
+        SIZE_24, jal(1, CODE_BASE + 4, MALLOC), SAVE_A0, jal(1, CODE_BASE + 12, FREE),
+        RESTORE_A0, jal(1, CODE_BASE + 20, FREE),
+    ]));
+    assert_eq!(fault, Err(CpuFault::DoubleFree{ pc: CODE_BASE + 20, tval: FIRST_CHUNK }));
+
+    //Freeing an address that is not a chunk, ADDI a0, x0, 16
+    let mut cpu = heap_cpu();
+    let fault = try_run_bytes(&mut cpu, &code(&[0x0100_0513, jal(1, CODE_BASE + 4, FREE)]));
+    assert_eq!(fault, Err(CpuFault::InvalidFree{ pc: CODE_BASE + 4, tval: 16 }));
+}
+
+/// PT_LOAD segment with the permissions given as PF_ flags
+fn load_segment(offset: u64, vaddr: u64, filesz: u64, memsz: u64, flags: u32) -> elf::types::ProgramHeader{
+    elf::types::ProgramHeader{
+        progtype: elf::types::PT_LOAD,
+        offset,
+        vaddr,
+        paddr: vaddr,
+        filesz,
+        memsz,
+        flags: elf::types::ProgFlag(flags),
+        align: 0x1000,
+    }
+}
+
+#[test]
+fn bss_zero_fill(){
+    const RW: u32 = 0b110;
+    let file: Vec<u8> = (1..=0x40).collect();
+
+    //The bytes past p_filesz and up to the end of the page are zeros
+    let segments = elf_reader::load_segments(&file, &[load_segment(0x10, 0x1_0010, 0x20, 0x100, RW)], 0).unwrap();
+    assert_eq!(segments.len(), 1);
+    let segment = &segments[0];
+    assert_eq!(segment.addr, 0x1_0000);
+    assert_eq!(segment.data.len(), 0x1000);
+    assert_eq!(&segment.data[0x10..0x30], &file[0x10..0x30]);
+    assert!(segment.data[..0x10].iter().chain(&segment.data[0x30..]).all(|&b| b == 0));
+    assert_eq!(segment.permissions, Permissions::RW);
+
+    //A .bss only segment takes no byte of the file
+    let segments = elf_reader::load_segments(&file, &[load_segment(0x40, 0x2_0000, 0, 0x1800, RW)], 0).unwrap();
+    assert_eq!(segments[0].data, vec![0; 0x2000]);
+
+    //A code and a data segment sharing a page keep their own permissions
+    const RX: u32 = 0b101;
+    let segments = elf_reader::load_segments(&file, &[
+        load_segment(0, 0x3_0000, 0x20, 0x20, RX),
+        load_segment(0x20, 0x3_0020, 0x20, 0x40, RW),
+    ], 0).unwrap();
+    let layout: Vec<(u64, usize, Permissions)> = segments.iter().map(|s| (s.addr, s.data.len(), s.permissions)).collect();
+    let rx = Permissions{ read: true, write: false, execute: true };
+    assert_eq!(layout, vec![(0x3_0000, 0x20, rx), (0x3_0020, 0xfe0, Permissions::RW)]);
+    assert_eq!(&segments[0].data[..], &file[..0x20]);
+    assert_eq!(&segments[1].data[..0x20], &file[0x20..0x40]);
+
+    //Only the bytes in both overlapping segments get both permissions
+    let segments = elf_reader::load_segments(&file, &[
+        load_segment(0, 0x3_0010, 0x20, 0x20, RX),
+        load_segment(0x20, 0x3_0020, 0x20, 0x20, RW),
+    ], 0).unwrap();
+    let layout: Vec<(u64, usize, Permissions)> = segments.iter().map(|s| (s.addr, s.data.len(), s.permissions)).collect();
+    assert_eq!(layout, vec![(0x3_0000, 0x20, rx), (0x3_0020, 0x10, Permissions::RWX), (0x3_0030, 0xfd0, Permissions::RW)]);
+
+    //Malformed segments are refused
+    assert!(elf_reader::load_segments(&file, &[load_segment(0, 0x1_0000, 0x20, 0x10, RW)], 0).is_err());
+    assert!(elf_reader::load_segments(&file, &[load_segment(0x30, 0x1_0000, 0x20, 0x20, RW)], 0).is_err());
+    assert!(elf_reader::load_segments(&file, &[load_segment(0, u64::MAX - 0x10, 0, 0x20, RW)], 0).is_err());
+}
+
+#[test]
+fn pie_relocation(){
+    const BASE: u64 = 0x2000_0000;
+    const DT_RELA: u64 = 7;
+    const DT_RELASZ: u64 = 8;
+    const DT_RELAENT: u64 = 9;
+    const R_RISCV_NONE: u64 = 0;
+    const R_RISCV_RELATIVE: u64 = 3;
+    const R_RISCV_IRELATIVE: u64 = 58;
+
+    let words = |words: &[u64]| -> Vec<u8> { words.iter().flat_map(|w| w.to_le_bytes()).collect() };
+    let mut image = vec![0u8; 0x400];
+    image[0x100..0x140].copy_from_slice(&words(&[DT_RELA, 0x200, DT_RELASZ, 48, DT_RELAENT, 24, 0, 0]));
+    image[0x200..0x230].copy_from_slice(&words(&[0x300, R_RISCV_RELATIVE, 0x40, 0x308, R_RISCV_NONE, 0x50]));
+
+    let phdr = |progtype, vaddr, size| elf::types::ProgramHeader{
+        progtype, offset: vaddr, vaddr, paddr: vaddr, filesz: size, memsz: size,
+        flags: elf::types::PF_R, align: 8,
+    };
+    let phdrs = [phdr(elf::types::PT_LOAD, 0, 0x400), phdr(elf::types::PT_DYNAMIC, 0x100, 0x40)];
+
+    //The addresses of the image and the addends are moved by the base
+    let mut segments = vec![LoadedSegment{ addr: BASE, data: image.clone(), permissions: Permissions::RW }];
+    let count = elf_reader::relocate(&mut segments, &phdrs, BASE, elf::types::ELFCLASS64).unwrap();
+    assert_eq!(count, 1);
+    let word = |segments: &[LoadedSegment], at: usize| u64::from_le_bytes(segments[0].data[at..at + 8].try_into().unwrap());
+    assert_eq!(word(&segments, 0x300), BASE + 0x40);
+    assert_eq!(word(&segments, 0x308), 0);
+
+    //IRELATIVE and the other types not handled are left to the libc
+    image[0x220..0x228].copy_from_slice(&R_RISCV_IRELATIVE.to_le_bytes());
+    let mut segments = vec![LoadedSegment{ addr: BASE, data: image, permissions: Permissions::RW }];
+    assert_eq!(elf_reader::relocate(&mut segments, &phdrs, BASE, elf::types::ELFCLASS64), Ok(1));
+    assert_eq!(word(&segments, 0x308), 0);
+
+    //Without dynamic section there is nothing to do
+    assert_eq!(elf_reader::relocate(&mut segments, &phdrs[..1], BASE, elf::types::ELFCLASS64), Ok(0));
+}
+
+#[test]
+fn sysroot_paths(){
+    let mut process = Process::new();
+    //No file can be opened without a sysroot
+    assert_eq!(process.host_path("/lib/libc.so.6"), None);
+
+    process.sysroot = Some(PathBuf::from("/opt/sysroot"));
+    assert_eq!(process.host_path("/lib/libc.so.6"), Some(PathBuf::from("/opt/sysroot/lib/libc.so.6")));
+    assert_eq!(process.host_path("lib/ld.so"), Some(PathBuf::from("/opt/sysroot/lib/ld.so")));
+    //The guest cannot leave the sysroot, .. stops at its root
+    assert_eq!(process.host_path("/lib/../../etc/passwd"), Some(PathBuf::from("/opt/sysroot/etc/passwd")));
+
+    //The symbolic links are followed inside the sysroot
+    let sysroot = std::env::temp_dir().join(format!("sysroot-test-{}", std::process::id()));
+    fs::create_dir_all(sysroot.join("usr/lib")).unwrap();
+    std::os::unix::fs::symlink("/usr/lib", sysroot.join("lib")).unwrap();
+    std::os::unix::fs::symlink("../../..", sysroot.join("usr/lib/up")).unwrap();
+    process.sysroot = Some(sysroot.clone());
+    assert_eq!(process.host_path("/lib/libc.so.6"), Some(sysroot.join("usr/lib/libc.so.6")));
+    assert_eq!(process.host_path("/lib/up/etc/passwd"), Some(sysroot.join("etc/passwd")));
+    fs::remove_dir_all(&sysroot).unwrap();
+}
+
+#[test]
+fn initial_stack_layout(){
+    const TOP: u64 = 0x7001_0000;
+    let mut cpu = CPU::new(false);
+    cpu.memory.allocate(TOP - 0x1_0000, 0x1_0000, &[], Permissions::RW);
+
+    let argv = [String::from("prog"), String::from("arg")];
+    let envp = [String::from("HOME=/")];
+    let sp = process::initial_stack(&mut cpu, TOP, &argv, &envp, &[(AT_PAGESZ, 0x1000)]).unwrap();
+    assert_eq!(sp % 16, 0);
+
+    //argc, argv, envp then the auxiliary vector, each list ends with 0
+    let memory = &cpu.memory;
+    let word = |i: u64| read_u64(memory, sp + 8 * i);
+    assert_eq!(word(0), 2);
+    assert_eq!(read_cstring(memory, word(1)), "prog");
+    assert_eq!(read_cstring(memory, word(2)), "arg");
+    assert_eq!(word(3), 0);
+    assert_eq!(read_cstring(memory, word(4)), "HOME=/");
+    assert_eq!(word(5), 0);
+    assert_eq!((word(6), word(7)), (AT_PAGESZ, 0x1000));
+    assert_eq!(word(8), AT_RANDOM);
+    assert!(word(9) > sp && word(9) + 16 <= TOP);
+    assert_eq!((word(10), word(11)), (AT_NULL, 0));
+}
+
+
+/// Layout of the virtqueue used by the device tests
+const QUEUE_DESC: u64 = 0x1000;
+const QUEUE_DRIVER: u64 = 0x2000;
+const QUEUE_DEVICE: u64 = 0x3000;
+const QUEUE_BUFFERS: u64 = 0x4000;
+const QUEUE_BUFFER_SIZE: u64 = 0x400;
+
+#[test]
+fn write_syscall(){
+    //Returns a0 after writing len bytes at ptr to fd, DATA_BASE is mapped
+    let write_at = |fd: u64, ptr: u64, len: u64| {
+        let mut cpu = CPU::new(false);
+        let size = MAX_WRITE + CODE_SIZE;
+        cpu.memory.allocate(DATA_BASE, size, &vec![b'a'; size as usize], Permissions::RW);
+        cpu.registers.common[10] = fd;
+        cpu.registers.common[11] = ptr;
+        cpu.registers.common[12] = len;
+        cpu.registers.common[17] = 64;
+        run_code(&mut cpu, &[OPCODE_SYSTEM]);
+        cpu.registers.common[10]
+    };
+    let write = |fd: u64, len: u64| write_at(fd, DATA_BASE, len);
+    assert_eq!(write(1, 5), 5);
+    assert_eq!(write(2, 5), 5);
+    //The other descriptors are read only files or not opened
+    assert_eq!(write(0, 5), EBADF);
+    assert_eq!(write(3, 5), EBADF);
+    //The length the guest gives is capped, the write is partial
+    assert_eq!(write(1, u64::MAX), MAX_WRITE);
+    assert_eq!(write(2, 1 << 40), MAX_WRITE);
+    assert_eq!(write(1, MAX_WRITE + CODE_SIZE + 1), MAX_WRITE);
+    assert_eq!(write_at(1, DATA_BASE + 2 * CODE_SIZE, u64::MAX), EFAULT);
+}
+
+fn virtio_queue() -> (Memory, Queue){
+    let mut memory = Memory::new();
+    memory.allocate(QUEUE_DESC, 0x5000, &[0; 0x5000], Permissions::RW);
+    let mut queue = Queue::default();
+    queue.num = 8;
+    queue.ready = true;
+    queue.desc = QUEUE_DESC;
+    queue.driver = QUEUE_DRIVER;
+    queue.device = QUEUE_DEVICE;
+    (memory, queue)
+}
+
+/// Make available a chain starting at descriptor 0 with one buffer per
+/// slice then one writable buffer per length
+fn make_available(memory: &mut Memory, readable: &[&[u8]], writable: &[u32]){
+    let count = readable.len() + writable.len();
+    for i in 0..count{
+        let addr = QUEUE_BUFFERS + QUEUE_BUFFER_SIZE * i as u64;
+        let (len, mut flags) = match readable.get(i){
+            Some(data) => {
+                memory.write(addr, data).unwrap();
+                (data.len() as u32, 0u16)
+            },
+            None => (writable[i - readable.len()], 2),
+        };
+        if i + 1 < count{
+            flags |= 1;
+        }
+
+        let mut desc = Vec::new();
+        desc.extend_from_slice(&addr.to_le_bytes());
+        desc.extend_from_slice(&len.to_le_bytes());
+        desc.extend_from_slice(&flags.to_le_bytes());
+        desc.extend_from_slice(&(i as u16 + 1).to_le_bytes());
+        memory.write(QUEUE_DESC + 16 * i as u64, &desc).unwrap();
+    }
+
+    let mut idx = [0u8; 2];
+    memory.read(QUEUE_DRIVER + 2, &mut idx).unwrap();
+    let idx = u16::from_le_bytes(idx);
+    memory.write(QUEUE_DRIVER + 4 + 2 * (idx % 8) as u64, &0u16.to_le_bytes()).unwrap();
+    memory.write(QUEUE_DRIVER + 2, &idx.wrapping_add(1).to_le_bytes()).unwrap();
+}
+
+fn read_bytes(memory: &Memory, at: u64, len: usize) -> Vec<u8>{
+    let mut buf = vec![0u8; len];
+    memory.read(at, &mut buf).unwrap();
+    buf
+}
+
+#[test]
+fn virtqueue_pop_push(){
+    let (mut memory, mut queue) = virtio_queue();
+    assert!(!queue.has_available(&memory));
+
+    make_available(&mut memory, &[b"hello", b" world"], &[4, 8]);
+    assert!(queue.has_available(&memory));
+    let chain = queue.pop(&memory).unwrap();
+    assert_eq!(chain.head, 0);
+    assert_eq!(chain.read(&memory).unwrap(), b"hello world");
+    assert_eq!(chain.writable_len(), 12);
+    assert!(!queue.has_available(&memory));
+
+    //The data is spread over the writable buffers
+    assert_eq!(chain.write(&mut memory, b"abcdef"), Some(6));
+    assert_eq!(read_bytes(&memory, QUEUE_BUFFERS + 2 * QUEUE_BUFFER_SIZE, 4), b"abcd");
+    assert_eq!(read_bytes(&memory, QUEUE_BUFFERS + 3 * QUEUE_BUFFER_SIZE, 2), b"ef");
+
+    queue.push_used(&mut memory, chain.head, 6).unwrap();
+    assert_eq!(read_bytes(&memory, QUEUE_DEVICE + 2, 2), [1, 0]);
+    assert_eq!(read_bytes(&memory, QUEUE_DEVICE + 4, 8), [0, 0, 0, 0, 6, 0, 0, 0]);
+
+    //write_at starts inside the writable buffers
+    assert_eq!(chain.write_at(&mut memory, 5, b"XY"), Some(2));
+    assert_eq!(read_bytes(&memory, QUEUE_BUFFERS + 3 * QUEUE_BUFFER_SIZE, 3), b"eXY");
+
+    //A chain looping on itself comes without buffers, it is still given
+    //back to the driver
+    make_available(&mut memory, &[b"loop"], &[]);
+    memory.write(QUEUE_DESC + 12, &[1, 0, 0, 0]).unwrap();
+    let chain = queue.pop(&memory).unwrap();
+    assert!(chain.read(&memory).unwrap().is_empty());
+    assert_eq!(chain.writable_len(), 0);
+    assert!(!queue.has_available(&memory));
+}
+
+/// Send a one sector request through the queue, returns the sector read
+fn blk_request(disk: &mut VirtioBlk, memory: &mut Memory, queue: &mut Queue, kind: u32, sector: u64, data: &[u8]) -> Vec<u8>{
+    let mut header = Vec::new();
+    header.extend_from_slice(&kind.to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.extend_from_slice(&sector.to_le_bytes());
+
+    if kind == 0{
+        make_available(memory, &[&header], &[SECTOR_SIZE as u32, 1]);
+    }
+    else{
+        make_available(memory, &[&header, data], &[1]);
+    }
+    assert!(disk.process_queue(0, queue, memory));
+
+    //The status always lands in the third buffer
+    assert_eq!(read_bytes(memory, QUEUE_BUFFERS + 2 * QUEUE_BUFFER_SIZE, 1), [0]);
+    if kind == 0{
+        read_bytes(memory, QUEUE_BUFFERS + QUEUE_BUFFER_SIZE, SECTOR_SIZE as usize)
+    }
+    else{
+        Vec::new()
+    }
+}
+
+#[test]
+fn virtio_blk_copy_on_write(){
+    const IN: u32 = 0;
+    const OUT: u32 = 1;
+    let image = vec![0xaau8; 4 * SECTOR_SIZE as usize];
+    let path = std::env::temp_dir().join(format!("virtio-blk-test-{}", std::process::id()));
+    fs::write(&path, &image).unwrap();
+
+    let mut disk = VirtioBlk::open(&path, true).unwrap();
+    let (mut memory, mut queue) = virtio_queue();
+    let mut config = [0u8; 8];
+    disk.read_config(0, &mut config);
+    assert_eq!(u64::from_le_bytes(config), 4);
+
+    blk_request(&mut disk, &mut memory, &mut queue, OUT, 1, &[0x11; SECTOR_SIZE as usize]);
+    assert_eq!(blk_request(&mut disk, &mut memory, &mut queue, IN, 1, &[]), [0x11; SECTOR_SIZE as usize]);
+    assert_eq!(blk_request(&mut disk, &mut memory, &mut queue, IN, 2, &[]), [0xaa; SECTOR_SIZE as usize]);
+
+    //The sectors written after the snapshot are rolled back
+    disk.save_state();
+    blk_request(&mut disk, &mut memory, &mut queue, OUT, 1, &[0x22; SECTOR_SIZE as usize]);
+    blk_request(&mut disk, &mut memory, &mut queue, OUT, 2, &[0x33; SECTOR_SIZE as usize]);
+    assert_eq!(blk_request(&mut disk, &mut memory, &mut queue, IN, 1, &[]), [0x22; SECTOR_SIZE as usize]);
+    disk.reset_to_saved_state();
+    assert_eq!(blk_request(&mut disk, &mut memory, &mut queue, IN, 1, &[]), [0x11; SECTOR_SIZE as usize]);
+    assert_eq!(blk_request(&mut disk, &mut memory, &mut queue, IN, 2, &[]), [0xaa; SECTOR_SIZE as usize]);
+
+    //A request shorter than its header fails with an I/O error
+    make_available(&mut memory, &[b"short"], &[1]);
+    assert!(disk.process_queue(0, &mut queue, &mut memory));
+    assert_eq!(read_bytes(&memory, QUEUE_BUFFERS + QUEUE_BUFFER_SIZE, 1), [1]);
+
+    //The image itself is never written
+    assert_eq!(fs::read(&path).unwrap(), image);
+    fs::remove_file(&path).unwrap();
+}
+
+fn start_test_elf(path: &Path){
     let mut cpu: CPU = CPU::new(true);
 
-    let elf = match elf::File::open_path(&path) {
+    let elf = match elf::File::open_path(path) {
         Ok(f) => f,
         Err(e) => panic!("Error {:?}", e)
     };
 
     let entry_point = elf.ehdr.entry;
     println!("Entry point: {:#X}", entry_point);
+    cpu.set_xlen(elf_reader::xlen(elf.ehdr.class));
+
+    let file = fs::read(path).expect("Couldnt read the test");
+    let segments = elf_reader::load_segments(&file, &elf.phdrs, 0)
+        .unwrap_or_else(|e| panic!("Error {}", e));
+
+    println!("Mapping memory:");
+    for s in segments{
+        println!("  * {:08X} ({}b)", s.addr, s.data.len());
+        cpu.memory.allocate(s.addr, s.data.len() as u64, &s.data, s.permissions);
+    }
 
     let mut symtab: Option<elf::Section> = None;
     let mut strtab: Option<elf::Section> = None;
-    
-    println!("Mapping memory:");
-    for s in elf.sections{
-        if (s.shdr.flags.0 & elf::types::SHF_ALLOC.0) != 0 {
-            cpu.memory.allocate(s.shdr.addr, s.shdr.size, &s.data);
-            println!("  * {:}", s.shdr.name);
-        }
 
+    for s in elf.sections{
         match s.shdr.name.as_ref() {
             ".symtab" => {
-                symtab = Some(s); 
+                symtab = Some(s);
             },
             ".strtab" => {
                 strtab = Some(s);
@@ -46,12 +1761,12 @@ fn start_test_elf(path: &PathBuf){
             _ => {},
         }
     }
-    
+
     let symtab = symtab.expect("Symtab memory region not found in ELF");
     let strtab = strtab.expect("Strtab memory region not found in ELF");
 
-    let symbols =  elf_reader::read_symbols_list(symtab, strtab);
-    
+    let symbols =  elf_reader::read_symbols_list(symtab, strtab, elf.ehdr.class, 0);
+
     //Set a breakpoint on the success function of the test
     if let Some(addr) = symbols.get("pass"){
         println!("Breakpoint set at pass ({:#8X})", addr);
@@ -60,7 +1775,7 @@ fn start_test_elf(path: &PathBuf){
     else{
         println!("Couldnt find pass in exported symbols");
     }
-    
+
     //Set a breakpoint on the failure function of the test
     if let Some(addr) = symbols.get("fail"){
         println!("Breakpoint set at fail ({:#8X})", addr);
@@ -70,25 +1785,29 @@ fn start_test_elf(path: &PathBuf){
         println!("Couldnt find pass in exported symbols");
     }
 
-    //We start from the first test in order to skip the init part which requires 
-    //csrc extension
-    if let Some(entrypoint) = symbols.get("test_2"){
-        println!("Test_2 found at {:#8X}", entrypoint);
-        cpu.execute(*entrypoint);
-    }
-    else{
-        panic!("Couldnt find Test_2 in exported symbols");
-    }
+    //The tests run bare-metal from the reset vector, their init code sets up
+    //the trap handlers and drops to the tested privilege level
+    cpu.syscall_emulation = false;
+    cpu.execute(entry_point, Rc::new(RefCell::new(Fuzzer::new())))
+        .unwrap_or_else(|fault| panic!("{:X?} in {:?}", fault, path));
 }
 
-fn run_all_tests(){
-    let paths = read_dir("test/riscv-tests/").unwrap();
+#[test]
+fn riscv_tests(){
+    let paths = match read_dir("test/riscv-tests/"){
+        Ok(paths) => paths,
+        Err(_) => {
+            println!("No riscv-tests binaries in test/riscv-tests/");
+            return;
+        },
+    };
     let mut i = 0;
     for p in paths{
-        println!("Executing test: {:?}({:})", p, i);
-        start_test_elf(&p.unwrap().path());
+        let path: PathBuf = p.unwrap().path();
+        println!("Executing test: {:?}({:})", path, i);
+        start_test_elf(&path);
 
         i += 1;
     }
     println!("{:} tests passed", i);
-}
\ No newline at end of file
+}